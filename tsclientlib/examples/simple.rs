@@ -34,7 +34,8 @@ fn real_main() -> Result<(), failure::Error> {
     let args = Args::from_args();
     let mut core = Core::new()?;
 
-    let mut cm = ConnectionManager::new(core.handle());
+    let mut cm = ConnectionManager::builder().log_to_terminal()
+        .build(core.handle())?;
     let con_config = ConnectOptions::from_address(args.address);
 
     // Connect
@@ -42,7 +43,7 @@ fn real_main() -> Result<(), failure::Error> {
 
     {
         let con = cm.get_connection(con_id).unwrap();
-        let server = con.get_server();
+        let server = con.get_server().unwrap();
         println!("Server welcome message: {}", sanitize(&*server.get_welcome_message()));
     }
 
@@ -0,0 +1,149 @@
+use std::fs::File;
+use std::io::Read;
+
+use base64;
+use tomcrypt;
+use tsproto::algorithms as algs;
+
+use {Error, Result};
+
+/// The identity of a client.
+///
+/// An identity consists of a private key and the `key_offset`, which improves
+/// the security level of the key (see [`ConnectOptions::identity_ts`]).
+///
+/// [`ConnectOptions::identity_ts`]: struct.ConnectOptions.html#method.identity_ts
+#[derive(Debug)]
+pub struct Identity {
+    key: tomcrypt::EccKey,
+    key_offset: u64,
+    nickname: Option<String>,
+}
+
+impl Identity {
+    pub fn new(key: tomcrypt::EccKey, key_offset: u64) -> Self {
+        Self { key, key_offset, nickname: None }
+    }
+
+    /// Create an identity from a private key as it is encoded by TeamSpeak
+    /// (libtomcrypt export and base64 encoded).
+    ///
+    /// This is the reverse of [`export_ts`].
+    ///
+    /// [`export_ts`]: #method.export_ts
+    pub fn from_ts(private_key: &str, key_offset: u64) -> Result<Self> {
+        let key = tomcrypt::EccKey::import(&base64::decode(private_key)?)?;
+        Ok(Self::new(key, key_offset))
+    }
+
+    /// Export the private key like TeamSpeak encodes it (libtomcrypt export
+    /// and base64 encoded).
+    ///
+    /// The key can be used again with [`Identity::from_ts`] or
+    /// [`ConnectOptions::private_key_ts`]. The key offset has to be stored
+    /// separately.
+    ///
+    /// [`Identity::from_ts`]: #method.from_ts
+    /// [`ConnectOptions::private_key_ts`]: struct.ConnectOptions.html#method.private_key_ts
+    pub fn export_ts(&self) -> Result<String> {
+        Ok(base64::encode(&self.key.export_private()?))
+    }
+
+    /// Import an identity from a file which was exported by the TeamSpeak
+    /// client.
+    ///
+    /// The file contains an `[Identity]` section. The private key and offset
+    /// are read from the `identity` field and the name from the `nickname`
+    /// field.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the file cannot be read, if it contains no
+    /// identity or if the private key cannot be imported.
+    pub fn import_ts_ini(path: &str) -> Result<Self> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        let (identity, nickname) = parse_ts_ini(&content)?;
+        let mut res = Self::from_ts_exported(identity)?;
+        res.nickname = nickname;
+        Ok(res)
+    }
+
+    /// Import an identity string like it is stored by the TeamSpeak client.
+    ///
+    /// The string has the form `<offset>V<obfuscated key>`.
+    pub fn from_ts_exported(identity: &str) -> Result<Self> {
+        let pos = identity.find('V').ok_or_else(||
+            Error::InvalidIdentity("Missing key offset".into()))?;
+        let key_offset = identity[..pos].parse().map_err(|_|
+            Error::InvalidIdentity("Invalid key offset".into()))?;
+        let key = algs::deobfuscate_identity(
+            &base64::decode(&identity[pos + 1..])?)?;
+        Self::from_ts(&key, key_offset)
+    }
+
+    /// Improve the security level of the identity to at least `level`.
+    ///
+    /// The `key_offset` is increased until the level is reached. Computing a
+    /// level takes about twice as long as the level before, so this should
+    /// not run on the thread of the reactor. The identity can be exported
+    /// with [`export_ts`] and [`key_offset`] to pass it to the reactor.
+    ///
+    /// [`export_ts`]: #method.export_ts
+    /// [`key_offset`]: #method.key_offset
+    pub fn upgrade_level(&mut self, level: u8) -> Result<()> {
+        let omega = base64::encode(&self.key.export_public()?);
+        while algs::get_hash_cash_level(&omega, self.key_offset) < level {
+            self.key_offset += 1;
+        }
+        Ok(())
+    }
+
+    /// The security level of the identity.
+    pub fn level(&self) -> Result<u8> {
+        let omega = base64::encode(&self.key.export_public()?);
+        Ok(algs::get_hash_cash_level(&omega, self.key_offset))
+    }
+
+    pub fn key(&self) -> &tomcrypt::EccKey { &self.key }
+    pub fn key_offset(&self) -> u64 { self.key_offset }
+    /// The name which was stored with the identity, if it was imported from
+    /// the TeamSpeak client.
+    pub fn nickname(&self) -> Option<&str> {
+        self.nickname.as_ref().map(|s| s.as_str())
+    }
+
+    pub(crate) fn into_parts(self) -> (tomcrypt::EccKey, u64, Option<String>) {
+        (self.key, self.key_offset, self.nickname)
+    }
+}
+
+/// Get the `identity` and `nickname` fields of the `[Identity]` section in an
+/// identity file of the TeamSpeak client.
+pub(crate) fn parse_ts_ini(content: &str) -> Result<(&str, Option<String>)> {
+    let mut in_section = false;
+    let mut identity = None;
+    let mut nickname = None;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_section = line == "[Identity]";
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+        if let Some(pos) = line.find('=') {
+            let value = line[pos + 1..].trim().trim_matches('"');
+            match line[..pos].trim() {
+                "identity" => identity = Some(value),
+                "nickname" if !value.is_empty() =>
+                    nickname = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    let identity = identity.ok_or_else(||
+        Error::InvalidIdentity("No identity found".into()))?;
+    Ok((identity, nickname))
+}
@@ -6,41 +6,68 @@
 #![allow(dead_code)]
 
 extern crate base64;
+extern crate byteorder;
 extern crate chrono;
 #[macro_use]
 extern crate failure;
 extern crate futures;
 #[macro_use]
 extern crate lazy_static;
+extern crate num;
+extern crate opus;
+extern crate rand;
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_perf;
 extern crate slog_term;
 extern crate tokio_core;
+extern crate tokio_io;
 extern crate tomcrypt;
+extern crate trust_dns_resolver;
 extern crate tsproto;
 extern crate tsproto_commands;
 
-use std::cell::{Ref, RefCell};
-use std::net::SocketAddr;
-use std::rc::Rc;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::rc::{Rc, Weak};
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use failure::{SyncFailure, ResultExt};
+use rand::Rng;
 use futures::{future, Future, Sink, Stream};
+use num::FromPrimitive;
 use slog::{Drain, Logger};
-use tokio_core::reactor::Handle;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
 use tsproto::algorithms as algs;
 use tsproto::{client, packets, commands};
 use tsproto::connectionmanager::ConnectionManager as TsprotoCM;
 use tsproto::connectionmanager::{Resender, ResenderEvent};
-use tsproto::packets::{Header, Packet, PacketType};
+use tsproto::packets::{Header, Packet};
 use tsproto_commands::*;
+use tsproto_commands::errors::Error as TsError;
 use tsproto_commands::messages::*;
+use tsproto_commands::permissions::Permission;
 
 // Reexports
+pub use slog_async::OverflowStrategy;
+pub use tsproto::algorithms::{CryptoParams, KeyDerivation};
+pub use tsproto::connection::{ConnectionStats, DropReason, DroppedPacketSink};
+pub use tsproto::packets::{CodecType, PacketType};
+pub use tsproto::resend::{ResendConfig, ResendState};
 pub use tsproto_commands::MoveReason;
+pub use identity::Identity;
+pub use resolver::ResolveStep;
+pub use structs::{ChannelListProgress, ChatMessage, DisconnectReason, Event,
+    OwnClientIdChanged, TalkRequest};
+pub use voice::{TalkControl, WhisperTarget, FRAME_SIZE, SAMPLE_RATE};
 
 macro_rules! copy_attrs {
     ($from:ident, $to:ident; $($attr:ident),* $(,)*; $($extra:ident: $ex:expr),* $(,)*) => {
@@ -60,19 +87,130 @@ macro_rules! tryf {
     };
 }
 
+mod identity;
+mod resolver;
 mod structs;
+mod voice;
 
 type Result<T> = std::result::Result<T, Error>;
+/// A connection after the handshake with the server.
+///
+/// The time is the estimated time when the server accepted our `clientinit`.
+type ConnectedClient = (
+    Weak<RefCell<client::ClientConnection>>,
+    Box<Stream<Item = Packet, Error = tsproto::errors::Error>>,
+    InitServer,
+    DateTime<Utc>,
+);
 type BoxFuture<T> = Box<Future<Item = T, Error = Error>>;
+type BoxStream<T> = Box<Stream<Item = T, Error = Error>>;
 type Map<K, V> = std::collections::HashMap<K, V>;
+type Set<T> = std::collections::HashSet<T>;
 
 #[derive(Fail, Debug)]
 pub enum Error {
     #[fail(display = "Connection failed ({})", _0)]
     ConnectionFailed(String),
+    #[fail(display = "The tokio reactor does not exist anymore")]
+    ReactorGone,
+    #[fail(display = "The connection does not exist")]
+    ConnectionNotFound,
+    #[fail(display = "The channel does not exist")]
+    ChannelNotFound,
+    #[fail(display = "Server error {:?}: {}", _0, _1)]
+    Server(TsError, String),
+    #[fail(display = "Invalid response from the server ({})", _0)]
+    InvalidResponse(String),
+    /// The server does not allow another connection with this identity.
+    ///
+    /// This can happen if a client reconnects before the server noticed that
+    /// the old connection is dead. Connecting again after the old session
+    /// timed out will succeed.
+    #[fail(display = "This identity is already connected to the server")]
+    IdentityAlreadyConnected,
+    /// The server requires a newer client version.
+    ///
+    /// Connecting again with a newer version in [`ConnectOptions`] can
+    /// succeed. `required` contains the minimum version if the server sent it.
+    ///
+    /// [`ConnectOptions`]: struct.ConnectOptions.html
+    #[fail(display = "The client version is too old (required: {:?})",
+        required)]
+    ClientTooOld { required: Option<String> },
+    /// The server rejected the connection because we are banned.
+    ///
+    /// `reason` contains the message of the server and `until` the end of the
+    /// ban, if the server sent them.
+    ///
+    /// Servers can also drop the packets of banned ip addresses without any
+    /// answer. This cannot be distinguished from a server which is offline
+    /// and results in a timeout.
+    #[fail(display = "Banned from the server ({:?}, until {:?})", reason,
+        until)]
+    Banned {
+        reason: Option<String>,
+        until: Option<DateTime<Utc>>,
+    },
+    /// The connection was closed while waiting for an answer of the server.
+    ///
+    /// Contains the reason if the server or our client told it.
+    #[fail(display = "The connection was closed ({:?})", _0)]
+    Disconnected(Option<MoveReason>),
+    /// The expected event did not happen in time.
+    #[fail(display = "Timed out")]
+    Timeout,
+    /// The client version in [`ConnectOptions`] is incomplete.
+    ///
+    /// [`ConnectOptions`]: struct.ConnectOptions.html
+    #[fail(display = "Invalid client version ({})", _0)]
+    InvalidVersion(String),
+    /// The [`CryptoParams`] in [`ConnectOptions`] cannot be used.
+    ///
+    /// [`CryptoParams`]: struct.CryptoParams.html
+    /// [`ConnectOptions`]: struct.ConnectOptions.html
+    #[fail(display = "Invalid crypto parameters ({})", _0)]
+    InvalidCryptoParams(String),
+    /// The server denied an action because our client lacks this
+    /// permission.
+    #[fail(display = "Missing permission {:?}", _0)]
+    MissingPermission(Permission),
+    /// The address of the server could not be resolved.
+    ///
+    /// `step` is the last step which was tried.
+    #[fail(display = "Failed to resolve the address ({:?}: {})", step,
+        message)]
+    Resolve { step: ResolveStep, message: String },
+    /// The server rejected our server password.
+    ///
+    /// The password is set with [`ConnectOptions::server_password`].
+    ///
+    /// [`ConnectOptions::server_password`]: struct.ConnectOptions.html#method.server_password
+    #[fail(display = "Wrong server password")]
+    InvalidServerPassword,
+    /// A text message is longer than [`MAX_MESSAGE_LENGTH`].
+    ///
+    /// [`MAX_MESSAGE_LENGTH`]: constant.MAX_MESSAGE_LENGTH.html
+    #[fail(display = "The message is too long ({} characters)", _0)]
+    MessageTooLong(usize),
+    /// Channel messages can only be sent to the channel of our own client.
+    #[fail(display = "Our client is not in the channel")]
+    NotInChannel,
+    /// An identity file or string could not be read.
+    #[fail(display = "Invalid identity ({})", _0)]
+    InvalidIdentity(String),
+    /// A whisper can address at most 255 channels and 255 clients.
+    #[fail(display = "Too many whisper targets")]
+    TooManyWhisperTargets,
+    /// A ban has to last longer than zero seconds.
+    #[fail(display = "Invalid ban duration ({})", _0)]
+    InvalidBanDuration(Duration),
     #[fail(display = "{}", _0)]
     Base64(#[cause] base64::DecodeError),
     #[fail(display = "{}", _0)]
+    Io(#[cause] std::io::Error),
+    #[fail(display = "{}", _0)]
+    Opus(#[cause] opus::Error),
+    #[fail(display = "{}", _0)]
     Tomcrypt(#[cause] SyncFailure<tomcrypt::errors::Error>),
     #[fail(display = "{}", _0)]
     Tsproto(tsproto::errors::Error),
@@ -92,6 +230,18 @@ impl From<tomcrypt::errors::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<opus::Error> for Error {
+    fn from(e: opus::Error) -> Self {
+        Error::Opus(e)
+    }
+}
+
 impl From<tsproto::errors::Error> for Error {
     fn from(e: tsproto::errors::Error) -> Self {
         Error::Tsproto(SyncFailure::new(e))
@@ -112,425 +262,4512 @@ pub enum ChannelType {
     Temporary,
 }
 
-include!(concat!(env!("OUT_DIR"), "/facades.rs"));
+/// The maximum size of a server snapshot in bytes.
+const MAX_SNAPSHOT_SIZE: usize = 16 * 1024 * 1024;
+/// The maximum size of a downloaded icon in bytes.
+///
+/// The size is sent by the server and the memory for the file is allocated
+/// before it is downloaded.
+const MAX_ICON_SIZE: usize = 1024 * 1024;
 
-lazy_static! {
-    /// Initialize `tsproto`
-    static ref TSPROTO_INIT: () = tsproto::init()
-        .expect("tsproto failed to initialize");
+/// Statistics of a connection, which are collected when it is closed.
+#[derive(Debug, Clone)]
+pub struct ConnectionSummary {
+    /// The time since the connection was added.
+    pub uptime: Duration,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// How often command packets had to be sent again.
+    pub resent_packets: u64,
+    /// How often the connection was reconnected with
+    /// [`Connection::hard_reset`].
+    ///
+    /// [`Connection::hard_reset`]: struct.Connection.html#method.hard_reset
+    pub reconnects: u32,
+    /// The reason why the connection was closed, if it is known.
+    pub reason: Option<MoveReason>,
 }
 
-/// The connection manager which can be shared and cloned.
-struct InnerCM {
-    handle: Handle,
-    logger: Logger,
-    connections: Map<ConnectionId, structs::NetworkWrapper>,
+/// A snapshot of the state of a connection, e.g. for bug reports.
+///
+/// Returned by [`Connection::diagnostics`]. With the `serialize` feature, it
+/// can be serialized with serde.
+///
+/// [`Connection::diagnostics`]: struct.Connection.html#method.diagnostics
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct Diagnostics {
+    /// The state of the resender, `None` if the connection is already closed.
+    pub resend_state: Option<ResendState>,
+    /// The smoothed round trip time in milliseconds.
+    pub srtt_ms: Option<i64>,
+    /// The deviation of the smoothed round trip time in milliseconds.
+    pub srtt_dev_ms: Option<i64>,
+    /// The number of packets which were not yet acknowledged.
+    pub resend_queue_len: usize,
+    /// The number of commands which wait for an answer of the server.
+    pub pending_commands: usize,
+    /// The traffic, including the connections before a reset.
+    pub stats: ConnectionStats,
+    /// How often the connection was reset.
+    pub reconnects: u32,
+    /// The number of channels in the book.
+    pub channels: usize,
+    /// The number of clients in the book.
+    pub clients: usize,
+    pub server_version: String,
 }
 
-impl InnerCM {
-    /// Returns the first free connection id.
-    fn find_connection_id(&self) -> ConnectionId {
-        for i in 0..self.connections.len() + 1 {
-            let id = ConnectionId(i);
-            if !self.connections.contains_key(&id) {
-                return id;
-            }
-        }
-        unreachable!("Found no free connection id, this should not happen");
+/// A virtual server on the instance of the connected server.
+///
+/// Returned by [`Connection::server_list`].
+///
+/// [`Connection::server_list`]: struct.Connection.html#method.server_list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualServerInfo {
+    pub id: u64,
+    pub name: String,
+    pub port: u16,
+    pub status: VirtualServerStatus,
+    /// The number of connected clients, `0` if the server is offline.
+    pub clients_online: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VirtualServerStatus {
+    Online,
+    Offline,
+    /// Another status, e.g. `booting up`.
+    Other(String),
+}
+
+impl VirtualServerInfo {
+    fn from_row(row: &Map<String, String>) -> Result<Self> {
+        let status = match row.get("virtualserver_status").map(|s| s.as_str()) {
+            Some("online") => VirtualServerStatus::Online,
+            Some("offline") => VirtualServerStatus::Offline,
+            Some(s) => VirtualServerStatus::Other(s.to_string()),
+            None => return Err(Error::InvalidResponse(String::from(
+                "Cannot parse argument virtualserver_status"))),
+        };
+        Ok(Self {
+            id: parse_response_arg(row, "virtualserver_id")?,
+            name: row.get("virtualserver_name").cloned().unwrap_or_default(),
+            port: parse_response_arg(row, "virtualserver_port")?,
+            status,
+            clients_online: parse_response_arg(row,
+                "virtualserver_clientsonline").unwrap_or(0),
+        })
     }
 }
 
-/// The main type of this crate, which holds all connections.
-///
-/// It can be created with the [`ConnectionManager::new`] function:
+/// Detailed information about a client.
 ///
-/// ```
-/// let core = tokio_core::Core::new()?;
-/// let cm = ConnectionManager::new(core.handle());
-/// ```
+/// Returned by [`Connection::clients_info`].
 ///
-/// [`ConnectionManager::new`]: #method.new
-pub struct ConnectionManager {
-    inner: Rc<RefCell<InnerCM>>,
+/// [`Connection::clients_info`]: struct.Connection.html#method.clients_info
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    pub version: String,
+    pub platform: String,
+    pub login_name: String,
+    pub created: DateTime<Utc>,
+    pub last_connected: DateTime<Utc>,
+    /// How often the client connected to the server.
+    pub connection_total: u32,
+    pub month_bytes_uploaded: u64,
+    pub month_bytes_downloaded: u64,
+    pub total_bytes_uploaded: u64,
+    pub total_bytes_downloaded: u64,
 }
 
-impl ConnectionManager {
-    /// Creates a new `ConnectionManager` which is then used to add new
-    /// connections.
-    ///
-    /// ```
-    /// let core = tokio_core::Core::new()?;
-    /// let cm = ConnectionManager::new(core.handle());
-    /// ```
-    ///
-    /// Connecting to a server is done by [`ConnectionManager::add_connection`].
-    ///
-    /// [`ConnectionManager::add_connection`]: #method.add_connection
-    pub fn new(handle: Handle) -> Self {
-        // Initialize tsproto if it was not done yet
-        *TSPROTO_INIT;
+impl ClientInfo {
+    fn from_row(row: &Map<String, String>) -> Result<Self> {
+        Ok(Self {
+            id: ClientId(parse_response_arg(row, "clid")?),
+            version: row.get("client_version").cloned().unwrap_or_default(),
+            platform: row.get("client_platform").cloned().unwrap_or_default(),
+            login_name: row.get("client_login_name").cloned()
+                .unwrap_or_default(),
+            created: parse_timestamp_arg(row, "client_created")?,
+            last_connected: parse_timestamp_arg(row, "client_lastconnected")?,
+            connection_total: parse_response_arg(row,
+                "client_totalconnections").unwrap_or(0),
+            month_bytes_uploaded: parse_response_arg(row,
+                "client_month_bytes_uploaded").unwrap_or(0),
+            month_bytes_downloaded: parse_response_arg(row,
+                "client_month_bytes_downloaded").unwrap_or(0),
+            total_bytes_uploaded: parse_response_arg(row,
+                "client_total_bytes_uploaded").unwrap_or(0),
+            total_bytes_downloaded: parse_response_arg(row,
+                "client_total_bytes_downloaded").unwrap_or(0),
+        })
+    }
 
-        // TODO Create with builder so the logger is optional
-        // Don't log anything to console as default setting
-        // Option to log to a file
-        let logger = {
-            let decorator = slog_term::TermDecorator::new().build();
-            let drain = slog_term::FullFormat::new(decorator).build().fuse();
-            let drain = slog_async::Async::new(drain).build().fuse();
+    fn optional_data(&self) -> structs::OptionalClientData {
+        use structs::OptionalClientData;
+        copy_attrs!(self, OptionalClientData;
+            version,
+            platform,
+            login_name,
+            created,
+            last_connected,
+            connection_total,
+            month_bytes_uploaded,
+            month_bytes_downloaded,
+            total_bytes_uploaded,
+            total_bytes_downloaded,
+            ;
+        )
+    }
+}
 
-            slog::Logger::root(drain, o!())
-        };
+/// The entry of a client in the database of the server.
+///
+/// This is also available for clients which are offline. Returned by
+/// [`Connection::client_db_info`].
+///
+/// [`Connection::client_db_info`]: struct.Connection.html#method.client_db_info
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientDbInfo {
+    pub database_id: ClientDbId,
+    pub uid: Uid,
+    /// The last nickname of the client.
+    pub name: String,
+    pub description: String,
+    pub created: DateTime<Utc>,
+    pub last_connected: DateTime<Utc>,
+    /// How often the client connected to the server.
+    pub connection_total: u32,
+    /// The ip address of the last connection, if we are allowed to see it.
+    pub last_ip: Option<IpAddr>,
+    pub month_bytes_uploaded: u64,
+    pub month_bytes_downloaded: u64,
+    pub total_bytes_uploaded: u64,
+    pub total_bytes_downloaded: u64,
+}
 
-        Self {
-            inner: Rc::new(RefCell::new(InnerCM {
-                handle,
-                logger,
-                connections: Map::new(),
-            })),
-        }
+impl ClientDbInfo {
+    fn from_row(row: &Map<String, String>) -> Result<Self> {
+        let uid = row.get("client_unique_identifier").cloned()
+            .ok_or_else(|| Error::InvalidResponse(String::from(
+                "Cannot parse argument client_unique_identifier")))?;
+        Ok(Self {
+            database_id: ClientDbId(parse_response_arg(row,
+                "client_database_id")?),
+            uid: Uid(uid),
+            name: row.get("client_nickname").cloned().unwrap_or_default(),
+            description: row.get("client_description").cloned()
+                .unwrap_or_default(),
+            created: parse_timestamp_arg(row, "client_created")?,
+            last_connected: parse_timestamp_arg(row, "client_lastconnected")?,
+            connection_total: parse_response_arg(row,
+                "client_totalconnections").unwrap_or(0),
+            // The ip is empty if we are not allowed to see it
+            last_ip: row.get("client_lastip").and_then(|ip| ip.parse().ok()),
+            month_bytes_uploaded: parse_response_arg(row,
+                "client_month_bytes_uploaded").unwrap_or(0),
+            month_bytes_downloaded: parse_response_arg(row,
+                "client_month_bytes_downloaded").unwrap_or(0),
+            total_bytes_uploaded: parse_response_arg(row,
+                "client_total_bytes_uploaded").unwrap_or(0),
+            total_bytes_downloaded: parse_response_arg(row,
+                "client_total_bytes_downloaded").unwrap_or(0),
+        })
     }
+}
 
-    /// Connect to a server.
-    pub fn add_connection(&mut self, mut config: ConnectOptions)
-        -> BoxFuture<ConnectionId> {
-        let inner = self.inner.borrow();
-        let addr = config.address.expect(
-            "Invalid ConnectOptions, this should not happen");
-        let private_key = tryf!(config.private_key.take().map(|k| Ok(k))
-            .unwrap_or_else(|| {
-                // Create new ECDH key
-                let prng = tomcrypt::sprng();
-                tomcrypt::EccKey::new(prng, 32)
-            }));
+/// The severity of a [`LogEntry`].
+///
+/// [`LogEntry`]: struct.LogEntry.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogLevel {
+    Critical,
+    Error,
+    Warning,
+    Debug,
+    Info,
+    Develop,
+    /// A level which is not known to this library.
+    Unknown,
+}
 
-        let client = tryf!(client::ClientData::new(
-            config.local_address,
-            private_key,
-            inner.handle.clone(),
-            true,
-            tsproto::connectionmanager::SocketConnectionManager::new(),
-            None,
-        ));
+/// A line of the server log.
+///
+/// Returned by [`Connection::server_log`] and [`Connection::log_stream`].
+///
+/// [`Connection::server_log`]: struct.Connection.html#method.server_log
+/// [`Connection::log_stream`]: struct.Connection.html#method.log_stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub level: LogLevel,
+    /// The part of the server which wrote the entry, e.g. `VirtualServer`.
+    pub channel: String,
+    pub message: String,
+}
 
-        // Set the data reference
-        {
-            let c2 = client.clone();
-            let mut client = client.borrow_mut();
-            client.connection_manager.set_data_ref(c2);
+impl LogEntry {
+    /// Parse a line of the log.
+    ///
+    /// The line looks like
+    /// `2018-01-01 12:00:00.123456|INFO    |VirtualServer |   1| message`,
+    /// the column with the virtual server id is missing in the instance log.
+    fn from_line(line: &str) -> Option<Self> {
+        let parts = line.splitn(5, '|').collect::<Vec<_>>();
+        if parts.len() < 4 {
+            return None;
         }
-        client::default_setup(client.clone(), false);
-
-        // Create a connection
-        let connect_fut = client::connect(client.clone(), addr);
-
-        let logger = inner.logger.clone();
-        let inner = Rc::downgrade(&self.inner);
-        Box::new(connect_fut.map_err(|e| e.into()).and_then(move |()| {
-            // TODO Add possibility to specify offset and level in ConnectOptions
-            // Compute hash cash
-            let mut time_reporter = slog_perf::TimeReporter::new_with_level(
-                "Compute public key hash cash level", logger.clone(),
-                slog::Level::Info);
-            time_reporter.start("Compute public key hash cash level");
-            let (offset, omega) = {
-                let mut c = client.borrow_mut();
-                (algs::hash_cash(&mut c.private_key, 8).unwrap(),
-                base64::encode(&c.private_key.export_public().unwrap()))
-            };
-            time_reporter.finish();
-            info!(logger, "Computed hash cash level";
-                "level" => algs::get_hash_cash_level(&omega, offset),
-                "offset" => offset);
-
-            // Create clientinit packet
-            let header = Header::new(PacketType::Command);
-            let mut command = commands::Command::new("clientinit");
-            command.push("client_nickname", config.name);
-            command.push("client_version", "3.1.6 [Build: 1502873983]");
-            command.push("client_platform", "Linux");
-            command.push("client_input_hardware", "1");
-            command.push("client_output_hardware", "1");
-            command.push("client_default_channel", "");
-            command.push("client_default_channel_password", "");
-            command.push("client_server_password", "");
-            command.push("client_meta_data", "");
-            command.push("client_version_sign", "o+l92HKfiUF+THx2rBsuNjj/S1QpxG1fd5o3Q7qtWxkviR3LI3JeWyc26eTmoQoMTgI3jjHV7dCwHsK1BVu6Aw==");
-            command.push("client_key_offset", offset.to_string());
-            command.push("client_nickname_phonetic", "");
-            command.push("client_default_token", "");
-            command.push("hwid", "123,456");
-            let p_data = packets::Data::Command(command);
-            let clientinit_packet = Packet::new(header, p_data);
-
-            let con = client.borrow().connection_manager
-                .get_connection(addr).unwrap();
-            let sink = client::ClientConnection::get_packets(con.clone());
-
-            let client2 = client.clone();
-            let con_weak = Rc::downgrade(&con);
-            sink.send(clientinit_packet).and_then(move |_| {
-                client::wait_until_connected(client2, addr)
-            })
-            .and_then(move |()| {
-                // Wait for the initserver packet
-                let stream = tsproto_commands::codec::CommandCodec::
-                    new_stream_from_connection(con);
-                stream.into_future().map_err(|(e, _)| e)
-            }).map_err(|e| e.into())
-            .and_then(move |(p, stream)| {
-                if let Some(Notification::InitServer(p)) = p {
-                    // Create a connection id
-                    let inner = inner.upgrade().expect(
-                        "Connection manager does not exist anymore");
-                    let mut inner = inner.borrow_mut();
-                    let id = inner.find_connection_id();
+        let timestamp = chrono::NaiveDateTime::parse_from_str(
+            parts[0].trim(), "%Y-%m-%d %H:%M:%S%.f").ok()?;
+        let level = match parts[1].trim() {
+            "CRITICAL" => LogLevel::Critical,
+            "ERROR" => LogLevel::Error,
+            "WARNING" => LogLevel::Warning,
+            "DEBUG" => LogLevel::Debug,
+            "INFO" => LogLevel::Info,
+            "DEVELOP" => LogLevel::Develop,
+            _ => LogLevel::Unknown,
+        };
+        Some(Self {
+            timestamp: DateTime::from_utc(timestamp, Utc),
+            level,
+            channel: parts[2].trim().to_string(),
+            message: parts[parts.len() - 1].trim().to_string(),
+        })
+    }
+}
 
-                    // Create the connection
-                    let con = structs::NetworkWrapper::new(id, client, con_weak,
-                        stream, p);
+/// A password which is needed to continue.
+///
+/// This is given to the [`ConnectOptions::password_provider`].
+///
+/// [`ConnectOptions::password_provider`]: struct.ConnectOptions.html#method.password_provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PasswordRequest {
+    /// The password of the server at this address.
+    Server { address: SocketAddr },
+    /// The password to join a channel.
+    Channel { connection: ConnectionId, channel: ChannelId },
+}
 
-                    // Add the connection
-                    inner.connections.insert(id, con);
+#[derive(Clone)]
+struct PasswordProvider(Rc<Fn(PasswordRequest) -> Option<String>>);
 
-                    // TODO spawn a future that polls the NetworkWrapper for this connection
+impl PasswordProvider {
+    fn ask(&self, request: PasswordRequest) -> Option<String> {
+        (self.0)(request)
+    }
+}
 
-                    future::ok(id)
-                } else {
-                    future::err(Error::ConnectionFailed(String::from(
-                        "Got no initserver")))
-                }
-            })
-        }))
+impl fmt::Debug for PasswordProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PasswordProvider")
     }
+}
 
-    /// Disconnect from a server.
-    ///
-    /// # Arguments
-    /// - `id`: The connection which should be removed.
-    /// - `options`: Either `None` or `DisconnectOptions`.
-    ///
-    /// # Examples
-    ///
-    /// Use default options:
-    ///
-    /// ```
-    /// let mut core = tokio_core::Core::new()?;
-    /// let mut cm = ConnectionManager::new(core.handle());
-    ///
-    /// // Add connection...
-    ///
-    /// # let con_id = ConnectionId(0);
-    /// let disconnect_future = cm.remove_connection(con_id, None);
-    /// core.run(disconnect_future)?;
-    /// ```
-    ///
-    /// ```
-    /// # let mut core = tokio_core::Core::new()?;
-    /// # let mut cm = ConnectionManager::new(core.handle());
-    /// # let con_id = ConnectionId(0);
-    /// cm.remove_connection(con_id, DisconnectOptions::new()
-    ///     .reason(MoveReason::Clientdisconnect)
-    ///     .message("Away for a while"));
-    /// ```
-    pub fn remove_connection<O: Into<Option<DisconnectOptions>>>(&mut self,
-        id: ConnectionId, options: O) -> BoxFuture<()> {
-        let con = {
-            let mut inner = self.inner.borrow_mut();
-            if let Some(con) = inner.connections.remove(&id) {
-                con
-            } else {
-                return Box::new(future::ok(()));
-            }
-        };
-        let client_con = if let Some(c) = con.client_connection.upgrade() {
-            c
-        } else {
-            // Already disconnected
-            return Box::new(future::ok(()));
+/// Ask the provider for passwords and try them with `attempt`, after an
+/// action failed with `error`.
+///
+/// This stops when `attempt` succeeds, when it fails with an error for which
+/// `is_wrong_password` returns `false` or when the provider returns `None`.
+/// Then the last error is returned. On success, the result and the accepted
+/// password are returned.
+fn retry_with_passwords<T, F>(provider: Option<PasswordProvider>,
+    request: PasswordRequest, error: Error,
+    is_wrong_password: fn(&Error) -> bool, attempt: F)
+    -> BoxFuture<(T, String)>
+    where T: 'static, F: Fn(String) -> BoxFuture<T> + 'static {
+    Box::new(future::loop_fn(error, move |error|
+        -> BoxFuture<future::Loop<(T, String), Error>> {
+        if !is_wrong_password(&error) {
+            return Box::new(future::err(error));
+        }
+        // The connection is not borrowed here, so the provider may use it
+        let password = match provider.as_ref().and_then(|p| p.ask(request)) {
+            Some(p) => p,
+            None => return Box::new(future::err(error)),
         };
+        Box::new(attempt(password.clone()).then(move |res| match res {
+            Ok(r) => Ok(future::Loop::Break((r, password))),
+            Err(e) => Ok(future::Loop::Continue(e)),
+        }))
+    }))
+}
 
-        let header = Header::new(PacketType::Command);
-        let mut command = commands::Command::new("clientdisconnect");
+/// The maximum number of characters in a text message.
+///
+/// The server rejects longer messages.
+pub const MAX_MESSAGE_LENGTH: usize = 1024;
 
-        // TODO use Notification for this
-        let options = options.into().unwrap_or_default();
-        if let Some(reason) = options.reason {
-            command.push("reasonid", (reason as u8).to_string());
-        }
-        if let Some(msg) = options.message {
-            command.push("reasonmsg", msg);
-        }
+/// Check if a text message is not too long for the server.
+fn check_message_length(text: &str) -> Result<()> {
+    let length = text.chars().count();
+    if length > MAX_MESSAGE_LENGTH {
+        Err(Error::MessageTooLong(length))
+    } else {
+        Ok(())
+    }
+}
 
-        let p_data = packets::Data::Command(command);
-        let packet = Packet::new(header, p_data);
+/// The channel which our client joins when connecting.
+///
+/// A path contains the names of the channel and its parents, separated by
+/// `/`, e.g. `Lobby/Gaming`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DefaultChannel {
+    Id(ChannelId),
+    Path(String),
+}
 
-        let addr;
-        {
-            let mut con = client_con.borrow_mut();
-            con.resender.handle_event(ResenderEvent::Disconnecting);
-            addr = con.address;
+impl DefaultChannel {
+    /// The value of `client_default_channel` in the `clientinit`.
+    fn encode(&self) -> String {
+        match *self {
+            // Ids are distinguished from paths by a leading slash
+            DefaultChannel::Id(id) => format!("/{}", id.0),
+            DefaultChannel::Path(ref path) =>
+                path.trim_left_matches('/').to_string(),
         }
-
-        // TODO Remove connection here and also auto-remove on disconnect (e.g. kick)
-        let sink = client::ClientConnection::get_packets(client_con);
-        Box::new(sink.send(packet).and_then(move |_| {
-            client::wait_for_state(con.client_data, addr, |state| {
-                if let client::ServerConnectionState::Disconnected = *state {
-                    true
-                } else {
-                    false
-                }
-            })
-        }).map_err(|e| e.into()))
     }
+}
 
-    pub fn get_connection(&self, id: ConnectionId) -> Option<Connection> {
-        if self.inner.borrow().connections.contains_key(&id) {
-            Some(Connection { cm: &self, id })
-        } else {
-            None
-        }
+impl From<ChannelId> for DefaultChannel {
+    fn from(id: ChannelId) -> Self {
+        DefaultChannel::Id(id)
     }
 }
 
-// Private methods
-impl ConnectionManager {
-    fn get_server(&self, con: ConnectionId) -> Ref<structs::Server> {
-        Ref::map(self.inner.borrow(), |r| &r.connections[&con].server)
-    }
-    fn get_optional_server_data(&self, con: ConnectionId) -> Ref<structs::OptionalServerData> {
-        Ref::map(self.inner.borrow(), |r| r.connections[&con].server.optional_data.as_ref().unwrap())
-    }
-    fn get_connection_server_data(&self, con: ConnectionId) -> Ref<structs::ConnectionServerData> {
-        Ref::map(self.inner.borrow(), |r| r.connections[&con].server.connection_data.as_ref().unwrap())
+impl<'a> From<&'a str> for DefaultChannel {
+    fn from(path: &'a str) -> Self {
+        DefaultChannel::Path(path.to_string())
     }
+}
 
-    fn get_client(&self, con: ConnectionId, client: ClientId) -> Ref<structs::Client> {
-        Ref::map(self.inner.borrow(), |r| &r.connections[&con].server.clients[&client])
-    }
-    fn get_optional_client_data(&self, con: ConnectionId, client: ClientId) -> Ref<structs::OptionalClientData> {
-        Ref::map(self.inner.borrow(), |r| r.connections[&con].server.clients[&client].optional_data.as_ref().unwrap())
-    }
-    fn get_connection_client_data(&self, con: ConnectionId, client: ClientId) -> Ref<structs::ConnectionClientData> {
-        Ref::map(self.inner.borrow(), |r| r.connections[&con].server.clients[&client].connection_data.as_ref().unwrap())
+impl From<String> for DefaultChannel {
+    fn from(path: String) -> Self {
+        DefaultChannel::Path(path)
     }
+}
 
-    fn get_channel(&self, con: ConnectionId, chan: ChannelId) -> Ref<structs::Channel> {
-        Ref::map(self.inner.borrow(), |r| &r.connections[&con].server.channels[&chan])
-    }
-    fn get_optional_channel_data(&self, con: ConnectionId, chan: ChannelId) -> Ref<structs::OptionalChannelData> {
-        Ref::map(self.inner.borrow(), |r| r.connections[&con].server.channels[&chan].optional_data.as_ref().unwrap())
-    }
+/// The answer of the server to a command which was sent with
+/// [`Connection::send_command`].
+///
+/// [`Connection::send_command`]: struct.Connection.html#method.send_command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandResponse {
+    /// The rows of the response notification, if a response was requested
+    /// with [`Connection::send_command_with_response`].
+    ///
+    /// [`Connection::send_command_with_response`]: struct.Connection.html#method.send_command_with_response
+    pub rows: Vec<Map<String, String>>,
+}
 
-    fn get_chat_entry(&self, _con: ConnectionId, _sender: ClientId) -> Ref<structs::ChatEntry> {
-        unimplemented!("Chatting is not yet implemented")
-    }
+/// Where a client is kicked from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KickTarget {
+    /// Move the client into the default channel.
+    Channel,
+    /// Disconnect the client from the server.
+    Server,
 }
 
-impl<'a> Connection<'a> {
-    pub fn get_server(&self) -> Server {
-        Server {
-            cm: self.cm,
-            connection_id: self.id,
-        }
+/// Replace an insufficient permissions error of the server with the
+/// permission which is needed for an action.
+///
+/// This is only needed if the server did not say which permission is
+/// missing, otherwise the error already contains it.
+fn needs_permission(error: Error, permission: Permission) -> Error {
+    match error {
+        Error::Server(TsError::PermissionsClientInsufficient, _) =>
+            Error::MissingPermission(permission),
+        e => e,
     }
 }
 
-/// The configuration used to create a new connection.
-///
-/// Basically, this is a builder for a connection.
-///
-/// # Example
-///
-/// ```
-/// let addr: std::net::SocketAddr = "127.0.0.1:9987".parse().unwrap();
-/// let con_config = ConnectOptions::from_address(addr);
-///
-/// let mut cm = ConnectionManager::new();
-/// let con = cm.add_connection(con_config)?;
-/// ```
-#[derive(Debug)]
-pub struct ConnectOptions {
-    address: Option<SocketAddr>,
-    local_address: SocketAddr,
-    private_key: Option<tomcrypt::EccKey>,
-    name: String,
+/// The receiver of a text message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageTarget {
+    /// The server chat.
+    Server,
+    /// The chat of a channel.
+    Channel(ChannelId),
+    /// A private chat with a client.
+    Client(ClientId),
 }
 
-impl ConnectOptions {
-    /// A private method to create a config with only default values.
+/// How much data of the server is stored in the book of a connection.
+///
+/// On big servers, the reduced modes can save a lot of memory if only a part
+/// of the data is needed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum BookMode {
+    /// Store everything that the server sends.
+    Full,
+    /// Store channels, but only the id and nickname of clients.
     ///
-    /// This is not in the public interface because the created configuration
-    /// is invalid.
+    /// The clients are not part of the book in this mode, their names can be
+    /// read with [`Connection::client_name`].
+    ///
+    /// [`Connection::client_name`]: struct.Connection.html#method.client_name
+    MinimalClients,
+    /// Store only channels and no clients.
+    ChannelsOnly,
+}
+
+impl Default for BookMode {
     fn default() -> Self {
-        Self {
-            address: None,
-            local_address: "0.0.0.0:0".parse().unwrap(),
-            private_key: None,
-            name: String::from("TeamSpeakUser"),
-        }
+        BookMode::Full
     }
+}
 
-    /// Start creating the configuration of a new connection.
+/// How the address is chosen if a server has multiple addresses.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum AddressStrategy {
+    /// Always connect to the first address.
+    First,
+    /// Use the next address for every new connection of a
+    /// [`ConnectionManager`].
     ///
-    /// The address of the server has to be supplied.
-    pub fn from_address(address: SocketAddr) -> Self {
+    /// [`ConnectionManager`]: struct.ConnectionManager.html
+    RoundRobin,
+    /// Connect to a random address.
+    Random,
+    /// Start the handshake with all addresses at the same time and connect to
+    /// the address which finishes it first.
+    ///
+    /// The handshakes with the other addresses are aborted. The `clientinit`
+    /// is only sent to the chosen address, so our client joins only one
+    /// server.
+    FirstResponding,
+}
+
+impl Default for AddressStrategy {
+    fn default() -> Self {
+        AddressStrategy::First
+    }
+}
+
+/// Features which are not supported by all server versions.
+///
+/// Use [`Connection::supports`] to check if the connected server has a
+/// capability.
+///
+/// [`Connection::supports`]: struct.Connection.html#method.supports
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ServerCapability {
+    /// Channel groups and their permissions.
+    ChannelGroups,
+    /// Up- and downloading files.
+    FileTransfer,
+    /// Move multiple clients with a single `clientmove` command.
+    BatchedClientMove,
+}
+
+impl ServerCapability {
+    /// The first server version which supports this capability.
+    fn min_version(&self) -> &'static [u32] {
+        match *self {
+            ServerCapability::ChannelGroups => &[3, 0, 0],
+            ServerCapability::FileTransfer => &[3, 0, 0],
+            ServerCapability::BatchedClientMove => &[3, 0, 13],
+        }
+    }
+}
+
+/// Parse the version number of a server version string like
+/// `3.0.13.8 [Build: 1500452811]`.
+fn parse_server_version(version: &str) -> Option<Vec<u32>> {
+    let version = version.split_whitespace().next()?;
+    version.split('.').map(|v| v.parse().ok()).collect()
+}
+
+include!(concat!(env!("OUT_DIR"), "/facades.rs"));
+
+lazy_static! {
+    /// Initialize `tsproto`
+    static ref TSPROTO_INIT: () = tsproto::init()
+        .expect("tsproto failed to initialize");
+}
+
+/// The connection manager which can be shared and cloned.
+struct InnerCM {
+    handle: Handle,
+    logger: Logger,
+    /// Every connection has its own `RefCell`, so a connection can be updated
+    /// while the books of other connections are borrowed.
+    connections: Map<ConnectionId, Rc<RefCell<structs::NetworkWrapper>>>,
+    connection_ids: ConnectionIds,
+    /// The counter for [`AddressStrategy::RoundRobin`].
+    ///
+    /// [`AddressStrategy::RoundRobin`]: enum.AddressStrategy.html#variant.RoundRobin
+    next_address: usize,
+}
+
+impl InnerCM {
+    /// Borrow a connection.
+    ///
+    /// Returns `None` if the connection does not exist.
+    fn get(&self, id: ConnectionId) -> Option<Ref<structs::NetworkWrapper>> {
+        self.connections.get(&id).map(|c| c.borrow())
+    }
+
+    /// Borrow a connection mutably.
+    ///
+    /// Returns `None` if the connection does not exist.
+    fn get_mut(&self, id: ConnectionId)
+        -> Option<RefMut<structs::NetworkWrapper>> {
+        self.connections.get(&id).map(|c| c.borrow_mut())
+    }
+
+    /// Remove a connection and release its id.
+    fn remove_connection(&mut self, id: ConnectionId)
+        -> Option<Rc<RefCell<structs::NetworkWrapper>>> {
+        let con = self.connections.remove(&id);
+        if let Some(ref con) = con {
+            self.connection_ids.release(id, &con.borrow().id_token);
+        }
+        con
+    }
+
+    /// Check if the reactor behind our handle is still alive.
+    ///
+    /// `tokio_core` only notices a dropped `Core` when a timeout or socket is
+    /// created, otherwise spawning fails silently or panics later. So we
+    /// create a short timeout and drop it again.
+    fn check_reactor(&self) -> Result<()> {
+        Timeout::new(std::time::Duration::from_secs(0), &self.handle)
+            .map(|_| ())
+            .map_err(|_| Error::ReactorGone)
+    }
+}
+
+/// Hands out the ids of connections.
+///
+/// Ids of removed connections are reused, but only after all futures which
+/// still reference the id are gone. For this, every connection owns a token
+/// which is cloned into the futures that are spawned for the connection.
+#[derive(Default)]
+struct ConnectionIds {
+    next: usize,
+    /// Released ids and their tokens, the oldest first.
+    free: VecDeque<(ConnectionId, Weak<()>)>,
+}
+
+impl ConnectionIds {
+    /// Returns an id which is not used by a connection or a future.
+    fn allocate(&mut self) -> ConnectionId {
+        let reusable = self.free.front()
+            .map(|&(_, ref token)| token.upgrade().is_none())
+            .unwrap_or(false);
+        if reusable {
+            self.free.pop_front().unwrap().0
+        } else {
+            let id = ConnectionId(self.next);
+            self.next += 1;
+            id
+        }
+    }
+
+    /// The connection with this id was removed.
+    ///
+    /// The id gets reused when all clones of the token are dropped.
+    fn release(&mut self, id: ConnectionId, token: &Rc<()>) {
+        self.free.push_back((id, Rc::downgrade(token)));
+    }
+}
+
+/// Where the [`ConnectionManager`] writes its log messages.
+///
+/// [`ConnectionManager`]: struct.ConnectionManager.html
+#[derive(Debug)]
+enum LogTarget {
+    Discard,
+    Terminal,
+    File(PathBuf),
+    Logger(Logger),
+}
+
+/// Creates a [`ConnectionManager`] with custom settings.
+///
+/// ```
+/// let core = tokio_core::Core::new()?;
+/// let cm = ConnectionManager::builder()
+///     .log_to_file("tsclientlib.log".into())
+///     .build(core.handle())?;
+/// ```
+///
+/// [`ConnectionManager`]: struct.ConnectionManager.html
+#[derive(Debug)]
+pub struct ConnectionManagerBuilder {
+    log_target: LogTarget,
+    log_overflow: OverflowStrategy,
+}
+
+impl ConnectionManagerBuilder {
+    fn new() -> Self {
+        Self {
+            log_target: LogTarget::Discard,
+            log_overflow: OverflowStrategy::DropAndReport,
+        }
+    }
+
+    /// Write all log messages to this logger.
+    ///
+    /// # Default
+    ///
+    /// Nothing is logged.
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.log_target = LogTarget::Logger(logger);
+        self
+    }
+
+    /// Append all log messages to a file.
+    ///
+    /// The file is created if it does not exist.
+    ///
+    /// # Default
+    ///
+    /// Nothing is logged.
+    pub fn log_to_file(mut self, path: PathBuf) -> Self {
+        self.log_target = LogTarget::File(path);
+        self
+    }
+
+    /// Write all log messages to the terminal.
+    ///
+    /// # Default
+    ///
+    /// Nothing is logged.
+    pub fn log_to_terminal(mut self) -> Self {
+        self.log_target = LogTarget::Terminal;
+        self
+    }
+
+    /// Do not log anything.
+    ///
+    /// # Default
+    ///
+    /// Nothing is logged.
+    pub fn disable_logging(mut self) -> Self {
+        self.log_target = LogTarget::Discard;
+        self
+    }
+
+    /// Choose what happens when the logger cannot keep up.
+    ///
+    /// Log messages to a file or the terminal are written in a background
+    /// thread. If too many messages are logged, e.g. because many packets are
+    /// resent, the queue of this thread gets full. Then messages are either
+    /// dropped or logging blocks until there is space again.
+    ///
+    /// This setting has no effect for a custom [`logger`].
+    ///
+    /// # Default
+    ///
+    /// `OverflowStrategy::DropAndReport`, which drops messages and logs how
+    /// many messages were dropped.
+    ///
+    /// [`logger`]: #method.logger
+    pub fn log_overflow(mut self, log_overflow: OverflowStrategy) -> Self {
+        self.log_overflow = log_overflow;
+        self
+    }
+
+    /// Create the `ConnectionManager`.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the log file cannot be opened.
+    pub fn build(self, handle: Handle) -> Result<ConnectionManager> {
+        // Initialize tsproto if it was not done yet
+        *TSPROTO_INIT;
+
+        let logger = match self.log_target {
+            LogTarget::Discard => Logger::root(slog::Discard, o!()),
+            LogTarget::Logger(logger) => logger,
+            LogTarget::Terminal => {
+                let decorator = slog_term::TermDecorator::new().build();
+                let drain = slog_term::FullFormat::new(decorator).build()
+                    .fuse();
+                let drain = slog_async::Async::new(drain)
+                    .overflow_strategy(self.log_overflow).build().fuse();
+                Logger::root(drain, o!())
+            }
+            LogTarget::File(path) => {
+                let file = std::fs::OpenOptions::new().create(true)
+                    .append(true).open(path)?;
+                let decorator = slog_term::PlainDecorator::new(file);
+                let drain = slog_term::FullFormat::new(decorator).build()
+                    .fuse();
+                let drain = slog_async::Async::new(drain)
+                    .overflow_strategy(self.log_overflow).build().fuse();
+                Logger::root(drain, o!())
+            }
+        };
+
+        Ok(ConnectionManager {
+            inner: Rc::new(RefCell::new(InnerCM {
+                handle,
+                logger,
+                connections: Map::new(),
+                connection_ids: ConnectionIds::default(),
+                next_address: 0,
+            })),
+        })
+    }
+}
+
+/// The main type of this crate, which holds all connections.
+///
+/// It can be created with the [`ConnectionManager::new`] function:
+///
+/// ```
+/// let core = tokio_core::Core::new()?;
+/// let cm = ConnectionManager::new(core.handle());
+/// ```
+///
+/// [`ConnectionManager::new`]: #method.new
+pub struct ConnectionManager {
+    inner: Rc<RefCell<InnerCM>>,
+}
+
+impl ConnectionManager {
+    /// Creates a new `ConnectionManager` which is then used to add new
+    /// connections.
+    ///
+    /// ```
+    /// let core = tokio_core::Core::new()?;
+    /// let cm = ConnectionManager::new(core.handle());
+    /// ```
+    ///
+    /// Nothing is logged, use [`ConnectionManager::builder`] to configure
+    /// logging.
+    ///
+    /// Connecting to a server is done by [`ConnectionManager::add_connection`].
+    ///
+    /// [`ConnectionManager::builder`]: #method.builder
+    /// [`ConnectionManager::add_connection`]: #method.add_connection
+    pub fn new(handle: Handle) -> Self {
+        // Building without a log file cannot fail
+        Self::builder().build(handle).unwrap()
+    }
+
+    /// Create a [`ConnectionManagerBuilder`], e.g. to set a logger.
+    ///
+    /// [`ConnectionManagerBuilder`]: struct.ConnectionManagerBuilder.html
+    pub fn builder() -> ConnectionManagerBuilder {
+        ConnectionManagerBuilder::new()
+    }
+
+    /// Creates a new `ConnectionManager` which logs to the terminal and
+    /// choose what happens when the logger cannot keep up.
+    ///
+    /// This is a shortcut for
+    /// [`ConnectionManagerBuilder::log_to_terminal`] and
+    /// [`ConnectionManagerBuilder::log_overflow`].
+    ///
+    /// [`ConnectionManagerBuilder::log_to_terminal`]: struct.ConnectionManagerBuilder.html#method.log_to_terminal
+    /// [`ConnectionManagerBuilder::log_overflow`]: struct.ConnectionManagerBuilder.html#method.log_overflow
+    pub fn with_log_overflow(handle: Handle, overflow: OverflowStrategy)
+        -> Self {
+        // Building without a log file cannot fail
+        Self::builder().log_to_terminal().log_overflow(overflow).build(handle)
+            .unwrap()
+    }
+
+    /// Check if a server is reachable and measure the time until it answers.
+    ///
+    /// Only the first packet of the handshake is sent, no connection is
+    /// created. This is a lot cheaper than connecting, e.g. to monitor many
+    /// servers.
+    ///
+    /// Returns `None` if the server does not answer within 5 seconds.
+    pub fn ping_server(&self, address: SocketAddr)
+        -> BoxFuture<Option<std::time::Duration>> {
+        let inner = self.inner.borrow();
+        tryf!(inner.check_reactor());
+        Box::new(client::ping_server(&inner.handle, address,
+            std::time::Duration::from_secs(5)).map_err(|e| e.into()))
+    }
+
+    /// Connect to multiple servers at once and keep the first connection
+    /// which is established.
+    ///
+    /// The other connections are disconnected cleanly as soon as they are
+    /// established, so they do not stay as ghost clients on their servers. If
+    /// no connection can be established, the last error is returned.
+    ///
+    /// If none of the options has an own identity, the same new identity is
+    /// used for all servers, so the hash cash is only computed once.
+    pub fn connect_any(&mut self, mut configs: Vec<ConnectOptions>)
+        -> BoxFuture<ConnectionId> {
+        if configs.is_empty() {
+            return Box::new(future::err(Error::ConnectionFailed(String::from(
+                "No server to connect to"))));
+        }
+        if configs.iter().all(|c| c.private_key.is_none()
+            && c.key_offset.is_none()) {
+            let key = tryf!(tomcrypt::EccKey::new(tomcrypt::sprng(), 32));
+            let exported = tryf!(key.export_private());
+            let mut identity = Identity::new(key, 0);
+            if !configs.iter().all(|c| c.skip_security_level) {
+                let level = configs.iter().map(|c| c.identity_level).max()
+                    .unwrap_or_default();
+                tryf!(identity.upgrade_level(level));
+            }
+            for c in &mut configs {
+                c.private_key = Some(tryf!(tomcrypt::EccKey::import(
+                    &exported)));
+                c.key_offset = Some(identity.key_offset());
+            }
+        }
+
+        let handle = self.inner.borrow().handle.clone();
+        let connects = configs.into_iter().map(|c| self.add_connection(c))
+            .collect::<Vec<_>>();
+        let inner = Rc::downgrade(&self.inner);
+        first_ok(&handle, connects, Rc::new(move |id: ConnectionId| {
+            // Disconnect the servers which were too slow
+            if let Some(inner) = inner.upgrade() {
+                let handle = inner.borrow().handle.clone();
+                let mut cm = ConnectionManager { inner };
+                handle.spawn(cm.remove_connection(id, DisconnectOptions::new())
+                    .then(|_| Ok(())));
+            }
+        }))
+    }
+
+    /// Connect to a server.
+    ///
+    /// Dropping the returned future before it finished aborts connecting.
+    pub fn add_connection(&mut self, mut config: ConnectOptions)
+        -> BoxFuture<ConnectionId> {
+        if let Some(hostname) = config.hostname.take() {
+            // Resolve the address first
+            let (logger, handle) = {
+                let inner = self.inner.borrow();
+                tryf!(inner.check_reactor());
+                (inner.logger.clone(), inner.handle.clone())
+            };
+            let inner = Rc::downgrade(&self.inner);
+            return Box::new(resolver::resolve(logger, handle, &hostname)
+                .and_then(move |addr| {
+                    let inner = inner.upgrade()
+                        .ok_or(Error::ConnectionNotFound)?;
+                    config.address = Some(addr);
+                    Ok(ConnectionManager { inner }.add_connection(config))
+                }).flatten());
+        }
+
+        let client = {
+            let inner = self.inner.borrow();
+            tryf!(inner.check_reactor());
+            let private_key = tryf!(config.private_key.take()
+                .map(|k| Ok(k)).unwrap_or_else(|| {
+                    // Create new ECDH key
+                    let prng = tomcrypt::sprng();
+                    tomcrypt::EccKey::new(prng, 32)
+                }));
+            tryf!(create_client(inner.handle.clone(), config.local_address,
+                private_key, config.resend_config.clone()))
+        };
+        {
+            let mut client = client.borrow_mut();
+            client.dropped_packet_sink = config.dropped_packet_sink.clone();
+            client.crypto_params = config.crypto_params;
+            client.ping_responder = config.ping_responder;
+            client.ack_delay = config.ack_delay;
+        }
+        self.connect(client, config)
+    }
+
+    /// Connect to a server with an existing client.
+    fn connect(&mut self, client: Rc<RefCell<client::ClientData>>,
+        config: ConnectOptions) -> BoxFuture<ConnectionId> {
+        let mut inner = self.inner.borrow_mut();
+        let logger = inner.logger.clone();
+        let handle = inner.handle.clone();
+        let round_robin = inner.next_address;
+        inner.next_address = round_robin.wrapping_add(1);
+
+        // Compute the hash cash once for all addresses
+        tryf!(key_offset(&logger, &client, &config));
+
+        // Remove the half-open connections if the future is dropped
+        let mut guard = ConnectGuard::new(client.clone(), &config);
+        let inner = Rc::downgrade(&self.inner);
+        let config = Rc::new(config);
+        let config2 = config.clone();
+        Box::new(connect_with_strategy(logger, client.clone(), config,
+            round_robin).and_then(move |(addr, (con_weak, stream, p,
+                accepted_at))| {
+            let inner_rc = if let Some(inner) = inner.upgrade() {
+                inner
+            } else {
+                return Err(Error::ConnectionNotFound);
+            };
+            guard.finished = true;
+            let mut inner = inner_rc.borrow_mut();
+            // Create a connection id
+            let id = inner.connection_ids.allocate();
+
+            // Create the connection
+            let con = structs::NetworkWrapper::new(id, client, con_weak,
+                stream, p, addr, config2.clone());
+
+            let client = Rc::downgrade(&con.client_data);
+
+            // Add the connection
+            let token = con.id_token.clone();
+            let con = Rc::new(RefCell::new(con));
+            inner.connections.insert(id, con.clone());
+
+            handle.spawn(ConnectionDriver::new(Rc::downgrade(&inner_rc), id,
+                &con, token.clone(), inner.logger.clone()));
+            handle.spawn(hold_id(token.clone(),
+                measure_clock_offset(Rc::downgrade(&inner_rc), id,
+                accepted_at, inner.logger.clone())));
+            if config2.book_mode == BookMode::Full {
+                handle.spawn(hold_id(token.clone(), request_client_list(
+                    Rc::downgrade(&inner_rc), id, inner.logger.clone())));
+            }
+            if let Some((interval, timeout)) = config2.app_heartbeat {
+                handle.spawn(hold_id(token, app_heartbeat(
+                    Rc::downgrade(&inner_rc), id, client, interval, timeout,
+                    handle.clone(), inner.logger.clone())));
+            }
+
+            Ok(id)
+        }))
+    }
+
+    /// Disconnect from a server.
+    ///
+    /// The event listeners of the connection get a [`ConnectionSummary`] with
+    /// the [`Event::Disconnected`] event. Nothing happens if there is no
+    /// connection with this id.
+    ///
+    /// # Arguments
+    /// - `id`: The connection which should be removed.
+    /// - `options`: Either `None` or `DisconnectOptions`.
+    ///
+    /// [`ConnectionSummary`]: struct.ConnectionSummary.html
+    /// [`Event::Disconnected`]: enum.Event.html#variant.Disconnected
+    ///
+    /// # Examples
+    ///
+    /// Use default options:
+    ///
+    /// ```
+    /// let mut core = tokio_core::Core::new()?;
+    /// let mut cm = ConnectionManager::new(core.handle());
+    ///
+    /// // Add connection...
+    ///
+    /// # let con_id = ConnectionId(0);
+    /// let disconnect_future = cm.remove_connection(con_id, None);
+    /// core.run(disconnect_future)?;
+    /// ```
+    ///
+    /// ```
+    /// # let mut core = tokio_core::Core::new()?;
+    /// # let mut cm = ConnectionManager::new(core.handle());
+    /// # let con_id = ConnectionId(0);
+    /// cm.remove_connection(con_id, DisconnectOptions::new()
+    ///     .reason(MoveReason::Clientdisconnect)
+    ///     .message("Away for a while"));
+    /// ```
+    pub fn remove_connection<O: Into<Option<DisconnectOptions>>>(&mut self,
+        id: ConnectionId, options: O) -> BoxFuture<()> {
+        let options = options.into().unwrap_or_default();
+        let con = {
+            let mut inner = self.inner.borrow_mut();
+            tryf!(inner.check_reactor());
+            if let Some(con) = inner.remove_connection(id) {
+                con
+            } else {
+                return Box::new(future::ok(()));
+            }
+        };
+        let mut con = con.borrow_mut();
+        con.fail_pending_commands(options.reason);
+        if con.disconnect_reason.is_none() {
+            con.disconnect_reason = options.reason;
+        }
+        con.notify_disconnected(DisconnectReason::Removed);
+        let client_data = con.client_data.clone();
+        let client_con = if let Some(c) = con.client_connection.upgrade() {
+            c
+        } else {
+            // Already disconnected
+            return Box::new(future::ok(()));
+        };
+
+        let header = Header::new(PacketType::Command);
+        let mut command = commands::Command::new("clientdisconnect");
+
+        // TODO use Notification for this
+        if let Some(reason) = options.reason {
+            command.push("reasonid", (reason as u8).to_string());
+        }
+        if let Some(msg) = options.message {
+            command.push("reasonmsg", msg);
+        }
+
+        let p_data = packets::Data::Command(command);
+        let packet = Packet::new(header, p_data);
+
+        let addr = client_con.borrow().address;
+
+        // End the voice transmissions before disconnecting, voice packets are
+        // not sent anymore afterwards.
+        let voice_stopped = voice::VoiceShutdown::stop(
+            con.voice_shutdown.clone(), &self.inner.borrow().handle);
+
+        Box::new(voice_stopped.and_then(move |()| {
+            client_con.borrow_mut().resender
+                .handle_event(ResenderEvent::Disconnecting);
+            let sink = client::ClientConnection::get_packets(client_con);
+            sink.send(packet).map_err(|e| e.into())
+        }).and_then(move |_| {
+            client::wait_for_state(client_data, addr, |state| {
+                if let client::ServerConnectionState::Disconnected = *state {
+                    true
+                } else {
+                    false
+                }
+            }).map_err(|e| e.into())
+        }))
+    }
+
+    /// Disconnect from all servers and stop the connection manager.
+    ///
+    /// The future resolves when all connections are disconnected or after
+    /// 10 seconds. Connections which cannot be disconnected cleanly, e.g.
+    /// because they are already dead, are only logged.
+    pub fn shutdown(mut self, options: DisconnectOptions) -> BoxFuture<()> {
+        let (logger, handle) = {
+            let inner = self.inner.borrow();
+            (inner.logger.clone(), inner.handle.clone())
+        };
+        let timeout = tryf!(Timeout::new(std::time::Duration::from_secs(10),
+            &handle));
+        let ids: Vec<_> = self.inner.borrow().connections.keys().cloned()
+            .collect();
+        let futs: Vec<_> = ids.into_iter().map(|id| {
+            let logger = logger.clone();
+            self.remove_connection(id, options.clone()).then(move |res| {
+                if let Err(e) = res {
+                    warn!(logger, "Failed to disconnect"; "connection" => ?id,
+                        "error" => ?e);
+                }
+                Ok::<_, ()>(())
+            })
+        }).collect();
+
+        Box::new(future::join_all(futs).select2(timeout).then(move |res| {
+            if let Ok(future::Either::B(_)) = res {
+                warn!(logger, "Timeout while disconnecting all connections");
+            }
+            // Stop the connection manager after the connections are gone
+            drop(self);
+            Ok(())
+        }))
+    }
+
+    /// Get all events of a connection, e.g. clients which joined or left and
+    /// changes of channels.
+    ///
+    /// Every stream gets all events, so a connection can have multiple
+    /// subscribers. The stream ends when the connection is removed or
+    /// immediately if the connection does not exist.
+    pub fn get_events(&self, id: ConnectionId) -> BoxStream<Event> {
+        let (send, recv) = futures::unsync::mpsc::unbounded();
+        let inner = self.inner.borrow();
+        if let Some(con) = inner.connections.get(&id) {
+            con.borrow_mut().event_listeners.push(send);
+        }
+        Box::new(recv.map_err(|()| unreachable!(
+            "A receiver cannot fail")))
+    }
+
+    pub fn get_connection(&self, id: ConnectionId) -> Option<Connection> {
+        if self.inner.borrow().connections.contains_key(&id) {
+            Some(Connection { cm: &self, id })
+        } else {
+            None
+        }
+    }
+}
+
+/// Connect a client to the server of `config` and wait for the `initserver`
+/// packet.
+///
+/// If the server rejects the password, the [`ConnectOptions::password_provider`]
+/// is asked for it and connecting is tried again, until the provider returns
+/// `None`.
+///
+/// The client has to be already set up. This is used for new connections and
+/// to reconnect in [`Connection::hard_reset`].
+///
+/// [`ConnectOptions::password_provider`]: struct.ConnectOptions.html#method.password_provider
+/// [`Connection::hard_reset`]: struct.Connection.html#method.hard_reset
+fn connect_client(logger: Logger, client: Rc<RefCell<client::ClientData>>,
+    config: Rc<ConnectOptions>, addr: SocketAddr)
+    -> BoxFuture<ConnectedClient> {
+    let password = config.server_password.clone();
+    let first = connect_client_with_password(logger.clone(), client.clone(),
+        config.clone(), addr, password);
+    retry_wrong_password(logger, client, config, addr, first)
+}
+
+/// Like [`connect_client`], but the handshake with the server at `addr` is
+/// already done, so only the `clientinit` is sent.
+///
+/// [`connect_client`]: fn.connect_client.html
+fn init_client(logger: Logger, client: Rc<RefCell<client::ClientData>>,
+    config: Rc<ConnectOptions>, addr: SocketAddr)
+    -> BoxFuture<ConnectedClient> {
+    let password = config.server_password.clone();
+    let first = send_clientinit(logger.clone(), client.clone(), config.clone(),
+        addr, password);
+    retry_wrong_password(logger, client, config, addr, first)
+}
+
+/// If the server rejects the password of the `first` try, connect again with
+/// the passwords of the [`ConnectOptions::password_provider`].
+///
+/// [`ConnectOptions::password_provider`]: struct.ConnectOptions.html#method.password_provider
+fn retry_wrong_password(logger: Logger,
+    client: Rc<RefCell<client::ClientData>>, config: Rc<ConnectOptions>,
+    addr: SocketAddr, first: BoxFuture<ConnectedClient>)
+    -> BoxFuture<ConnectedClient> {
+    let provider = config.password_provider.clone();
+    let attempt = move |password| connect_client_with_password(
+        logger.clone(), client.clone(), config.clone(), addr, password);
+    Box::new(first.or_else(move |e| retry_with_passwords(provider,
+        PasswordRequest::Server { address: addr }, e,
+        |e| if let Error::InvalidServerPassword = *e { true } else { false },
+        attempt).map(|(c, _)| c)))
+}
+
+/// If the server rejected a channel password.
+fn is_wrong_channel_password(e: &Error) -> bool {
+    if let Error::Server(TsError::ChannelInvalidPassword, _) = *e {
+        true
+    } else {
+        false
+    }
+}
+
+fn connect_client_with_password(logger: Logger,
+    client: Rc<RefCell<client::ClientData>>, config: Rc<ConnectOptions>,
+    addr: SocketAddr, password: String) -> BoxFuture<ConnectedClient> {
+    // Compute the offset before the handshake, so the server does not wait
+    tryf!(key_offset(&logger, &client, &config));
+    let connect_fut = client::connect(client.clone(), addr);
+
+    Box::new(connect_fut.map_err(|e| e.into()).and_then(move |()|
+        send_clientinit(logger, client, config, addr, password)))
+}
+
+/// Send the `clientinit` packet after the handshake and wait for the
+/// `initserver` packet.
+fn send_clientinit(logger: Logger, client: Rc<RefCell<client::ClientData>>,
+    config: Rc<ConnectOptions>, addr: SocketAddr, password: String)
+    -> BoxFuture<ConnectedClient> {
+    let offset = tryf!(key_offset(&logger, &client, &config));
+
+    // Create clientinit packet
+    let header = Header::new(PacketType::Command);
+    let mut command = commands::Command::new("clientinit");
+    command.push("client_nickname", config.name.clone());
+    command.push("client_version", config.version.version.clone());
+    command.push("client_platform", config.version.platform.clone());
+    command.push("client_input_hardware", "1");
+    command.push("client_output_hardware", "1");
+    command.push("client_default_channel", config.default_channel.as_ref()
+        .map(DefaultChannel::encode).unwrap_or_default());
+    command.push("client_default_channel_password",
+        algs::hash_password(&config.default_channel_password));
+    command.push("client_server_password",
+        algs::hash_password(&password));
+    command.push("client_meta_data", "");
+    command.push("client_version_sign", config.version.sign.clone());
+    command.push("client_key_offset", offset.to_string());
+    command.push("client_nickname_phonetic",
+        config.nickname_phonetic.clone());
+    command.push("client_default_token", "");
+    command.push("hwid", "123,456");
+    let p_data = packets::Data::Command(command);
+    let clientinit_packet = Packet::new(header, p_data);
+
+    let con = client.borrow().connection_manager
+        .get_connection(addr).unwrap();
+    let sink = client::ClientConnection::get_packets(con.clone());
+
+    let con_weak = Rc::downgrade(&con);
+    // The server answers the clientinit either with initserver or with an
+    // error
+    let stream: Box<Stream<Item = _, Error = _>> = Box::new(
+        client::ClientConnection::get_commands(con));
+    let sent_at = Utc::now();
+    Box::new(sink.send(clientinit_packet).and_then(move |_| {
+        stream.into_future().map_err(|(e, _)| e)
+    }).map_err(|e| e.into())
+    .and_then(move |(p, stream)| {
+        // Errors can contain more information in extra_msg
+        let mut extra_msg = None;
+        let answer = p.and_then(|p| {
+            if let packets::Data::Command(ref cmd) = p.data {
+                cmd.get_commands().into_iter().next().and_then(|c| {
+                    extra_msg = c.args.get("extra_msg")
+                        .map(|m| m.to_string());
+                    Notification::parse(c).ok()
+                })
+            } else {
+                None
+            }
+        });
+        let res = match answer {
+            Some(Notification::InitServer(p)) => {
+                // Assume that the server handled the clientinit in the
+                // middle of the round trip
+                let accepted_at = sent_at + Utc::now()
+                    .signed_duration_since(sent_at) / 2;
+                return Ok((con_weak, stream, p, accepted_at));
+            }
+            Some(Notification::CommandError(e)) =>
+                handshake_error(e.id, e.msg, extra_msg),
+            _ => Error::ConnectionFailed(String::from(
+                "Got no initserver")),
+        };
+        // Remove the failed connection
+        client::ClientData::remove_connection(client, addr);
+        Err(res)
+    }))
+}
+
+/// The `key_offset` of the identity of the client.
+///
+/// If no offset is known, the hash cash is computed for the
+/// [`ConnectOptions::identity_level`]. The offset is stored in the options,
+/// so it is reused for other addresses and to reconnect.
+///
+/// Computing the hash cash blocks the reactor. For high levels, use
+/// [`Identity::upgrade_level`] on another thread before connecting.
+///
+/// [`ConnectOptions::identity_level`]: struct.ConnectOptions.html#method.identity_level
+/// [`Identity::upgrade_level`]: struct.Identity.html#method.upgrade_level
+fn key_offset(logger: &Logger, client: &Rc<RefCell<client::ClientData>>,
+    config: &ConnectOptions) -> Result<u64> {
+    // Reuse the offset of a previous connection, e.g. for a hard reset
+    let offset = if let Some(offset) = config.key_offset
+        .or(config.used_key_offset.get()) {
+        offset
+    } else if config.skip_security_level {
+        0
+    } else {
+        // Compute hash cash
+        let mut time_reporter = slog_perf::TimeReporter::new_with_level(
+            "Compute public key hash cash level", logger.clone(),
+            slog::Level::Info);
+        time_reporter.start("Compute public key hash cash level");
+        let (offset, omega) = {
+            let c = client.borrow();
+            (algs::hash_cash(&c.private_key, config.identity_level)?,
+            base64::encode(&c.private_key.export_public()?))
+        };
+        time_reporter.finish();
+        info!(logger, "Computed hash cash level";
+            "level" => algs::get_hash_cash_level(&omega, offset),
+            "offset" => offset);
+        offset
+    };
+    config.used_key_offset.set(Some(offset));
+    Ok(offset)
+}
+
+/// Choose one of the addresses in the options with the
+/// [`AddressStrategy`] and connect to it.
+///
+/// Returns the address which is used by the connection.
+///
+/// [`AddressStrategy`]: enum.AddressStrategy.html
+fn connect_with_strategy(logger: Logger,
+    client: Rc<RefCell<client::ClientData>>, config: Rc<ConnectOptions>,
+    round_robin: usize) -> BoxFuture<(SocketAddr, ConnectedClient)> {
+    let mut addresses = vec![config.address.expect(
+        "Invalid ConnectOptions, this should not happen")];
+    addresses.extend_from_slice(&config.alternative_addresses);
+
+    let addr = match config.address_strategy {
+        AddressStrategy::First => addresses[0],
+        AddressStrategy::RoundRobin => addresses[round_robin % addresses.len()],
+        AddressStrategy::Random => *rand::thread_rng().choose(&addresses)
+            .unwrap(),
+        AddressStrategy::FirstResponding => {
+            // Only race the handshake, so our client joins only one server
+            let handshakes = addresses.iter().map(|&addr| {
+                client::connect(client.clone(), addr).map(move |()| addr)
+            }).collect::<Vec<_>>();
+            return Box::new(future::select_ok(handshakes)
+                .map_err(|e| e.into())
+                .and_then(move |(addr, _)| {
+                // Drop the connections which are still in the handshake
+                for &a in &addresses {
+                    if a != addr {
+                        client::ClientData::remove_connection(client.clone(),
+                            a);
+                    }
+                }
+                init_client(logger, client, config, addr)
+                    .map(move |res| (addr, res))
+            }));
+        }
+    };
+    Box::new(connect_client(logger, client, config, addr)
+        .map(move |res| (addr, res)))
+}
+
+/// Create the tsproto client, which owns the socket.
+fn create_client(handle: Handle, local_address: SocketAddr,
+    private_key: tomcrypt::EccKey, resend_config: ResendConfig)
+    -> Result<Rc<RefCell<client::ClientData>>> {
+    let client = client::ClientData::new(
+        local_address,
+        private_key,
+        handle,
+        true,
+        tsproto::connectionmanager::SocketConnectionManager
+            ::with_resender_config(resend_config),
+        None,
+    )?;
+
+    // Set the data reference
+    {
+        let c2 = client.clone();
+        let mut client = client.borrow_mut();
+        client.connection_manager.set_data_ref(c2);
+    }
+    client::default_setup(client.clone(), false);
+    Ok(client)
+}
+
+/// Removes the connections of a client when connecting was aborted.
+///
+/// The client and its socket are freed when the future which connects is
+/// dropped, but the tsproto connections keep running until they are removed.
+struct ConnectGuard {
+    client: Rc<RefCell<client::ClientData>>,
+    addresses: Vec<SocketAddr>,
+    /// Set when the connection was added to the connection manager.
+    finished: bool,
+}
+
+impl ConnectGuard {
+    fn new(client: Rc<RefCell<client::ClientData>>, config: &ConnectOptions)
+        -> Self {
+        let mut addresses: Vec<_> = config.address.iter().cloned().collect();
+        addresses.extend_from_slice(&config.alternative_addresses);
+        Self { client, addresses, finished: false }
+    }
+}
+
+impl Drop for ConnectGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            for &addr in &self.addresses {
+                client::ClientData::remove_connection(self.client.clone(),
+                    addr);
+            }
+        }
+    }
+}
+
+/// Estimate the offset between our clock and the clock of the server.
+///
+/// The server stores the time when our client connected in
+/// `client_lastconnected`, which is compared with our estimate of the time
+/// when the server accepted the `clientinit`. The accuracy is limited to a
+/// second because the server only sends seconds.
+fn measure_clock_offset(inner: Weak<RefCell<InnerCM>>, con: ConnectionId,
+    accepted_at: DateTime<Utc>, logger: Logger)
+    -> Box<Future<Item = (), Error = ()>> {
+    let own_client = {
+        let inner = if let Some(inner) = inner.upgrade() {
+            inner
+        } else {
+            return Box::new(future::ok(()));
+        };
+        let inner = inner.borrow();
+        if let Some(con) = inner.connections.get(&con) {
+            con.borrow().own_client
+        } else {
+            return Box::new(future::ok(()));
+        }
+    };
+    let logger2 = logger.clone();
+    let mut command = commands::Command::new("clientgetvariables");
+    command.push("clid", own_client.0.to_string());
+    // The server also sends this notification when other clients change
+    let filter = structs::rows_with("clid", vec![own_client.0.to_string()]);
+    Box::new(send_command_with_response(&inner, con, command,
+        Some("notifyclientupdated"), Some(filter)).and_then(|rows| {
+        let row = rows.first().ok_or_else(|| Error::InvalidResponse(
+            String::from("Got no client variables")))?;
+        parse_timestamp_arg(row, "client_lastconnected")
+    }).map(move |connected| {
+        let offset = connected.signed_duration_since(accepted_at);
+        debug!(logger, "Measured clock offset";
+            "offset_ms" => offset.num_milliseconds());
+        if let Some(inner) = inner.upgrade() {
+            if let Some(con) = inner.borrow().connections.get(&con) {
+                con.borrow_mut().clock_offset = offset;
+            }
+        }
+    }).map_err(move |e| warn!(logger2, "Failed to measure the clock offset";
+        "error" => ?e)))
+}
+
+/// Get the ip addresses and countries of the clients which were on the
+/// server before we connected.
+///
+/// The server only sends the ip addresses of clients which enter our view
+/// later. Without the permission to see addresses, the list contains no
+/// addresses.
+fn request_client_list(inner: Weak<RefCell<InnerCM>>, con: ConnectionId,
+    logger: Logger) -> Box<Future<Item = (), Error = ()>> {
+    let mut command = commands::Command::new("clientlist");
+    command.push("-country", "");
+    command.push("-ip", "");
+    Box::new(send_command_with_response(&inner, con, command,
+        Some("notifyclientlist"), None).map(move |rows| {
+        if let Some(inner) = inner.upgrade() {
+            if let Some(con) = inner.borrow().connections.get(&con) {
+                let mut con = con.borrow_mut();
+                for row in &rows {
+                    con.apply_client_list_row(row);
+                }
+            }
+        }
+    }).map_err(move |e| warn!(logger, "Failed to get the client list";
+        "error" => ?e)))
+}
+
+/// Resolve with the result of the first future which succeeds.
+///
+/// The other futures keep running, `cleanup` is called with the results of
+/// those which succeed later. If all futures fail, the last error is returned.
+fn first_ok<T: 'static>(handle: &Handle, futs: Vec<BoxFuture<T>>,
+    cleanup: Rc<Fn(T)>) -> BoxFuture<T> {
+    let handle = handle.clone();
+    Box::new(future::select_ok(futs).map(move |(res, rest)| {
+        for f in rest {
+            let cleanup = cleanup.clone();
+            handle.spawn(f.then(move |res| {
+                if let Ok(res) = res {
+                    cleanup(res);
+                }
+                Ok(())
+            }));
+        }
+        res
+    }))
+}
+
+/// Keep the id of a connection reserved until the future finishes.
+fn hold_id<F: Future<Item = (), Error = ()> + 'static>(token: Rc<()>, f: F)
+    -> Box<Future<Item = (), Error = ()>> {
+    Box::new(f.then(move |r| {
+        drop(token);
+        r
+    }))
+}
+
+/// The maximum number of lines which the server returns for one `logview`
+/// command.
+const MAX_LOG_LINES: u32 = 100;
+
+/// Fetch the last `lines` lines of the server log, the oldest entry first.
+///
+/// The server returns at most [`MAX_LOG_LINES`] at once, so the log is read
+/// backwards in multiple requests.
+///
+/// [`MAX_LOG_LINES`]: constant.MAX_LOG_LINES.html
+fn fetch_server_log(inner: Weak<RefCell<InnerCM>>, con: ConnectionId,
+    lines: u32) -> BoxFuture<Vec<LogEntry>> {
+    if lines == 0 {
+        return Box::new(future::ok(Vec::new()));
+    }
+    Box::new(future::loop_fn((Vec::new(), None), move |(mut entries, pos):
+        (Vec<LogEntry>, Option<u64>)| {
+        let mut command = commands::Command::new("logview");
+        command.push("lines", std::cmp::min(lines - entries.len() as u32,
+            MAX_LOG_LINES).to_string());
+        command.push("reverse", "1");
+        command.push("instance", "0");
+        if let Some(pos) = pos {
+            command.push("begin_pos", pos.to_string());
+        }
+        send_command_with_response(&inner, con, command,
+            Some("notifyserverlog"), None).and_then(move |rows| {
+            let mut received = 0;
+            for line in rows.iter().filter_map(|r| r.get("l")) {
+                received += 1;
+                if let Some(entry) = LogEntry::from_line(line) {
+                    entries.push(entry);
+                }
+            }
+            // The position where the next older lines start
+            let last_pos = rows.first()
+                .and_then(|r| r.get("last_pos"))
+                .and_then(|p| p.parse::<u64>().ok())
+                .unwrap_or(0);
+            if received == 0 || last_pos == 0
+                || entries.len() as u32 >= lines {
+                entries.reverse();
+                Ok(future::Loop::Break(entries))
+            } else {
+                Ok(future::Loop::Continue((entries, Some(last_pos))))
+            }
+        })
+    }))
+}
+
+/// The entries of `entries` which come after `last`.
+///
+/// If `last` is not contained anymore, all entries which are newer than it are
+/// returned.
+fn new_log_entries(entries: Vec<LogEntry>, last: &LogEntry) -> Vec<LogEntry> {
+    if let Some(i) = entries.iter().rposition(|e| e == last) {
+        entries.into_iter().skip(i + 1).collect()
+    } else {
+        entries.into_iter().filter(|e| e.timestamp > last.timestamp)
+            .collect()
+    }
+}
+
+/// Send a command regularly and reset the connection if the server does not
+/// answer it in time.
+///
+/// The heartbeat stops when the connection is removed or cannot be reset.
+fn app_heartbeat(inner: Weak<RefCell<InnerCM>>, con: ConnectionId,
+    client: Weak<RefCell<client::ClientData>>,
+    interval: std::time::Duration, timeout: std::time::Duration,
+    handle: Handle, logger: Logger) -> Box<Future<Item = (), Error = ()>> {
+    /// If the connection still exists, the id may be reused by another
+    /// connection.
+    fn is_alive(inner: &Weak<RefCell<InnerCM>>, con: ConnectionId,
+        client: &Weak<RefCell<client::ClientData>>) -> bool {
+        match (inner.upgrade(), client.upgrade()) {
+            (Some(inner), Some(client)) => inner.borrow().get(con)
+                .map(|c| Rc::ptr_eq(&c.client_data, &client))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    let logger2 = logger.clone();
+    Box::new(future::loop_fn((), move |()|
+        -> BoxFuture<future::Loop<(), ()>> {
+        let inner = inner.clone();
+        let client = client.clone();
+        let handle = handle.clone();
+        let logger = logger.clone();
+        let wait = tryf!(Timeout::new(interval, &handle));
+        Box::new(wait.map_err(|e| e.into()).and_then(move |()|
+            -> BoxFuture<future::Loop<(), ()>> {
+            // Stop if the connection was removed
+            if !is_alive(&inner, con, &client) {
+                return Box::new(future::ok(future::Loop::Break(())));
+            }
+
+            let answer = if let Some(inner) = inner.upgrade() {
+                let inner = inner.borrow();
+                match inner.get_mut(con) {
+                    Some(mut con) => con.send_heartbeat(),
+                    None => return Box::new(future::ok(
+                        future::Loop::Break(()))),
+                }
+            } else {
+                return Box::new(future::ok(future::Loop::Break(())));
+            };
+            let deadline = tryf!(Timeout::new(timeout, &handle));
+            Box::new(answer.select2(deadline).then(move |res|
+                -> BoxFuture<future::Loop<(), ()>> {
+                match res {
+                    // An error of the server is an answer too
+                    Ok(future::Either::A(_)) |
+                    Err(future::Either::A((Error::Server(..), _))) =>
+                        Box::new(future::ok(future::Loop::Continue(()))),
+                    Err(future::Either::A((e, _))) => Box::new(future::err(e)),
+                    Ok(future::Either::B(_)) | Err(future::Either::B(_)) => {
+                        // The connection may have been removed meanwhile
+                        if !is_alive(&inner, con, &client) {
+                            return Box::new(future::ok(
+                                future::Loop::Break(())));
+                        }
+                        warn!(logger, "Server did not answer the heartbeat, \
+                            resetting the connection");
+                        let inner = if let Some(inner) = inner.upgrade() {
+                            inner
+                        } else {
+                            return Box::new(future::ok(
+                                future::Loop::Break(())));
+                        };
+                        let cm = ConnectionManager { inner };
+                        let reset = Connection { cm: &cm, id: con }
+                            .hard_reset();
+                        Box::new(reset.map(|()| future::Loop::Continue(())))
+                    }
+                }
+            }))
+        }))
+    }).map_err(move |e| warn!(logger2, "Stopped the application heartbeat";
+        "error" => ?e)))
+}
+
+/// Convert an error which the server sent as answer to our `clientinit`.
+fn handshake_error(id: TsError, msg: String, extra_msg: Option<String>)
+    -> Error {
+    match id {
+        TsError::ClientTooManyClonesConnected => Error::IdentityAlreadyConnected,
+        TsError::ServerInvalidPassword => Error::InvalidServerPassword,
+        TsError::ClientVersionOutdated => Error::ClientTooOld {
+            required: extra_msg.filter(|m| !m.is_empty()),
+        },
+        TsError::ConnectFailedBanned => {
+            let extra_msg = extra_msg.filter(|m| !m.is_empty());
+            let until = extra_msg.as_ref().and_then(|m| parse_ban_time(m))
+                .and_then(|d| Utc::now().checked_add_signed(d));
+            Error::Banned { reason: extra_msg, until }
+        }
+        _ => Error::Server(id, msg),
+    }
+}
+
+/// Parse the remaining time of a ban from a message like
+/// `you may retry in 600 seconds`.
+///
+/// Times which do not fit into an `u32` are ignored.
+fn parse_ban_time(msg: &str) -> Option<Duration> {
+    let words = msg.split_whitespace().collect::<Vec<_>>();
+    words.windows(2).filter(|w| w[1].starts_with("second"))
+        .filter_map(|w| w[0].parse::<u32>().ok())
+        .next()
+        .map(|s| Duration::seconds(i64::from(s)))
+}
+
+/// Request the variables of clients with one command.
+///
+/// Only the `notifyclientupdated` rows of the requested clients are taken, the
+/// server also sends them when other clients change. Returns the result for
+/// every client in the order of `clients`.
+fn request_clients_info(inner: &Weak<RefCell<InnerCM>>, con: ConnectionId,
+    clients: &[ClientId]) -> BoxFuture<Vec<(ClientId, Result<ClientInfo>)>> {
+    let mut command = commands::Command::new("clientgetvariables");
+    command.list_args = clients.iter()
+        .map(|c| vec![(String::from("clid"), c.0.to_string())])
+        .collect();
+    let filter = structs::rows_with("clid",
+        clients.iter().map(|c| c.0.to_string()).collect());
+    let clients = clients.to_vec();
+    Box::new(send_command_with_response(inner, con, command,
+        Some("notifyclientupdated"), Some(filter)).and_then(move |rows| {
+        let mut infos = Map::new();
+        for row in &rows {
+            let info = ClientInfo::from_row(row)?;
+            infos.insert(info.id, info);
+        }
+        Ok(clients.into_iter().map(|c| (c, infos.remove(&c).ok_or_else(||
+            Error::InvalidResponse(String::from("Got no client variables")))))
+            .collect())
+    }))
+}
+
+/// Send a command on a connection and wait for the answer of the server.
+fn send_command(inner: &Weak<RefCell<InnerCM>>, con: ConnectionId,
+    command: commands::Command) -> BoxFuture<()> {
+    Box::new(send_command_with_response(inner, con, command, None, None)
+        .map(|_| ()))
+}
+
+/// Send a command on a connection and collect the response with the name
+/// `response_name`, which is accepted by `filter`.
+fn send_command_with_response(inner: &Weak<RefCell<InnerCM>>,
+    con: ConnectionId, command: commands::Command,
+    response_name: Option<&'static str>, filter: Option<structs::RowFilter>)
+    -> BoxFuture<structs::ResponseRows> {
+    let inner = if let Some(inner) = inner.upgrade() {
+        inner
+    } else {
+        return Box::new(future::err(Error::ConnectionNotFound));
+    };
+    let inner = inner.borrow();
+    let mut con = if let Some(con) = inner.get_mut(con) {
+        con
+    } else {
+        return Box::new(future::err(Error::ConnectionNotFound));
+    };
+    con.send_command_with_response(command, response_name, filter)
+}
+
+/// Get an argument of a response row and parse it.
+fn parse_response_arg<T: std::str::FromStr>(row: &Map<String, String>,
+    arg: &str) -> Result<T> {
+    row.get(arg).and_then(|v| v.parse().ok()).ok_or_else(||
+        Error::InvalidResponse(format!("Cannot parse argument {}", arg)))
+}
+
+/// Parse an argument of a response, which contains a unix timestamp.
+fn parse_timestamp_arg(row: &Map<String, String>, arg: &str)
+    -> Result<DateTime<Utc>> {
+    let secs = parse_response_arg(row, arg)?;
+    Utc.timestamp_opt(secs, 0).single().ok_or_else(||
+        Error::InvalidResponse(format!("Invalid timestamp in {}", arg)))
+}
+
+/// Parse the rows of a permission list into the permissions and their values.
+///
+/// Newer servers know permissions which we do not know, they are skipped.
+fn parse_permission_rows(logger: &Logger, rows: &[Map<String, String>])
+    -> Result<Vec<(Permission, i32)>> {
+    let mut res = Vec::with_capacity(rows.len());
+    for row in rows {
+        let id: u32 = parse_response_arg(row, "permid")?;
+        let value = parse_response_arg(row, "permvalue")?;
+        if let Some(perm) = Permission::from_u32(id) {
+            res.push((perm, value));
+        } else {
+            warn!(logger, "Skipping unknown permission"; "id" => id);
+        }
+    }
+    Ok(res)
+}
+
+/// Download a file from the file transfer of the server.
+///
+/// `channel` is the channel which contains the file, files which are not part
+/// of a channel like icons use channel `0`. Files which are larger than
+/// `max_size` bytes are not downloaded.
+fn download_file(inner: &Weak<RefCell<InnerCM>>, con: ConnectionId,
+    channel: ChannelId, path: String, max_size: usize) -> BoxFuture<Vec<u8>> {
+    let (handle, server_addr, transfer_id) = {
+        let inner = if let Some(inner) = inner.upgrade() {
+            inner
+        } else {
+            return Box::new(future::err(Error::ConnectionNotFound));
+        };
+        let inner = inner.borrow();
+        let handle = inner.handle.clone();
+        let mut con = if let Some(con) = inner.get_mut(con) {
+            con
+        } else {
+            return Box::new(future::err(Error::ConnectionNotFound));
+        };
+        let transfer_id = con.next_file_transfer_id;
+        con.next_file_transfer_id = transfer_id.wrapping_add(1);
+        (handle, con.address, transfer_id)
+    };
+
+    let mut command = commands::Command::new("ftinitdownload");
+    command.push("clientftfid", transfer_id.to_string());
+    command.push("name", path);
+    command.push("cid", channel.0.to_string());
+    command.push("cpw", "");
+    command.push("seekpos", "0");
+    command.push("proto", "1");
+    Box::new(send_command_with_response(inner, con, command,
+        Some("notifystartdownload"), None)
+        .and_then(move |rows| -> BoxFuture<_> {
+        let row = tryf!(rows.first().ok_or_else(|| Error::InvalidResponse(
+            String::from("Got no download information"))));
+        let key = tryf!(row.get("ftkey").cloned().ok_or_else(||
+            Error::InvalidResponse(String::from("Got no file transfer key"))));
+        let port: u16 = tryf!(parse_response_arg(row, "port"));
+        let size = tryf!(parse_download_size(row, max_size));
+        // The server sends 0.0.0.0 if the file transfer is on the same ip
+        let ip = match row.get("ip").and_then(|ip| ip.parse::<IpAddr>().ok()) {
+            Some(ip) if !ip.is_unspecified() => ip,
+            _ => server_addr.ip(),
+        };
+
+        Box::new(TcpStream::connect(&SocketAddr::new(ip, port), &handle)
+            .and_then(move |stream| tokio_io::io::write_all(stream,
+                key.into_bytes()))
+            .and_then(move |(stream, _)| tokio_io::io::read_exact(stream,
+                vec![0; size]))
+            .map(|(_, data)| data)
+            .map_err(|e| e.into()))
+    }))
+}
+
+/// Get the size of a file download and check that it is not too large.
+fn parse_download_size(row: &Map<String, String>, max_size: usize)
+    -> Result<usize> {
+    let size: usize = parse_response_arg(row, "size")?;
+    if size > max_size {
+        return Err(Error::InvalidResponse(format!(
+            "The file is too large ({} bytes)", size)));
+    }
+    Ok(size)
+}
+
+/// Write the rows of a snapshot as the arguments of `serversnapshotdeploy`.
+///
+/// The rows are kept in the order in which they were received, it is part of
+/// the snapshot.
+fn serialize_snapshot(rows: &[Map<String, String>]) -> Result<String> {
+    if rows.is_empty() {
+        return Err(Error::InvalidResponse(String::from("Got no snapshot")));
+    }
+    let mut command = commands::Command::new("serversnapshotdeploy");
+    for row in rows {
+        let mut args = row.iter().map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        args.sort();
+        command.list_args.push(args);
+    }
+    let mut data = Vec::new();
+    command.write_arguments(&mut data)?;
+    Ok(String::from_utf8(data).map_err(|_| Error::InvalidResponse(
+        String::from("Invalid snapshot")))?)
+}
+
+/// Find a channel in the book by its parent and name.
+///
+/// If there are multiple channels with this name, the one with the smallest id
+/// is returned.
+fn find_channel(inner: &Weak<RefCell<InnerCM>>, con: ConnectionId,
+    parent: ChannelId, name: &str) -> Option<ChannelId> {
+    let inner = inner.upgrade()?;
+    let inner = inner.borrow();
+    let con = inner.get(con)?;
+    let res = con.server.channels.values()
+        .filter(|c| c.parent == parent && c.name == name)
+        .map(|c| c.id)
+        .min_by_key(|c| c.0);
+    res
+}
+
+/// Bring a nickname into the form which the server uses to compare names.
+fn normalize_nickname(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn create_move_command(client: ClientId, channel: ChannelId,
+    password: Option<&str>) -> commands::Command {
+    let mut command = commands::Command::new("clientmove");
+    command.push("clid", client.0.to_string());
+    command.push("cid", channel.0.to_string());
+    if let Some(password) = password {
+        command.push("cpw", algs::hash_password(password));
+    }
+    command
+}
+
+/// Create the command to kick a client and the permission which it needs.
+fn create_kick_command(client: ClientId, target: KickTarget,
+    reason: Option<&str>) -> (commands::Command, Permission) {
+    let (reason_id, permission) = match target {
+        KickTarget::Channel => (MoveReason::KickChannel,
+            Permission::i_client_kick_from_channel_power),
+        KickTarget::Server => (MoveReason::KickServer,
+            Permission::i_client_kick_from_server_power),
+    };
+    let mut command = commands::Command::new("clientkick");
+    command.push("clid", client.0.to_string());
+    command.push("reasonid", (reason_id as u8).to_string());
+    if let Some(reason) = reason {
+        command.push("reasonmsg", reason);
+    }
+    (command, permission)
+}
+
+fn create_ban_command(client: ClientId, duration: Option<Duration>,
+    reason: Option<&str>) -> Result<commands::Command> {
+    let mut command = commands::Command::new("banclient");
+    command.push("clid", client.0.to_string());
+    // 0 means a permanent ban, so shorter bans are not allowed
+    let seconds = match duration {
+        Some(d) if d <= Duration::zero() =>
+            return Err(Error::InvalidBanDuration(d)),
+        // The server only knows seconds
+        Some(d) => std::cmp::max(d.num_seconds(), 1),
+        None => 0,
+    };
+    command.push("time", seconds.to_string());
+    if let Some(reason) = reason {
+        command.push("banreason", reason);
+    }
+    Ok(command)
+}
+
+/// Create the commands to move multiple clients into a channel.
+///
+/// If `batched` is set, all clients are moved with a single command, otherwise
+/// one command per client is created.
+fn create_move_commands(clients: &[ClientId], channel: ChannelId,
+    password: Option<&str>, batched: bool) -> Vec<commands::Command> {
+    if !batched {
+        return clients.iter()
+            .map(|c| create_move_command(*c, channel, password))
+            .collect();
+    }
+
+    let mut command = commands::Command::new("clientmove");
+    command.push("cid", channel.0.to_string());
+    if let Some(password) = password {
+        command.push("cpw", algs::hash_password(password));
+    }
+    command.list_args = clients.iter()
+        .map(|c| vec![(String::from("clid"), c.0.to_string())])
+        .collect();
+    vec![command]
+}
+
+/// Polls the [`NetworkWrapper`] of a connection, so the book gets updated and
+/// commands get their answers.
+///
+/// When the connection ends or fails, or when the server kicked us and we are
+/// not allowed to reconnect, it is removed from the connection manager. The
+/// driver stops when its connection was removed or replaced, e.g. by a hard
+/// reset, which starts a new driver.
+///
+/// [`NetworkWrapper`]: structs/struct.NetworkWrapper.html
+struct ConnectionDriver {
+    inner: Weak<RefCell<InnerCM>>,
+    id: ConnectionId,
+    /// The connection which this driver was created for.
+    con: Weak<RefCell<structs::NetworkWrapper>>,
+    /// Keeps the id reserved while the driver runs.
+    _id_token: Rc<()>,
+    logger: Logger,
+}
+
+impl ConnectionDriver {
+    fn new(inner: Weak<RefCell<InnerCM>>, id: ConnectionId,
+        con: &Rc<RefCell<structs::NetworkWrapper>>, id_token: Rc<()>,
+        logger: Logger) -> Self {
+        Self { inner, id, con: Rc::downgrade(con), _id_token: id_token,
+            logger }
+    }
+
+    /// Remove the connection from the connection manager, if it was not
+    /// replaced meanwhile, e.g. by a hard reset.
+    ///
+    /// The event listeners get the reason why the connection was closed.
+    fn remove(&self, inner: &RefCell<InnerCM>,
+        con_rc: &Rc<RefCell<structs::NetworkWrapper>>,
+        con: &mut structs::NetworkWrapper) {
+        let mut inner = inner.borrow_mut();
+        let is_current = inner.connections.get(&self.id)
+            .map(|c| Rc::ptr_eq(c, con_rc)).unwrap_or(false);
+        // A hard reset takes over the connection
+        if is_current && !con.resetting {
+            debug!(self.logger, "Removing closed connection");
+            inner.connections.remove(&self.id);
+            inner.connection_ids.release(self.id, &con.id_token);
+            let reason = match con.disconnect_reason {
+                Some(MoveReason::LostConnection) | None =>
+                    DisconnectReason::LostConnection,
+                Some(r) => DisconnectReason::Server(r),
+            };
+            con.notify_disconnected(reason);
+        }
+    }
+}
+
+impl Future for ConnectionDriver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        let inner = if let Some(inner) = self.inner.upgrade() {
+            inner
+        } else {
+            // The connection manager is gone
+            return Ok(futures::Async::Ready(()));
+        };
+        // Only borrow this connection while it is updated, so the other
+        // connections can be used meanwhile.
+        let con = match (inner.borrow().connections.get(&self.id),
+            self.con.upgrade()) {
+            (Some(con), Some(own)) if Rc::ptr_eq(con, &own) => own,
+            // The connection was removed or replaced
+            _ => return Ok(futures::Async::Ready(())),
+        };
+        let con_rc = con;
+        let mut con = con_rc.borrow_mut();
+        loop {
+            match con.poll() {
+                Ok(futures::Async::Ready(Some(_))) => if con.is_kicked() {
+                    info!(self.logger, "Removing connection after a kick";
+                        "reason" => ?con.disconnect_reason);
+                    con.fail_pending_commands(None);
+                    let (client, addr) = (con.client_data.clone(),
+                        con.address);
+                    client::ClientData::remove_connection(client, addr);
+                    self.remove(&inner, &con_rc, &mut con);
+                    return Ok(futures::Async::Ready(()));
+                },
+                Ok(futures::Async::Ready(None)) => {
+                    self.remove(&inner, &con_rc, &mut con);
+                    return Ok(futures::Async::Ready(()));
+                }
+                Ok(futures::Async::NotReady) =>
+                    return Ok(futures::Async::NotReady),
+                Err(e) => {
+                    error!(self.logger, "Connection exited with error";
+                        "error" => ?e);
+                    // The connection is unusable, e.g. the socket failed
+                    if con.disconnect_reason.is_none() {
+                        con.disconnect_reason =
+                            Some(MoveReason::LostConnection);
+                        con.disconnected_at = Some(Utc::now());
+                    }
+                    con.fail_pending_commands(None);
+                    let (client, addr) = (con.client_data.clone(),
+                        con.address);
+                    client::ClientData::remove_connection(client, addr);
+                    self.remove(&inner, &con_rc, &mut con);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+// Private methods
+impl ConnectionManager {
+    fn get_server(con: &structs::NetworkWrapper, _: ConnectionId) -> &structs::Server {
+        &con.server
+    }
+    fn get_optional_server_data(con: &structs::NetworkWrapper, _: ConnectionId) -> &structs::OptionalServerData {
+        con.server.optional_data.as_ref().unwrap()
+    }
+    fn get_connection_server_data(con: &structs::NetworkWrapper, _: ConnectionId) -> &structs::ConnectionServerData {
+        con.server.connection_data.as_ref().unwrap()
+    }
+
+    fn get_client(con: &structs::NetworkWrapper, _: ConnectionId, client: ClientId) -> &structs::Client {
+        &con.server.clients[&client]
+    }
+    fn get_optional_client_data(con: &structs::NetworkWrapper, _: ConnectionId, client: ClientId) -> &structs::OptionalClientData {
+        con.server.clients[&client].optional_data.as_ref().unwrap()
+    }
+    fn get_connection_client_data(con: &structs::NetworkWrapper, _: ConnectionId, client: ClientId) -> &structs::ConnectionClientData {
+        con.server.clients[&client].connection_data.as_ref().unwrap()
+    }
+
+    fn get_channel(con: &structs::NetworkWrapper, _: ConnectionId, chan: ChannelId) -> &structs::Channel {
+        &con.server.channels[&chan]
+    }
+    fn get_optional_channel_data(con: &structs::NetworkWrapper, _: ConnectionId, chan: ChannelId) -> &structs::OptionalChannelData {
+        con.server.channels[&chan].optional_data.as_ref().unwrap()
+    }
+
+    fn get_chat_entry(_con: &structs::NetworkWrapper, _: ConnectionId, _sender: ClientId) -> &structs::ChatEntry {
+        unimplemented!("Chatting is not yet implemented")
+    }
+}
+
+impl<'a> Connection<'a> {
+    /// Check if the connected server supports a capability.
+    ///
+    /// This is derived from the version of the server. If the version cannot
+    /// be parsed (e.g. for custom server implementations), everything is
+    /// assumed to be supported.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn supports(&self, capability: ServerCapability) -> Option<bool> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        if let Some(version) = parse_server_version(&con.server.version) {
+            Some(version.as_slice() >= capability.min_version())
+        } else {
+            Some(true)
+        }
+    }
+
+    /// If a client on the server uses this nickname.
+    ///
+    /// Names are compared like the server does it to detect collisions:
+    /// Case is ignored, surrounding whitespace is removed and runs of
+    /// whitespace inside the name count as a single space.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn is_nickname_in_use(&self, name: &str) -> Option<bool> {
+        let name = normalize_nickname(name);
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        let res = con.server.clients.values()
+            .any(|c| normalize_nickname(&c.name) == name);
+        Some(res)
+    }
+
+    /// The codec and the codec quality of a channel.
+    ///
+    /// The quality ranges from 0 to 10, higher values need more bandwidth.
+    pub fn channel_codec(&self, channel: ChannelId) -> Option<(Codec, u8)> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        con.server.channels.get(&channel).map(|c| (c.codec, c.codec_quality))
+    }
+
+    /// Encode and send audio from `source` in the background.
+    ///
+    /// The source has to yield mono frames of [`FRAME_SIZE`] samples with a
+    /// sample rate of [`SAMPLE_RATE`]. The audio is only sent while `talk` is
+    /// active, frames which arrive while not talking are discarded. To save
+    /// bandwidth, silent audio can be suppressed with
+    /// [`TalkControl::set_silence_threshold`].
+    ///
+    /// The bitrate is chosen according to the codec quality of the channel
+    /// of our client, see [`channel_codec`].
+    ///
+    /// The capture stops when the source ends or the connection is closed.
+    ///
+    /// [`channel_codec`]: #method.channel_codec
+    /// [`FRAME_SIZE`]: constant.FRAME_SIZE.html
+    /// [`SAMPLE_RATE`]: constant.SAMPLE_RATE.html
+    /// [`TalkControl::set_silence_threshold`]: struct.TalkControl.html#method.set_silence_threshold
+    pub fn start_voice_capture<S>(&self, source: S, talk: TalkControl)
+        -> Result<()>
+        where S: Stream<Item = Vec<i16>, Error = Error> + 'static {
+        let inner = self.cm.inner.borrow();
+        inner.check_reactor()?;
+        let (con, quality, shutdown, next_id) = {
+            let con = inner.get(self.id).ok_or(Error::ConnectionNotFound)?;
+            (con.client_connection.clone(), con.voice_quality.clone(),
+                con.voice_shutdown.clone(), con.next_voice_id.clone())
+        };
+        let logger = inner.logger.clone();
+        let capture = voice::VoiceCapture::new(logger.clone(), con, source,
+            talk, quality, shutdown, next_id, None)?;
+        inner.handle.spawn(capture.map_err(move |e| {
+            error!(logger, "Voice capture exited with error"; "error" => ?e);
+        }));
+        Ok(())
+    }
+
+    /// Encode audio from `source` and whisper it to `target` in the
+    /// background.
+    ///
+    /// This works like [`start_voice_capture`], but only the clients of the
+    /// target hear the audio instead of the clients in our channel.
+    ///
+    /// [`start_voice_capture`]: #method.start_voice_capture
+    pub fn start_whisper_capture<S>(&self, source: S, talk: TalkControl,
+        target: WhisperTarget) -> Result<()>
+        where S: Stream<Item = Vec<i16>, Error = Error> + 'static {
+        let inner = self.cm.inner.borrow();
+        inner.check_reactor()?;
+        let (con, quality, shutdown, next_id) = {
+            let con = inner.get(self.id).ok_or(Error::ConnectionNotFound)?;
+            (con.client_connection.clone(), con.voice_quality.clone(),
+                con.voice_shutdown.clone(), con.next_voice_id.clone())
+        };
+        let logger = inner.logger.clone();
+        let capture = voice::VoiceCapture::new(logger.clone(), con, source,
+            talk, quality, shutdown, next_id, Some(&target))?;
+        inner.handle.spawn(capture.map_err(move |e| {
+            error!(logger, "Whisper capture exited with error"; "error" => ?e);
+        }));
+        Ok(())
+    }
+
+    /// Send an encoded voice frame to the channel of our client.
+    ///
+    /// The data is sent as it is, it has to be encoded with `codec` already.
+    /// An empty frame ends the transmission, which can also be done with
+    /// [`stop_voice`]. The packets are numbered automatically.
+    ///
+    /// Voice is not sent while the connection is not established, e.g. while
+    /// it is disconnecting. The frame is dropped then and the future resolves
+    /// successfully.
+    ///
+    /// [`stop_voice`]: #method.stop_voice
+    pub fn send_voice(&self, codec: CodecType, data: &[u8]) -> BoxFuture<()> {
+        self.send_voice_frame(None, codec, data)
+    }
+
+    /// Whisper an encoded voice frame to `target`.
+    ///
+    /// This works like [`send_voice`], but only the clients of the target
+    /// hear it instead of the clients in our channel. An empty frame ends the
+    /// whisper, which can also be done with [`stop_voice`]. Voice and whisper
+    /// packets, also of voice captures, share their numbering, the target can
+    /// change between packets.
+    ///
+    /// [`send_voice`]: #method.send_voice
+    /// [`stop_voice`]: #method.stop_voice
+    pub fn whisper(&self, target: &WhisperTarget, codec: CodecType,
+        data: &[u8]) -> BoxFuture<()> {
+        self.send_voice_frame(Some(target), codec, data)
+    }
+
+    /// Send a voice frame to our channel or whisper it and remember the
+    /// transmission for [`stop_voice`].
+    ///
+    /// [`stop_voice`]: #method.stop_voice
+    fn send_voice_frame(&self, whisper: Option<&WhisperTarget>,
+        codec: CodecType, data: &[u8]) -> BoxFuture<()> {
+        let encoded = match whisper {
+            Some(target) => Some(tryf!(target.encode())),
+            None => None,
+        };
+        let (con, id) = {
+            let inner = self.cm.inner.borrow();
+            let mut con = if let Some(con) = inner.get_mut(self.id) {
+                con
+            } else {
+                return Box::new(future::err(Error::ConnectionNotFound));
+            };
+            let id = voice::next_voice_id(&con.next_voice_id);
+            con.voice_transmission = if data.is_empty() {
+                None
+            } else {
+                Some((codec, whisper.cloned()))
+            };
+            (con.client_connection.clone(), id)
+        };
+        voice::send_voice_packet(con, voice::create_voice_packet(id,
+            codec as u8, data, encoded.as_ref()))
+    }
+
+    /// End the transmission which was started with [`send_voice`] or
+    /// [`whisper`].
+    ///
+    /// Other clients see that we stopped talking. The end of a whisper is
+    /// sent to the same target. Nothing is sent if we are not sending voice.
+    ///
+    /// [`send_voice`]: #method.send_voice
+    /// [`whisper`]: #method.whisper
+    pub fn stop_voice(&self) -> BoxFuture<()> {
+        let transmission = match self.cm.inner.borrow().get(self.id) {
+            Some(con) => con.voice_transmission.clone(),
+            None => return Box::new(future::err(Error::ConnectionNotFound)),
+        };
+        if let Some((codec, whisper)) = transmission {
+            self.send_voice_frame(whisper.as_ref(), codec, &[])
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Get the voice data which other clients send to us.
+    ///
+    /// Every item contains the sending client, the codec and the encoded
+    /// frame, which is not decoded. An empty frame means that the client
+    /// stopped talking. Whispers are returned too, unless the sender is not
+    /// allowed to whisper to us (see [`is_whisper_allowed`]).
+    ///
+    /// The stream ends when the connection is closed.
+    ///
+    /// [`is_whisper_allowed`]: #method.is_whisper_allowed
+    pub fn get_voice_stream(&self)
+        -> BoxStream<(ClientId, CodecType, Vec<u8>)> {
+        let client_con = {
+            let inner = self.cm.inner.borrow();
+            let con = inner.get(self.id);
+            con.and_then(|con| con.client_connection.upgrade())
+        };
+        let client_con = if let Some(c) = client_con {
+            c
+        } else {
+            return Box::new(futures::stream::empty());
+        };
+        let inner = Rc::downgrade(&self.cm.inner);
+        let id = self.id;
+        Box::new(client::ClientConnection::get_voice(client_con)
+            .map_err(|e| e.into())
+            .filter_map(move |packet| {
+                let voice = voice::parse_server_voice(&packet)?;
+                if packet.header.get_type() == PacketType::VoiceWhisper {
+                    let inner = inner.upgrade()?;
+                    let inner = inner.borrow();
+                    if !inner.get(id)?.is_whisper_allowed(voice.0) {
+                        return None;
+                    }
+                }
+                Some(voice)
+            }))
+    }
+
+    /// Tear down the connection and connect again with the same identity.
+    ///
+    /// This is a recovery for situations where the crypto state got out of
+    /// sync and no packet can be decrypted anymore. The protocol has no way to
+    /// negotiate new keys for an existing connection, so a new handshake is
+    /// done. The [`ConnectionId`] stays the same.
+    ///
+    /// The channels and clients of the book are kept and updated in place from
+    /// the lists which the server sends for the new connection. Entries which
+    /// vanished in the meantime are removed when the lists are complete, so
+    /// the book can contain some stale channels and clients for a short time.
+    /// The server sends the full lists again, so this saves no bandwidth and
+    /// the notifications for all channels and clients are returned again.
+    ///
+    /// The old connection is not disconnected gracefully, as the server would
+    /// not understand us anyway. It drops our old client after a timeout.
+    ///
+    /// Our client is moved back into the channel where it was before. If the
+    /// server was restarted and the channel got a new id, it is found by the
+    /// names of the channel and its parents.
+    ///
+    /// If the server kicked or banned us, this fails with
+    /// [`Error::Disconnected`] unless it was allowed with
+    /// [`ConnectOptions::reconnect_after_kick`].
+    ///
+    /// [`ConnectionId`]: struct.ConnectionId.html
+    /// [`Error::Disconnected`]: enum.Error.html#variant.Disconnected
+    /// [`ConnectOptions::reconnect_after_kick`]: struct.ConnectOptions.html#method.reconnect_after_kick
+    pub fn hard_reset(&self) -> BoxFuture<()> {
+        /// The connection if it was not removed or replaced meanwhile.
+        fn current(inner: &InnerCM, id: ConnectionId,
+            old: &Weak<RefCell<structs::NetworkWrapper>>)
+            -> Option<Rc<RefCell<structs::NetworkWrapper>>> {
+            match (inner.connections.get(&id), old.upgrade()) {
+                (Some(con), Some(old)) if Rc::ptr_eq(con, &old) => Some(old),
+                _ => None,
+            }
+        }
+
+        let (client, config, logger, handle, stats, type_stats, addr,
+            cooldown, old) = {
+            let inner = self.cm.inner.borrow();
+            tryf!(inner.check_reactor());
+            let con_rc = tryf!(inner.connections.get(&self.id)
+                .ok_or(Error::ConnectionNotFound));
+            let con = con_rc.borrow();
+            let cooldown = tryf!(con.kick_cooldown());
+            (con.client_data.clone(), con.options.clone(),
+                inner.logger.clone(), inner.handle.clone(), con.get_stats(),
+                con.get_packet_type_stats(), con.address, cooldown,
+                Rc::downgrade(con_rc))
+        };
+
+        // Wait until we are allowed to connect again after a kick
+        let wait: BoxFuture<()> = if let Some(cooldown) = cooldown {
+            info!(logger, "Waiting before reconnecting after a kick";
+                "seconds" => cooldown.as_secs());
+            Box::new(tryf!(Timeout::new(cooldown, &handle))
+                .map_err(|e| e.into()))
+        } else {
+            Box::new(future::ok(()))
+        };
+
+        let id = self.id;
+        let inner = Rc::downgrade(&self.cm.inner);
+        let inner2 = inner.clone();
+        let inner3 = inner.clone();
+        let old2 = old.clone();
+        let old3 = old.clone();
+        let client2 = client.clone();
+        let config2 = config.clone();
+        Box::new(wait.and_then(move |()| -> BoxFuture<ConnectedClient> {
+            // The connection may have been removed during the cooldown
+            let inner = tryf!(inner2.upgrade()
+                .ok_or(Error::ConnectionNotFound));
+            let old = tryf!(current(&inner.borrow(), id, &old2)
+                .ok_or(Error::ConnectionNotFound));
+            // Detach the old driver and drop the old connection
+            old.borrow_mut().resetting = true;
+            client::ClientData::remove_connection(client2.clone(), addr);
+            connect_client(logger, client2, config2, addr)
+        }).and_then(move |(con_weak, stream, p, accepted_at)| {
+            let inner_rc = inner.upgrade().ok_or(Error::ConnectionNotFound)?;
+            let mut inner = inner_rc.borrow_mut();
+
+            // Replace the connection and its book
+            let mut con = structs::NetworkWrapper::new(id, client, con_weak,
+                stream, p, addr, config);
+            // Abort if the connection was removed during the handshake
+            let old = if let Some(old) = current(&inner, id, &old) {
+                old
+            } else {
+                let (client, addr) = (con.client_data.clone(), con.address);
+                client::ClientData::remove_connection(client, addr);
+                return Err(Error::ConnectionNotFound);
+            };
+            // The id stays the same, so it is not released
+            inner.connections.remove(&id);
+            {
+                let mut old = old.borrow_mut();
+                con.id_token = old.id_token.clone();
+                con.retain_book(&mut old);
+                con.take_listeners(&mut old);
+                con.connected_since = old.connected_since;
+                con.previous_stats = stats;
+                con.previous_packet_type_stats = type_stats;
+                con.reconnects = old.reconnects + 1;
+                con.channel_passwords = std::mem::replace(
+                    &mut old.channel_passwords, Map::new());
+                con.chat_history = std::mem::replace(
+                    &mut old.chat_history, Map::new());
+                con.client_names = std::mem::replace(
+                    &mut old.client_names, Map::new());
+                con.whisper_allow_list = old.whisper_allow_list.take();
+                con.client_waiters = std::mem::replace(
+                    &mut old.client_waiters, Vec::new());
+                // Keep the voice state which is shared with running captures
+                con.voice_quality = old.voice_quality.clone();
+                con.voice_shutdown = old.voice_shutdown.clone();
+                con.next_voice_id = old.next_voice_id.clone();
+            }
+            let token = con.id_token.clone();
+            let con = Rc::new(RefCell::new(con));
+            inner.connections.insert(id, con.clone());
+
+            // The driver of the old connection stops because its connection
+            // was replaced
+            handle.spawn(ConnectionDriver::new(Rc::downgrade(&inner_rc), id,
+                &con, token.clone(), inner.logger.clone()));
+            handle.spawn(hold_id(token.clone(), measure_clock_offset(
+                Rc::downgrade(&inner_rc), id, accepted_at,
+                inner.logger.clone())));
+            if con.borrow().book_mode == BookMode::Full {
+                handle.spawn(hold_id(token, request_client_list(
+                    Rc::downgrade(&inner_rc), id, inner.logger.clone())));
+            }
+            Ok(())
+        }).map_err(move |e| {
+            // Remove the connection if the old one was dropped and the new
+            // one could not be created.
+            if let Some(inner) = inner3.upgrade() {
+                let mut inner = inner.borrow_mut();
+                let old = current(&inner, id, &old3)
+                    .filter(|c| c.borrow().resetting);
+                if let Some(old) = old {
+                    let mut old = old.borrow_mut();
+                    inner.connections.remove(&id);
+                    inner.connection_ids.release(id, &old.id_token);
+                    old.notify_disconnected(DisconnectReason::LostConnection);
+                }
+            }
+            e
+        }))
+    }
+
+    /// Remember the password of a channel.
+    ///
+    /// The password is used by [`move_to`] if no password is given and the
+    /// channel needs one. It gets forgotten when the server rejects it.
+    ///
+    /// [`move_to`]: #method.move_to
+    pub fn remember_channel_password(&self, channel: ChannelId,
+        password: String) {
+        let inner = self.cm.inner.borrow();
+        if let Some(con) = inner.connections.get(&self.id) {
+            con.borrow_mut().channel_passwords.insert(channel, password);
+        }
+    }
+
+    /// Move our own client into another channel.
+    ///
+    /// If no password is given but the channel needs one, the password which
+    /// was remembered with [`remember_channel_password`] is tried. If there is
+    /// none or it is wrong, the [`ConnectOptions::password_provider`] is asked
+    /// and the password is remembered if it is right.
+    ///
+    /// [`remember_channel_password`]: #method.remember_channel_password
+    /// [`ConnectOptions::password_provider`]: struct.ConnectOptions.html#method.password_provider
+    pub fn move_to(&self, channel: ChannelId, password: Option<String>)
+        -> BoxFuture<()> {
+        let own_client = match self.cm.inner.borrow().get(self.id) {
+            Some(con) => con.own_client,
+            None => return Box::new(future::err(Error::ConnectionNotFound)),
+        };
+        let inner = Rc::downgrade(&self.cm.inner);
+        let id = self.id;
+        if let Some(password) = password {
+            return send_command(&inner, id, create_move_command(own_client,
+                channel, Some(&password)));
+        }
+
+        let inner2 = inner.clone();
+        Box::new(send_command(&inner, id, create_move_command(own_client,
+            channel, None)).or_else(move |e| -> BoxFuture<()> {
+            if is_wrong_channel_password(&e) {
+                let password = inner.upgrade().and_then(|i| i.borrow()
+                    .get(id)
+                    .and_then(|c| c.channel_passwords.get(&channel).cloned()));
+                if let Some(password) = password {
+                    let cmd = create_move_command(own_client, channel,
+                        Some(&password));
+                    return Box::new(send_command(&inner, id, cmd)
+                        .map_err(move |e| {
+                        if is_wrong_channel_password(&e) {
+                            // Forget the wrong password
+                            if let Some(inner) = inner.upgrade() {
+                                let inner = inner.borrow();
+                                if let Some(con) = inner.connections.get(&id) {
+                                    con.borrow_mut().channel_passwords
+                                        .remove(&channel);
+                                }
+                            }
+                        }
+                        e
+                    }));
+                }
+            }
+            Box::new(future::err(e))
+        }).or_else(move |e| {
+            let inner = inner2;
+            let provider = inner.upgrade().and_then(|i| i.borrow().get(id)
+                .and_then(|c| c.options.password_provider.clone()));
+            let inner2 = inner.clone();
+            retry_with_passwords(provider,
+                PasswordRequest::Channel { connection: id, channel }, e,
+                is_wrong_channel_password, move |password| send_command(&inner,
+                    id, create_move_command(own_client, channel,
+                    Some(&password))))
+                .map(move |((), password)| {
+                // Remember the right password
+                if let Some(inner) = inner2.upgrade() {
+                    let inner = inner.borrow();
+                    if let Some(con) = inner.connections.get(&id) {
+                        con.borrow_mut().channel_passwords
+                            .insert(channel, password);
+                    }
+                }
+            })
+        }))
+    }
+
+    /// Move our own client into another channel and wait until the server
+    /// confirms the move.
+    ///
+    /// Unlike [`move_to`], this resolves only after the book contains our
+    /// client in the new channel, so [`get_client`] shows the new channel
+    /// afterwards. It fails with [`Error::Timeout`] if the server does not
+    /// confirm the move within 10 seconds. If the channel does not exist or
+    /// the password is wrong, the error of the server is returned.
+    ///
+    /// [`move_to`]: #method.move_to
+    /// [`get_client`]: #method.get_client
+    /// [`Error::Timeout`]: enum.Error.html#variant.Timeout
+    pub fn move_to_channel(&self, channel: ChannelId, password: Option<&str>)
+        -> BoxFuture<()> {
+        let (own_client, own_channel) =
+            match self.cm.inner.borrow().get(self.id) {
+                // Clients are not stored in every book mode
+                Some(con) => (con.own_client, con.own_channel),
+                None =>
+                    return Box::new(future::err(Error::ConnectionNotFound)),
+            };
+        if own_channel == Some(channel) {
+            return Box::new(future::ok(()));
+        }
+
+        // Register the waiter before moving, so the confirmation cannot be
+        // missed
+        let moved = self.wait_for_client(Some(own_client),
+            Box::new(|_, _| true), channel,
+            std::time::Duration::from_secs(10));
+        Box::new(self.move_to(channel, password.map(String::from))
+            .join(moved).map(|_| ()))
+    }
+
+    /// Move multiple clients into a channel.
+    ///
+    /// Servers which support [`ServerCapability::BatchedClientMove`] get a
+    /// single command, on older servers one command per client is sent.
+    ///
+    /// [`ServerCapability::BatchedClientMove`]: enum.ServerCapability.html#variant.BatchedClientMove
+    pub fn move_clients(&self, clients: Vec<ClientId>, channel: ChannelId,
+        password: Option<String>) -> BoxFuture<()> {
+        if clients.is_empty() {
+            return Box::new(future::ok(()));
+        }
+        let batched = self.supports(ServerCapability::BatchedClientMove)
+            .unwrap_or(true);
+        let inner = Rc::downgrade(&self.cm.inner);
+        let futs = create_move_commands(&clients, channel,
+            password.as_ref().map(|p| p.as_str()), batched).into_iter()
+            .map(|cmd| send_command(&inner, self.id, cmd))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futs).map(|_| ()))
+    }
+
+    /// Request talk power in a moderated channel.
+    ///
+    /// The channel moderators can see the `message`. A previous request is
+    /// replaced.
+    pub fn request_talk_power(&self, message: String) -> BoxFuture<()> {
+        let mut command = commands::Command::new("clientupdate");
+        command.push("client_talk_request", "1");
+        command.push("client_talk_request_msg", message);
+        send_command(&Rc::downgrade(&self.cm.inner), self.id, command)
+    }
+
+    /// Withdraw our request for talk power.
+    pub fn cancel_talk_request(&self) -> BoxFuture<()> {
+        let mut command = commands::Command::new("clientupdate");
+        command.push("client_talk_request", "0");
+        send_command(&Rc::downgrade(&self.cm.inner), self.id, command)
+    }
+
+    /// The open talk power request of a client.
+    ///
+    /// Requests are withdrawn when the client leaves the channel or gets
+    /// talk power.
+    pub fn talk_request(&self, client: ClientId) -> Option<TalkRequest> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        con.talk_requests.get(&client).cloned()
+    }
+
+    /// All open talk power requests, the oldest comes first.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn talk_requests(&self) -> Option<Vec<TalkRequest>> {
+        let inner = self.cm.inner.borrow();
+        let mut requests = inner.get(self.id)?.talk_requests.values()
+            .cloned().collect::<Vec<_>>();
+        requests.sort_by_key(|r| r.received);
+        Some(requests)
+    }
+
+    /// Search channels by their name on the server.
+    ///
+    /// This does not need the channel list, so it can be faster than searching
+    /// in the book on big servers. The `pattern` has to be a part of the
+    /// channel name.
+    pub fn find_channels_on_server(&self, pattern: String)
+        -> BoxFuture<Vec<ChannelId>> {
+        let mut command = commands::Command::new("channelfind");
+        command.push("pattern", pattern);
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, Some("notifychannelfind"), None)
+            .then(|res| match res {
+            Ok(rows) => rows.iter()
+                .map(|r| parse_response_arg(r, "cid").map(ChannelId))
+                .collect(),
+            // Nothing found
+            Err(Error::Server(TsError::DatabaseEmptyResult, _)) =>
+                Ok(Vec::new()),
+            Err(e) => Err(e),
+        }))
+    }
+
+    /// Fetch the database entry of a client, which can also be offline.
+    ///
+    /// This needs the permission to view the client database.
+    pub fn client_db_info(&self, id: ClientDbId) -> BoxFuture<ClientDbInfo> {
+        let mut command = commands::Command::new("clientdbinfo");
+        command.push("cldbid", id.0.to_string());
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, Some("notifyclientdbinfo"), None)
+            .and_then(|rows| {
+            let row = rows.first().ok_or_else(|| Error::InvalidResponse(
+                String::from("Got no client database entry")))?;
+            ClientDbInfo::from_row(row)
+        }))
+    }
+
+    /// Fetch the last `lines` entries of the server log.
+    ///
+    /// The oldest entry comes first. This needs the permission to view the
+    /// log.
+    pub fn server_log(&self, lines: u32) -> BoxFuture<Vec<LogEntry>> {
+        fetch_server_log(Rc::downgrade(&self.cm.inner), self.id, lines)
+    }
+
+    /// Get new entries of the server log as they are written.
+    ///
+    /// The server does not send log entries by itself, so the log is read
+    /// every `interval`. Entries which existed before the stream was created
+    /// are not returned. If more than [`MAX_LOG_LINES`] are written in one
+    /// interval, the older ones are missed.
+    ///
+    /// The stream ends with an error if the log cannot be read.
+    ///
+    /// [`MAX_LOG_LINES`]: constant.MAX_LOG_LINES.html
+    pub fn log_stream(&self, interval: std::time::Duration)
+        -> BoxStream<LogEntry> {
+        let inner = Rc::downgrade(&self.cm.inner);
+        let handle = self.cm.inner.borrow().handle.clone();
+        let con = self.id;
+        // Remember the newest entry
+        let start = fetch_server_log(inner.clone(), con, 1)
+            .map(|mut e| e.pop());
+        let stream = futures::stream::unfold(Some(Box::new(start)
+            as BoxFuture<_>), move |start| {
+            let inner = inner.clone();
+            let handle = handle.clone();
+            let start = start?;
+            Some(start.and_then(move |last| -> BoxFuture<(Vec<LogEntry>,
+                Option<BoxFuture<Option<LogEntry>>>)> {
+                let wait = tryf!(Timeout::new(interval, &handle));
+                Box::new(wait.map_err(|e| e.into()).and_then(move |()|
+                    fetch_server_log(inner, con, MAX_LOG_LINES))
+                    .map(move |entries| {
+                    let new = match last {
+                        Some(ref last) => new_log_entries(entries, last),
+                        None => entries,
+                    };
+                    let next = new.last().cloned().or(last);
+                    (new, Some(Box::new(future::ok(next)) as BoxFuture<_>))
+                }))
+            }))
+        });
+        Box::new(stream.map(futures::stream::iter_ok).flatten())
+    }
+
+    /// The database id of a connected client.
+    ///
+    /// The ids are not related, so this looks the client up in the book.
+    pub fn client_db_id(&self, client: ClientId) -> Option<ClientDbId> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        con.server.clients.get(&client).map(|c| c.database_id)
+    }
+
+    /// Ask the server for the database id of a client, which can also be
+    /// offline.
+    pub fn client_db_id_from_uid(&self, uid: &Uid) -> BoxFuture<ClientDbId> {
+        let mut command = commands::Command::new("clientgetdbidfromuid");
+        command.push("cluid", uid.0.clone());
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, Some("notifyclientdbidfromuid"), None)
+            .and_then(|rows| {
+                let row = rows.first().ok_or_else(|| Error::InvalidResponse(
+                    String::from("Got no database id")))?;
+                Ok(ClientDbId(parse_response_arg(row, "cldbid")?))
+            }))
+    }
+
+    /// Search clients in the database of the server by their last nickname.
+    ///
+    /// The `pattern` has to be a part of the nickname. This finds offline
+    /// clients too and needs the permission to view the client database.
+    pub fn client_db_find(&self, pattern: String)
+        -> BoxFuture<Vec<ClientDbId>> {
+        let mut command = commands::Command::new("clientdbfind");
+        command.push("pattern", pattern);
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, Some("notifyclientdbfind"), None)
+            .then(|res| match res {
+            Ok(rows) => rows.iter()
+                .map(|r| parse_response_arg(r, "cldbid").map(ClientDbId))
+                .collect(),
+            // Nothing found
+            Err(Error::Server(TsError::DatabaseEmptyResult, _)) =>
+                Ok(Vec::new()),
+            Err(e) => Err(e),
+        }))
+    }
+
+    /// Send a text message to the server chat, a channel or a client.
+    ///
+    /// The server only delivers channel messages to the channel of our own
+    /// client, so sending to another channel fails with
+    /// [`Error::NotInChannel`]. Messages with more than
+    /// [`MAX_MESSAGE_LENGTH`] characters are not truncated, instead
+    /// [`Error::MessageTooLong`] is returned.
+    ///
+    /// Received messages are returned as [`Event::Message`] by
+    /// [`ConnectionManager::get_events`].
+    ///
+    /// [`Error::NotInChannel`]: enum.Error.html#variant.NotInChannel
+    /// [`MAX_MESSAGE_LENGTH`]: constant.MAX_MESSAGE_LENGTH.html
+    /// [`Error::MessageTooLong`]: enum.Error.html#variant.MessageTooLong
+    /// [`Event::Message`]: enum.Event.html#variant.Message
+    /// [`ConnectionManager::get_events`]: struct.ConnectionManager.html#method.get_events
+    pub fn send_message(&self, target: MessageTarget, text: &str)
+        -> BoxFuture<()> {
+        tryf!(check_message_length(text));
+        let mut command = commands::Command::new("sendtextmessage");
+        match target {
+            MessageTarget::Server => {
+                command.push("targetmode", "3");
+            }
+            MessageTarget::Channel(channel) => {
+                let own_channel = match self.cm.inner.borrow().get(self.id) {
+                    Some(con) => con.own_channel,
+                    None => return Box::new(future::err(
+                        Error::ConnectionNotFound)),
+                };
+                if own_channel != Some(channel) {
+                    return Box::new(future::err(Error::NotInChannel));
+                }
+                command.push("targetmode", "2");
+            }
+            MessageTarget::Client(client) => {
+                command.push("targetmode", "1");
+                command.push("target", client.0.to_string());
+            }
+        }
+        command.push("msg", text);
+        send_command(&Rc::downgrade(&self.cm.inner), self.id, command)
+    }
+
+    /// Send a command to the server and wait for its answer.
+    ///
+    /// A unique `return_code` is added to the command, so the `error`
+    /// notification of the server can be matched to it. The future resolves
+    /// when the server accepted the command and fails with [`Error::Server`]
+    /// if it answered with an error.
+    ///
+    /// The command should not contain a `return_code` already.
+    ///
+    /// [`Error::Server`]: enum.Error.html#variant.Server
+    pub fn send_command(&self, command: commands::Command)
+        -> BoxFuture<CommandResponse> {
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, None, None).map(|rows| CommandResponse { rows }))
+    }
+
+    /// Send a command to the server and collect the notifications with the
+    /// name `response_name`, which the server sends as answer.
+    ///
+    /// Some commands, e.g. `clientgetdbidfromuid`, are answered with a
+    /// notification like `notifyclientdbidfromuid` before the `error`
+    /// notification. The rows of these notifications are returned in the
+    /// [`CommandResponse`].
+    ///
+    /// The server sends some notifications also on its own, e.g.
+    /// `notifyclientupdated` when any client changes. Only rows for which
+    /// `filter` returns `true` are taken as response, the other rows are
+    /// handled as normal notifications.
+    ///
+    /// [`CommandResponse`]: struct.CommandResponse.html
+    pub fn send_command_with_response<F>(&self, command: commands::Command,
+        response_name: &'static str, filter: F) -> BoxFuture<CommandResponse>
+        where F: Fn(&Map<String, String>) -> bool + 'static {
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, Some(response_name), Some(Box::new(filter)))
+            .map(|rows| CommandResponse { rows }))
+    }
+
+    /// Kick a client from its channel or from the server.
+    ///
+    /// If our client is not allowed to kick the client,
+    /// [`Error::MissingPermission`] is returned.
+    ///
+    /// [`Error::MissingPermission`]: enum.Error.html#variant.MissingPermission
+    pub fn kick_client(&self, client: ClientId, target: KickTarget,
+        reason: Option<&str>) -> BoxFuture<()> {
+        let (command, permission) = create_kick_command(client, target,
+            reason);
+        Box::new(send_command(&Rc::downgrade(&self.cm.inner), self.id, command)
+            .map_err(move |e| needs_permission(e, permission)))
+    }
+
+    /// Ban a client from the server.
+    ///
+    /// The ban applies to the identity and ip address of the client. If no
+    /// `duration` is given, the ban is permanent. A zero or negative
+    /// `duration` returns [`Error::InvalidBanDuration`]. If our client is not
+    /// allowed to ban the client, [`Error::MissingPermission`] is returned.
+    ///
+    /// [`Error::InvalidBanDuration`]: enum.Error.html#variant.InvalidBanDuration
+    /// [`Error::MissingPermission`]: enum.Error.html#variant.MissingPermission
+    pub fn ban_client(&self, client: ClientId, duration: Option<Duration>,
+        reason: Option<&str>) -> BoxFuture<()> {
+        let command = tryf!(create_ban_command(client, duration, reason));
+        Box::new(send_command(&Rc::downgrade(&self.cm.inner), self.id, command)
+            .map_err(|e| needs_permission(e, Permission::i_client_ban_power)))
+    }
+
+    /// Send a private text message to multiple clients.
+    ///
+    /// The protocol has no command to send a private message to multiple
+    /// clients, so one command per client is sent. The commands are sent at
+    /// once without waiting for the answers in between.
+    ///
+    /// Returns the result for every client in the order of `targets`. If the
+    /// message is longer than [`MAX_MESSAGE_LENGTH`], nothing is sent and
+    /// [`Error::MessageTooLong`] is returned.
+    ///
+    /// [`MAX_MESSAGE_LENGTH`]: constant.MAX_MESSAGE_LENGTH.html
+    /// [`Error::MessageTooLong`]: enum.Error.html#variant.MessageTooLong
+    pub fn send_messages(&self, targets: Vec<ClientId>, text: String)
+        -> BoxFuture<Vec<(ClientId, Result<()>)>> {
+        tryf!(check_message_length(&text));
+        let inner = Rc::downgrade(&self.cm.inner);
+        let futs = targets.into_iter().map(|target| {
+            let mut command = commands::Command::new("sendtextmessage");
+            command.push("targetmode", "1");
+            command.push("target", target.0.to_string());
+            command.push("msg", text.as_str());
+            send_command(&inner, self.id, command)
+                .then(move |res| Ok((target, res)))
+        }).collect::<Vec<_>>();
+        Box::new(future::join_all(futs))
+    }
+
+    /// List all virtual servers of the instance.
+    ///
+    /// This needs the permission to list servers, which normal clients do not
+    /// have. Otherwise an [`Error::Server`] with
+    /// `PermissionsClientInsufficient` is returned.
+    ///
+    /// [`Error::Server`]: enum.Error.html#variant.Server
+    pub fn server_list(&self) -> BoxFuture<Vec<VirtualServerInfo>> {
+        let command = commands::Command::new("serverlist");
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, Some("notifyserverlist"), None).and_then(|rows|
+            rows.iter().map(VirtualServerInfo::from_row)
+                .collect::<Result<Vec<_>>>()))
+    }
+
+    /// Create a snapshot of the configuration of the virtual server.
+    ///
+    /// The snapshot can be restored with [`deploy_server_snapshot`], its
+    /// content should be treated as opaque. This needs the permission to
+    /// create snapshots, otherwise an [`Error::Server`] with
+    /// `PermissionsClientInsufficient` is returned.
+    ///
+    /// Snapshots can be much larger than normal commands, so the maximum size
+    /// of received commands is raised for this connection until the snapshot
+    /// arrived.
+    ///
+    /// [`deploy_server_snapshot`]: #method.deploy_server_snapshot
+    /// [`Error::Server`]: enum.Error.html#variant.Server
+    pub fn create_server_snapshot(&self) -> BoxFuture<String> {
+        let client_con = {
+            let inner = self.cm.inner.borrow();
+            let con = tryf!(inner.get(self.id)
+                .ok_or(Error::ConnectionNotFound));
+            con.client_connection.clone()
+        };
+        let previous_size = client_con.upgrade().and_then(|c| {
+            let mut c = c.borrow_mut();
+            c.params.as_mut().map(|params| std::mem::replace(
+                &mut params.max_command_size,
+                std::cmp::max(params.max_command_size, MAX_SNAPSHOT_SIZE)))
+        });
+
+        let command = commands::Command::new("serversnapshotcreate");
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, Some("notifyserversnapshot"), None)
+            .then(move |res| {
+            // Only accept large commands while waiting for the snapshot
+            if let (Some(size), Some(c)) = (previous_size, client_con.upgrade())
+            {
+                if let Some(ref mut params) = c.borrow_mut().params {
+                    params.max_command_size = size;
+                }
+            }
+            // Keep all rows, they are needed to deploy the snapshot
+            serialize_snapshot(&res?)
+        }))
+    }
+
+    /// Restore a snapshot which was created with [`create_server_snapshot`].
+    ///
+    /// This overwrites the configuration of the virtual server and needs the
+    /// permission to deploy snapshots.
+    ///
+    /// [`create_server_snapshot`]: #method.create_server_snapshot
+    pub fn deploy_server_snapshot(&self, data: String) -> BoxFuture<()> {
+        let command = tryf!(commands::Command::read((), &mut std::io::Cursor::new(
+            format!("serversnapshotdeploy {}", data))));
+        send_command(&Rc::downgrade(&self.cm.inner), self.id, command)
+    }
+
+    /// Fetch detailed information about multiple clients.
+    ///
+    /// The clients are requested with as few commands as possible, the
+    /// responses are matched to the clients by their id. If the server rejects
+    /// a batch, e.g. because one of the clients left, every client of the
+    /// batch is requested on its own. The optional data of the clients in the
+    /// book is updated with the results.
+    ///
+    /// Returns the result for every client in the order of `ids`.
+    pub fn clients_info(&self, ids: Vec<ClientId>)
+        -> BoxFuture<Vec<(ClientId, Result<ClientInfo>)>> {
+        /// The maximum number of clients which are requested in one command.
+        const BATCH_SIZE: usize = 50;
+
+        let inner = Rc::downgrade(&self.cm.inner);
+        let id = self.id;
+        let futs = ids.chunks(BATCH_SIZE).map(|chunk| {
+            let chunk = chunk.to_vec();
+            let inner = inner.clone();
+            request_clients_info(&inner, id, &chunk).then(move |res|
+                -> BoxFuture<Vec<(ClientId, Result<ClientInfo>)>> {
+                match res {
+                    Ok(infos) => Box::new(future::ok(infos)),
+                    Err(_) if chunk.len() > 1 => {
+                        // Find out which clients failed
+                        let futs = chunk.iter().map(|&c|
+                            request_clients_info(&inner, id, &[c])
+                                .then(move |res| Ok(match res {
+                                    Ok(mut infos) => infos.remove(0),
+                                    Err(e) => (c, Err(e)),
+                                }))
+                        ).collect::<Vec<_>>();
+                        Box::new(future::join_all(futs))
+                    }
+                    Err(e) => Box::new(future::ok(vec![(chunk[0], Err(e))])),
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        Box::new(future::join_all(futs).map(move |results| {
+            let results = results.into_iter().flat_map(|r| r)
+                .collect::<Vec<_>>();
+
+            // Update the book
+            if let Some(inner) = inner.upgrade() {
+                let inner = inner.borrow();
+                if let Some(con) = inner.connections.get(&id) {
+                    let mut con = con.borrow_mut();
+                    for info in results.iter().filter_map(|r| r.1.as_ref().ok())
+                    {
+                        if let Some(client) =
+                            con.server.clients.get_mut(&info.id) {
+                            client.optional_data = Some(info.optional_data());
+                        }
+                    }
+                }
+            }
+            results
+        }))
+    }
+
+    /// List the permissions which a channel group grants.
+    ///
+    /// Returns the permissions with their values. This needs the permission
+    /// to view the permissions of channel groups.
+    pub fn channel_group_permissions(&self, group: ChannelGroupId)
+        -> BoxFuture<Vec<(Permission, i32)>> {
+        let mut command = commands::Command::new("channelgrouppermlist");
+        command.push("cgid", group.0.to_string());
+        let logger = self.cm.inner.borrow().logger.clone();
+        Box::new(send_command_with_response(&Rc::downgrade(&self.cm.inner),
+            self.id, command, Some("notifychannelgrouppermlist"), None)
+            .then(move |res| match res {
+            Ok(rows) => parse_permission_rows(&logger, &rows),
+            // The group has no permissions
+            Err(Error::Server(TsError::DatabaseEmptyResult, _)) =>
+                Ok(Vec::new()),
+            Err(e) => Err(e),
+        }))
+    }
+
+    /// Move a client into the channel `name` below `parent` and create the
+    /// channel if it does not exist.
+    ///
+    /// Returns the id of the channel. If another client creates a channel with
+    /// the same name at the same time, all clients are moved into the same
+    /// channel.
+    pub fn move_to_or_create(&self, client: ClientId, parent: ChannelId,
+        name: String, options: ChannelOptions) -> BoxFuture<ChannelId> {
+        let inner = Rc::downgrade(&self.cm.inner);
+        let id = self.id;
+        let password = options.password.clone();
+        let move_client = move |inner: Weak<RefCell<InnerCM>>,
+            channel: ChannelId| -> BoxFuture<ChannelId> {
+            let cmd = create_move_command(client, channel,
+                password.as_ref().map(|p| p.as_str()));
+            Box::new(send_command(&inner, id, cmd).map(move |()| channel))
+        };
+
+        if let Some(channel) = find_channel(&inner, id, parent, &name) {
+            return move_client(inner, channel);
+        }
+
+        let command = options.create_command(parent, &name);
+        Box::new(send_command(&inner, id, command).then(move |res| {
+            match res {
+                Ok(()) => {}
+                // Someone else created the channel in the meantime
+                Err(Error::Server(TsError::ChannelNameInuse, _)) => {}
+                Err(e) => return Box::new(future::err(e)) as BoxFuture<_>,
+            }
+            // Look at the book again, in case of a race, the channel with the
+            // smallest id wins.
+            if let Some(channel) = find_channel(&inner, id, parent, &name) {
+                move_client(inner, channel)
+            } else {
+                Box::new(future::err(Error::InvalidResponse(String::from(
+                    "Created channel not found"))))
+            }
+        }))
+    }
+
+    /// Download the icon of a channel.
+    ///
+    /// Returns `None` if the channel has no icon.
+    pub fn channel_icon(&self, channel: ChannelId)
+        -> BoxFuture<Option<Vec<u8>>> {
+        let icon = {
+            let inner = self.cm.inner.borrow();
+            let con = tryf!(inner.get(self.id)
+                .ok_or(Error::ConnectionNotFound));
+            let icon = tryf!(con.server.channels.get(&channel)
+                .ok_or(Error::ChannelNotFound)).icon;
+            icon
+        };
+        self.download_icon(icon as u32)
+    }
+
+    /// Download the icon of the server.
+    ///
+    /// Returns `None` if the server has no icon.
+    pub fn server_icon(&self) -> BoxFuture<Option<Vec<u8>>> {
+        let icon = {
+            let inner = self.cm.inner.borrow();
+            let con = tryf!(inner.get(self.id)
+                .ok_or(Error::ConnectionNotFound));
+            con.server.icon
+        };
+        self.download_icon(icon as u32)
+    }
+
+    /// Icons are stored with the name `/icon_<id>` in the file transfer.
+    fn download_icon(&self, icon: u32) -> BoxFuture<Option<Vec<u8>>> {
+        if icon == 0 {
+            return Box::new(future::ok(None));
+        }
+        Box::new(download_file(&Rc::downgrade(&self.cm.inner), self.id,
+            ChannelId(0), format!("/icon_{}", icon), MAX_ICON_SIZE)
+            .map(Some))
+    }
+
+    /// The server of this connection.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn get_server(&self) -> Option<Server> {
+        let connection = self.cm.inner.borrow().connections.get(&self.id)?
+            .clone();
+        Some(Server {
+            cm: self.cm,
+            connection,
+            connection_id: self.id,
+        })
+    }
+
+    /// The nickname of a client.
+    ///
+    /// This works in [`BookMode::Full`] and [`BookMode::MinimalClients`].
+    ///
+    /// [`BookMode::Full`]: enum.BookMode.html#variant.Full
+    /// [`BookMode::MinimalClients`]: enum.BookMode.html#variant.MinimalClients
+    pub fn client_name(&self, id: ClientId) -> Option<String> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        con.server.clients.get(&id).map(|c| c.name.clone())
+            .or_else(|| con.client_names.get(&id).cloned())
+    }
+
+    /// The country code of a client, e.g. `DE`.
+    ///
+    /// Returns `None` if the client is unknown or the server did not send a
+    /// country for this client.
+    pub fn client_country(&self, id: ClientId) -> Option<String> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        con.server.clients.get(&id)
+            .map(|c| c.country_code.clone())
+            .and_then(|c| if c.is_empty() { None } else { Some(c) })
+    }
+
+    /// The last text messages which were received in a chat.
+    ///
+    /// TeamSpeak has no chat history on the server, so this only contains
+    /// messages which were received by this connection, including the time
+    /// before a reset. The number of kept
+    /// messages can be set with [`ConnectOptions::chat_history`]. The oldest
+    /// message comes first.
+    ///
+    /// Returns `None` if the connection does not exist.
+    ///
+    /// [`ConnectOptions::chat_history`]: struct.ConnectOptions.html#method.chat_history
+    pub fn recent_messages(&self, target: MessageTarget) -> Option<Vec<ChatMessage>> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        Some(con.chat_history.get(&target)
+            .map(|m| m.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    /// All channels in the order in which the official client shows them.
+    ///
+    /// Child channels follow their parent, the number is the depth of the
+    /// channel in the tree, starting with `0` for channels without a parent.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn channel_tree(&self) -> Option<Vec<(ChannelId, usize)>> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        Some(con.channel_tree())
+    }
+
+    /// All clients grouped by their channel.
+    ///
+    /// The clients of a channel are sorted like the official client shows
+    /// them: Clients with a higher talk power come first, clients with the
+    /// same talk power are sorted by the time when they joined the channel.
+    /// Only channels which contain clients are returned, sorted by their id.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn clients_by_channel(&self) -> Option<Vec<(ChannelId, Vec<ClientId>)>> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        let mut channels: Map<ChannelId, Vec<&structs::Client>> = Map::new();
+        for client in con.server.clients.values() {
+            channels.entry(client.channel).or_insert_with(Vec::new)
+                .push(client);
+        }
+
+        let mut res = channels.into_iter().map(|(channel, mut clients)| {
+            clients.sort_by_key(|c| (std::cmp::Reverse(c.talk_power),
+                con.channel_join_order(c.id)));
+            (channel, clients.iter().map(|c| c.id).collect())
+        }).collect::<Vec<_>>();
+        res.sort_by_key(|&(channel, _)| channel.0);
+        Some(res)
+    }
+
+    /// The ip address of a client.
+    ///
+    /// The addresses of the clients which were on the server when we connected
+    /// are requested with `clientlist -ip`, other clients get their address
+    /// when the server sends their connection info (e.g. for
+    /// `getconnectioninfo` or `clientinfo`). This is only the case if we have
+    /// the permission to see ip addresses and the book mode is
+    /// [`BookMode::Full`], otherwise `None` is returned.
+    ///
+    /// [`BookMode::Full`]: enum.BookMode.html#variant.Full
+    pub fn client_ip(&self, id: ClientId) -> Option<IpAddr> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        con.client_ips.get(&id).cloned()
+    }
+
+    /// The security level of the identity of a client.
+    ///
+    /// The level is computed from the public key and the key offset of the
+    /// client. Returns `None` if the server did not send them for this client
+    /// (e.g. in `clientlist` or `clientinfo`).
+    pub fn client_security_level(&self, id: ClientId) -> Option<u8> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        con.client_identities.get(&id)
+            .map(|&(ref omega, offset)| algs::get_hash_cash_level(omega, offset))
+    }
+
+    /// Set the description of our client, which is visible to other clients.
+    pub fn set_description(&self, description: String) -> BoxFuture<()> {
+        self.update_self(ClientSelfEdit::new().description(description))
+    }
+
+    /// Change properties of our own client.
+    ///
+    /// The book is updated when the server accepted all changes.
+    pub fn update_self(&self, edit: ClientSelfEdit) -> BoxFuture<()> {
+        let inner = Rc::downgrade(&self.cm.inner);
+        let id = self.id;
+        let own_client = {
+            let inner = self.cm.inner.borrow();
+            let con = tryf!(inner.get(id).ok_or(Error::ConnectionNotFound));
+            con.own_client
+        };
+
+        let mut futs: Vec<BoxFuture<()>> = Vec::new();
+        if let Some(command) = edit.update_command() {
+            futs.push(send_command(&inner, id, command));
+        }
+        // The description can only be changed with clientedit
+        if let Some(ref description) = edit.description {
+            let mut command = commands::Command::new("clientedit");
+            command.push("clid", own_client.0.to_string());
+            command.push("client_description", description.as_str());
+            futs.push(send_command(&inner, id, command));
+        }
+
+        Box::new(future::join_all(futs).map(move |_| {
+            if let Some(inner) = inner.upgrade() {
+                let inner = inner.borrow();
+                if let Some(con) = inner.connections.get(&id) {
+                    let mut con = con.borrow_mut();
+                    if let Some(client) = con.server.clients
+                        .get_mut(&own_client) {
+                        edit.apply(client);
+                    }
+                }
+            }
+        }))
+    }
+
+    /// The unique identifier of our own client.
+    ///
+    /// The server derives it from our public key, so it identifies the
+    /// identity which is used by this connection.
+    pub fn own_uid(&self) -> Result<Uid> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id).ok_or(Error::ConnectionNotFound)?;
+        let omega = con.client_data.borrow().private_key.to_ts_public()?;
+        Ok(Uid(algs::get_uid(&omega)))
+    }
+
+    /// The identity which is used by this connection.
+    ///
+    /// If no private key was given in the [`ConnectOptions`], a new one was
+    /// generated when connecting. It can be stored with
+    /// [`Identity::export_ts`] to connect with the same identity again, so
+    /// the server recognizes our client and keeps its permissions.
+    ///
+    /// [`ConnectOptions`]: struct.ConnectOptions.html
+    /// [`Identity::export_ts`]: struct.Identity.html#method.export_ts
+    pub fn identity(&self) -> Result<Identity> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id).ok_or(Error::ConnectionNotFound)?;
+        // Copy the key by exporting it
+        let key = con.client_data.borrow().private_key.export_private()?;
+        let key = tomcrypt::EccKey::import(&key)?;
+        let res = Identity::new(key,
+            con.options.used_key_offset.get().unwrap_or(0));
+        Ok(res)
+    }
+
+    /// The `key_offset` of our identity, which was sent to the server.
+    ///
+    /// If it was computed when connecting, it can be stored and passed to
+    /// [`ConnectOptions::identity_offset`] next time to connect faster.
+    ///
+    /// Returns `None` if the connection does not exist.
+    ///
+    /// [`ConnectOptions::identity_offset`]: struct.ConnectOptions.html#method.identity_offset
+    pub fn identity_offset(&self) -> Option<u64> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        let res = con.options.used_key_offset.get().unwrap_or(0);
+        Some(res)
+    }
+
+    /// Get notified when the groups of our own client change.
+    ///
+    /// The stream yields an item when we are added to or removed from a
+    /// server group or when our channel group changes. Our effective
+    /// permissions may be different afterwards, so they should be queried
+    /// again.
+    ///
+    /// The stream ends when the connection is removed.
+    pub fn own_permissions_changed(&self) -> BoxStream<()> {
+        let (send, recv) = futures::unsync::mpsc::unbounded();
+        let inner = self.cm.inner.borrow();
+        if let Some(con) = inner.connections.get(&self.id) {
+            con.borrow_mut().permission_listeners.push(send);
+        }
+        Box::new(recv.map_err(|()| unreachable!(
+            "A receiver cannot fail")))
+    }
+
+    /// Get notified when the server assigns a new id to our own client.
+    ///
+    /// The id of our client can change when the connection is reconnected with
+    /// [`hard_reset`]. Cached ids of our client, e.g. to filter our own
+    /// messages, should be updated then.
+    ///
+    /// The stream ends when the connection is removed.
+    ///
+    /// [`hard_reset`]: #method.hard_reset
+    pub fn own_client_id_changed(&self) -> BoxStream<OwnClientIdChanged> {
+        let (send, recv) = futures::unsync::mpsc::unbounded();
+        let inner = self.cm.inner.borrow();
+        if let Some(con) = inner.connections.get(&self.id) {
+            con.borrow_mut().own_id_listeners.push(send);
+        }
+        Box::new(recv.map_err(|()| unreachable!(
+            "A receiver cannot fail")))
+    }
+
+    /// Get notified when a client requests talk power.
+    ///
+    /// This is useful for moderators of a channel, who can grant talk power
+    /// to the requesting clients.
+    ///
+    /// The stream ends when the connection is removed.
+    pub fn talk_requests_received(&self) -> BoxStream<TalkRequest> {
+        let (send, recv) = futures::unsync::mpsc::unbounded();
+        let inner = self.cm.inner.borrow();
+        if let Some(con) = inner.connections.get(&self.id) {
+            con.borrow_mut().talk_request_listeners.push(send);
+        }
+        Box::new(recv.map_err(|()| unreachable!(
+            "A receiver cannot fail")))
+    }
+
+    /// Get notified about notifications which are not known to this
+    /// library, e.g. because they were added in a newer server version.
+    ///
+    /// The notifications are returned as they were received, together with
+    /// the time when they were received. If a
+    /// [`ConnectOptions::notification_filter`] is set, it has to contain the
+    /// names of these notifications too.
+    ///
+    /// The stream ends when the connection is removed.
+    ///
+    /// [`ConnectOptions::notification_filter`]: struct.ConnectOptions.html#method.notification_filter
+    pub fn unknown_notifications(&self)
+        -> BoxStream<(DateTime<Utc>, commands::Command)> {
+        let (send, recv) = futures::unsync::mpsc::unbounded();
+        let inner = self.cm.inner.borrow();
+        if let Some(con) = inner.connections.get(&self.id) {
+            con.borrow_mut().unknown_notification_listeners.push(send);
+        }
+        Box::new(recv.map_err(|()| unreachable!(
+            "A receiver cannot fail")))
+    }
+
+    /// Wait until a client which matches `predicate` is in `channel`.
+    ///
+    /// The predicate gets the nickname and the unique id of a client. If a
+    /// matching client is already in the channel, the future resolves
+    /// immediately, otherwise when a matching client joins the channel or
+    /// enters our view there. It fails with [`Error::Timeout`] if no client
+    /// matched within `timeout`.
+    ///
+    /// The predicate is not called while the connection is borrowed, so it
+    /// can use the connection manager.
+    ///
+    /// [`Error::Timeout`]: enum.Error.html#variant.Timeout
+    pub fn wait_for_client_in_channel<P>(&self, predicate: P,
+        channel: ChannelId, timeout: std::time::Duration)
+        -> BoxFuture<ClientId>
+        where P: Fn(&str, &Uid) -> bool + 'static {
+        self.wait_for_client(None, Box::new(predicate), channel, timeout)
+    }
+
+    /// Wait until `client` or a client which matches `predicate` is in
+    /// `channel`.
+    fn wait_for_client(&self, client: Option<ClientId>,
+        predicate: Box<Fn(&str, &Uid) -> bool>, channel: ChannelId,
+        timeout: std::time::Duration) -> BoxFuture<ClientId> {
+        let inner = self.cm.inner.borrow();
+        tryf!(inner.check_reactor());
+        let deadline = tryf!(Timeout::new(timeout, &inner.handle));
+        let mut con = tryf!(inner.get_mut(self.id)
+            .ok_or(Error::ConnectionNotFound));
+        let (waiter, matches) = structs::ClientWaiter::new(channel, client,
+            predicate);
+        // Pass the clients which are already in the channel to the waiter
+        let mut found = false;
+        for c in con.server.clients.values() {
+            if c.channel == channel
+                && client.map(|id| id == c.id).unwrap_or(true) {
+                found = true;
+                let _ = waiter.sender.unbounded_send((c.id,
+                    Some((c.name.clone(), c.uid.clone()))));
+            }
+        }
+        // A waiter for a certain client is not needed anymore if the client
+        // is already there
+        if client.is_none() || !found {
+            con.client_waiters.push(waiter);
+        }
+        Box::new(matches.into_future().select2(deadline).then(|res|
+            match res {
+                Ok(future::Either::A(((Some(client), _), _))) => Ok(client),
+                // The connection was removed
+                Ok(future::Either::A(((None, _), _))) |
+                Err(future::Either::A(_)) => Err(Error::Disconnected(None)),
+                Ok(future::Either::B(_)) => Err(Error::Timeout),
+                Err(future::Either::B((e, _))) => Err(e.into()),
+            }))
+    }
+
+    /// How much of the channel list was received after connecting.
+    ///
+    /// The server sends the channel list on its own after the handshake, there
+    /// is no command to request parts of it. The list is split into multiple
+    /// commands and the channels of a command are added to the book as soon
+    /// as it arrived, so the book fills up while the list is received.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn channel_list_progress(&self) -> Option<ChannelListProgress> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        Some(con.channel_list_progress)
+    }
+
+    /// Only accept whispers from these clients.
+    ///
+    /// The server forwards all whispers which are sent to us, there is no
+    /// command to register which clients may whisper. So the list is applied
+    /// on our side: [`get_voice_stream`] does not return whisper packets of
+    /// other clients. Nothing else checks the list, the packets are still
+    /// received and acknowledged. Calling this multiple times extends the
+    /// list.
+    ///
+    /// [`get_voice_stream`]: #method.get_voice_stream
+    pub fn allow_whispers_from(&self, clients: Vec<ClientId>) {
+        let inner = self.cm.inner.borrow();
+        if let Some(con) = inner.connections.get(&self.id) {
+            con.borrow_mut().whisper_allow_list.get_or_insert_with(Set::new)
+                .extend(clients);
+        }
+    }
+
+    /// Accept whispers from all clients again.
+    pub fn clear_whisper_allow_list(&self) {
+        let inner = self.cm.inner.borrow();
+        if let Some(con) = inner.connections.get(&self.id) {
+            con.borrow_mut().whisper_allow_list = None;
+        }
+    }
+
+    /// If whispers of a client are accepted.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn is_whisper_allowed(&self, client: ClientId) -> Option<bool> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        Some(con.is_whisper_allowed(client))
+    }
+
+    /// If voice packets in a channel are encrypted.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn channel_voice_encrypted(&self, channel: ChannelId) -> Option<bool> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        Some(con.is_voice_encrypted(channel))
+    }
+
+    /// The number of sent and received udp packets of each type.
+    ///
+    /// The values are `(sent, received)`. Resent command packets are counted
+    /// again, received packets are counted even if they are dropped later.
+    /// Packets of the connections before a [`hard_reset`] are included.
+    ///
+    /// Returns `None` if the connection does not exist.
+    ///
+    /// [`hard_reset`]: #method.hard_reset
+    pub fn packet_type_stats(&self) -> Option<Map<PacketType, (u64, u64)>> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        Some(con.get_packet_type_stats())
+    }
+
+    /// If voice packets currently cannot be sent as fast as they are
+    /// produced.
+    ///
+    /// This is set when a voice packet has to wait because the uplink did not
+    /// take the previous packets yet, and cleared when a voice packet is sent
+    /// without waiting. Commands do not influence this state.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn voice_send_congested(&self) -> Option<bool> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        let res = con.client_connection.upgrade()
+            .map(|c| c.borrow().voice_send_congested)
+            .unwrap_or(false);
+        Some(res)
+    }
+
+    /// The smoothed round trip time to the server.
+    ///
+    /// It is measured with acknowledged commands and answered keepalive
+    /// pings, so it is also updated on an idle connection. Returns zero if
+    /// the connection to the server is gone.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn get_ping(&self) -> Option<std::time::Duration> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        let res = con.client_connection.upgrade()
+            .and_then(|c| c.borrow().resender.get_srtt().to_std().ok())
+            .unwrap_or_default();
+        Some(res)
+    }
+
+    /// The time since the last packet of the server arrived.
+    ///
+    /// The server answers our keepalive pings, so this should not grow much
+    /// beyond the keepalive interval. A higher value is an early sign of a
+    /// dying connection, before the resender gives up on it.
+    ///
+    /// Returns `None` if the connection is already closed.
+    pub fn time_since_last_packet(&self) -> Option<std::time::Duration> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        let client_con = con.client_connection.upgrade()?;
+        let elapsed = client_con.borrow().last_received.elapsed();
+        Some(elapsed)
+    }
+
+    /// The shared iv and mac which were derived in the handshake.
+    ///
+    /// This is meant to audit the key exchange and decrypt captured traffic
+    /// while debugging. Everyone who knows these values can read and forge
+    /// packets of this connection, so the `crypto-debug` feature must never
+    /// be enabled in production builds.
+    ///
+    /// Returns `None` if the connection is not established.
+    #[cfg(feature = "crypto-debug")]
+    pub fn shared_secrets(&self) -> Option<([u8; 20], [u8; 8])> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        let client_con = con.client_connection.upgrade()?;
+        let client_con = client_con.borrow();
+        client_con.params.as_ref()
+            .map(|params| (params.shared_iv, params.shared_mac))
+    }
+
+    /// Collect the state of the connection, e.g. to attach it to a bug
+    /// report.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn diagnostics(&self) -> Option<Diagnostics> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        let mut diagnostics = Diagnostics {
+            resend_state: None,
+            srtt_ms: None,
+            srtt_dev_ms: None,
+            resend_queue_len: 0,
+            pending_commands: con.pending_command_count(),
+            stats: con.get_stats(),
+            reconnects: con.reconnects,
+            channels: con.server.channels.len(),
+            clients: con.server.clients.len(),
+            server_version: con.server.version.clone(),
+        };
+        if let Some(client_con) = con.client_connection.upgrade() {
+            let client_con = client_con.borrow();
+            let resender = &client_con.resender;
+            diagnostics.resend_state = Some(resender.state());
+            diagnostics.srtt_ms = Some(resender.srtt().num_milliseconds());
+            diagnostics.srtt_dev_ms =
+                Some(resender.srtt_dev().num_milliseconds());
+            diagnostics.resend_queue_len = resender.queue_len();
+        }
+        Some(diagnostics)
+    }
+
+    /// The current time of the server.
+    ///
+    /// Timestamps in notifications use the clock of the server. The offset to
+    /// our clock is measured after connecting, until then and if the
+    /// measurement fails, our own time is returned.
+    ///
+    /// Returns `None` if the connection does not exist.
+    pub fn server_time(&self) -> Option<DateTime<Utc>> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        Some(Utc::now() + con.clock_offset)
+    }
+
+    /// If the server told us that we are flooding, commands are held back
+    /// until this time.
+    ///
+    /// Returns `None` if commands are sent immediately.
+    pub fn flood_throttled_until(&self) -> Option<DateTime<Utc>> {
+        let inner = self.cm.inner.borrow();
+        let con = inner.get(self.id)?;
+        con.throttled_until
+            .filter(|t| *t > Utc::now())
+    }
+}
+
+/// The configuration used to create a new connection.
+///
+/// Basically, this is a builder for a connection.
+///
+/// # Example
+///
+/// ```
+/// let addr: std::net::SocketAddr = "127.0.0.1:9987".parse().unwrap();
+/// let con_config = ConnectOptions::from_address(addr);
+///
+/// let mut cm = ConnectionManager::new();
+/// let con = cm.add_connection(con_config)?;
+/// ```
+#[derive(Debug)]
+pub struct ConnectOptions {
+    address: Option<SocketAddr>,
+    /// Resolved to the `address` when connecting.
+    hostname: Option<String>,
+    alternative_addresses: Vec<SocketAddr>,
+    address_strategy: AddressStrategy,
+    local_address: SocketAddr,
+    private_key: Option<tomcrypt::EccKey>,
+    name: String,
+    nickname_phonetic: String,
+    book_mode: BookMode,
+    skip_security_level: bool,
+    key_offset: Option<u64>,
+    identity_level: u8,
+    /// The key offset which was sent to the server, it is kept to connect
+    /// again without computing the hash cash.
+    used_key_offset: Cell<Option<u64>>,
+    notification_filter: Option<Set<String>>,
+    reconnect_after_kick: Option<Duration>,
+    dropped_packet_sink: Option<DroppedPacketSink>,
+    chat_history: usize,
+    crypto_params: CryptoParams,
+    app_heartbeat: Option<(std::time::Duration, std::time::Duration)>,
+    ping_responder: bool,
+    ack_delay: Option<std::time::Duration>,
+    password_provider: Option<PasswordProvider>,
+    version: ClientVersion,
+    resend_config: ResendConfig,
+    server_password: String,
+    default_channel: Option<DefaultChannel>,
+    default_channel_password: String,
+}
+
+/// The version which is sent to the server in the `clientinit`.
+#[derive(Clone, Debug)]
+struct ClientVersion {
+    platform: String,
+    version: String,
+    sign: String,
+}
+
+impl Default for ClientVersion {
+    fn default() -> Self {
+        Self {
+            platform: String::from("Linux"),
+            version: String::from("3.1.6 [Build: 1502873983]"),
+            sign: String::from("o+l92HKfiUF+THx2rBsuNjj/S1QpxG1fd5o3Q7qtWxkviR\
+                3LI3JeWyc26eTmoQoMTgI3jjHV7dCwHsK1BVu6Aw=="),
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// A private method to create a config with only default values.
+    ///
+    /// This is not in the public interface because the created configuration
+    /// is invalid.
+    fn default() -> Self {
+        Self {
+            address: None,
+            hostname: None,
+            alternative_addresses: Vec::new(),
+            address_strategy: AddressStrategy::default(),
+            local_address: "0.0.0.0:0".parse().unwrap(),
+            private_key: None,
+            name: String::from("TeamSpeakUser"),
+            nickname_phonetic: String::new(),
+            book_mode: BookMode::default(),
+            skip_security_level: false,
+            key_offset: None,
+            identity_level: 8,
+            used_key_offset: Cell::new(None),
+            notification_filter: None,
+            reconnect_after_kick: None,
+            dropped_packet_sink: None,
+            chat_history: 50,
+            crypto_params: CryptoParams::default(),
+            app_heartbeat: None,
+            ping_responder: true,
+            ack_delay: None,
+            password_provider: None,
+            version: ClientVersion::default(),
+            resend_config: ResendConfig::default(),
+            server_password: String::new(),
+            default_channel: None,
+            default_channel_password: String::new(),
+        }
+    }
+
+    /// Start creating the configuration of a new connection.
+    ///
+    /// The address of the server has to be supplied.
+    pub fn from_address(address: SocketAddr) -> Self {
         Self {
             address: Some(address),
             .. Self::default()
         }
     }
 
-    /// The address for the socket of our client
+    /// Start creating the configuration of a new connection to a server which
+    /// is given by its hostname, e.g. `voice.example.com`.
+    ///
+    /// The address is resolved when connecting like the TeamSpeak client does
+    /// it: The TSDNS server of the host is asked first, then the `_ts3._udp`
+    /// SRV record is used and at last the ip address of the host with the
+    /// default port 9987. If a port is given, e.g. `example.com:9988`, only
+    /// the ip address is looked up.
+    ///
+    /// If the address cannot be resolved, connecting fails with
+    /// [`Error::Resolve`].
+    ///
+    /// [`Error::Resolve`]: enum.Error.html#variant.Resolve
+    pub fn from_hostname(hostname: &str) -> Self {
+        Self {
+            hostname: Some(hostname.to_string()),
+            .. Self::default()
+        }
+    }
+
+    /// Start creating the configuration of a new connection to a server with
+    /// multiple addresses.
+    ///
+    /// Which address is used, is decided by the [`address_strategy`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `addresses` is empty.
+    ///
+    /// [`address_strategy`]: #method.address_strategy
+    pub fn from_addresses(addresses: Vec<SocketAddr>) -> Self {
+        let mut addresses = addresses.into_iter();
+        Self {
+            address: Some(addresses.next()
+                .expect("At least one address is needed")),
+            alternative_addresses: addresses.collect(),
+            .. Self::default()
+        }
+    }
+
+    /// How the address is chosen if the server has multiple addresses.
+    ///
+    /// # Default
+    ///
+    /// [`AddressStrategy::First`]
+    ///
+    /// [`AddressStrategy::First`]: enum.AddressStrategy.html#variant.First
+    pub fn address_strategy(mut self, strategy: AddressStrategy) -> Self {
+        self.address_strategy = strategy;
+        self
+    }
+
+    /// The address for the socket of our client
+    ///
+    /// # Default
+    ///
+    /// 0.0.0.0:0
+    pub fn local_address(mut self, local_address: SocketAddr) -> Self {
+        self.local_address = local_address;
+        self
+    }
+
+    /// Set the private key of the user.
+    ///
+    /// # Default
+    ///
+    /// A new identity is generated when connecting.
+    ///
+    pub fn private_key_tomcrypt(mut self, private_key: tomcrypt::EccKey)
+        -> Self {
+        self.private_key = Some(private_key);
+        self
+    }
+
+    /// Takes the private key as encoded by TeamSpeak (libtomcrypt export and
+    /// base64 encoded).
+    ///
+    /// # Default
+    ///
+    /// A new identity is generated when connecting.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if either the string is not encoded in valid base64
+    /// or libtomcrypt cannot import the key.
+    pub fn private_key_ts(mut self, private_key: &str) -> Result<Self> {
+        self.private_key = Some(tomcrypt::EccKey::import(
+            &base64::decode(private_key)?)?);
+        Ok(self)
+    }
+
+    /// Use an identity as it is exported by the TeamSpeak client.
+    ///
+    /// The key is encoded like in [`private_key_ts`] and `offset` is the
+    /// `key_offset`, which improved the security level of the key. The offset
+    /// is used as it is, so the hash cash does not have to be computed again.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the private key cannot be imported.
+    ///
+    /// [`private_key_ts`]: #method.private_key_ts
+    pub fn identity_ts(self, private_key: &str, offset: u64) -> Result<Self> {
+        let mut res = self.private_key_ts(private_key)?;
+        res.key_offset = Some(offset);
+        Ok(res)
+    }
+
+    /// Use an [`Identity`], e.g. one that was imported with
+    /// [`Identity::import_ts_ini`].
+    ///
+    /// If the identity contains a nickname, it is used as the name.
+    ///
+    /// [`Identity`]: struct.Identity.html
+    /// [`Identity::import_ts_ini`]: struct.Identity.html#method.import_ts_ini
+    pub fn identity(mut self, identity: Identity) -> Self {
+        let (key, offset, nickname) = identity.into_parts();
+        self.private_key = Some(key);
+        self.key_offset = Some(offset);
+        if let Some(nickname) = nickname {
+            self.name = nickname;
+        }
+        self
+    }
+
+    /// The version of the client which is sent to the server.
+    ///
+    /// The `sign` is a base64 encoded signature of `platform` and `version`,
+    /// created by TeamSpeak. The server rejects the connection if it does not
+    /// match, so all three values have to be taken from the same client.
+    /// Valid combinations can be found in the `clientinit` which an official
+    /// client sends, or in community maintained lists of TeamSpeak versions.
+    ///
+    /// # Default
+    ///
+    /// `3.1.6 [Build: 1502873983]` on `Linux`
+    ///
+    /// # Error
+    ///
+    /// An error is returned if one of the values is empty.
+    pub fn version(mut self, platform: &str, version: &str, sign: &str)
+        -> Result<Self> {
+        if platform.is_empty() || version.is_empty() || sign.is_empty() {
+            return Err(Error::InvalidVersion(String::from(
+                "Platform, version and sign have to be set together")));
+        }
+        self.version = ClientVersion {
+            platform: platform.to_string(),
+            version: version.to_string(),
+            sign: sign.to_string(),
+        };
+        Ok(self)
+    }
+
+    /// Set the timeouts and intervals for resending packets.
+    ///
+    /// On links with a high latency, e.g. over satellite, the timeouts should
+    /// be increased, otherwise the connection is closed too early.
+    ///
+    /// # Default
+    ///
+    /// `ResendConfig::default()`
+    pub fn resend_config(mut self, resend_config: ResendConfig) -> Self {
+        self.resend_config = resend_config;
+        self
+    }
+
+    /// The password of the server.
+    ///
+    /// The password is given in plain text, it is hashed before it is sent.
+    /// If it is wrong, connecting fails with [`Error::InvalidServerPassword`],
+    /// unless a [`password_provider`] returns the right one.
+    ///
+    /// # Default
+    ///
+    /// No password
+    ///
+    /// [`Error::InvalidServerPassword`]: enum.Error.html#variant.InvalidServerPassword
+    /// [`password_provider`]: #method.password_provider
+    pub fn server_password(mut self, password: &str) -> Self {
+        self.server_password = password.to_string();
+        self
+    }
+
+    /// The channel which our client joins after connecting.
+    ///
+    /// This can be a [`ChannelId`] or a path like `/Lobby/Gaming`. If the
+    /// channel does not exist or we are not allowed to join it, the server
+    /// puts our client into its default channel.
+    ///
+    /// # Default
+    ///
+    /// The default channel of the server
+    ///
+    /// [`ChannelId`]: struct.ChannelId.html
+    pub fn default_channel<C: Into<DefaultChannel>>(mut self, channel: C)
+        -> Self {
+        self.default_channel = Some(channel.into());
+        self
+    }
+
+    /// The password of the channel set with [`default_channel`].
+    ///
+    /// # Default
+    ///
+    /// No password
+    ///
+    /// [`default_channel`]: #method.default_channel
+    pub fn default_channel_password(mut self, password: &str) -> Self {
+        self.default_channel_password = password.to_string();
+        self
+    }
+
+    /// The name of the user.
+    ///
+    /// # Default
+    ///
+    /// TeamSpeakUser
+    pub fn name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// How the name of the user should be pronounced.
+    ///
+    /// Other clients use this e.g. for text-to-speech.
+    ///
+    /// # Default
+    ///
+    /// An empty string
+    pub fn nickname_phonetic(mut self, nickname_phonetic: String) -> Self {
+        self.nickname_phonetic = nickname_phonetic;
+        self
+    }
+
+    /// How much data of the server should be stored.
+    ///
+    /// If only a part of the data is needed, a reduced mode can be used to
+    /// save memory on big servers.
+    ///
+    /// # Default
+    ///
+    /// [`BookMode::Full`]
+    ///
+    /// [`BookMode::Full`]: enum.BookMode.html#variant.Full
+    pub fn book_mode(mut self, book_mode: BookMode) -> Self {
+        self.book_mode = book_mode;
+        self
+    }
+
+    /// Do not compute the hash cash for the identity and connect with security
+    /// level 0.
+    ///
+    /// This makes connecting faster, e.g. for tests against a local server.
+    /// Real servers usually require a higher security level and will reject
+    /// the connection.
+    ///
+    /// # Default
+    ///
+    /// The identity is improved to level 8 when connecting.
+    pub fn skip_security_level(mut self) -> Self {
+        self.skip_security_level = true;
+        self
+    }
+
+    /// The security level to which the identity is improved when connecting.
+    ///
+    /// Computing the hash cash takes about twice as long for every level, so
+    /// high levels should be computed once and reused with
+    /// [`identity_offset`]. Servers reject clients whose level is lower than
+    /// their required level.
+    ///
+    /// The hash cash is computed before connecting, on the thread of the
+    /// reactor, so all connections wait meanwhile. To avoid this, compute a
+    /// high level with [`Identity::upgrade_level`] on another thread and use
+    /// the identity with [`identity`].
+    ///
+    /// # Default
+    ///
+    /// 8
+    ///
+    /// [`identity_offset`]: #method.identity_offset
+    /// [`Identity::upgrade_level`]: struct.Identity.html#method.upgrade_level
+    /// [`identity`]: #method.identity
+    pub fn identity_level(mut self, level: u8) -> Self {
+        self.identity_level = level;
+        self
+    }
+
+    /// Use this `key_offset` for the identity instead of computing it.
+    ///
+    /// The offset determines the security level of the identity. It can be
+    /// read after connecting with [`Connection::identity_offset`] and stored
+    /// together with the private key.
+    ///
+    /// # Default
+    ///
+    /// The offset is computed for the [`identity_level`] when connecting.
+    ///
+    /// [`Connection::identity_offset`]: struct.Connection.html#method.identity_offset
+    /// [`identity_level`]: #method.identity_level
+    pub fn identity_offset(mut self, offset: u64) -> Self {
+        self.key_offset = Some(offset);
+        self
+    }
+
+    /// Only parse notifications with the given names, e.g.
+    /// `notifytextmessage` or `notifycliententerview`.
+    ///
+    /// All other notifications are dropped before they are parsed, which
+    /// saves time on busy servers if only a few notifications are
+    /// interesting. The book is not updated for dropped notifications, so it
+    /// gets out of date, e.g. if channel edits are not parsed.
+    ///
+    /// Command errors are always parsed because they are needed to answer
+    /// commands.
+    ///
+    /// # Default
+    ///
+    /// All notifications are parsed.
+    pub fn notification_filter<I, S>(mut self, notifications: I) -> Self
+        where I: IntoIterator<Item = S>, S: Into<String> {
+        self.notification_filter = Some(notifications.into_iter()
+            .map(|n| n.into()).collect());
+        self
+    }
+
+    /// Allow [`Connection::hard_reset`] after the server kicked or banned us,
+    /// but only after `cooldown` passed since the kick.
+    ///
+    /// If [`hard_reset`] is called earlier, it waits until the cooldown is
+    /// over before connecting.
+    ///
+    /// # Default
+    ///
+    /// After an intentional kick or ban, [`hard_reset`] fails with
+    /// [`Error::Disconnected`], so we do not fight against the server admin.
+    /// Connection failures are not affected by this.
+    ///
+    /// [`Connection::hard_reset`]: struct.Connection.html#method.hard_reset
+    /// [`hard_reset`]: struct.Connection.html#method.hard_reset
+    /// [`Error::Disconnected`]: enum.Error.html#variant.Disconnected
+    pub fn reconnect_after_kick(mut self, cooldown: Duration) -> Self {
+        self.reconnect_after_kick = Some(cooldown);
+        self
+    }
+
+    /// Receive the raw bytes of packets which are dropped, because the
+    /// receive buffer is full or they cannot be decrypted or parsed.
+    ///
+    /// This is meant to collect a corpus for fuzzing the packet parser.
+    ///
+    /// # Default
+    ///
+    /// Dropped packets are only logged.
+    pub fn dropped_packet_sink(mut self, sink: DroppedPacketSink) -> Self {
+        self.dropped_packet_sink = Some(sink);
+        self
+    }
+
+    /// How many received text messages are kept per chat.
+    ///
+    /// They can be retrieved with [`Connection::recent_messages`].
+    ///
+    /// # Default
+    ///
+    /// The last 50 messages of every chat are kept.
+    ///
+    /// [`Connection::recent_messages`]: struct.Connection.html#method.recent_messages
+    pub fn chat_history(mut self, messages: usize) -> Self {
+        self.chat_history = messages;
+        self
+    }
+
+    /// The fake key and nonce and the key derivation which are used to
+    /// encrypt packets.
+    ///
+    /// This is only useful for servers which do not use the TeamSpeak
+    /// defaults, e.g. for testing.
+    ///
+    /// The key and nonce of a packet need a hash of at least 32 bytes, so
+    /// [`KeyDerivation::Sha1`] cannot be used for them and an
+    /// [`Error::InvalidCryptoParams`] is returned.
+    ///
+    /// # Default
+    ///
+    /// The values of TeamSpeak are used.
+    ///
+    /// [`KeyDerivation::Sha1`]: enum.KeyDerivation.html#variant.Sha1
+    /// [`Error::InvalidCryptoParams`]: enum.Error.html#variant.InvalidCryptoParams
+    pub fn crypto_params(mut self, params: CryptoParams) -> Result<Self> {
+        if params.key_nonce_derivation == KeyDerivation::Sha1 {
+            return Err(Error::InvalidCryptoParams(String::from(
+                "SHA-1 is too short to derive the key and nonce")));
+        }
+        self.crypto_params = params;
+        Ok(self)
+    }
+
+    /// Check regularly if the server still answers commands.
+    ///
+    /// Every `interval`, a `clientupdate` command without changes is sent,
+    /// also while the server throttles our commands. If the server does not
+    /// answer within `timeout`, the connection is considered stale and gets
+    /// reset with [`Connection::hard_reset`], even if the packets are still
+    /// acknowledged.
     ///
     /// # Default
     ///
-    /// 0.0.0.0:0
-    pub fn local_address(mut self, local_address: SocketAddr) -> Self {
-        self.local_address = local_address;
+    /// Only the protocol keepalive is used.
+    ///
+    /// [`Connection::hard_reset`]: struct.Connection.html#method.hard_reset
+    pub fn app_heartbeat(mut self, interval: std::time::Duration,
+        timeout: std::time::Duration) -> Self {
+        self.app_heartbeat = Some((interval, timeout));
         self
     }
 
-    /// Set the private key of the user.
+    /// Answer the pings of the server on a separate task.
+    ///
+    /// The received commands are buffered until they are handled. When the
+    /// buffer is full, e.g. because the application is busy with a long
+    /// operation, no more packets are read from the connection. With this
+    /// option, pings are still answered in the meantime, so the server does
+    /// not drop the connection.
+    ///
+    /// This cannot help if the event loop itself is blocked.
     ///
     /// # Default
     ///
-    /// A new identity is generated when connecting.
+    /// Pings are answered on a separate task.
+    pub fn ping_responder(mut self, ping_responder: bool) -> Self {
+        self.ping_responder = ping_responder;
+        self
+    }
+
+    /// Collect the acks for received commands for this time and send them
+    /// together.
     ///
-    pub fn private_key_tomcrypt(mut self, private_key: tomcrypt::EccKey)
-        -> Self {
-        self.private_key = Some(private_key);
+    /// This saves work while a big server sends its channel and client lists.
+    /// The protocol has no cumulative acks, so every command still gets its
+    /// own ack, but they are sent in one go. Acks are sent earlier if many of
+    /// them are waiting. The server sees a higher round trip time and resends
+    /// commands whose ack takes too long, so the delay is limited to
+    /// 100 milliseconds and should only be a few milliseconds.
+    ///
+    /// # Default
+    ///
+    /// Acks are sent immediately.
+    pub fn ack_delay(mut self, delay: std::time::Duration) -> Self {
+        self.ack_delay = Some(delay);
         self
     }
 
-    /// Takes the private key as encoded by TeamSpeak (libtomcrypt export and
-    /// base64 encoded).
+    /// Ask for a password when the server or a channel rejects the one which
+    /// was sent.
+    ///
+    /// This is called when connecting to the server and when moving into a
+    /// channel with [`Connection::move_to`]. The action is tried again with
+    /// the returned password, until the provider returns `None`. Then the
+    /// error of the server is returned.
+    ///
+    /// [`Connection::move_to`]: struct.Connection.html#method.move_to
+    pub fn password_provider(mut self,
+        provider: Box<Fn(PasswordRequest) -> Option<String>>) -> Self {
+        self.password_provider = Some(PasswordProvider(provider.into()));
+        self
+    }
+
+    /// If a notification with this name should be parsed.
+    fn parses_notification(&self, name: &str) -> bool {
+        if let Some(ref filter) = self.notification_filter {
+            name == "error" || filter.contains(name)
+        } else {
+            true
+        }
+    }
+}
+
+/// Properties of a new channel.
+#[derive(Debug, Clone)]
+pub struct ChannelOptions {
+    topic: Option<String>,
+    password: Option<String>,
+    channel_type: ChannelType,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            topic: None,
+            password: None,
+            channel_type: ChannelType::Temporary,
+        }
+    }
+}
+
+impl ChannelOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the topic of the channel.
     ///
     /// # Default
     ///
-    /// A new identity is generated when connecting.
+    /// None
+    pub fn topic<S: Into<String>>(mut self, topic: S) -> Self {
+        self.topic = Some(topic.into());
+        self
+    }
+
+    /// Protect the channel with a password.
     ///
-    /// # Error
+    /// # Default
     ///
-    /// An error is returned if either the string is not encoded in valid base64
-    /// or libtomcrypt cannot import the key.
-    pub fn private_key_ts(mut self, private_key: &str) -> Result<Self> {
-        self.private_key = Some(tomcrypt::EccKey::import(
-            &base64::decode(private_key)?)?);
-        Ok(self)
+    /// None
+    pub fn password<S: Into<String>>(mut self, password: S) -> Self {
+        self.password = Some(password.into());
+        self
     }
 
-    /// The name of the user.
+    /// If the channel is permanent, semi-permanent or temporary.
     ///
     /// # Default
     ///
-    /// TeamSpeakUser
-    pub fn name(mut self, name: String) -> Self {
-        self.name = name;
+    /// [`ChannelType::Temporary`]
+    ///
+    /// [`ChannelType::Temporary`]: enum.ChannelType.html#variant.Temporary
+    pub fn channel_type(mut self, channel_type: ChannelType) -> Self {
+        self.channel_type = channel_type;
+        self
+    }
+
+    fn create_command(&self, parent: ChannelId, name: &str)
+        -> commands::Command {
+        let mut command = commands::Command::new("channelcreate");
+        command.push("channel_name", name);
+        command.push("cpid", parent.0.to_string());
+        if let Some(ref topic) = self.topic {
+            command.push("channel_topic", topic.as_str());
+        }
+        if let Some(ref password) = self.password {
+            command.push("channel_password", algs::hash_password(password));
+        }
+        match self.channel_type {
+            ChannelType::Permanent =>
+                command.push("channel_flag_permanent", "1"),
+            ChannelType::SemiPermanent =>
+                command.push("channel_flag_semi_permanent", "1"),
+            ChannelType::Temporary => {}
+        }
+        command
+    }
+}
+
+/// Changes of our own client.
+///
+/// Only the fields which are set get sent to the server.
+#[derive(Debug, Clone, Default)]
+pub struct ClientSelfEdit {
+    name: Option<String>,
+    phonetic_name: Option<String>,
+    description: Option<String>,
+    away_message: Option<Option<String>>,
+    input_muted: Option<bool>,
+    output_muted: Option<bool>,
+    channel_commander: Option<bool>,
+}
+
+impl ClientSelfEdit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Change the nickname.
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
         self
     }
+
+    /// Change the phonetic nickname.
+    pub fn phonetic_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.phonetic_name = Some(name.into());
+        self
+    }
+
+    /// Change the description, which is visible to other clients.
+    pub fn description<S: Into<String>>(mut self, description: S) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Set the client away with a message or back with `None`.
+    pub fn away(mut self, message: Option<String>) -> Self {
+        self.away_message = Some(message);
+        self
+    }
+
+    /// Mute or unmute the microphone.
+    pub fn input_muted(mut self, muted: bool) -> Self {
+        self.input_muted = Some(muted);
+        self
+    }
+
+    /// Mute or unmute the speakers.
+    pub fn output_muted(mut self, muted: bool) -> Self {
+        self.output_muted = Some(muted);
+        self
+    }
+
+    /// Enable or disable the channel commander flag.
+    pub fn channel_commander(mut self, commander: bool) -> Self {
+        self.channel_commander = Some(commander);
+        self
+    }
+
+    /// The `clientupdate` command for all changed fields except the
+    /// description.
+    ///
+    /// Returns `None` if nothing has to be updated.
+    fn update_command(&self) -> Option<commands::Command> {
+        fn flag(b: bool) -> &'static str {
+            if b { "1" } else { "0" }
+        }
+
+        let mut command = commands::Command::new("clientupdate");
+        if let Some(ref name) = self.name {
+            command.push("client_nickname", name.as_str());
+        }
+        if let Some(ref name) = self.phonetic_name {
+            command.push("client_nickname_phonetic", name.as_str());
+        }
+        if let Some(ref away) = self.away_message {
+            command.push("client_away", flag(away.is_some()));
+            if let Some(ref msg) = *away {
+                command.push("client_away_message", msg.as_str());
+            }
+        }
+        if let Some(muted) = self.input_muted {
+            command.push("client_input_muted", flag(muted));
+        }
+        if let Some(muted) = self.output_muted {
+            command.push("client_output_muted", flag(muted));
+        }
+        if let Some(commander) = self.channel_commander {
+            command.push("client_is_channel_commander", flag(commander));
+        }
+
+        if command.static_args.is_empty() {
+            None
+        } else {
+            Some(command)
+        }
+    }
+
+    /// Apply the changes to our client in the book.
+    fn apply(&self, client: &mut structs::Client) {
+        if let Some(ref name) = self.name {
+            client.name = name.clone();
+        }
+        if let Some(ref name) = self.phonetic_name {
+            client.phonetic_name = name.clone();
+        }
+        if let Some(ref description) = self.description {
+            client.description = description.clone();
+        }
+        if let Some(ref away) = self.away_message {
+            client.away_message = away.clone();
+        }
+        if let Some(muted) = self.input_muted {
+            client.input_muted = muted;
+        }
+        if let Some(muted) = self.output_muted {
+            client.output_muted = muted;
+        }
+        if let Some(commander) = self.channel_commander {
+            client.is_channel_commander = commander;
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct DisconnectOptions {
     reason: Option<MoveReason>,
     message: Option<String>,
@@ -573,3 +4810,537 @@ impl DisconnectOptions {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Duration as ChronoDuration;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    fn command_args(command: &commands::Command) -> Vec<(&str, &str)> {
+        command.static_args.iter().map(|&(ref k, ref v)|
+            (k.as_str(), v.as_str())).collect()
+    }
+
+    /// A connection manager with a connection which is not connected to a
+    /// server and handled `commands`.
+    fn test_manager(core: &Core, mode: BookMode, commands: &[&str])
+        -> (ConnectionManager, ConnectionId) {
+        let options = ConnectOptions::default().book_mode(mode);
+        let (mut con, send) = structs::tests::test_connection(core, options);
+        for cmd in commands {
+            send.unbounded_send(structs::tests::command_packet(cmd)).unwrap();
+        }
+        drop(send);
+        structs::tests::handle_all(&mut con);
+        let cm = ConnectionManager::new(core.handle());
+        let id = ConnectionId(0);
+        cm.inner.borrow_mut().connections.insert(id,
+            Rc::new(RefCell::new(con)));
+        (cm, id)
+    }
+
+    #[test]
+    fn own_channel_without_clients() {
+        let mut core = Core::new().unwrap();
+        for mode in &[BookMode::MinimalClients, BookMode::ChannelsOnly] {
+            let (cm, id) = test_manager(&core, *mode, &[
+                "notifycliententerview cfid=0 ctid=1 reasonid=0 clid=1",
+                "notifyclientmoved ctid=2 reasonid=0 clid=1",
+            ]);
+            let con = cm.get_connection(id).unwrap();
+
+            // We are in the channel already
+            core.run(con.move_to_channel(ChannelId(2), None)).unwrap();
+
+            // The message is sent, which fails without a server
+            match core.run(con.send_message(MessageTarget::Channel(
+                ChannelId(2)), "Hi")) {
+                Err(Error::ConnectionFailed(_)) => {}
+                r => panic!("Unexpected result {:?}", r),
+            }
+            match core.run(con.send_message(MessageTarget::Channel(
+                ChannelId(1)), "Hi")) {
+                Err(Error::NotInChannel) => {}
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn removed_connection() {
+        let mut core = Core::new().unwrap();
+        let (cm, id) = test_manager(&core, BookMode::Full, &[]);
+        let con = cm.get_connection(id).unwrap();
+        cm.inner.borrow_mut().remove_connection(id);
+
+        assert!(con.get_server().is_none());
+        assert!(con.supports(ServerCapability::BatchedClientMove).is_none());
+        assert!(con.client_name(ClientId(1)).is_none());
+        assert!(con.diagnostics().is_none());
+        assert!(con.server_time().is_none());
+        assert!(con.own_uid().is_err());
+        match core.run(con.create_server_snapshot()) {
+            Err(Error::ConnectionNotFound) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn kick_command() {
+        let (command, permission) = create_kick_command(ClientId(5),
+            KickTarget::Server, Some("Bye"));
+        assert_eq!(command.command, "clientkick");
+        assert_eq!(command_args(&command), vec![("clid", "5"),
+            ("reasonid", "5"), ("reasonmsg", "Bye")]);
+        match permission {
+            Permission::i_client_kick_from_server_power => {}
+            p => panic!("Unexpected permission {:?}", p),
+        }
+
+        let (command, _) = create_kick_command(ClientId(5),
+            KickTarget::Channel, None);
+        assert_eq!(command_args(&command), vec![("clid", "5"),
+            ("reasonid", "4")]);
+    }
+
+    #[test]
+    fn ban_command() {
+        let ban = |d| create_ban_command(ClientId(5), d, None)
+            .map(|c| command_args(&c)[1].1.to_string());
+        assert_eq!(ban(None).unwrap(), "0");
+        assert_eq!(ban(Some(ChronoDuration::minutes(10))).unwrap(), "600");
+        // Less than a second still bans
+        assert_eq!(ban(Some(ChronoDuration::milliseconds(10))).unwrap(),
+            "1");
+        for d in &[ChronoDuration::zero(), ChronoDuration::seconds(-5)] {
+            match ban(Some(*d)) {
+                Err(Error::InvalidBanDuration(_)) => {}
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+
+        let command = create_ban_command(ClientId(5), None, Some("Spam"))
+            .unwrap();
+        assert_eq!(command.command, "banclient");
+        assert_eq!(command_args(&command), vec![("clid", "5"), ("time", "0"),
+            ("banreason", "Spam")]);
+    }
+
+    #[test]
+    fn parse_identity_ini() {
+        let content = "[General]\nidentity=\"ignored\"\n\n[Identity]\n\
+            id=Default\nidentity=\"123VAbC+/=\"\nnickname=Some Name\n\
+            phonetic_nickname=\n";
+        let (identity, nickname) = identity::parse_ts_ini(content).unwrap();
+        assert_eq!(identity, "123VAbC+/=");
+        assert_eq!(nickname.as_ref().map(|s| s.as_str()), Some("Some Name"));
+
+        assert!(identity::parse_ts_ini("[Identity]\nnickname=a\n").is_err());
+        assert!(Identity::from_ts_exported("AbC").is_err());
+    }
+
+    #[test]
+    fn parse_log_line() {
+        let entry = LogEntry::from_line("2018-01-02 12:30:00.123456|WARNING \
+            |VirtualServer |   1| client disconnected").unwrap();
+        assert_eq!(entry.timestamp, Utc.ymd(2018, 1, 2)
+            .and_hms_micro(12, 30, 0, 123456));
+        assert_eq!(entry.level, LogLevel::Warning);
+        assert_eq!(entry.channel, "VirtualServer");
+        assert_eq!(entry.message, "client disconnected");
+
+        let entry = LogEntry::from_line("2018-01-02 12:30:00.000000|INFO    \
+            |ServerLibPriv |listening on 0.0.0.0:10011").unwrap();
+        assert_eq!(entry.channel, "ServerLibPriv");
+        assert_eq!(entry.message, "listening on 0.0.0.0:10011");
+
+        assert!(LogEntry::from_line("no log line").is_none());
+    }
+
+    #[test]
+    fn new_log_entries_after_last() {
+        let entry = |s, message: &str| LogEntry {
+            timestamp: Utc.timestamp(s, 0),
+            level: LogLevel::Info,
+            channel: String::new(),
+            message: message.to_string(),
+        };
+        let entries = vec![entry(1, "a"), entry(2, "b"), entry(2, "c")];
+        assert_eq!(new_log_entries(entries.clone(), &entry(2, "b")),
+            [entry(2, "c")]);
+        // The last entry is not contained anymore
+        assert_eq!(new_log_entries(entries, &entry(1, "x")),
+            [entry(2, "b"), entry(2, "c")]);
+    }
+
+    #[test]
+    fn nickname_collisions() {
+        assert_eq!(normalize_nickname("  Some \t  Bot "), "some bot");
+        assert_eq!(normalize_nickname("SOME bot"),
+            normalize_nickname("some  Bot"));
+        assert_ne!(normalize_nickname("somebot"), normalize_nickname("some bot"));
+    }
+
+    #[test]
+    fn batched_move_uses_one_command() {
+        let clients = [ClientId(1), ClientId(2), ClientId(3)];
+        let cmds = create_move_commands(&clients, ChannelId(5), Some("pw"),
+            true);
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(cmds[0].get_static_arg("cid"), Some("5"));
+        assert_eq!(cmds[0].get_static_arg("cpw"),
+            Some(algs::hash_password("pw").as_str()));
+        let ids = cmds[0].list_args.iter().map(|a| a[0].1.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn unbatched_move_uses_one_command_per_client() {
+        let clients = [ClientId(1), ClientId(2)];
+        let cmds = create_move_commands(&clients, ChannelId(5), None, false);
+        assert_eq!(cmds.len(), 2);
+        for (cmd, id) in cmds.iter().zip(&["1", "2"]) {
+            assert_eq!(cmd.get_static_arg("clid"), Some(*id));
+            assert_eq!(cmd.get_static_arg("cid"), Some("5"));
+            assert!(cmd.list_args.is_empty());
+        }
+    }
+
+    #[test]
+    fn server_voice_packet() {
+        // Packet id, sending client, codec and data
+        let packet = Packet::new(Header::new(PacketType::Voice),
+            packets::Data::Voice {
+                id: 1,
+                codec_type: 0,
+                voice_data: vec![5, CodecType::OpusVoice as u8, 1, 2],
+            });
+        assert_eq!(voice::parse_server_voice(&packet),
+            Some((ClientId(5), CodecType::OpusVoice, vec![1, 2])));
+
+        // Too short
+        let packet = Packet::new(Header::new(PacketType::Voice),
+            packets::Data::Voice { id: 1, codec_type: 0, voice_data: vec![5] });
+        assert_eq!(voice::parse_server_voice(&packet), None);
+    }
+
+    #[test]
+    fn server_whisper_packet() {
+        // Packet id, sending client, codec and data
+        let header = Header::new(PacketType::VoiceWhisper);
+        let data = packets::Data::read(&header, &mut std::io::Cursor::new(
+            &[0, 1, 0, 5, CodecType::OpusVoice as u8, 1, 2][..])).unwrap();
+        let packet = Packet::new(header, data);
+        assert_eq!(voice::parse_server_voice(&packet),
+            Some((ClientId(5), CodecType::OpusVoice, vec![1, 2])));
+    }
+
+    #[test]
+    fn tsdns_answer() {
+        assert_eq!(resolver::parse_tsdns_answer(b"1.2.3.4:9988\n").unwrap(),
+            "1.2.3.4:9988".parse().unwrap());
+        match resolver::parse_tsdns_answer(b"404") {
+            Err(Error::Resolve { step: ResolveStep::Tsdns, .. }) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn default_channel_encoding() {
+        assert_eq!(DefaultChannel::from(ChannelId(12)).encode(), "/12");
+        assert_eq!(DefaultChannel::from("/Lobby/Gaming").encode(),
+            "Lobby/Gaming");
+        assert_eq!(DefaultChannel::from("Lobby").encode(), "Lobby");
+    }
+
+    #[test]
+    fn ban_time_is_parsed() {
+        assert_eq!(parse_ban_time("you may retry in 600 seconds"),
+            Some(chrono::Duration::seconds(600)));
+        assert_eq!(parse_ban_time("you are banned"), None);
+    }
+
+    #[test]
+    fn client_db_info_is_parsed() {
+        let mut row = Map::new();
+        for &(k, v) in &[
+            ("client_database_id", "5"),
+            ("client_unique_identifier", "abc="),
+            ("client_nickname", "Name"),
+            ("client_created", "1500000000"),
+            ("client_lastconnected", "1500000060"),
+            ("client_totalconnections", "3"),
+            ("client_lastip", "127.0.0.1"),
+            ("client_total_bytes_uploaded", "1024"),
+        ] {
+            row.insert(k.to_string(), v.to_string());
+        }
+        let info = ClientDbInfo::from_row(&row).unwrap();
+        assert_eq!(info.database_id, ClientDbId(5));
+        assert_eq!(info.uid, Uid(String::from("abc=")));
+        assert_eq!(info.name, "Name");
+        assert_eq!(info.created, Utc.timestamp(1500000000, 0));
+        assert_eq!(info.last_connected, Utc.timestamp(1500000060, 0));
+        assert_eq!(info.connection_total, 3);
+        assert_eq!(info.last_ip, Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(info.total_bytes_uploaded, 1024);
+        assert_eq!(info.month_bytes_uploaded, 0);
+
+        // Out of range timestamps are an error instead of a panic
+        row.insert(String::from("client_created"),
+            i64::max_value().to_string());
+        match ClientDbInfo::from_row(&row) {
+            Err(Error::InvalidResponse(_)) => {}
+            r => panic!("Expected an invalid response, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn short_key_derivation_is_rejected() {
+        let mut params = CryptoParams::default();
+        assert!(ConnectOptions::default().crypto_params(params).is_ok());
+        params.key_nonce_derivation = KeyDerivation::Sha1;
+        match ConnectOptions::default().crypto_params(params) {
+            Err(Error::InvalidCryptoParams(_)) => {}
+            r => panic!("Expected invalid crypto params, got {:?}",
+                r.map(|_| ())),
+        }
+        // SHA-1 is fine for the iv and mac
+        params.key_nonce_derivation = KeyDerivation::Sha256;
+        params.iv_mac_derivation = KeyDerivation::Sha1;
+        assert!(ConnectOptions::default().crypto_params(params).is_ok());
+    }
+
+    #[test]
+    fn permission_rows_are_parsed() {
+        let logger = Logger::root(slog::Discard, o!());
+        let row = |id: u32, value: i32| {
+            let mut row = Map::new();
+            row.insert(String::from("permid"), id.to_string());
+            row.insert(String::from("permvalue"), value.to_string());
+            row
+        };
+        let known = (0..1000).filter_map(|id| Permission::from_u32(id)
+            .map(|p| (id, p))).take(2).collect::<Vec<_>>();
+        let rows = vec![row(known[0].0, 75), row(u32::max_value(), 1),
+            row(known[1].0, -1)];
+        assert_eq!(parse_permission_rows(&logger, &rows).unwrap(),
+            vec![(known[0].1, 75), (known[1].1, -1)]);
+
+        // A broken row is still an error
+        let mut broken = row(known[0].0, 0);
+        broken.remove("permvalue");
+        assert!(parse_permission_rows(&logger, &[broken]).is_err());
+    }
+
+    #[test]
+    fn huge_ban_time_is_ignored() {
+        let msg = "you may retry in 99999999999999999999 seconds";
+        assert_eq!(parse_ban_time(msg), None);
+        assert_eq!(parse_ban_time("you may retry in -5 seconds"), None);
+
+        match handshake_error(TsError::ConnectFailedBanned, String::new(),
+            Some(msg.to_string())) {
+            Error::Banned { reason, until } => {
+                assert_eq!(reason.as_ref().map(|r| r.as_str()), Some(msg));
+                assert!(until.is_none());
+            }
+            e => panic!("Expected a ban, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let rows = |command: &str| commands::Command::read((),
+            &mut std::io::Cursor::new(command)).unwrap().get_commands()
+            .into_iter().map(|c| c.args.iter().map(|(k, v)|
+                (k.to_string(), v.to_string())).collect::<Map<_, _>>())
+            .collect::<Vec<_>>();
+        let snapshot = rows("notifyserversnapshot hash=abc \
+            virtualserver_name=Test\\sServer channel_id=3|channel_id=1|\
+            channel_id=2 channel_name=Second");
+        assert_eq!(snapshot.len(), 3);
+
+        let data = serialize_snapshot(&snapshot).unwrap();
+        let deployed = rows(&format!("serversnapshotdeploy {}", data));
+        // All rows are kept in their order
+        assert_eq!(deployed, snapshot);
+        assert_eq!(deployed[1]["channel_id"], "1");
+        assert_eq!(deployed[2]["channel_name"], "Second");
+
+        match serialize_snapshot(&[]) {
+            Err(Error::InvalidResponse(_)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn download_size_is_limited() {
+        let row = |size: &str| {
+            let mut row = Map::new();
+            row.insert(String::from("size"), size.to_string());
+            row
+        };
+        assert_eq!(parse_download_size(&row("1024"), MAX_ICON_SIZE).unwrap(),
+            1024);
+        assert_eq!(parse_download_size(&row("1048576"), MAX_ICON_SIZE)
+            .unwrap(), MAX_ICON_SIZE);
+        for size in &["1048577", "18446744073709551615", "-1"] {
+            match parse_download_size(&row(size), MAX_ICON_SIZE) {
+                Err(Error::InvalidResponse(_)) => {}
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn remove_unknown_connection() {
+        let mut core = Core::new().unwrap();
+        let mut cm = ConnectionManager::new(core.handle());
+        core.run(cm.remove_connection(ConnectionId(0), None)).unwrap();
+    }
+
+    #[test]
+    fn dropped_connect_frees_client() {
+        let mut core = Core::new().unwrap();
+        let mut cm = ConnectionManager::new(core.handle());
+        let key = tomcrypt::EccKey::new(tomcrypt::sprng(), 32).unwrap();
+        let client = create_client(core.handle(),
+            "127.0.0.1:0".parse().unwrap(), key, ResendConfig::default())
+            .unwrap();
+        let client_weak = Rc::downgrade(&client);
+
+        // Nobody answers on this port, so the handshake does not finish
+        let config = ConnectOptions::from_address(
+            "127.0.0.1:1".parse().unwrap()).skip_security_level();
+        let connect = cm.connect(client, config);
+        let timeout = Timeout::new(Duration::from_millis(100), &core.handle())
+            .unwrap();
+        match core.run(connect.select2(timeout)) {
+            Err(futures::future::Either::A(_)) => {}
+            Ok(futures::future::Either::B((_, connect))) => drop(connect),
+            _ => panic!("Connecting should not succeed"),
+        }
+
+        // Let the spawned tasks notice that the client is gone
+        let timeout = Timeout::new(Duration::from_millis(100), &core.handle())
+            .unwrap();
+        core.run(timeout).unwrap();
+
+        assert!(client_weak.upgrade().is_none());
+        assert!(cm.inner.borrow().connections.is_empty());
+        assert_eq!(Rc::strong_count(&cm.inner), 1);
+    }
+
+    #[test]
+    fn connection_ids_are_unique() {
+        let mut ids = ConnectionIds::default();
+        let mut live: Vec<(ConnectionId, Rc<()>)> = Vec::new();
+        // Removed connections whose futures are still running
+        let mut draining: Vec<(ConnectionId, Rc<()>)> = Vec::new();
+        for i in 0..1000 {
+            let id = ids.allocate();
+            assert!(live.iter().chain(&draining).all(|&(other, _)|
+                other != id), "Id {:?} is used twice", id);
+            live.push((id, Rc::new(())));
+
+            if i % 2 == 1 {
+                // Close the oldest connection, its driver still runs
+                let (id, token) = live.remove(0);
+                ids.release(id, &token);
+                draining.push((id, token));
+            }
+            if i % 5 == 4 {
+                // Some futures finish
+                draining.remove(0);
+            }
+        }
+        assert!(ids.next < 1000, "Ids should be reused");
+    }
+
+    /// A provider which returns the passwords from the end of `passwords`.
+    fn list_provider(passwords: Vec<&'static str>)
+        -> (PasswordProvider, Rc<Cell<usize>>) {
+        let passwords = RefCell::new(passwords);
+        let asked = Rc::new(Cell::new(0));
+        let asked2 = asked.clone();
+        (PasswordProvider(Rc::new(move |_| {
+            asked2.set(asked2.get() + 1);
+            passwords.borrow_mut().pop().map(String::from)
+        })), asked)
+    }
+
+    fn wrong_channel_password() -> Error {
+        Error::Server(TsError::ChannelInvalidPassword, String::new())
+    }
+
+    fn try_channel_password(password: String) -> BoxFuture<()> {
+        if password == "secret" {
+            Box::new(future::ok(()))
+        } else {
+            Box::new(future::err(wrong_channel_password()))
+        }
+    }
+
+    #[test]
+    fn password_provider_is_asked_until_accepted() {
+        let request = PasswordRequest::Channel { connection: ConnectionId(0),
+            channel: ChannelId(1) };
+
+        let (provider, asked) = list_provider(vec!["secret", "wrong"]);
+        let (_, password) = retry_with_passwords(Some(provider), request,
+            wrong_channel_password(), is_wrong_channel_password,
+            try_channel_password).wait().unwrap();
+        assert_eq!(password, "secret");
+        assert_eq!(asked.get(), 2);
+
+        // The provider has no right password
+        let (provider, asked) = list_provider(vec!["a", "b"]);
+        match retry_with_passwords(Some(provider), request,
+            wrong_channel_password(), is_wrong_channel_password,
+            try_channel_password).wait() {
+            Err(Error::Server(TsError::ChannelInvalidPassword, _)) => {}
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+        assert_eq!(asked.get(), 3);
+
+        // Other errors are not retried
+        let (provider, asked) = list_provider(vec!["secret"]);
+        match retry_with_passwords(Some(provider), request,
+            Error::ChannelNotFound, is_wrong_channel_password,
+            try_channel_password).wait() {
+            Err(Error::ChannelNotFound) => {}
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+        assert_eq!(asked.get(), 0);
+    }
+
+    #[test]
+    fn first_ok_cleans_up_later_results() {
+        let mut core = Core::new().unwrap();
+        let (send1, recv1) = futures::unsync::oneshot::channel::<u32>();
+        let (send2, recv2) = futures::unsync::oneshot::channel::<u32>();
+        let (_send3, recv3) = futures::unsync::oneshot::channel::<u32>();
+        let futs: Vec<BoxFuture<u32>> = vec![
+            Box::new(recv1.map_err(|_| Error::Timeout)),
+            Box::new(recv2.map_err(|_| Error::Timeout)),
+            Box::new(recv3.map_err(|_| Error::Timeout)),
+        ];
+        let cleaned = Rc::new(RefCell::new(Vec::new()));
+        let cleaned2 = cleaned.clone();
+        let fut = first_ok(&core.handle(), futs,
+            Rc::new(move |v: u32| cleaned2.borrow_mut().push(v)));
+
+        send2.send(2).unwrap();
+        assert_eq!(core.run(fut).unwrap(), 2);
+        // The slower connection is cleaned up when it finishes
+        send1.send(1).unwrap();
+        core.turn(Some(Duration::from_millis(10)));
+        assert_eq!(*cleaned.borrow(), [1]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{future, Future};
+use slog::Logger;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::io;
+use trust_dns_resolver::ResolverFuture;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+
+use {BoxFuture, Error, Result};
+
+/// The default port of TeamSpeak servers.
+pub const DEFAULT_PORT: u16 = 9987;
+/// The port of TSDNS servers.
+const TSDNS_PORT: u16 = 41144;
+/// How long we wait for the answer of a TSDNS server (in seconds).
+const TSDNS_TIMEOUT: u64 = 2;
+
+/// The steps which are tried to resolve the address of a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResolveStep {
+    /// Ask the TSDNS server on the host.
+    Tsdns,
+    /// Look up the `_ts3._udp` SRV record.
+    Srv,
+    /// Look up the ip address of the host.
+    Dns,
+}
+
+fn resolve_error<E: ToString>(step: ResolveStep, error: E) -> Error {
+    Error::Resolve { step, message: error.to_string() }
+}
+
+/// Resolve the address of a TeamSpeak server.
+///
+/// If `host` is no ip address, TSDNS, the SRV record and the plain ip address
+/// of the host are tried in this order. If a port is given, only the ip
+/// address is looked up.
+///
+/// The error of the last step is returned if all steps fail.
+pub(crate) fn resolve(logger: Logger, handle: Handle, host: &str)
+    -> BoxFuture<SocketAddr> {
+    if let Ok(addr) = host.parse() {
+        return Box::new(future::ok(addr));
+    }
+    if let Ok(ip) = host.parse() {
+        return Box::new(future::ok(SocketAddr::new(ip, DEFAULT_PORT)));
+    }
+
+    let resolver = Rc::new(ResolverFuture::from_system_conf(&handle)
+        .unwrap_or_else(|e| {
+            warn!(logger, "Failed to read the system dns configuration";
+                "error" => ?e);
+            ResolverFuture::new(ResolverConfig::default(),
+                ResolverOpts::default(), &handle)
+        }));
+
+    if let Some((name, port)) = split_port(host) {
+        return Box::new(lookup_ip(&resolver, name, ResolveStep::Dns)
+            .map(move |ip| SocketAddr::new(ip, port)));
+    }
+
+    let host = host.to_string();
+    let (host2, host3) = (host.clone(), host.clone());
+    let (resolver2, resolver3) = (resolver.clone(), resolver.clone());
+    let (logger2, logger3) = (logger.clone(), logger.clone());
+    Box::new(tsdns(&handle, &resolver, host).or_else(move |e| {
+        debug!(logger2, "Failed to resolve with TSDNS"; "error" => %e);
+        srv(&resolver2, host2)
+    }).or_else(move |e| {
+        debug!(logger3, "Failed to resolve with SRV"; "error" => %e);
+        lookup_ip(&resolver3, &host3, ResolveStep::Dns)
+            .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+    }))
+}
+
+/// Split `host:port` if the port is a number.
+fn split_port(host: &str) -> Option<(&str, u16)> {
+    let pos = host.rfind(':')?;
+    let port = host[pos + 1..].parse().ok()?;
+    Some((&host[..pos], port))
+}
+
+fn lookup_ip(resolver: &ResolverFuture, host: &str, step: ResolveStep)
+    -> BoxFuture<IpAddr> {
+    let host2 = host.to_string();
+    Box::new(resolver.lookup_ip(host).then(move |res| match res {
+        Ok(ips) => ips.iter().next().ok_or_else(|| resolve_error(step,
+            format!("No ip address found for {}", host2))),
+        Err(e) => Err(resolve_error(step, e)),
+    }))
+}
+
+/// Ask the TSDNS server on `host` for the address of `host`.
+fn tsdns(handle: &Handle, resolver: &ResolverFuture, host: String)
+    -> BoxFuture<SocketAddr> {
+    let step = ResolveStep::Tsdns;
+    let timeout = tryf!(Timeout::new(Duration::from_secs(TSDNS_TIMEOUT),
+        handle).map_err(|e| resolve_error(step, e)));
+    let handle = handle.clone();
+    let answer = lookup_ip(resolver, &host, step).and_then(move |ip|
+        TcpStream::connect(&SocketAddr::new(ip, TSDNS_PORT), &handle)
+            .map_err(move |e| resolve_error(step, e)))
+        .and_then(move |stream| io::write_all(stream, host.into_bytes())
+            .map_err(move |e| resolve_error(step, e)))
+        .and_then(move |(stream, _)| io::read_to_end(stream, Vec::new())
+            .map_err(move |e| resolve_error(step, e)))
+        .and_then(|(_, data)| parse_tsdns_answer(&data));
+
+    Box::new(answer.select2(timeout).then(move |res| match res {
+        Ok(future::Either::A((addr, _))) => Ok(addr),
+        Err(future::Either::A((e, _))) => Err(e),
+        Ok(future::Either::B(_)) => Err(resolve_error(step, "Timed out")),
+        Err(future::Either::B((e, _))) => Err(resolve_error(step, e)),
+    }))
+}
+
+/// Parse the answer of a TSDNS server, which is either `ip:port` or `404`.
+pub(crate) fn parse_tsdns_answer(data: &[u8]) -> Result<SocketAddr> {
+    let step = ResolveStep::Tsdns;
+    let answer = ::std::str::from_utf8(data).map_err(|e|
+        resolve_error(step, e))?.trim();
+    if answer == "404" {
+        return Err(resolve_error(step, "Host not found"));
+    }
+    answer.parse().map_err(|_| resolve_error(step,
+        format!("Invalid answer {:?}", answer)))
+}
+
+/// Look up the `_ts3._udp` SRV record of `host`.
+fn srv(resolver: &Rc<ResolverFuture>, host: String) -> BoxFuture<SocketAddr> {
+    let step = ResolveStep::Srv;
+    let resolver = resolver.clone();
+    Box::new(resolver.lookup_srv(&format!("_ts3._udp.{}", host))
+        .map_err(move |e| resolve_error(step, e))
+        .and_then(move |records| {
+            // Use the record with the highest priority (lowest number)
+            let record = tryf!(records.iter().min_by_key(|r| r.priority())
+                .map(|r| (r.target().to_string(), r.port()))
+                .ok_or_else(|| resolve_error(step, "No SRV record found")));
+            Box::new(lookup_ip(&resolver, &record.0, step)
+                .map(move |ip| SocketAddr::new(ip, record.1)))
+                as BoxFuture<_>
+        }))
+}
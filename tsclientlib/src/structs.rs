@@ -1,19 +1,86 @@
-use std::cell::RefCell;
-use std::net::SocketAddr;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::{Deref, DerefMut};
 use std::rc::{Rc, Weak};
 
 use chrono::{DateTime, Duration, Utc};
-use futures::{self, Stream};
+use num::FromPrimitive;
+use futures::{self, future, Future, Sink, Stream};
+use futures::unsync::{mpsc, oneshot};
+use slog::Logger;
+use tokio_core::reactor::Timeout;
 use tsproto::errors::Error as tsproto_error;
-use tsproto::client;
+use tsproto::{client, commands, packets};
+use tsproto::connection::ConnectionStats;
+use tsproto::packets::{CodecType, Header, Packet, PacketType};
 use tsproto_commands::*;
+use tsproto_commands::errors::Error as TsError;
 use tsproto_commands::messages::*;
+use tsproto_commands::permissions::Permission;
 
-use {ChannelType, Map};
+use {BookMode, BoxFuture, Set, ChannelType, ConnectOptions, ConnectionSummary,
+    Error, Map, MessageTarget, create_move_command};
+use voice::{VoiceShutdown, WhisperTarget};
 
 include!(concat!(env!("OUT_DIR"), "/structs.rs"));
 
+/// The command which is sent by the application heartbeat.
+///
+/// A `clientupdate` without arguments changes nothing, but the server answers
+/// it like every other command.
+pub(crate) fn heartbeat_command() -> commands::Command {
+    commands::Command::new("clientupdate")
+}
+
+/// Create a `Channel` from a packet which contains all the channel properties,
+/// like `channellist` and `notifychannelcreated`.
+macro_rules! channel_from_packet {
+    ($packet:ident, $id:expr) => {
+        copy_attrs!($packet, Channel;
+            name,
+            topic,
+            codec,
+            codec_quality,
+            order,
+            has_password,
+            codec_latency_factor,
+            delete_delay,
+            needed_talk_power,
+            forced_silence,
+            phonetic_name,
+            is_unencrypted,
+            is_private,
+            ;
+
+            connection_id: $id,
+            id: $packet.channel_id,
+            parent: $packet.channel_parent_id,
+            max_clients: if $packet.is_max_clients_unlimited {
+                None
+            } else {
+                Some($packet.max_clients)
+            },
+            max_family_clients: if $packet.is_max_family_clients_unlimited {
+                None
+            } else {
+                Some($packet.max_family_clients)
+            },
+            channel_type: if $packet.is_permanent {
+                ChannelType::Permanent
+            } else if $packet.is_semi_permanent {
+                ChannelType::SemiPermanent
+            } else {
+                ChannelType::Temporary
+            },
+            default: $packet.is_default_channel,
+            icon: $packet.icon_id,
+
+            optional_data: None,
+        )
+    };
+}
+
 impl Connection {
     fn new(id: ConnectionId, server_uid: Uid, packet: &InitServer)
         -> Self {
@@ -62,63 +129,587 @@ impl Connection {
         }
     }
 
-    fn handle_message(&mut self, msg: &Notification) {
+    /// If voice packets in a channel are encrypted.
+    ///
+    /// This depends on the encryption mode of the server and, if the server
+    /// lets the channel decide, the setting of the channel.
+    pub fn is_voice_encrypted(&self, channel: ChannelId) -> bool {
+        match self.server.codec_encryption_mode {
+            CodecEncryptionMode::ForcedOn => true,
+            CodecEncryptionMode::ForcedOff => false,
+            CodecEncryptionMode::PerChannel => self.server.channels
+                .get(&channel).map(|c| !c.is_unencrypted).unwrap_or(false),
+        }
+    }
+
+    /// All channels in the order of the channel tree.
+    ///
+    /// Child channels follow their parent, the number is the depth of the
+    /// channel in the tree, starting with `0`.
+    pub fn channel_tree(&self) -> Vec<(ChannelId, usize)> {
+        channel_tree(&self.channel_links())
+    }
+
+    /// The names of a channel and its parents, starting at the top level.
+    pub fn channel_path(&self, channel: ChannelId) -> Option<Vec<String>> {
+        let mut path = Vec::new();
+        let mut cur = channel;
+        while cur.0 != 0 {
+            // Stop at cycles in a broken channel tree
+            if path.len() > self.server.channels.len() {
+                return None;
+            }
+            let c = self.server.channels.get(&cur)?;
+            path.push(c.name.clone());
+            cur = c.parent;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Find a channel by the names of its parents and its own name.
+    ///
+    /// If there are multiple channels with a name, the one with the smallest
+    /// id is taken.
+    pub fn find_channel_by_path(&self, path: &[String]) -> Option<ChannelId> {
+        let mut cur = ChannelId(0);
+        for name in path {
+            cur = self.server.channels.values()
+                .filter(|c| c.parent == cur && c.name == *name)
+                .map(|c| c.id)
+                .min_by_key(|c| c.0)?;
+        }
+        if cur.0 == 0 { None } else { Some(cur) }
+    }
+
+    fn channel_links(&self) -> ChannelLinks {
+        self.server.channels.values().map(|c| (c.id, (c.parent, c.order)))
+            .collect()
+    }
+
+    fn apply_channel_links(&mut self, links: &ChannelLinks) {
+        for (id, &(parent, order)) in links {
+            if let Some(channel) = self.server.channels.get_mut(id) {
+                channel.parent = parent;
+                channel.order = order;
+            }
+        }
+    }
+
+    /// Move a channel to another position and update the order of the
+    /// siblings.
+    fn move_channel(&mut self, id: ChannelId, parent: ChannelId,
+        order: ChannelId) {
+        if !self.server.channels.contains_key(&id) {
+            return;
+        }
+        let mut links = self.channel_links();
+        move_channel(&mut links, id, parent, order);
+        self.apply_channel_links(&links);
+    }
+
+    fn handle_message(&mut self, msg: &Notification, book_mode: BookMode) {
         match *msg {
             Notification::ChannelList(ref packet) => {
                 // Add new channel
-                let channel = copy_attrs!(packet, Channel;
+                let channel = channel_from_packet!(packet, self.id);
+                self.server.channels.insert(channel.id, channel);
+            }
+            Notification::ChannelCreated(ref packet) => {
+                let channel = channel_from_packet!(packet, self.id);
+                // The channel which was at this position moves below it
+                let mut links = self.channel_links();
+                move_channel(&mut links, channel.id, channel.parent,
+                    channel.order);
+                self.apply_channel_links(&links);
+                self.server.channels.insert(channel.id, channel);
+            }
+            Notification::ChannelDeleted(ref packet) => {
+                let mut links = self.channel_links();
+                unlink_channel(&mut links, packet.channel_id);
+                self.apply_channel_links(&links);
+                self.server.channels.remove(&packet.channel_id);
+            }
+            Notification::ClientEnterView(ref packet) => {
+                // The names of the minimal mode are kept by the
+                // `NetworkWrapper`
+                if book_mode != BookMode::Full {
+                    return;
+                }
+                let client = copy_attrs!(packet, Client;
                     name,
-                    topic,
-                    codec,
-                    codec_quality,
-                    order,
-                    has_password,
-                    codec_latency_factor,
-                    delete_delay,
-                    needed_talk_power,
-                    forced_silence,
+                    uid,
+                    database_id,
+                    client_type,
+                    input_muted,
+                    output_muted,
+                    output_only_muted,
+                    input_hardware_enabled,
+                    output_hardware_enabled,
+                    is_recording,
+                    channel_group,
+                    server_groups,
+                    talk_power,
+                    is_talker,
+                    is_priority_speaker,
+                    is_channel_commander,
+                    description,
                     phonetic_name,
-                    is_unencrypted,
-                    is_private,
+                    country_code,
                     ;
 
                     connection_id: self.id,
-                    id: packet.channel_id,
-                    parent: packet.channel_parent_id,
-                    max_clients: if packet.is_max_clients_unlimited {
-                        None
+                    id: packet.client_id,
+                    channel: packet.target_channel_id,
+                    away_message: if packet.is_away {
+                        Some(packet.away_message.clone())
                     } else {
-                        Some(packet.max_clients)
-                    },
-                    max_family_clients: if packet.is_max_family_clients_unlimited {
                         None
-                    } else {
-                        Some(packet.max_family_clients)
                     },
-                    channel_type: if packet.is_permanent {
-                        ChannelType::Permanent
-                    } else if packet.is_semi_permanent {
-                        ChannelType::SemiPermanent
-                    } else {
-                        ChannelType::Temporary
-                    },
-                    default: packet.is_default_channel,
                     icon: packet.icon_id,
 
                     optional_data: None,
+                    connection_data: None,
                 );
-                self.server.channels.insert(channel.id, channel);
+                self.server.clients.insert(client.id, client);
+            }
+            Notification::ClientLeftView(ref packet) => {
+                self.server.clients.remove(&packet.client_id);
+            }
+            Notification::ClientMoved(ref packet) => {
+                if let Some(client) = self.server.clients
+                    .get_mut(&packet.client_id) {
+                    client.channel = packet.target_channel_id;
+                }
             }
             _ => {} // TODO
         }
     }
 }
 
+/// The parent and the channel above of each channel.
+///
+/// Channels are a linked list, every channel stores the id of the channel
+/// above it, the first channel of a parent stores `0`.
+type ChannelLinks = Map<ChannelId, (ChannelId, ChannelId)>;
+
+/// Remove a channel from the list of its siblings.
+///
+/// The channel below it takes its position.
+fn unlink_channel(links: &mut ChannelLinks, id: ChannelId) {
+    if let Some((parent, order)) = links.remove(&id) {
+        if let Some(next) = links.values_mut().find(|l| **l == (parent, id)) {
+            next.1 = order;
+        }
+    }
+}
+
+/// Move a channel below `parent` and after the channel `order`.
+///
+/// The channel which was at this position before moves below the channel.
+fn move_channel(links: &mut ChannelLinks, id: ChannelId, parent: ChannelId,
+    order: ChannelId) {
+    unlink_channel(links, id);
+    if let Some(next) = links.values_mut().find(|l| **l == (parent, order)) {
+        next.1 = id;
+    }
+    links.insert(id, (parent, order));
+}
+
+/// Sort channels like the channel tree, children follow their parent.
+///
+/// Returns the channels with their depth in the tree.
+fn channel_tree(links: &ChannelLinks) -> Vec<(ChannelId, usize)> {
+    /// The channel below a parent and after a channel.
+    type NextChannels = Map<(ChannelId, ChannelId), ChannelId>;
+
+    fn add_children(next: &NextChannels, parent: ChannelId, depth: usize,
+        res: &mut Vec<(ChannelId, usize)>) {
+        let mut above = ChannelId(0);
+        // Stop on broken links which form a cycle
+        for _ in 0..next.len() {
+            if let Some(&id) = next.get(&(parent, above)) {
+                res.push((id, depth));
+                add_children(next, id, depth + 1, res);
+                above = id;
+            } else {
+                break;
+            }
+        }
+    }
+
+    let next: NextChannels = links.iter().map(|(id, l)| (*l, *id)).collect();
+    let mut res = Vec::new();
+    add_children(&next, ChannelId(0), 0, &mut res);
+    res
+}
+
 pub struct NetworkWrapper {
     connection: Connection,
     pub client_data: Rc<RefCell<client::ClientData>>,
     pub client_connection: Weak<RefCell<client::ClientConnection>>,
-    pub inner_stream: Box<Stream<Item = Notification, Error = tsproto_error>>,
+    /// The codec quality of the channel of our client, voice captures adjust
+    /// their bitrate to it.
+    pub voice_quality: Rc<Cell<Option<u8>>>,
+    /// Ends the voice transmissions before disconnecting.
+    pub(crate) voice_shutdown: Rc<VoiceShutdown>,
+    /// Keeps the id of this connection reserved, futures which use the id
+    /// hold a clone of it.
+    pub(crate) id_token: Rc<()>,
+    /// The id of the next voice or whisper packet.
+    ///
+    /// All packets share the numbering, so it is also used by the voice
+    /// captures.
+    pub next_voice_id: Rc<Cell<u16>>,
+    /// The codec and the whisper target of the current transmission, if we
+    /// are sending voice with [`Connection::send_voice`] or
+    /// [`Connection::whisper`].
+    ///
+    /// [`Connection::send_voice`]: ../struct.Connection.html#method.send_voice
+    /// [`Connection::whisper`]: ../struct.Connection.html#method.whisper
+    pub voice_transmission: Option<(CodecType, Option<WhisperTarget>)>,
+    /// The address of the server.
+    pub address: SocketAddr,
+    /// The stream of command packets from the server.
+    pub inner_stream: Box<Stream<Item = Packet, Error = tsproto_error>>,
+    logger: Logger,
+    /// Parsed notifications which were not yet returned by the stream, with
+    /// the time when they were received.
+    notifications: VecDeque<(DateTime<Utc>, Notification)>,
+    /// When the command which is currently handled was received.
+    received: DateTime<Utc>,
+    /// The options which were used to create this connection.
+    ///
+    /// The private key is not stored in here.
+    pub options: Rc<ConnectOptions>,
+    /// How much data is stored in the book.
+    pub book_mode: BookMode,
+    /// The ip addresses of clients.
+    ///
+    /// They are only sent by the server if we have the permission to see them.
+    /// The declarations of the book have no field for the address, so they
+    /// are stored next to the book, only in [`BookMode::Full`].
+    ///
+    /// [`BookMode::Full`]: ../enum.BookMode.html#variant.Full
+    pub client_ips: Map<ClientId, IpAddr>,
+    /// The nicknames of clients in [`BookMode::MinimalClients`], which does
+    /// not store clients in the book.
+    ///
+    /// [`BookMode::MinimalClients`]: ../enum.BookMode.html#variant.MinimalClients
+    pub client_names: Map<ClientId, String>,
+    /// The public keys (omega) and key offsets of other clients.
+    ///
+    /// They are only known if the server sends them.
+    pub client_identities: Map<ClientId, (String, u64)>,
+    /// When clients joined their current channel, as a counter.
+    ///
+    /// Clients in a channel with the same talk power are sorted by this.
+    channel_joins: Map<ClientId, u64>,
+    next_channel_join: u64,
+    /// The last received text messages of every chat.
+    pub chat_history: Map<MessageTarget, VecDeque<ChatMessage>>,
+    /// The channel of our client, in the order of the received commands.
+    ///
+    /// This is ahead of the book, which is only updated when a notification
+    /// is returned, and it is also known if clients are not stored.
+    pub(crate) own_channel: Option<ChannelId>,
+    /// The open talk power requests of clients.
+    pub talk_requests: Map<ClientId, TalkRequest>,
+    /// Passwords which are used when joining channels.
+    pub channel_passwords: Map<ChannelId, String>,
+
+    /// When the connection was added.
+    pub connected_since: DateTime<Utc>,
+    /// How often the connection was reset.
+    pub reconnects: u32,
+    /// If a hard reset replaces this connection, its driver must not remove
+    /// it when the old connection closes.
+    pub(crate) resetting: bool,
+    /// The time of the server minus our time.
+    pub clock_offset: Duration,
+    /// The traffic of the connections before the last reset.
+    pub previous_stats: ConnectionStats,
+    /// The packets of each type of the connections before the last reset.
+    pub previous_packet_type_stats: Map<PacketType, (u64, u64)>,
+
+    /// The id which is used for the next file transfer.
+    pub next_file_transfer_id: u16,
+    /// The server does not accept commands before this time because we sent
+    /// too many.
+    pub throttled_until: Option<DateTime<Utc>>,
+    /// If set, only whispers of these clients are returned by
+    /// [`Connection::get_voice_stream`].
+    ///
+    /// [`Connection::get_voice_stream`]: ../struct.Connection.html#method.get_voice_stream
+    pub whisper_allow_list: Option<Set<ClientId>>,
+    /// Listeners which are notified when the groups of our client change.
+    pub permission_listeners: Vec<mpsc::UnboundedSender<()>>,
+    /// Listeners which are notified when the server assigns a new id to our
+    /// client.
+    pub own_id_listeners: Vec<mpsc::UnboundedSender<OwnClientIdChanged>>,
+    /// Listeners which are notified when a client requests talk power.
+    pub talk_request_listeners: Vec<mpsc::UnboundedSender<TalkRequest>>,
+    /// Waiting for clients to join channels.
+    pub client_waiters: Vec<ClientWaiter>,
+    /// Listeners which get all events of this connection.
+    pub event_listeners: Vec<mpsc::UnboundedSender<Event>>,
+    /// Listeners for notifications which are not known to this library.
+    pub unknown_notification_listeners:
+        Vec<mpsc::UnboundedSender<(DateTime<Utc>, commands::Command)>>,
+    /// How much of the initial channel list was received.
+    pub channel_list_progress: ChannelListProgress,
+    /// Set while the retained book of a reset connection is synchronized.
+    resync: Option<Resync>,
+    /// The reason why the server removed our client, if it told us.
+    pub disconnect_reason: Option<MoveReason>,
+    /// When the server removed our client.
+    pub disconnected_at: Option<DateTime<Utc>>,
+    /// Commands which wait for an answer of the server.
+    pending_commands: PendingCommands,
+}
+
+/// The rows of a response to a command, as key-value pairs.
+pub type ResponseRows = Vec<Map<String, String>>;
+
+/// Selects the rows of a response which belong to a command.
+///
+/// Some responses, e.g. `notifyclientupdated`, are also sent by the server on
+/// its own, so the name alone does not identify the response.
+pub type RowFilter = Box<Fn(&Map<String, String>) -> bool>;
+
+/// A [`RowFilter`] which accepts rows where `key` has one of the `values`.
+///
+/// [`RowFilter`]: type.RowFilter.html
+pub(crate) fn rows_with(key: &'static str, values: Vec<String>) -> RowFilter {
+    Box::new(move |row| row.get(key).map(|v| values.contains(v))
+        .unwrap_or(false))
+}
+
+/// A command that waits for an answer of the server.
+struct PendingCommand {
+    /// The name of the command which contains the response data.
+    response_name: Option<&'static str>,
+    /// Selects the rows with the name `response_name` which belong to this
+    /// command, all rows are taken if it is `None`.
+    filter: Option<RowFilter>,
+    /// The sent command, it is sent again if the server throttles us.
+    command: commands::Command,
+    rows: ResponseRows,
+    /// The permission which the server reported as missing in its error.
+    failed_permission: Option<Permission>,
+    sender: oneshot::Sender<Result<ResponseRows, Error>>,
+}
+
+/// A received text message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub sender: ClientId,
+    pub sender_name: String,
+    pub sender_uid: Uid,
+    pub message: String,
+    /// When the message was received.
+    pub received: DateTime<Utc>,
+}
+
+/// Something that happened on a connection.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A notification of the server, together with the time when it was
+    /// received.
+    ///
+    /// The book is already updated with this notification.
+    Notification(DateTime<Utc>, Notification),
+    /// A text message was received.
+    ///
+    /// The target tells in which chat the message was sent, for private
+    /// messages it contains the other client. Our own messages are returned
+    /// too, as the server sends them back.
+    Message(MessageTarget, ChatMessage),
+    /// The connection was closed and removed from the connection manager.
+    ///
+    /// The summary contains the statistics of the whole connection. This is
+    /// the last event of a connection.
+    Disconnected(DisconnectReason, ConnectionSummary),
+}
+
+/// Why a connection was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection was removed with
+    /// [`ConnectionManager::remove_connection`].
+    ///
+    /// [`ConnectionManager::remove_connection`]: ../struct.ConnectionManager.html#method.remove_connection
+    Removed,
+    /// The server removed our client, e.g. because we were kicked or banned
+    /// or the server shut down.
+    Server(MoveReason),
+    /// The connection timed out or failed without an explanation of the
+    /// server.
+    LostConnection,
+}
+
+/// A client requested talk power in a moderated channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TalkRequest {
+    pub client: ClientId,
+    /// The channel of the client when it requested talk power.
+    pub channel: ChannelId,
+    pub message: String,
+    /// When the request was received.
+    pub received: DateTime<Utc>,
+}
+
+/// Waits until a client which matches the predicate is in a channel.
+pub struct ClientWaiter {
+    pub channel: ChannelId,
+    /// Only this client matches if it is set, the predicate is not used
+    /// then.
+    pub client: Option<ClientId>,
+    /// Gets the clients which are now in the channel, with their nickname and
+    /// unique id if they are stored in the book.
+    pub sender: mpsc::UnboundedSender<(ClientId, Option<(String, Uid)>)>,
+}
+
+impl ClientWaiter {
+    /// Create a waiter and the stream of the clients which match it.
+    ///
+    /// The predicate gets the nickname and the unique id of a client. It runs
+    /// when the returned stream is polled and not while the connection is
+    /// borrowed, so it can use the connection manager.
+    pub fn new(channel: ChannelId, client: Option<ClientId>,
+        predicate: Box<Fn(&str, &Uid) -> bool>)
+        -> (Self, Box<Stream<Item = ClientId, Error = ()>>) {
+        let (sender, recv) = mpsc::unbounded();
+        let matches = recv.filter_map(move |(id, identity)| match client {
+            Some(_) => Some(id),
+            None => identity.and_then(|(name, uid)|
+                if predicate(&name, &uid) { Some(id) } else { None }),
+        });
+        (Self { channel, client, sender }, Box::new(matches))
+    }
+}
+
+/// The server assigned a new id to our own client, e.g. after a reconnect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OwnClientIdChanged {
+    pub old: ClientId,
+    pub new: ClientId,
+}
+
+/// How much of the channel list was received after connecting.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelListProgress {
+    /// The number of received channels.
+    pub received: usize,
+    /// If the server sent all channels.
+    pub finished: bool,
+}
+
+/// The channels and clients which the server sent after a reset.
+#[derive(Default)]
+struct Resync {
+    channels: Set<ChannelId>,
+    clients: Set<ClientId>,
+    /// If the channel list is complete.
+    channels_done: bool,
+    /// The channel of our client before the reset and its path.
+    rejoin: Option<(ChannelId, Vec<String>)>,
+}
+
+impl Resync {
+    /// If the channel list and the client list were received.
+    fn is_complete(&self) -> bool {
+        self.channels_done && !self.clients.is_empty()
+    }
+}
+
+/// Commands which wait for an answer of the server, identified by their
+/// return code.
+#[derive(Default)]
+struct PendingCommands {
+    /// The return code which is used for the next command.
+    next_return_code: u16,
+    commands: Map<String, PendingCommand>,
+}
+
+impl PendingCommands {
+    /// Register a new command and add a return code to it.
+    fn add(&mut self, command: &mut commands::Command,
+        response_name: Option<&'static str>, filter: Option<RowFilter>)
+        -> oneshot::Receiver<Result<ResponseRows, Error>> {
+        let return_code = self.next_return_code.to_string();
+        self.next_return_code = self.next_return_code.wrapping_add(1);
+        command.push("return_code", return_code.clone());
+        let (sender, recv) = oneshot::channel();
+        self.commands.insert(return_code, PendingCommand {
+            response_name,
+            filter,
+            command: command.clone(),
+            rows: Vec::new(),
+            failed_permission: None,
+            sender,
+        });
+        recv
+    }
+
+    /// The command which waits for this return code.
+    fn get_command(&self, return_code: &str) -> Option<&commands::Command> {
+        self.commands.get(return_code).map(|p| &p.command)
+    }
+
+    /// Give a row to the oldest command which waits for a response with this
+    /// name and accepts the row.
+    ///
+    /// Returns `false` if no command waits for this row.
+    fn add_row(&mut self, name: &str, row: Map<String, String>) -> bool {
+        let code = self.commands.iter()
+            .filter(|&(_, p)| p.response_name == Some(name)
+                && p.filter.as_ref().map(|f| f(&row)).unwrap_or(true))
+            .map(|(code, _)| code)
+            .min_by_key(|code| code.parse::<u16>().unwrap_or(0))
+            .cloned();
+        if let Some(code) = code {
+            self.commands.get_mut(&code).unwrap().rows.push(row);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remember the missing permission of an error, the parsed error
+    /// notification does not contain it.
+    fn track_failed_permission(&mut self, error: &commands::CanonicalCommand) {
+        let permission = error.args.get("failed_permid")
+            .and_then(|id| id.parse().ok()).and_then(Permission::from_u32);
+        let pending = error.args.get("return_code")
+            .and_then(|code| self.commands.get_mut(*code));
+        if let (Some(pending), Some(permission)) = (pending, permission) {
+            pending.failed_permission = Some(permission);
+        }
+    }
+
+    /// The server answered the command with this return code.
+    fn finish(&mut self, return_code: &str, error: TsError, msg: &str) {
+        if let Some(pending) = self.commands.remove(return_code) {
+            let res = if error == TsError::Ok {
+                Ok(pending.rows)
+            } else if let Some(p) = pending.failed_permission.filter(|_|
+                error == TsError::PermissionsClientInsufficient) {
+                Err(Error::MissingPermission(p))
+            } else {
+                Err(Error::Server(error, msg.to_string()))
+            };
+            // Ignore if nobody is waiting for the answer
+            let _ = pending.sender.send(res);
+        }
+    }
+
+    /// Resolve all waiting commands because the connection is closed.
+    fn disconnected(&mut self, reason: Option<MoveReason>) {
+        for (_, pending) in self.commands.drain() {
+            let _ = pending.sender.send(Err(Error::Disconnected(reason)));
+        }
+    }
 }
 
 impl NetworkWrapper {
@@ -126,16 +717,866 @@ impl NetworkWrapper {
         id: ConnectionId,
         client_data: Rc<RefCell<client::ClientData>>,
         client_connection: Weak<RefCell<client::ClientConnection>>,
-        inner_stream: Box<Stream<Item = Notification, Error = tsproto_error>>,
+        inner_stream: Box<Stream<Item = Packet, Error = tsproto_error>>,
         initserver: InitServer,
+        address: SocketAddr,
+        options: Rc<ConnectOptions>,
     ) -> Self {
+        let book_mode = options.book_mode;
+        let logger = client_data.borrow().logger.clone();
         let connection = Connection::new(id, Uid(String::from("TODO")),
             &initserver);
         Self {
             connection,
             client_data,
             client_connection,
+            voice_quality: Rc::new(Cell::new(None)),
+            voice_shutdown: Rc::default(),
+            id_token: Rc::new(()),
+            next_voice_id: Rc::new(Cell::new(0)),
+            voice_transmission: None,
+            address,
             inner_stream,
+            logger,
+            notifications: VecDeque::new(),
+            received: Utc::now(),
+            options,
+            book_mode,
+            client_ips: Map::new(),
+            client_names: Map::new(),
+            client_identities: Map::new(),
+            channel_joins: Map::new(),
+            chat_history: Map::new(),
+            own_channel: None,
+            talk_requests: Map::new(),
+            next_channel_join: 0,
+            channel_passwords: Map::new(),
+            connected_since: Utc::now(),
+            reconnects: 0,
+            resetting: false,
+            clock_offset: Duration::zero(),
+            previous_stats: Default::default(),
+            previous_packet_type_stats: Map::new(),
+            next_file_transfer_id: 0,
+            throttled_until: None,
+            whisper_allow_list: None,
+            permission_listeners: Vec::new(),
+            own_id_listeners: Vec::new(),
+            talk_request_listeners: Vec::new(),
+            client_waiters: Vec::new(),
+            unknown_notification_listeners: Vec::new(),
+            event_listeners: Vec::new(),
+            channel_list_progress: ChannelListProgress::default(),
+            resync: None,
+            disconnect_reason: None,
+            disconnected_at: None,
+            pending_commands: PendingCommands::default(),
+        }
+    }
+
+    /// The traffic of this connection, including the connections before a
+    /// reset.
+    pub fn get_stats(&self) -> ConnectionStats {
+        let mut stats = self.previous_stats;
+        if let Some(con) = self.client_connection.upgrade() {
+            let cur = con.borrow().stats;
+            stats.packets_received += cur.packets_received;
+            stats.bytes_received += cur.bytes_received;
+            stats.packets_sent += cur.packets_sent;
+            stats.bytes_sent += cur.bytes_sent;
+            stats.resent_packets += cur.resent_packets;
+        }
+        stats
+    }
+
+    /// The number of sent and received packets of each type, including the
+    /// connections before a reset.
+    pub fn get_packet_type_stats(&self) -> Map<PacketType, (u64, u64)> {
+        let mut stats = self.previous_packet_type_stats.clone();
+        if let Some(con) = self.client_connection.upgrade() {
+            for (p_type, cur) in &con.borrow().packet_type_stats {
+                let s = stats.entry(*p_type).or_insert((0, 0));
+                s.0 += cur.0;
+                s.1 += cur.1;
+            }
+        }
+        stats
+    }
+
+    /// The number of commands which wait for an answer of the server.
+    pub fn pending_command_count(&self) -> usize {
+        self.pending_commands.commands.len()
+    }
+
+    pub fn summary(&self, reason: Option<MoveReason>) -> ConnectionSummary {
+        let stats = self.get_stats();
+        ConnectionSummary {
+            uptime: Utc::now().signed_duration_since(self.connected_since),
+            bytes_received: stats.bytes_received,
+            bytes_sent: stats.bytes_sent,
+            resent_packets: stats.resent_packets,
+            reconnects: self.reconnects,
+            reason,
+        }
+    }
+
+    /// Resolve all commands which wait for an answer with
+    /// [`Error::Disconnected`].
+    ///
+    /// [`Error::Disconnected`]: ../enum.Error.html#variant.Disconnected
+    pub fn fail_pending_commands(&mut self, reason: Option<MoveReason>) {
+        let reason = reason.or(self.disconnect_reason);
+        self.pending_commands.disconnected(reason);
+    }
+
+    /// Send a command to the server.
+    ///
+    /// The returned future resolves when the server answered the command, an
+    /// error is returned if the server responded with an error.
+    pub fn send_command(&mut self, command: commands::Command)
+        -> BoxFuture<()> {
+        Box::new(self.send_command_with_response(command, None, None)
+            .map(|_| ()))
+    }
+
+    /// Send a command to the server and collect the response.
+    ///
+    /// All commands with the name `response_name`, which arrive before the
+    /// server answered the command and are accepted by `filter`, are returned
+    /// as the response. Rows which are not accepted are handled as normal
+    /// notifications.
+    pub fn send_command_with_response(&mut self,
+        command: commands::Command, response_name: Option<&'static str>,
+        filter: Option<RowFilter>) -> BoxFuture<ResponseRows> {
+        self.send_pending_command(command, response_name, filter, true)
+    }
+
+    /// Send the command of the application heartbeat.
+    ///
+    /// The heartbeat is sent even while the server throttles our commands,
+    /// otherwise a throttled connection would look dead.
+    pub fn send_heartbeat(&mut self) -> BoxFuture<()> {
+        Box::new(self.send_pending_command(heartbeat_command(), None, None,
+            false).map(|_| ()))
+    }
+
+    fn send_pending_command(&mut self, mut command: commands::Command,
+        response_name: Option<&'static str>, filter: Option<RowFilter>,
+        throttled: bool) -> BoxFuture<ResponseRows> {
+        if self.client_connection.upgrade().is_none() {
+            return Box::new(future::err(Error::ConnectionFailed(String::from(
+                "The connection does not exist anymore"))));
+        }
+
+        let recv = self.pending_commands.add(&mut command, response_name,
+            filter);
+        Box::new(self.send_packet_with(command, throttled)
+            // The sender is dropped if the connection is removed
+            .and_then(move |_| recv.map_err(|_| Error::Disconnected(None)))
+            .and_then(|res| res))
+    }
+
+    /// How long we have to wait before reconnecting.
+    ///
+    /// If the server kicked or banned us, reconnecting is only allowed if it
+    /// was configured with [`ConnectOptions::reconnect_after_kick`], otherwise
+    /// [`Error::Disconnected`] is returned.
+    ///
+    /// [`ConnectOptions::reconnect_after_kick`]: ../struct.ConnectOptions.html#method.reconnect_after_kick
+    /// [`Error::Disconnected`]: ../enum.Error.html#variant.Disconnected
+    pub fn kick_cooldown(&self)
+        -> Result<Option<std::time::Duration>, Error> {
+        let reason = match self.disconnect_reason {
+            Some(r) if r.is_kick() => r,
+            _ => return Ok(None),
+        };
+        let cooldown = self.options.reconnect_after_kick
+            .ok_or(Error::Disconnected(Some(reason)))?;
+        let kicked_at = self.disconnected_at.unwrap_or_else(Utc::now);
+        Ok((kicked_at + cooldown).signed_duration_since(Utc::now())
+            .to_std().ok())
+    }
+
+    /// The time until the server accepts commands again.
+    fn throttle_time(&self) -> Option<std::time::Duration> {
+        self.throttled_until.and_then(|t|
+            t.signed_duration_since(Utc::now()).to_std().ok())
+    }
+
+    /// How long a command has to wait before it is sent.
+    fn send_delay(&self, throttled: bool) -> Option<std::time::Duration> {
+        if throttled {
+            self.throttle_time()
+        } else {
+            None
+        }
+    }
+
+    /// Send a command packet.
+    ///
+    /// If the server throttles us, the command is sent when the server accepts
+    /// commands again.
+    fn send_packet(&self, command: commands::Command) -> BoxFuture<()> {
+        self.send_packet_with(command, true)
+    }
+
+    /// Send a command packet, `throttled` commands wait while the server
+    /// throttles us.
+    fn send_packet_with(&self, command: commands::Command, throttled: bool)
+        -> BoxFuture<()> {
+        let con = if let Some(con) = self.client_connection.upgrade() {
+            con
+        } else {
+            return Box::new(future::err(Error::ConnectionFailed(String::from(
+                "The connection does not exist anymore"))));
+        };
+        let header = Header::new(PacketType::Command);
+        let packet = Packet::new(header, packets::Data::Command(command));
+        let sink = client::ClientConnection::get_packets(con);
+        let send = sink.send(packet).map(|_| ()).map_err(|e| e.into());
+
+        if let Some(wait) = self.send_delay(throttled) {
+            let handle = self.client_data.borrow().handle.clone();
+            let timeout = tryf!(Timeout::new(wait, &handle));
+            Box::new(timeout.map_err(|e| e.into()).and_then(move |()| send))
+        } else {
+            Box::new(send)
+        }
+    }
+
+    /// Keep the channels and clients of the book of an old connection.
+    ///
+    /// The lists which the server sends after connecting update the retained
+    /// entries in place. Channels and clients which the server does not send
+    /// anymore are removed when the lists are complete.
+    ///
+    /// When the lists are complete, our client is moved back into the channel
+    /// where it was before.
+    pub fn retain_book(&mut self, old: &mut Connection) {
+        use std::mem::replace;
+        let rejoin = old.server.clients.get(&old.own_client)
+            .and_then(|c| old.channel_path(c.channel).map(|p| (c.channel, p)));
+        self.server.channels = replace(&mut old.server.channels, Map::new());
+        self.server.clients = replace(&mut old.server.clients, Map::new());
+        self.resync = Some(Resync { rejoin, ..Resync::default() });
+    }
+
+    /// Move our client back into the channel where it was before a reset.
+    ///
+    /// If the server was restarted in the meantime, the channel may have been
+    /// recreated with a new id. In this case, the channel is searched by its
+    /// path.
+    fn rejoin_channel(&mut self, old_id: ChannelId, path: Vec<String>) {
+        let target = if self.channel_path(old_id).as_ref() == Some(&path) {
+            old_id
+        } else if let Some(id) = self.find_channel_by_path(&path) {
+            info!(self.logger, "Channel has a new id, the server was probably \
+                restarted"; "old" => old_id.0, "new" => id.0);
+            // Remember the password for the new id
+            if let Some(password) = self.channel_passwords.remove(&old_id) {
+                self.channel_passwords.insert(id, password);
+            }
+            id
+        } else {
+            warn!(self.logger, "Cannot find the previous channel to rejoin";
+                "path" => path.join("/"));
+            return;
+        };
+
+        if self.server.clients.get(&self.own_client).map(|c| c.channel)
+            == Some(target) {
+            return;
+        }
+        let command = create_move_command(self.own_client, target,
+            self.channel_passwords.get(&target).map(|p| p.as_str()));
+        let logger = self.logger.clone();
+        let handle = self.client_data.borrow().handle.clone();
+        handle.spawn(self.send_packet(command).map_err(move |e|
+            warn!(logger, "Failed to rejoin the previous channel";
+                "error" => ?e)));
+    }
+
+    /// Take over the listeners from the connection which was replaced by this
+    /// one and notify the listeners for our client id if our id changed.
+    pub fn take_listeners(&mut self, old: &mut NetworkWrapper) {
+        use std::mem::replace;
+        self.own_id_listeners = replace(&mut old.own_id_listeners, Vec::new());
+        self.event_listeners = replace(&mut old.event_listeners, Vec::new());
+        self.permission_listeners = replace(&mut old.permission_listeners,
+            Vec::new());
+        self.talk_request_listeners = replace(&mut old.talk_request_listeners,
+            Vec::new());
+        self.unknown_notification_listeners = replace(
+            &mut old.unknown_notification_listeners, Vec::new());
+        if old.own_client != self.own_client {
+            let event = OwnClientIdChanged {
+                old: old.own_client,
+                new: self.own_client,
+            };
+            // Remove listeners which were dropped
+            self.own_id_listeners.retain(|l| l.unbounded_send(event).is_ok());
+        }
+    }
+
+    /// If the server kicked us and we are not allowed to reconnect with a
+    /// hard reset, so the connection can be removed.
+    pub fn is_kicked(&self) -> bool {
+        match self.disconnect_reason {
+            Some(r) if r.is_kick() => self.options.reconnect_after_kick
+                .is_none(),
+            _ => false,
+        }
+    }
+
+    /// Tell the event listeners that the connection is closed.
+    ///
+    /// The listeners are removed afterwards, so their streams end.
+    pub fn notify_disconnected(&mut self, reason: DisconnectReason) {
+        let summary = self.summary(self.disconnect_reason);
+        info!(self.logger, "Connection summary"; "summary" => ?summary);
+        for l in std::mem::replace(&mut self.event_listeners, Vec::new()) {
+            // Ignore listeners which were dropped
+            let _ = l.unbounded_send(Event::Disconnected(reason,
+                summary.clone()));
+        }
+    }
+
+    /// Remember which channels and clients the server sent after a reset and
+    /// remove the others from the book.
+    fn track_resync(&mut self, cmd: &commands::CanonicalCommand) {
+        let remove_clients = if let Some(ref mut resync) = self.resync {
+            match cmd.command {
+                "channellist" => {
+                    if let Some(id) = cmd.args.get("cid")
+                        .and_then(|id| id.parse().ok()) {
+                        resync.channels.insert(ChannelId(id));
+                    }
+                    return;
+                }
+                "channellistfinished" => {
+                    resync.channels_done = true;
+                    let channels = &resync.channels;
+                    self.connection.server.channels
+                        .retain(|id, _| channels.contains(id));
+                    // Our own client is always in the list, so the clients
+                    // were sent before if there are any
+                    !resync.clients.is_empty()
+                }
+                "notifycliententerview" => {
+                    if let Some(id) = cmd.args.get("clid")
+                        .and_then(|id| id.parse().ok()) {
+                        resync.clients.insert(ClientId(id));
+                    }
+                    return;
+                }
+                _ => resync.is_complete(),
+            }
+        } else {
+            return;
+        };
+
+        if remove_clients {
+            self.finish_resync();
+        }
+    }
+
+    /// End the resync after a packet if the initial lists are complete.
+    ///
+    /// The server sends all clients of the initial list in one command, so
+    /// the list is complete at the end of its packet.
+    fn check_resync_complete(&mut self) {
+        if self.resync.as_ref().map(|r| r.is_complete()).unwrap_or(false) {
+            self.finish_resync();
+        }
+    }
+
+    /// Remove the channels and clients which were not sent again after a
+    /// reset and rejoin our previous channel.
+    fn finish_resync(&mut self) {
+        let resync = if let Some(r) = self.resync.take() {
+            r
+        } else {
+            return;
+        };
+        self.connection.server.clients
+            .retain(|id, _| resync.clients.contains(id));
+        self.client_names.retain(|id, _| resync.clients.contains(id));
+        if let Some((old_id, path)) = resync.rejoin {
+            self.rejoin_channel(old_id, path);
+        }
+    }
+
+    /// If whisper packets of this client should be accepted.
+    pub fn is_whisper_allowed(&self, client: ClientId) -> bool {
+        self.whisper_allow_list.as_ref().map(|l| l.contains(&client))
+            .unwrap_or(true)
+    }
+
+    /// Set if voice packets are encrypted and the codec quality for the
+    /// channel of our client.
+    ///
+    /// The channel is taken from the notification because our client is not
+    /// stored in every [`BookMode`].
+    ///
+    /// [`BookMode`]: enum.BookMode.html
+    fn update_voice_settings(&mut self, msg: &Notification) {
+        let (client, channel) = match *msg {
+            Notification::ClientEnterView(ref packet) =>
+                (packet.client_id, packet.target_channel_id),
+            Notification::ClientMoved(ref packet) =>
+                (packet.client_id, packet.target_channel_id),
+            _ => return,
+        };
+        if client != self.own_client {
+            return;
+        }
+        self.apply_voice_settings(channel);
+    }
+
+    /// Use the codec quality and encryption of a channel for our voice
+    /// packets.
+    fn apply_voice_settings(&mut self, channel: ChannelId) {
+        let quality = self.server.channels.get(&channel)
+            .map(|c| c.codec_quality);
+        self.voice_quality.set(quality);
+        let encrypted = self.is_voice_encrypted(channel);
+        if let Some(con) = self.client_connection.upgrade() {
+            if let Some(ref mut params) = con.borrow_mut().params {
+                params.voice_encryption = encrypted;
+            }
+        }
+    }
+
+    /// Pass a client which entered or joined a channel to the waiters.
+    fn check_client_waiters(&mut self, msg: &Notification) {
+        if self.client_waiters.is_empty() {
+            return;
+        }
+        let (client, channel) = match *msg {
+            Notification::ClientEnterView(ref packet) =>
+                (packet.client_id, packet.target_channel_id),
+            Notification::ClientMoved(ref packet) =>
+                (packet.client_id, packet.target_channel_id),
+            _ => return,
+        };
+        // Clients are not stored in every book mode
+        let identity = self.server.clients.get(&client)
+            .map(|c| (c.name.clone(), c.uid.clone()));
+        self.client_waiters.retain(|waiter| {
+            if waiter.channel != channel
+                || waiter.client.map(|c| c != client).unwrap_or(false) {
+                return true;
+            }
+            // The predicate is checked by the receiver. Waiters for a certain
+            // client are done now, the others are removed when they were
+            // dropped.
+            waiter.sender.unbounded_send((client, identity.clone())).is_ok()
+                && waiter.client.is_none()
+        });
+    }
+
+    /// Notify the permission listeners if the groups of our own client
+    /// changed.
+    fn check_own_groups(&mut self, cmd: &commands::CanonicalCommand) {
+        match cmd.command {
+            "notifyclientchannelgroupchanged" |
+            "notifyservergroupclientadded" |
+            "notifyservergroupclientdeleted" => {}
+            _ => return,
+        }
+        let own_id = self.own_client.0.to_string();
+        if cmd.args.get("clid") != Some(&own_id.as_str()) {
+            return;
+        }
+        // Remove listeners which were dropped
+        self.permission_listeners.retain(|l| l.unbounded_send(()).is_ok());
+    }
+
+    /// Remember the channel of our client when it enters the view or moves.
+    fn track_own_channel(&mut self, cmd: &commands::CanonicalCommand) {
+        match cmd.command {
+            "notifycliententerview" | "notifyclientmoved" => {}
+            _ => return,
+        }
+        let client = cmd.args.get("clid").and_then(|c| c.parse().ok())
+            .map(ClientId);
+        if client != Some(self.own_client) {
+            return;
+        }
+        if let Some(channel) = cmd.args.get("ctid")
+            .and_then(|c| c.parse().ok()) {
+            self.own_channel = Some(ChannelId(channel));
+        }
+    }
+
+    /// Apply edits of the codec quality and voice encryption of our channel
+    /// to the sent audio.
+    ///
+    /// The book does not apply channel edits, so the new values are written
+    /// to it here.
+    fn track_voice_settings(&mut self, cmd: &commands::CanonicalCommand) {
+        if cmd.command != "notifychanneledited" {
+            return;
+        }
+        let channel = match cmd.args.get("cid").and_then(|c| c.parse().ok()) {
+            Some(c) => ChannelId(c),
+            None => return,
+        };
+        let quality = cmd.args.get("channel_codec_quality")
+            .and_then(|q| q.parse().ok());
+        let unencrypted = cmd.args.get("channel_codec_is_unencrypted")
+            .map(|u| *u == "1");
+        if let Some(c) = self.connection.server.channels.get_mut(&channel) {
+            if let Some(quality) = quality {
+                c.codec_quality = quality;
+            }
+            if let Some(unencrypted) = unencrypted {
+                c.is_unencrypted = unencrypted;
+            }
+        }
+        if self.own_channel == Some(channel) {
+            self.apply_voice_settings(channel);
+        }
+    }
+
+    /// Keep received text messages for [`Connection::recent_messages`] and
+    /// notify the event listeners.
+    ///
+    /// Messages are tracked when they are received, before the book contains
+    /// the changes of earlier notifications, so channel messages belong to
+    /// the channel which our client was in when the message arrived.
+    ///
+    /// [`Connection::recent_messages`]: ../struct.Connection.html#method.recent_messages
+    fn track_chat(&mut self, cmd: &commands::CanonicalCommand) {
+        if cmd.command != "notifytextmessage" || (self.options.chat_history == 0
+            && self.event_listeners.is_empty()) {
+            return;
+        }
+        let parse_client = |arg| cmd.args.get(arg)
+            .and_then(|a| a.parse().ok()).map(ClientId);
+        let sender = match parse_client("invokerid") {
+            Some(s) => s,
+            None => return,
+        };
+        let target = match cmd.args.get("targetmode") {
+            Some(&"1") => {
+                // Private messages which we sent are echoed by the server
+                let other = if sender == self.own_client {
+                    parse_client("target")
+                } else {
+                    Some(sender)
+                };
+                match other {
+                    Some(c) => MessageTarget::Client(c),
+                    None => return,
+                }
+            }
+            Some(&"2") => {
+                let channel = cmd.args.get("target")
+                    .and_then(|c| c.parse().ok()).map(ChannelId)
+                    .or(self.own_channel);
+                match channel {
+                    Some(c) => MessageTarget::Channel(c),
+                    None => return,
+                }
+            }
+            Some(&"3") => MessageTarget::Server,
+            _ => return,
+        };
+
+        let msg = ChatMessage {
+            sender,
+            sender_name: cmd.args.get("invokername").unwrap_or(&"")
+                .to_string(),
+            sender_uid: Uid(cmd.args.get("invokeruid").unwrap_or(&"")
+                .to_string()),
+            message: cmd.args.get("msg").unwrap_or(&"").to_string(),
+            received: self.received,
+        };
+        if !self.event_listeners.is_empty() {
+            // Remove listeners which were dropped
+            self.event_listeners.retain(|l| l.unbounded_send(
+                Event::Message(target, msg.clone())).is_ok());
+        }
+        let capacity = self.options.chat_history;
+        if capacity == 0 {
+            return;
+        }
+        let history = self.chat_history.entry(target)
+            .or_insert_with(VecDeque::new);
+        if history.len() >= capacity {
+            history.pop_front();
+        }
+        history.push_back(msg);
+    }
+
+    /// Keep the open talk power requests and notify the listeners about new
+    /// ones.
+    fn track_talk_requests(&mut self, cmd: &commands::CanonicalCommand) {
+        let client = match cmd.args.get("clid").and_then(|c| c.parse().ok()) {
+            Some(c) => ClientId(c),
+            None => return,
+        };
+        match cmd.command {
+            "notifycliententerview" | "notifyclientupdated" => {}
+            // A request is only valid in the channel where it was made
+            "notifyclientleftview" | "notifyclientmoved" => {
+                self.talk_requests.remove(&client);
+                return;
+            }
+            _ => return,
+        }
+        match cmd.args.get("client_talk_request") {
+            Some(&"0") => {
+                self.talk_requests.remove(&client);
+            }
+            Some(_) => {
+                let channel = cmd.args.get("ctid")
+                    .and_then(|c| c.parse().ok()).map(ChannelId)
+                    .or_else(|| self.server.clients.get(&client)
+                        .map(|c| c.channel));
+                let channel = match channel {
+                    Some(c) => c,
+                    None => return,
+                };
+                let request = TalkRequest {
+                    client,
+                    channel,
+                    message: cmd.args.get("client_talk_request_msg")
+                        .unwrap_or(&"").to_string(),
+                    received: self.received,
+                };
+                // Remove listeners which were dropped
+                self.talk_request_listeners
+                    .retain(|l| l.unbounded_send(request.clone()).is_ok());
+                self.talk_requests.insert(client, request);
+            }
+            None => {}
+        }
+    }
+
+    /// Update the order of channels when they are moved or edited.
+    ///
+    /// The positions of the siblings change too, but the server does not send
+    /// updates for them.
+    fn track_channel_order(&mut self, cmd: &commands::CanonicalCommand) {
+        let parse_id = |arg| cmd.args.get(arg).and_then(|a| a.parse().ok())
+            .map(ChannelId);
+        let (id, parent, order) = match cmd.command {
+            "notifychanneledited" => {
+                let id = match parse_id("cid") {
+                    Some(id) => id,
+                    None => return,
+                };
+                let parent = match self.server.channels.get(&id) {
+                    Some(c) => c.parent,
+                    None => return,
+                };
+                (id, Some(parent), parse_id("channel_order"))
+            }
+            "notifychannelmoved" =>
+                (match parse_id("cid") {
+                    Some(id) => id,
+                    None => return,
+                }, parse_id("cpid"), parse_id("order")),
+            _ => return,
+        };
+        if let (Some(parent), Some(order)) = (parent, order) {
+            self.connection.move_channel(id, parent, order);
+        }
+    }
+
+    /// Remember the public key and key offset of a client if the server sent
+    /// them.
+    fn track_client_identity(&mut self, cmd: &commands::CanonicalCommand) {
+        let client = match cmd.args.get("clid").and_then(|c| c.parse().ok()) {
+            Some(c) => ClientId(c),
+            None => return,
+        };
+        let omega = cmd.args.get("client_omega");
+        let offset = cmd.args.get("client_key_offset")
+            .and_then(|o| o.parse().ok());
+        if let (Some(omega), Some(offset)) = (omega, offset) {
+            self.client_identities.insert(client, (omega.to_string(), offset));
+        }
+    }
+
+    /// Handle a flood error of the server.
+    ///
+    /// The server tells us how long we should wait, we stop sending commands
+    /// for this time and send the rejected command again afterwards.
+    ///
+    /// Returns `false` if the error is not a flood error of a pending command.
+    fn handle_flood_error(&mut self, error: &commands::CanonicalCommand)
+        -> bool {
+        if error.args.get("id").and_then(|id| id.parse::<u32>().ok())
+            != Some(TsError::ClientIsFlooding as u32) {
+            return false;
+        }
+        let command = if let Some(command) = error.args.get("return_code")
+            .and_then(|code| self.pending_commands.get_command(code)) {
+            command.clone()
+        } else {
+            return false;
+        };
+
+        // The message looks like `please wait 2 seconds`. Do not trust the
+        // server to send a sensible time, wait at most an hour.
+        let secs = error.args.get("extra_msg").and_then(|msg|
+            msg.split(' ').filter_map(|w| w.parse::<i64>().ok()).next())
+            .unwrap_or(1).max(1).min(60 * 60);
+        let now = Utc::now();
+        let until = now.checked_add_signed(Duration::seconds(secs))
+            .unwrap_or(now);
+        if self.throttled_until.map(|t| t < until).unwrap_or(true) {
+            self.throttled_until = Some(until);
+        }
+        warn!(self.logger, "Server throttles commands because of flooding";
+            "seconds" => secs);
+
+        let logger = self.logger.clone();
+        let handle = self.client_data.borrow().handle.clone();
+        handle.spawn(self.send_packet(command).map_err(move |e|
+            error!(logger, "Failed to resend command"; "error" => ?e)));
+        true
+    }
+
+    /// Parse a command packet into notifications.
+    ///
+    /// Commands which are awaited as a response are collected for the
+    /// pending command.
+    fn handle_packet(&mut self, packet: Packet) {
+        let cmd = match packet.data {
+            packets::Data::Command(cmd) |
+            packets::Data::CommandLow(cmd) => cmd,
+            _ => return,
+        };
+        // All notifications of a packet are received at the same time
+        self.received = Utc::now();
+        for c in cmd.get_commands() {
+            // The oldest command which waits for this response gets it
+            let row = c.args.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            if self.pending_commands.add_row(c.command, row) {
+                continue;
+            }
+
+            if c.command == "error" {
+                self.pending_commands.track_failed_permission(&c);
+                if self.handle_flood_error(&c) {
+                    continue;
+                }
+            }
+            self.check_own_groups(&c);
+            self.track_client_identity(&c);
+            self.track_channel_order(&c);
+            self.track_own_channel(&c);
+            self.track_voice_settings(&c);
+            self.track_chat(&c);
+            self.track_talk_requests(&c);
+            self.track_resync(&c);
+            match c.command {
+                "channellist" => self.channel_list_progress.received += 1,
+                "channellistfinished" =>
+                    self.channel_list_progress.finished = true,
+                _ => {}
+            }
+            if !self.options.parses_notification(c.command) {
+                continue;
+            }
+            match Notification::parse(c) {
+                Ok(n) => self.notifications.push_back((self.received, n)),
+                Err(e) => warn!(self.logger, "Error parsing packet";
+                    "error" => ?e),
+            }
+        }
+        self.check_resync_complete();
+    }
+
+    fn joined_channel(&mut self, client: ClientId) {
+        self.channel_joins.insert(client, self.next_channel_join);
+        self.next_channel_join += 1;
+    }
+
+    /// The order in which clients joined their current channel, clients which
+    /// joined earlier have a smaller number.
+    pub fn channel_join_order(&self, client: ClientId) -> u64 {
+        self.channel_joins.get(&client).cloned().unwrap_or(0)
+    }
+
+    /// Remember the ip address of a client.
+    ///
+    /// The ip is empty if we are not allowed to see it.
+    fn set_client_ip(&mut self, client: ClientId, ip: &str) {
+        if self.book_mode != BookMode::Full {
+            return;
+        }
+        if let Ok(ip) = ip.parse() {
+            self.client_ips.insert(client, ip);
+        } else {
+            self.client_ips.remove(&client);
+        }
+    }
+
+    /// Apply a row of `clientlist -country -ip` to a client.
+    ///
+    /// The server sends the ip addresses of clients which enter our view
+    /// later, but the clients which were already there when we connected are
+    /// only known from this list.
+    pub(crate) fn apply_client_list_row(&mut self, row: &Map<String, String>) {
+        if self.book_mode != BookMode::Full {
+            return;
+        }
+        let client = match row.get("clid").and_then(|c| c.parse().ok()) {
+            Some(c) => ClientId(c),
+            None => return,
+        };
+        if let Some(ip) = row.get("connection_client_ip") {
+            self.set_client_ip(client, ip);
+        }
+        if let (Some(c), Some(country)) = (self.connection.server.clients
+            .get_mut(&client), row.get("client_country")) {
+            c.country_code = country.clone();
+        }
+    }
+
+    /// Handle messages which update data that is not part of the book.
+    fn handle_extra_message(&mut self, msg: &Notification) {
+        match *msg {
+            Notification::ClientConnectionInfo(ref packet) =>
+                self.set_client_ip(packet.client_id, &packet.ip),
+            Notification::ClientEnterView(ref packet) => {
+                if self.book_mode == BookMode::MinimalClients {
+                    self.client_names.insert(packet.client_id,
+                        packet.name.clone());
+                }
+                self.joined_channel(packet.client_id);
+            }
+            Notification::ClientMoved(ref packet) =>
+                self.joined_channel(packet.client_id),
+            Notification::ClientLeftView(ref packet) => {
+                self.client_ips.remove(&packet.client_id);
+                self.client_names.remove(&packet.client_id);
+                self.client_identities.remove(&packet.client_id);
+                self.channel_joins.remove(&packet.client_id);
+                if packet.client_id == self.own_client {
+                    self.disconnect_reason = Some(packet.reason);
+                    self.disconnected_at = Some(self.received);
+                }
+            }
+            Notification::CommandError(ref packet) => {
+                self.pending_commands.finish(&packet.return_code, packet.id,
+                    &packet.msg);
+            }
+            Notification::Unknown(ref cmd) => {
+                debug!(self.logger, "Got unknown notification";
+                    "command" => %cmd.command);
+                // Remove listeners which were dropped
+                let received = self.received;
+                self.unknown_notification_listeners.retain(|l|
+                    l.unbounded_send((received, cmd.clone())).is_ok());
+            }
+            _ => {}
         }
     }
 }
@@ -154,15 +1595,555 @@ impl DerefMut for NetworkWrapper {
     }
 }
 
+/// Returns the notifications of the server.
+///
+/// Notifications are returned in the order in which the server sent them,
+/// tsproto already sorts command packets by their id. The book is updated with
+/// a notification right before it is returned, so it never contains changes of
+/// later notifications.
+///
+/// Every notification is returned with the time when it was received.
 impl Stream for NetworkWrapper {
-    type Item = Notification;
+    type Item = (DateTime<Utc>, Notification);
     type Error = tsproto_error;
 
     fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
-        let res = self.inner_stream.poll()?;
-        if let futures::Async::Ready(Some(ref msg)) = res {
-            self.connection.handle_message(msg);
+        loop {
+            if let Some((received, msg)) = self.notifications.pop_front() {
+                self.received = received;
+                self.connection.handle_message(&msg, self.book_mode);
+                self.handle_extra_message(&msg);
+                self.update_voice_settings(&msg);
+                self.check_client_waiters(&msg);
+                if !self.event_listeners.is_empty() {
+                    // Remove listeners which were dropped
+                    self.event_listeners.retain(|l| l.unbounded_send(
+                        Event::Notification(received, msg.clone())).is_ok());
+                }
+                return Ok(futures::Async::Ready(Some((received, msg))));
+            }
+
+            match self.inner_stream.poll() {
+                Ok(futures::Async::Ready(Some(packet))) =>
+                    self.handle_packet(packet),
+                Ok(futures::Async::Ready(None)) => {
+                    self.fail_pending_commands(None);
+                    return Ok(futures::Async::Ready(None));
+                }
+                Err(e) => {
+                    self.fail_pending_commands(None);
+                    return Err(e);
+                }
+                Ok(futures::Async::NotReady) =>
+                    return Ok(futures::Async::NotReady),
+            }
         }
-        Ok(res)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use {create_client, ResendConfig};
+
+    /// The `initserver` packet of a test server, our client has the id 1.
+    const INITSERVER: &str = "initserver virtualserver_name=Test \
+        virtualserver_welcomemessage virtualserver_platform=Linux \
+        virtualserver_version=3.0.13.8\\s[Build:\\s1500452811] \
+        virtualserver_maxclients=32 virtualserver_created=0 \
+        virtualserver_codec_encryption_mode=0 virtualserver_hostmessage \
+        virtualserver_hostmessage_mode=0 \
+        virtualserver_default_server_group=8 \
+        virtualserver_default_channel_group=8 virtualserver_hostbanner_url \
+        virtualserver_hostbanner_gfx_url \
+        virtualserver_hostbanner_gfx_interval=0 \
+        virtualserver_priority_speaker_dimm_modificator=-18.0000 \
+        virtualserver_id=1 virtualserver_hostbutton_tooltip \
+        virtualserver_hostbutton_url virtualserver_hostbutton_gfx_url \
+        virtualserver_name_phonetic virtualserver_icon_id=0 \
+        virtualserver_ip=0.0.0.0,\\s:: virtualserver_ask_for_privilegekey=0 \
+        acn=Client aclid=1 pv=6 lt=0 client_talk_power=-1 \
+        client_needed_serverquery_view_power=75 \
+        virtualserver_hostbanner_mode=0 \
+        virtualserver_channel_temp_delete_delay_default=10";
+
+    fn parse_command(command: &str) -> commands::Command {
+        commands::Command::read((), &mut Cursor::new(command.as_bytes()))
+            .unwrap()
+    }
+
+    pub(crate) fn command_packet(command: &str) -> Packet {
+        Packet::new(Header::new(PacketType::Command),
+            packets::Data::Command(parse_command(command)))
+    }
+
+    /// Create a connection which is not connected to a server.
+    ///
+    /// Packets which are sent into the returned sink are handled by the
+    /// connection as if the server sent them.
+    pub(crate) fn test_connection(core: &Core, options: ConnectOptions)
+        -> (NetworkWrapper, mpsc::UnboundedSender<Packet>) {
+        let key = ::tomcrypt::EccKey::new(::tomcrypt::sprng(), 32).unwrap();
+        let client = create_client(core.handle(),
+            "127.0.0.1:0".parse().unwrap(), key, ResendConfig::default())
+            .unwrap();
+        let initserver = parse_command(INITSERVER);
+        let initserver = match Notification::parse(
+            initserver.get_commands().remove(0)) {
+            Ok(Notification::InitServer(p)) => p,
+            _ => panic!("Cannot parse initserver"),
+        };
+        let (send, recv) = mpsc::unbounded();
+        let stream = recv.map_err(|()| -> tsproto_error {
+            unreachable!("Receivers do not fail")
+        });
+        let con = NetworkWrapper::new(ConnectionId(0), client, Weak::new(),
+            Box::new(stream), initserver, "127.0.0.1:1".parse().unwrap(),
+            Rc::new(options));
+        (con, send)
+    }
+
+    /// Handle all packets which were sent to the connection.
+    ///
+    /// The sender has to be dropped before, otherwise this blocks.
+    pub(crate) fn handle_all(con: &mut NetworkWrapper)
+        -> Vec<Notification> {
+        con.wait().map(|r| r.unwrap().1).collect()
+    }
+
+    #[test]
+    fn listeners_are_taken_over() {
+        let core = Core::new().unwrap();
+        let (mut old, _) = test_connection(&core, ConnectOptions::default());
+        let (talk_send, talk_recv) = mpsc::unbounded();
+        let (perm_send, perm_recv) = mpsc::unbounded();
+        let (unknown_send, unknown_recv) = mpsc::unbounded();
+        old.talk_request_listeners.push(talk_send);
+        old.permission_listeners.push(perm_send);
+        old.unknown_notification_listeners.push(unknown_send);
+
+        let (mut con, send) = test_connection(&core,
+            ConnectOptions::default());
+        con.take_listeners(&mut old);
+        assert!(old.talk_request_listeners.is_empty());
+        assert!(old.permission_listeners.is_empty());
+        assert!(old.unknown_notification_listeners.is_empty());
+        drop(old);
+
+        // The new connection notifies the listeners
+        send.unbounded_send(command_packet("notifysomethingnew a=1")).unwrap();
+        drop(send);
+        handle_all(&mut con);
+        let unknown = unknown_recv.wait().next().unwrap().unwrap();
+        assert_eq!(unknown.1.command, "notifysomethingnew");
+
+        // The streams only end when the new connection is dropped
+        drop(con);
+        assert_eq!(talk_recv.wait().count(), 0);
+        assert_eq!(perm_recv.wait().count(), 0);
+    }
+
+    #[test]
+    fn heartbeat_is_not_throttled() {
+        let command = heartbeat_command();
+        assert_eq!(command.command, "clientupdate");
+        assert!(command.static_args.is_empty());
+        assert!(command.list_args.is_empty());
+
+        let core = Core::new().unwrap();
+        let (mut con, _) = test_connection(&core, ConnectOptions::default());
+        assert!(con.send_delay(true).is_none());
+        con.throttled_until = Some(Utc::now() + Duration::seconds(60));
+        assert!(con.send_delay(true).is_some());
+        assert!(con.send_delay(false).is_none());
+    }
+
+    #[test]
+    fn channel_messages_use_current_channel() {
+        let core = Core::new().unwrap();
+        let options = ConnectOptions::default()
+            .book_mode(BookMode::ChannelsOnly);
+        let (mut con, send) = test_connection(&core, options);
+        for cmd in &[
+            "notifycliententerview cfid=0 ctid=1 reasonid=0 clid=1",
+            "notifytextmessage targetmode=2 msg=first invokerid=2",
+            // The message arrives right after the move
+            "notifyclientmoved ctid=2 reasonid=0 clid=1",
+            "notifytextmessage targetmode=2 msg=second invokerid=2",
+            "notifytextmessage targetmode=2 target=3 msg=third invokerid=2",
+        ] {
+            send.unbounded_send(command_packet(cmd)).unwrap();
+        }
+        drop(send);
+        handle_all(&mut con);
+
+        let messages = |c| con.chat_history
+            .get(&MessageTarget::Channel(ChannelId(c)))
+            .map(|h| h.iter().map(|m| m.message.clone()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        assert_eq!(messages(1), vec!["first"]);
+        assert_eq!(messages(2), vec!["second"]);
+        assert_eq!(messages(3), vec!["third"]);
+    }
+
+    #[test]
+    fn waiter_matches_own_client() {
+        let core = Core::new().unwrap();
+        let options = ConnectOptions::default()
+            .book_mode(BookMode::ChannelsOnly);
+        let (mut con, send) = test_connection(&core, options);
+        let waiter = |client| ClientWaiter::new(ChannelId(2),
+            Some(ClientId(client)), Box::new(|_, _| false));
+        let (own_waiter, own) = waiter(1);
+        let (other_waiter, other) = waiter(3);
+        con.client_waiters.push(own_waiter);
+        con.client_waiters.push(other_waiter);
+
+        for cmd in &[
+            "notifycliententerview cfid=0 ctid=1 reasonid=0 clid=1",
+            "notifyclientmoved ctid=2 reasonid=0 clid=1",
+        ] {
+            send.unbounded_send(command_packet(cmd)).unwrap();
+        }
+        drop(send);
+        handle_all(&mut con);
+
+        // Our client is not stored in the book, but the waiter is resolved
+        assert_eq!(own.into_future().wait().ok().unwrap().0, Some(ClientId(1)));
+        assert_eq!(con.client_waiters.len(), 1);
+        drop(con);
+        assert_eq!(other.into_future().wait().ok().unwrap().0, None);
+    }
+
+    #[test]
+    fn waiter_predicate_can_borrow_connection() {
+        let core = Core::new().unwrap();
+        let (con, send) = test_connection(&core, ConnectOptions::default());
+        let con = Rc::new(RefCell::new(con));
+        let weak = Rc::downgrade(&con);
+        // Like a predicate which uses the connection manager
+        let (waiter, matches) = ClientWaiter::new(ChannelId(2), None,
+            Box::new(move |name, _| {
+                let con = weak.upgrade().unwrap();
+                let con = con.borrow();
+                con.client_names.is_empty() && name == "Bot3"
+            }));
+        con.borrow_mut().client_waiters.push(waiter);
+
+        for cmd in &[
+            format!("notifycliententerview {}", enter_view_args(2, 2)),
+            format!("notifycliententerview {}", enter_view_args(3, 1)),
+            "notifyclientmoved ctid=2 reasonid=0 clid=3".to_string(),
+        ] {
+            send.unbounded_send(command_packet(cmd)).unwrap();
+        }
+        drop(send);
+        handle_all(&mut con.borrow_mut());
+
+        // The predicate runs after the connection handled the notifications
+        let (client, _) = matches.into_future().wait().ok().unwrap();
+        assert_eq!(client, Some(ClientId(3)));
+        assert_eq!(con.borrow().client_waiters.len(), 1);
+    }
+
+    #[test]
+    fn client_list_sets_ips() {
+        let core = Core::new().unwrap();
+        let (mut con, _) = test_connection(&core, ConnectOptions::default());
+        let row = |ip: &str| {
+            let mut row = Map::new();
+            row.insert(String::from("clid"), String::from("5"));
+            row.insert(String::from("connection_client_ip"), ip.to_string());
+            row
+        };
+        con.apply_client_list_row(&row("1.2.3.4"));
+        assert_eq!(con.client_ips.get(&ClientId(5)),
+            Some(&"1.2.3.4".parse().unwrap()));
+        // Without permission, the server sends no address
+        con.apply_client_list_row(&row(""));
+        assert!(con.client_ips.is_empty());
+
+        let options = ConnectOptions::default()
+            .book_mode(BookMode::MinimalClients);
+        let (mut con, _) = test_connection(&core, options);
+        con.apply_client_list_row(&row("1.2.3.4"));
+        assert!(con.client_ips.is_empty());
+    }
+
+    /// The arguments of a `notifycliententerview` for a client without
+    /// the command name.
+    fn enter_view_args(client: u16, channel: u64) -> String {
+        format!("cfid=0 ctid={channel} reasonid=0 clid={client} \
+            client_unique_identifier=P5H2hrN6+gpQI4n\\/dXp3p17vtY{client}= \
+            client_nickname=Bot{client} client_input_muted=0 \
+            client_output_muted=0 client_outputonly_muted=0 \
+            client_input_hardware=1 client_output_hardware=1 client_meta_data \
+            client_is_recording=0 client_database_id=3 \
+            client_channel_group_id=8 client_servergroups=8 client_away=0 \
+            client_away_message client_type=0 client_flag_avatar \
+            client_talk_power=0 client_talk_request=0 client_talk_request_msg \
+            client_description client_is_talker=0 \
+            client_is_priority_speaker=0 client_unread_messages=0 \
+            client_nickname_phonetic client_needed_serverquery_view_power=0 \
+            client_icon_id=0 client_is_channel_commander=0 client_country=DE \
+            client_channel_group_inherited_channel_id={channel} client_badges",
+            channel = channel, client = client)
+    }
+
+    #[test]
+    fn book_modes() {
+        let enter = &format!("notifycliententerview {}",
+            enter_view_args(2, 1));
+        let left = "notifyclientleftview cfid=1 ctid=0 reasonid=8 \
+            reasonmsg=Bye clid=2";
+        let core = Core::new().unwrap();
+        let run = |mode, commands: &[&str]| {
+            let options = ConnectOptions::default().book_mode(mode);
+            let (mut con, send) = test_connection(&core, options);
+            for cmd in commands {
+                send.unbounded_send(command_packet(cmd)).unwrap();
+            }
+            drop(send);
+            handle_all(&mut con);
+            con
+        };
+
+        let con = run(BookMode::Full, &[enter]);
+        let client = &con.server.clients[&ClientId(2)];
+        assert_eq!(client.name, "Bot2");
+        assert_eq!(client.country_code, "DE");
+        assert!(con.client_names.is_empty());
+
+        let con = run(BookMode::MinimalClients, &[enter]);
+        assert!(con.server.clients.is_empty());
+        assert_eq!(con.client_names.get(&ClientId(2)).map(|n| n.as_str()),
+            Some("Bot2"));
+
+        let con = run(BookMode::ChannelsOnly, &[enter]);
+        assert!(con.server.clients.is_empty());
+        assert!(con.client_names.is_empty());
+
+        for &mode in &[BookMode::Full, BookMode::MinimalClients] {
+            let con = run(mode, &[enter, left]);
+            assert!(con.server.clients.is_empty());
+            assert!(con.client_names.is_empty());
+        }
+    }
+
+    #[test]
+    fn resync_ends_with_client_list() {
+        let core = Core::new().unwrap();
+        let (mut old, send) = test_connection(&core,
+            ConnectOptions::default());
+        for &c in &[2, 3] {
+            send.unbounded_send(command_packet(&format!(
+                "notifycliententerview {}", enter_view_args(c, 1)))).unwrap();
+        }
+        drop(send);
+        handle_all(&mut old);
+        assert_eq!(old.server.clients.len(), 2);
+
+        // Client 3 left during the reset
+        let (mut con, send) = test_connection(&core,
+            ConnectOptions::default());
+        con.retain_book(&mut old);
+        send.unbounded_send(command_packet("channellistfinished")).unwrap();
+        send.unbounded_send(command_packet(&format!(
+            "notifycliententerview {}|{}", enter_view_args(1, 1),
+            enter_view_args(2, 1)))).unwrap();
+        drop(send);
+        handle_all(&mut con);
+
+        // The stale client is removed without waiting for other commands
+        assert!(con.resync.is_none());
+        let mut clients = con.server.clients.keys().map(|c| c.0)
+            .collect::<Vec<_>>();
+        clients.sort();
+        assert_eq!(clients, vec![1, 2]);
+    }
+
+    #[test]
+    fn reordered_channels_are_sorted() {
+        let id = ChannelId;
+        let mut links = ChannelLinks::new();
+        // 1, 2 (with child 4), 3
+        links.insert(id(1), (id(0), id(0)));
+        links.insert(id(2), (id(0), id(1)));
+        links.insert(id(3), (id(0), id(2)));
+        links.insert(id(4), (id(2), id(0)));
+        assert_eq!(channel_tree(&links), vec![(id(1), 0), (id(2), 0),
+            (id(4), 1), (id(3), 0)]);
+
+        // Move 3 to the top
+        move_channel(&mut links, id(3), id(0), id(0));
+        assert_eq!(channel_tree(&links), vec![(id(3), 0), (id(1), 0),
+            (id(2), 0), (id(4), 1)]);
+
+        // Move 1 below 2 into the child list of 2
+        move_channel(&mut links, id(1), id(2), id(4));
+        assert_eq!(channel_tree(&links), vec![(id(3), 0), (id(2), 0),
+            (id(4), 1), (id(1), 1)]);
+
+        unlink_channel(&mut links, id(2));
+        links.retain(|_, l| l.0 != id(2));
+        assert_eq!(channel_tree(&links), vec![(id(3), 0)]);
+    }
+
+    fn channel_args(id: u64, parent: u64, order: u64) -> String {
+        format!("cid={id} cpid={parent} channel_name=Channel{id} \
+            channel_topic channel_codec=4 channel_codec_quality=6 \
+            channel_maxclients=-1 channel_maxfamilyclients=-1 \
+            channel_order={order} channel_flag_permanent=1 \
+            channel_flag_semi_permanent=0 channel_flag_default=0 \
+            channel_flag_password=0 channel_codec_latency_factor=1 \
+            channel_codec_is_unencrypted=1 channel_delete_delay=0 \
+            channel_flag_maxclients_unlimited=1 \
+            channel_flag_maxfamilyclients_unlimited=0 \
+            channel_flag_maxfamilyclients_inherited=1 \
+            channel_needed_talk_power=0 channel_forced_silence=0 \
+            channel_name_phonetic channel_icon_id=0 channel_flag_private=0",
+            id = id, parent = parent, order = order)
+    }
+
+    #[test]
+    fn edited_and_moved_channels_are_sorted() {
+        let core = Core::new().unwrap();
+        let (mut con, send) = test_connection(&core,
+            ConnectOptions::default());
+        for cmd in &[
+            format!("channellist {}|{}|{}", channel_args(1, 0, 0),
+                channel_args(2, 0, 1), channel_args(3, 0, 2)),
+            "channellistfinished".to_string(),
+            // 3 moves to the top
+            "notifychanneledited cid=3 reasonid=10 invokerid=2 \
+                invokername=Admin invokeruid=abc= channel_order=0"
+                .to_string(),
+            // 1 becomes the first child of 2
+            "notifychannelmoved cid=1 cpid=2 order=0 reasonid=1 \
+                invokerid=2 invokername=Admin invokeruid=abc=".to_string(),
+        ] {
+            send.unbounded_send(command_packet(cmd)).unwrap();
+        }
+        drop(send);
+        handle_all(&mut con);
+
+        let id = ChannelId;
+        assert_eq!(con.channel_tree(), vec![(id(3), 0), (id(2), 0),
+            (id(1), 1)]);
+        assert_eq!(con.server.channels[&id(1)].parent, id(2));
+        assert_eq!(con.server.channels[&id(2)].order, id(3));
+    }
+
+    #[test]
+    fn disconnect_resolves_pending_commands() {
+        let mut pending = PendingCommands::default();
+        let mut command = commands::Command::new("clientupdate");
+        let recv = pending.add(&mut command, None, None);
+        assert_eq!(command.static_args.last().unwrap(),
+            &(String::from("return_code"), String::from("0")));
+
+        // The server closes the connection before it answers
+        pending.disconnected(Some(MoveReason::KickServer));
+        match recv.wait() {
+            Ok(Err(Error::Disconnected(Some(MoveReason::KickServer)))) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(pending.commands.is_empty());
+    }
+
+    #[test]
+    fn server_close_fails_pending_commands() {
+        let core = Core::new().unwrap();
+        let (mut con, send) = test_connection(&core,
+            ConnectOptions::default());
+        let mut command = commands::Command::new("clientupdate");
+        let recv = con.pending_commands.add(&mut command, None, None);
+        let left = format!("notifyclientleftview cfid=1 ctid=0 reasonid=5 \
+            invokerid=2 invokername=Admin invokeruid=abc= reasonmsg=Bye \
+            clid={}", con.own_client.0);
+        send.unbounded_send(command_packet(&left)).unwrap();
+
+        // The server kicks us and closes the connection before it answers
+        drop(send);
+        handle_all(&mut con);
+        match recv.wait() {
+            Ok(Err(Error::Disconnected(Some(MoveReason::KickServer)))) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(con.pending_commands.commands.is_empty());
+    }
+
+    #[test]
+    fn failed_permission_is_returned() {
+        let mut pending = PendingCommands::default();
+        let mut command = commands::Command::new("banclient");
+        let recv = pending.add(&mut command, None, None);
+        let error = parse_command(&format!("error id=2568 \
+            msg=insufficient\\sclient\\spermissions failed_permid={} \
+            return_code=0", Permission::i_client_ban_power as u32));
+        pending.track_failed_permission(&error.get_commands()[0]);
+        pending.finish("0", TsError::PermissionsClientInsufficient,
+            "insufficient client permissions");
+        match recv.wait() {
+            Ok(Err(Error::MissingPermission(
+                Permission::i_client_ban_power))) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Without the permission, the server error is kept
+        let mut command = commands::Command::new("banclient");
+        let recv = pending.add(&mut command, None, None);
+        pending.finish("1", TsError::PermissionsClientInsufficient,
+            "insufficient client permissions");
+        match recv.wait() {
+            Ok(Err(Error::Server(TsError::PermissionsClientInsufficient,
+                _))) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn answered_command_is_not_failed() {
+        let mut pending = PendingCommands::default();
+        let mut command = commands::Command::new("channelfind");
+        let recv = pending.add(&mut command, Some("notifychannelfind"),
+            None);
+        let code = pending.get_command("0").unwrap().static_args.last()
+            .unwrap().1.clone();
+        let mut row = Map::new();
+        row.insert(String::from("cid"), String::from("1"));
+        assert!(pending.add_row("notifychannelfind", row));
+        assert!(!pending.add_row("notifyclientfind", Map::new()));
+        pending.finish(&code, TsError::Ok, "ok");
+        pending.disconnected(None);
+
+        let rows = recv.wait().unwrap().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["cid"], "1");
+    }
+
+    #[test]
+    fn filtered_rows_are_not_taken() {
+        let mut pending = PendingCommands::default();
+        let mut command = commands::Command::new("clientgetvariables");
+        let recv = pending.add(&mut command, Some("notifyclientupdated"),
+            Some(rows_with("clid", vec![String::from("2")])));
+
+        // The server broadcasts an update of another client
+        let mut other = Map::new();
+        other.insert(String::from("clid"), String::from("5"));
+        assert!(!pending.add_row("notifyclientupdated", other));
+        let mut row = Map::new();
+        row.insert(String::from("clid"), String::from("2"));
+        assert!(pending.add_row("notifyclientupdated", row));
+        pending.finish("0", TsError::Ok, "ok");
+
+        let rows = recv.wait().unwrap().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["clid"], "2");
     }
 }
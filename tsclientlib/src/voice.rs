@@ -0,0 +1,770 @@
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use byteorder::{NetworkEndian, WriteBytesExt};
+use futures::{self, future, Async, Future, Sink, Stream};
+use futures::task::{self, Task};
+use num::FromPrimitive;
+use opus;
+use slog::Logger;
+use tokio_core::reactor::{Handle, Timeout};
+use tsproto::client;
+use tsproto::connectionmanager::Resender;
+use tsproto::packets::{self, CodecType, Header, Packet, PacketType};
+use tsproto_commands::*;
+
+use {BoxFuture, Error, Map, Result};
+
+/// The sample rate of the audio which is sent.
+pub const SAMPLE_RATE: u32 = 48000;
+/// The number of samples in one frame (20 ms).
+pub const FRAME_SIZE: usize = 960;
+/// The maximum size of an encoded opus frame.
+const MAX_OPUS_FRAME_SIZE: usize = 1275;
+
+/// The bitrate of the lowest codec quality in bits per second.
+const MIN_BITRATE: i32 = 6000;
+/// How much the bitrate increases with each step of the codec quality.
+///
+/// The quality goes up to 10, which results in about 40 kbit/s like the
+/// official client.
+const BITRATE_PER_QUALITY: i32 = 3400;
+
+/// The opus bitrate for a codec quality of a channel.
+fn quality_bitrate(quality: u8) -> i32 {
+    MIN_BITRATE + i32::from(::std::cmp::min(quality, 10)) * BITRATE_PER_QUALITY
+}
+
+/// How many silent frames are still sent before the transmission stops
+/// (200 ms).
+///
+/// This avoids cutting off the end of words and short pauses.
+const SILENCE_HANGOVER: u32 = 10;
+
+/// The receivers of a whisper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WhisperTarget {
+    /// All clients in these channels and these clients.
+    ///
+    /// At most 255 channels and 255 clients can be addressed.
+    List {
+        channels: Vec<ChannelId>,
+        clients: Vec<ClientId>,
+    },
+    /// All clients of a server group, in all channels.
+    ServerGroup(ServerGroupId),
+    /// All clients with a channel group in the channel of our client.
+    ChannelGroup(ChannelGroupId),
+}
+
+/// A [`WhisperTarget`] in the form in which it is sent in a voice packet.
+///
+/// [`WhisperTarget`]: enum.WhisperTarget.html
+#[derive(Debug, Clone)]
+pub(crate) struct EncodedWhisperTarget {
+    /// Groups are sent with the new protocol flag in the header.
+    newprotocol: bool,
+    channel_count: u8,
+    client_count: u8,
+    /// The ids of the targets, they are sent before the voice data.
+    targets: Vec<u8>,
+}
+
+/// The type of a group whisper, sent instead of the channel count.
+enum GroupWhisperType {
+    ServerGroup = 0,
+    ChannelGroup = 1,
+}
+
+/// The channels of a group whisper, sent instead of the client count.
+enum GroupWhisperChannels {
+    All = 0,
+    Current = 1,
+}
+
+impl WhisperTarget {
+    pub(crate) fn encode(&self) -> Result<EncodedWhisperTarget> {
+        match *self {
+            WhisperTarget::List { ref channels, ref clients } => {
+                if channels.len() > 255 || clients.len() > 255 {
+                    return Err(Error::TooManyWhisperTargets);
+                }
+                let mut targets = Vec::with_capacity(channels.len() * 8
+                    + clients.len() * 2);
+                for c in channels {
+                    targets.write_u64::<NetworkEndian>(c.0)?;
+                }
+                for c in clients {
+                    targets.write_u16::<NetworkEndian>(c.0)?;
+                }
+                Ok(EncodedWhisperTarget {
+                    newprotocol: false,
+                    channel_count: channels.len() as u8,
+                    client_count: clients.len() as u8,
+                    targets,
+                })
+            }
+            // The new protocol uses the two count bytes for the group type
+            // and the channels and sends only the group id.
+            WhisperTarget::ServerGroup(ServerGroupId(id)) |
+            WhisperTarget::ChannelGroup(ChannelGroupId(id)) => {
+                let (group_type, channels) = match *self {
+                    WhisperTarget::ServerGroup(_) => (
+                        GroupWhisperType::ServerGroup,
+                        GroupWhisperChannels::All),
+                    _ => (GroupWhisperType::ChannelGroup,
+                        GroupWhisperChannels::Current),
+                };
+                let mut targets = Vec::with_capacity(8);
+                targets.write_u64::<NetworkEndian>(id)?;
+                Ok(EncodedWhisperTarget {
+                    newprotocol: true,
+                    channel_count: group_type as u8,
+                    client_count: channels as u8,
+                    targets,
+                })
+            }
+        }
+    }
+}
+
+/// Take the id for the next voice packet of a connection.
+pub(crate) fn next_voice_id(next_id: &Cell<u16>) -> u16 {
+    let id = next_id.get();
+    next_id.set(id.wrapping_add(1));
+    id
+}
+
+/// Create a voice packet, or a whisper packet if a target is given.
+pub(crate) fn create_voice_packet(id: u16, codec_type: u8, data: &[u8],
+    whisper: Option<&EncodedWhisperTarget>) -> Packet {
+    if let Some(whisper) = whisper {
+        return whisper.create_packet(id, codec_type, data);
+    }
+    Packet::new(Header::new(PacketType::Voice), packets::Data::Voice {
+        id,
+        codec_type,
+        voice_data: data.to_vec(),
+    })
+}
+
+impl EncodedWhisperTarget {
+    pub(crate) fn create_packet(&self, id: u16, codec_type: u8,
+        voice_data: &[u8]) -> Packet {
+        let mut header = Header::new(PacketType::VoiceWhisper);
+        header.set_newprotocol(self.newprotocol);
+        let mut data = self.targets.clone();
+        data.extend_from_slice(voice_data);
+        Packet::new(header, packets::Data::VoiceWhisper {
+            id,
+            codec_type,
+            channel_count: self.channel_count,
+            client_count: self.client_count,
+            data,
+        })
+    }
+}
+
+/// Send a single voice or whisper packet.
+///
+/// The packet is dropped if the connection does not send voice at the moment,
+/// e.g. while it is connecting or disconnecting.
+pub(crate) fn send_voice_packet(
+    connection: Weak<RefCell<client::ClientConnection>>,
+    packet: Packet,
+) -> BoxFuture<()> {
+    let con = if let Some(con) = connection.upgrade() {
+        con
+    } else {
+        return Box::new(future::err(Error::ConnectionFailed(String::from(
+            "The connection does not exist anymore"))));
+    };
+    if !con.borrow().resender.send_voice_packets(packet.header.get_type()) {
+        return Box::new(future::ok(()));
+    }
+    let sink = client::ClientConnection::get_packets(con);
+    Box::new(sink.send(packet).map(|_| ()).map_err(|e| e.into()))
+}
+
+/// Get the sending client, the codec and the data of a voice or whisper
+/// packet of the server.
+///
+/// Voice packets of the server contain the id of the sending client after the
+/// packet id, but they are parsed with the layout of client packets, so the
+/// fields are put together again here. Empty data marks the end of a
+/// transmission.
+///
+/// Returns `None` if the packet is too short or has an unknown codec.
+pub(crate) fn parse_server_voice(packet: &Packet)
+    -> Option<(ClientId, CodecType, Vec<u8>)> {
+    let mut rest = match packet.data {
+        packets::Data::Voice { codec_type, ref voice_data, .. } => {
+            let mut rest = vec![codec_type];
+            rest.extend_from_slice(voice_data);
+            rest
+        }
+        packets::Data::VoiceWhisper { codec_type, channel_count,
+            client_count, ref data, .. } => {
+            let mut rest = vec![codec_type, channel_count, client_count];
+            rest.extend_from_slice(data);
+            rest
+        }
+        _ => return None,
+    };
+    if rest.len() < 3 {
+        return None;
+    }
+    let from = ClientId((u16::from(rest[0]) << 8) | u16::from(rest[1]));
+    let codec = CodecType::from_u8(rest[2])?;
+    Some((from, codec, rest.split_off(3)))
+}
+
+/// How long a disconnect waits for voice captures to end their transmission,
+/// in milliseconds.
+const VOICE_DRAIN_TIMEOUT: u64 = 100;
+
+/// Stops the voice captures of a connection before it is disconnected.
+///
+/// This is shared by all connections which replace each other when the
+/// connection is reset.
+#[derive(Debug, Default)]
+pub(crate) struct VoiceShutdown {
+    stopping: Cell<bool>,
+    /// The number of captures which are in the middle of a transmission.
+    talking: Cell<u32>,
+    /// The tasks of the captures which wait for audio or for the connection.
+    captures: RefCell<Map<u64, Task>>,
+    next_capture: Cell<u64>,
+    /// The task which waits until all transmissions ended.
+    waiter: RefCell<Option<Task>>,
+}
+
+/// Resolves when all voice captures ended their transmission or the timeout
+/// is reached.
+struct VoiceStopped {
+    shutdown: Rc<VoiceShutdown>,
+    timeout: Timeout,
+}
+
+impl VoiceShutdown {
+    /// Let all voice captures end their transmission and wait until they
+    /// finished or the timeout is reached.
+    pub(crate) fn stop(shutdown: Rc<Self>, handle: &Handle) -> BoxFuture<()> {
+        shutdown.stopping.set(true);
+        // Wake up the captures, so they see that they should stop
+        for t in shutdown.captures.borrow().values() {
+            t.notify();
+        }
+        let timeout = tryf!(Timeout::new(
+            Duration::from_millis(VOICE_DRAIN_TIMEOUT), handle));
+        Box::new(VoiceStopped { shutdown, timeout })
+    }
+
+    /// Get the id of a new capture.
+    fn add_capture(&self) -> u64 {
+        let id = self.next_capture.get();
+        self.next_capture.set(id + 1);
+        id
+    }
+
+    /// Remember the current task of a capture to wake it up when the
+    /// connection gets disconnected.
+    fn park_capture(&self, id: u64) {
+        self.captures.borrow_mut().insert(id, task::current());
+    }
+
+    fn remove_capture(&self, id: u64) {
+        self.captures.borrow_mut().remove(&id);
+    }
+
+    fn set_talking(&self, talking: bool) {
+        let count = self.talking.get();
+        self.talking.set(if talking { count + 1 } else { count - 1 });
+        if self.talking.get() == 0 {
+            if let Some(t) = self.waiter.borrow_mut().take() {
+                t.notify();
+            }
+        }
+    }
+}
+
+impl Future for VoiceStopped {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        if self.shutdown.talking.get() == 0 {
+            return Ok(Async::Ready(()));
+        }
+        if let Async::Ready(()) = self.timeout.poll()? {
+            return Ok(Async::Ready(()));
+        }
+        *self.shutdown.waiter.borrow_mut() = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// Controls if a voice capture is currently sending audio.
+///
+/// This can be cloned and shared, e.g. with the handler of a push-to-talk key.
+#[derive(Clone, Debug, Default)]
+pub struct TalkControl(Rc<TalkState>);
+
+#[derive(Debug, Default)]
+struct TalkState {
+    talking: Cell<bool>,
+    silence_threshold: Cell<Option<u16>>,
+}
+
+impl TalkControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start or stop sending audio.
+    pub fn set_talking(&self, talking: bool) {
+        self.0.talking.set(talking);
+    }
+
+    pub fn is_talking(&self) -> bool {
+        self.0.talking.get()
+    }
+
+    /// Stop sending while the audio is silent.
+    ///
+    /// Frames with a root mean square amplitude below `threshold` are silent,
+    /// as are empty frames, which a source can send if it has no input. The
+    /// transmission ends after a short time of silence and starts again when
+    /// the audio gets louder. `None` disables the silence suppression, empty
+    /// frames are then ignored.
+    ///
+    /// # Default
+    ///
+    /// Silence is sent like other audio.
+    pub fn set_silence_threshold(&self, threshold: Option<u16>) {
+        self.0.silence_threshold.set(threshold);
+    }
+
+    pub fn silence_threshold(&self) -> Option<u16> {
+        self.0.silence_threshold.get()
+    }
+}
+
+/// If a frame is quieter than the threshold.
+fn is_silent(frame: &[i16], threshold: u16) -> bool {
+    if frame.is_empty() {
+        return true;
+    }
+    let sum = frame.iter().map(|s| f64::from(*s) * f64::from(*s))
+        .sum::<f64>();
+    (sum / frame.len() as f64).sqrt() < f64::from(threshold)
+}
+
+/// Encodes audio from a source and sends it to the server.
+///
+/// The source has to yield mono frames of [`FRAME_SIZE`] samples at
+/// [`SAMPLE_RATE`]. Audio is only sent while the [`TalkControl`] is active.
+/// When it becomes inactive, an empty voice packet is sent to mark the end of
+/// the transmission.
+///
+/// The bitrate follows the codec quality of the channel of our client.
+///
+/// When the connection is disconnected, the transmission is ended before the
+/// disconnect is sent, so other clients do not hear a cut off.
+///
+/// The future resolves when the source ends or the connection is closed.
+///
+/// [`FRAME_SIZE`]: constant.FRAME_SIZE.html
+/// [`SAMPLE_RATE`]: constant.SAMPLE_RATE.html
+/// [`TalkControl`]: struct.TalkControl.html
+pub(crate) struct VoiceCapture<S: Stream<Item = Vec<i16>, Error = Error>> {
+    logger: Logger,
+    connection: Weak<RefCell<client::ClientConnection>>,
+    source: S,
+    talk: TalkControl,
+    encoder: opus::Encoder,
+    /// The codec quality of the current channel.
+    quality: Rc<Cell<Option<u8>>>,
+    /// The codec quality which the encoder currently uses.
+    encoder_quality: Option<u8>,
+    /// If we sent audio in the last frame.
+    was_talking: bool,
+    /// The number of silent frames in a row.
+    silent_frames: u32,
+    shutdown: Rc<VoiceShutdown>,
+    /// The id of this capture in the shutdown.
+    capture_id: u64,
+    /// Whisper to these targets instead of talking in the channel.
+    whisper: Option<EncodedWhisperTarget>,
+    /// The id of the next voice packet, shared with the connection.
+    next_id: Rc<Cell<u16>>,
+    /// A packet which could not be sent yet.
+    buffered: Option<Packet>,
+}
+
+impl<S: Stream<Item = Vec<i16>, Error = Error>> VoiceCapture<S> {
+    pub fn new(
+        logger: Logger,
+        connection: Weak<RefCell<client::ClientConnection>>,
+        source: S,
+        talk: TalkControl,
+        quality: Rc<Cell<Option<u8>>>,
+        shutdown: Rc<VoiceShutdown>,
+        next_id: Rc<Cell<u16>>,
+        whisper: Option<&WhisperTarget>,
+    ) -> Result<Self> {
+        let whisper = match whisper {
+            Some(w) => Some(w.encode()?),
+            None => None,
+        };
+        let encoder = opus::Encoder::new(SAMPLE_RATE, opus::Channels::Mono,
+            opus::Application::Voip)?;
+        let capture_id = shutdown.add_capture();
+        Ok(Self {
+            logger,
+            connection,
+            source,
+            talk,
+            encoder,
+            quality,
+            encoder_quality: None,
+            was_talking: false,
+            silent_frames: 0,
+            shutdown,
+            capture_id,
+            whisper,
+            next_id,
+            buffered: None,
+        })
+    }
+
+    fn create_packet(&mut self, data: Vec<u8>) -> Packet {
+        let id = next_voice_id(&self.next_id);
+        create_voice_packet(id, CodecType::OpusVoice as u8, &data,
+            self.whisper.as_ref())
+    }
+
+    /// Create the packet which marks the end of the transmission.
+    fn end_transmission(&mut self) -> Packet {
+        self.was_talking = false;
+        self.shutdown.set_talking(false);
+        self.create_packet(Vec::new())
+    }
+
+    /// Encode a frame if we are talking.
+    fn handle_frame(&mut self, frame: &[i16]) -> Result<Option<Packet>> {
+        // Empty frames mean that the source has no input, they are only used
+        // by the silence suppression
+        let no_input = frame.is_empty()
+            && self.talk.silence_threshold().is_some();
+        if !no_input && frame.len() != FRAME_SIZE {
+            warn!(self.logger, "Dropping audio frame with wrong size";
+                "size" => frame.len());
+            return Ok(None);
+        }
+        if self.talk.silence_threshold()
+            .map(|t| is_silent(frame, t)).unwrap_or(false) {
+            self.silent_frames = self.silent_frames.saturating_add(1);
+        } else {
+            self.silent_frames = 0;
+        }
+        let talking = self.talk.is_talking()
+            && self.silent_frames <= SILENCE_HANGOVER && !frame.is_empty();
+        if talking {
+            // Follow the quality of the channel
+            let quality = self.quality.get();
+            if quality != self.encoder_quality {
+                if let Some(quality) = quality {
+                    self.encoder.set_bitrate(opus::Bitrate::Bits(
+                        quality_bitrate(quality)))?;
+                }
+                self.encoder_quality = quality;
+            }
+            let mut buf = vec![0; MAX_OPUS_FRAME_SIZE];
+            let len = self.encoder.encode(frame, &mut buf)?;
+            buf.truncate(len);
+            if !self.was_talking {
+                self.was_talking = true;
+                self.shutdown.set_talking(true);
+            }
+            Ok(Some(self.create_packet(buf)))
+        } else if self.was_talking {
+            Ok(Some(self.end_transmission()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<S: Stream<Item = Vec<i16>, Error = Error>> Future for VoiceCapture<S> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        loop {
+            let con = if let Some(con) = self.connection.upgrade() {
+                con
+            } else {
+                // The connection is gone
+                return Ok(Async::Ready(()));
+            };
+            let mut sink = client::ClientConnection::get_packets(con);
+
+            if let Some(packet) = self.buffered.take() {
+                if let futures::AsyncSink::NotReady(p) =
+                    sink.start_send(packet)? {
+                    self.buffered = Some(p);
+                    self.shutdown.park_capture(self.capture_id);
+                    return Ok(Async::NotReady);
+                }
+            }
+            sink.poll_complete()?;
+
+            // The connection gets disconnected
+            if self.shutdown.stopping.get() {
+                if self.was_talking {
+                    self.buffered = Some(self.end_transmission());
+                    continue;
+                }
+                return Ok(Async::Ready(()));
+            }
+
+            let frame = match self.source.poll()? {
+                Async::Ready(Some(frame)) => frame,
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => {
+                    self.shutdown.park_capture(self.capture_id);
+                    return Ok(Async::NotReady);
+                }
+            };
+            self.buffered = self.handle_frame(&frame)?;
+        }
+    }
+}
+
+impl<S: Stream<Item = Vec<i16>, Error = Error>> Drop for VoiceCapture<S> {
+    fn drop(&mut self) {
+        self.shutdown.remove_capture(self.capture_id);
+        if self.was_talking {
+            self.shutdown.set_talking(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    #[test]
+    fn quality_bitrate_is_limited() {
+        assert_eq!(quality_bitrate(0), MIN_BITRATE);
+        assert_eq!(quality_bitrate(1), MIN_BITRATE + BITRATE_PER_QUALITY);
+        assert_eq!(quality_bitrate(10), 40000);
+        // Higher qualities do not exist, they use the highest bitrate
+        assert_eq!(quality_bitrate(11), quality_bitrate(10));
+        assert_eq!(quality_bitrate(255), quality_bitrate(10));
+    }
+
+    #[test]
+    fn encode_whisper_list() {
+        let target = WhisperTarget::List {
+            channels: vec![ChannelId(1), ChannelId(0x0102_0304_0506_0708)],
+            clients: vec![ClientId(0x1234)],
+        };
+        let encoded = target.encode().unwrap();
+        assert!(!encoded.newprotocol);
+        assert_eq!(encoded.channel_count, 2);
+        assert_eq!(encoded.client_count, 1);
+        assert_eq!(encoded.targets, vec![0, 0, 0, 0, 0, 0, 0, 1,
+            1, 2, 3, 4, 5, 6, 7, 8, 0x12, 0x34]);
+
+        let target = WhisperTarget::List {
+            channels: Vec::new(),
+            clients: vec![ClientId(1); 256],
+        };
+        match target.encode() {
+            Err(Error::TooManyWhisperTargets) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn encode_whisper_groups() {
+        let encoded = WhisperTarget::ServerGroup(ServerGroupId(0x0506))
+            .encode().unwrap();
+        assert!(encoded.newprotocol);
+        assert_eq!(encoded.channel_count, GroupWhisperType::ServerGroup as u8);
+        assert_eq!(encoded.client_count, GroupWhisperChannels::All as u8);
+        assert_eq!(encoded.targets, vec![0, 0, 0, 0, 0, 0, 5, 6]);
+
+        let encoded = WhisperTarget::ChannelGroup(ChannelGroupId(7))
+            .encode().unwrap();
+        assert!(encoded.newprotocol);
+        assert_eq!(encoded.channel_count, 1);
+        assert_eq!(encoded.client_count, 1);
+        assert_eq!(encoded.targets, vec![0, 0, 0, 0, 0, 0, 0, 7]);
+
+        // The targets are sent in front of the voice data
+        let packet = encoded.create_packet(3, CodecType::OpusVoice as u8,
+            &[9]);
+        assert!(packet.header.get_newprotocol());
+        match packet.data {
+            packets::Data::VoiceWhisper { id: 3, channel_count: 1,
+                client_count: 1, ref data, .. } =>
+                assert_eq!(data, &[0, 0, 0, 0, 0, 0, 0, 7, 9]),
+            ref d => panic!("Unexpected data {:?}", d),
+        }
+    }
+
+    #[test]
+    fn voice_and_whisper_share_ids() {
+        let next_id = Rc::new(Cell::new(u16::max_value()));
+        let whisper = WhisperTarget::ServerGroup(ServerGroupId(1)).encode()
+            .unwrap();
+        let voice = create_voice_packet(next_voice_id(&next_id),
+            CodecType::OpusVoice as u8, &[1], None);
+        let end = create_voice_packet(next_voice_id(&next_id),
+            CodecType::OpusVoice as u8, &[], Some(&whisper));
+        match voice.data {
+            packets::Data::Voice { id, ref voice_data, .. } => {
+                assert_eq!(id, u16::max_value());
+                assert_eq!(voice_data, &[1]);
+            }
+            ref d => panic!("Unexpected data {:?}", d),
+        }
+        // The end of a whisper is a whisper packet without voice data
+        assert_eq!(end.header.get_type(), PacketType::VoiceWhisper);
+        match end.data {
+            packets::Data::VoiceWhisper { id, ref data, .. } => {
+                assert_eq!(id, 0);
+                assert_eq!(data, &[0, 0, 0, 0, 0, 0, 0, 1]);
+            }
+            ref d => panic!("Unexpected data {:?}", d),
+        }
+        assert_eq!(next_id.get(), 1);
+    }
+
+    fn create_capture(talk: TalkControl)
+        -> VoiceCapture<futures::stream::Empty<Vec<i16>, Error>> {
+        let logger = Logger::root(::slog::Discard, o!());
+        VoiceCapture::new(logger, Weak::new(), futures::stream::empty(), talk,
+            Rc::new(Cell::new(None)), Rc::new(VoiceShutdown::default()),
+            Rc::new(Cell::new(0)), None).unwrap()
+    }
+
+    /// The voice data of a packet, `None` if no packet is sent.
+    fn voice_data(packet: Option<Packet>) -> Option<Vec<u8>> {
+        packet.map(|p| match p.data {
+            packets::Data::Voice { voice_data, .. } => voice_data,
+            d => panic!("Unexpected data {:?}", d),
+        })
+    }
+
+    #[test]
+    fn silence_is_detected() {
+        assert!(is_silent(&[], 100));
+        assert!(is_silent(&[0; FRAME_SIZE], 1));
+        assert!(is_silent(&[99, -99, 50], 100));
+        assert!(!is_silent(&[100, -100, 100], 100));
+        // Single loud samples count
+        let mut frame = vec![0; FRAME_SIZE];
+        frame[0] = i16::max_value();
+        assert!(!is_silent(&frame, 1000));
+        assert!(is_silent(&frame, 1100));
+    }
+
+    #[test]
+    fn silence_ends_after_hangover() {
+        let talk = TalkControl::new();
+        talk.set_talking(true);
+        talk.set_silence_threshold(Some(100));
+        let mut capture = create_capture(talk);
+        let loud = vec![1000; FRAME_SIZE];
+        let quiet = vec![0; FRAME_SIZE];
+
+        assert!(!voice_data(capture.handle_frame(&loud).unwrap()).unwrap()
+            .is_empty());
+        // Short pauses are still sent
+        for _ in 0..SILENCE_HANGOVER {
+            assert!(!voice_data(capture.handle_frame(&quiet).unwrap())
+                .unwrap().is_empty());
+        }
+        // Then the transmission ends and nothing is sent
+        assert_eq!(voice_data(capture.handle_frame(&quiet).unwrap()),
+            Some(Vec::new()));
+        assert_eq!(voice_data(capture.handle_frame(&quiet).unwrap()), None);
+        // Until the audio gets louder again
+        assert!(!voice_data(capture.handle_frame(&loud).unwrap()).unwrap()
+            .is_empty());
+        // No input ends the transmission immediately
+        assert_eq!(voice_data(capture.handle_frame(&[]).unwrap()),
+            Some(Vec::new()));
+    }
+
+    #[test]
+    fn no_suppression_without_threshold() {
+        let talk = TalkControl::new();
+        talk.set_talking(true);
+        let mut capture = create_capture(talk);
+        let quiet = vec![0; FRAME_SIZE];
+
+        for _ in 0..SILENCE_HANGOVER + 5 {
+            assert!(!voice_data(capture.handle_frame(&quiet).unwrap())
+                .unwrap().is_empty());
+        }
+        // Empty frames are ignored and do not end the transmission
+        assert_eq!(voice_data(capture.handle_frame(&[]).unwrap()), None);
+        assert!(capture.was_talking);
+        assert!(!voice_data(capture.handle_frame(&quiet).unwrap()).unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn voice_shutdown_wakes_up() {
+        let mut core = Core::new().unwrap();
+        let shutdown = Rc::new(VoiceShutdown::default());
+        let id = shutdown.add_capture();
+        shutdown.set_talking(true);
+
+        // A capture which waits for audio ends its transmission when it is
+        // woken up
+        let s = shutdown.clone();
+        let capture = future::poll_fn(move || -> futures::Poll<(), Error> {
+            if s.stopping.get() {
+                s.set_talking(false);
+                s.remove_capture(id);
+                Ok(Async::Ready(()))
+            } else {
+                s.park_capture(id);
+                Ok(Async::NotReady)
+            }
+        });
+        core.handle().spawn(capture.map_err(|e| panic!("{:?}", e)));
+        core.turn(Some(Duration::from_millis(0)));
+        assert_eq!(shutdown.captures.borrow().len(), 1);
+
+        let start = Instant::now();
+        let stop = VoiceShutdown::stop(shutdown.clone(), &core.handle());
+        core.run(stop).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(VOICE_DRAIN_TIMEOUT));
+        assert_eq!(shutdown.talking.get(), 0);
+        assert!(shutdown.captures.borrow().is_empty());
+    }
+
+    #[test]
+    fn voice_shutdown_times_out() {
+        let mut core = Core::new().unwrap();
+        let shutdown = Rc::new(VoiceShutdown::default());
+        shutdown.set_talking(true);
+        let start = Instant::now();
+        let stop = VoiceShutdown::stop(shutdown.clone(), &core.handle());
+        core.run(stop).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(VOICE_DRAIN_TIMEOUT));
+        assert!(shutdown.stopping.get());
+    }
+}
@@ -20,6 +20,11 @@ pub struct ConnectionId(pub usize);
 ///
 /// Every client that we see on a server has a `ClientId`, even our own
 /// connection.
+///
+/// It is only valid while the client is connected and has nothing to do with
+/// the [`ClientDbId`] of the client, which has to be looked up.
+///
+/// [`ClientDbId`]: struct.ClientDbId.html
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub struct ClientId(pub u16);
 /// Describes a client or server uid which is a base64
@@ -30,7 +35,7 @@ pub struct Uid(pub String);
 /// The database id of a client.
 ///
 /// This is the id which is saved for a client in the database of one specific
-/// server.
+/// server. It stays the same when the client reconnects.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub struct ClientDbId(pub u64);
 
@@ -133,6 +138,54 @@ pub enum MoveReason {
 	ClientdisconnectServerShutdown,
 }
 
+impl MoveReason {
+	/// If the client was kicked from a channel or the server.
+	///
+	/// This includes bans, as they also kick the client.
+	pub fn is_kick(&self) -> bool {
+		match *self {
+			MoveReason::KickChannel |
+			MoveReason::KickServer |
+			MoveReason::KickServerBan => true,
+			_ => false,
+		}
+	}
+
+	/// If the client was banned from the server.
+	pub fn is_ban(&self) -> bool {
+		*self == MoveReason::KickServerBan
+	}
+
+	/// If the connection of the client timed out.
+	pub fn is_timeout(&self) -> bool {
+		*self == MoveReason::LostConnection
+	}
+
+	/// If the client left on its own.
+	pub fn is_voluntary(&self) -> bool {
+		*self == MoveReason::Clientdisconnect
+	}
+
+	/// A short description of the reason in English.
+	pub fn description(&self) -> &'static str {
+		match *self {
+			MoveReason::None => "Joined or switched channel",
+			MoveReason::Moved => "Moved by another client",
+			MoveReason::Subscription => "Channel subscription changed",
+			MoveReason::LostConnection => "Connection timed out",
+			MoveReason::KickChannel => "Kicked from the channel",
+			MoveReason::KickServer => "Kicked from the server",
+			MoveReason::KickServerBan => "Banned from the server",
+			MoveReason::Serverstop => "Server stopped",
+			MoveReason::Clientdisconnect => "Disconnected",
+			MoveReason::Channelupdate => "Channel was updated",
+			MoveReason::Channeledit => "Channel was edited",
+			MoveReason::ClientdisconnectServerShutdown =>
+				"Disconnected because the server shut down",
+		}
+	}
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive)]
 pub enum ClientType {
 	Normal,
@@ -1,6 +1,7 @@
 //! Handle packet splitting and cryptography
 use std::u64;
 
+use base64;
 use byteorder::{NetworkEndian, WriteBytesExt};
 use num::BigUint;
 use quicklz::CompressionLevel;
@@ -10,6 +11,61 @@ use {crypto, Result};
 use crypto::EccKey;
 use packets::*;
 
+/// A hash function which is used to derive keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyDerivation {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl KeyDerivation {
+    fn algorithm(&self) -> &'static digest::Algorithm {
+        match *self {
+            KeyDerivation::Sha1 => &digest::SHA1,
+            KeyDerivation::Sha256 => &digest::SHA256,
+            KeyDerivation::Sha512 => &digest::SHA512,
+        }
+    }
+
+    fn digest(&self, data: &[u8]) -> digest::Digest {
+        digest::digest(self.algorithm(), data)
+    }
+}
+
+/// The parameters of the packet encryption.
+///
+/// The default values are the ones which are used by TeamSpeak. The packets
+/// are always encrypted with AES-EAX, only the key derivation can be changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CryptoParams {
+    /// The key for packets which are sent before the shared secret is known.
+    pub fake_key: [u8; 16],
+    /// The nonce for packets which are sent before the shared secret is known.
+    pub fake_nonce: [u8; 16],
+    /// Derives the shared iv from the shared secret and the shared mac from
+    /// the shared iv.
+    ///
+    /// The default is SHA-1.
+    pub iv_mac_derivation: KeyDerivation,
+    /// Derives the key and nonce of a packet from the shared iv.
+    ///
+    /// The hash has to be at least 32 bytes long, so SHA-1 cannot be used
+    /// here. The default is SHA-256.
+    pub key_nonce_derivation: KeyDerivation,
+}
+
+impl Default for CryptoParams {
+    fn default() -> Self {
+        Self {
+            fake_key: ::FAKE_KEY,
+            fake_nonce: ::FAKE_NONCE,
+            iv_mac_derivation: KeyDerivation::Sha1,
+            key_nonce_derivation: KeyDerivation::Sha256,
+        }
+    }
+}
+
 pub fn must_encrypt(t: PacketType) -> bool {
     match t {
         PacketType::Command | PacketType::CommandLow => true,
@@ -109,7 +165,8 @@ fn create_key_nonce(
     header: &Header,
     generation_id: u32,
     iv: &[u8; 20],
-) -> ([u8; 16], [u8; 16]) {
+    params: &CryptoParams,
+) -> Result<([u8; 16], [u8; 16])> {
     let mut temp = [0; 26];
     if header.c_id.is_some() {
         temp[0] = 0x31;
@@ -122,15 +179,18 @@ fn create_key_nonce(
     temp[2..6].copy_from_slice(&buf);
     temp[6..].copy_from_slice(iv);
 
-    let keynonce = digest::digest(&digest::SHA256, &temp);
+    let keynonce = params.key_nonce_derivation.digest(&temp);
     let keynonce = keynonce.as_ref();
+    if keynonce.len() < 32 {
+        return Err(format_err!("The key derivation hash is too short").into());
+    }
     let mut key = [0; 16];
     let mut nonce = [0; 16];
     key.copy_from_slice(&keynonce[..16]);
-    nonce.copy_from_slice(&keynonce[16..]);
+    nonce.copy_from_slice(&keynonce[16..32]);
     key[0] ^= (header.p_id >> 8) as u8;
     key[1] ^= (header.p_id & 0xff) as u8;
-    (key, nonce)
+    Ok((key, nonce))
 }
 
 pub fn encrypt_key_nonce(
@@ -149,8 +209,17 @@ pub fn encrypt_key_nonce(
     Ok(())
 }
 
+/// Encrypt with the default fake key and nonce.
 pub fn encrypt_fake(header: &mut Header, data: &mut [u8]) -> Result<()> {
-    encrypt_key_nonce(header, data, &::FAKE_KEY, &::FAKE_NONCE)
+    encrypt_fake_with(header, data, &CryptoParams::default())
+}
+
+pub fn encrypt_fake_with(
+    header: &mut Header,
+    data: &mut [u8],
+    params: &CryptoParams,
+) -> Result<()> {
+    encrypt_key_nonce(header, data, &params.fake_key, &params.fake_nonce)
 }
 
 pub fn encrypt(
@@ -158,9 +227,10 @@ pub fn encrypt(
     data: &mut [u8],
     generation_id: u32,
     iv: &[u8; 20],
+    params: &CryptoParams,
 ) -> Result<()> {
     // TODO Cache this more efficiently for a generation
-    let (key, nonce) = create_key_nonce(header, generation_id, iv);
+    let (key, nonce) = create_key_nonce(header, generation_id, iv, params)?;
     encrypt_key_nonce(header, data, &key, &nonce)
 }
 
@@ -178,8 +248,17 @@ pub fn decrypt_key_nonce(
     Ok(())
 }
 
+/// Decrypt with the default fake key and nonce.
 pub fn decrypt_fake(header: &Header, data: &mut [u8]) -> Result<()> {
-    decrypt_key_nonce(header, data, &::FAKE_KEY, &::FAKE_NONCE)
+    decrypt_fake_with(header, data, &CryptoParams::default())
+}
+
+pub fn decrypt_fake_with(
+    header: &Header,
+    data: &mut [u8],
+    params: &CryptoParams,
+) -> Result<()> {
+    decrypt_key_nonce(header, data, &params.fake_key, &params.fake_nonce)
 }
 
 pub fn decrypt(
@@ -187,8 +266,9 @@ pub fn decrypt(
     data: &mut [u8],
     generation_id: u32,
     iv: &[u8; 20],
+    params: &CryptoParams,
 ) -> Result<()> {
-    let (key, nonce) = create_key_nonce(header, generation_id, iv);
+    let (key, nonce) = create_key_nonce(header, generation_id, iv, params)?;
     decrypt_key_nonce(header, data, &key, &nonce)
 }
 
@@ -198,11 +278,13 @@ pub fn compute_iv_mac(
     beta: &[u8; 10],
     our_key: &EccKey,
     other_key: &EccKey,
+    params: &CryptoParams,
 ) -> Result<([u8; 20], [u8; 8])> {
     let shared_secret = our_key.create_shared_secret(other_key)?;
     let mut shared_iv = [0; 20];
-    shared_iv.copy_from_slice(digest::digest(&digest::SHA1, &shared_secret)
-                              .as_ref());
+    shared_iv.copy_from_slice(
+        &params.iv_mac_derivation.digest(&shared_secret).as_ref()[..20],
+    );
     for i in 0..10 {
         shared_iv[i] ^= alpha[i];
     }
@@ -211,7 +293,7 @@ pub fn compute_iv_mac(
     }
     let mut shared_mac = [0; 8];
     shared_mac.copy_from_slice(
-        &digest::digest(&digest::SHA1, &shared_iv).as_ref()[..8],
+        &params.iv_mac_derivation.digest(&shared_iv).as_ref()[..8],
     );
     Ok((shared_iv, shared_mac))
 }
@@ -242,6 +324,57 @@ pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
     res
 }
 
+/// Compute the unique identifier of a client from its public key, like the
+/// server does it.
+///
+/// `omega` is the public key, encoded like in [`EccKey::to_ts_public`].
+///
+/// [`EccKey::to_ts_public`]: ../crypto/struct.EccKey.html#method.to_ts_public
+pub fn get_uid(omega: &str) -> String {
+    base64::encode(digest::digest(&digest::SHA1, omega.as_bytes()).as_ref())
+}
+
+/// The key which the TeamSpeak client uses to obfuscate exported identities.
+const IDENTITY_OBFUSCATION_KEY: &[u8; 128] = b"b9dfaa7bee6ac57ac7b65f1094a1c15\
+    5e747327bc2fe5d51c512023fe54a280201004e90ad1daaae1075d53b7d571c30e063b5a6\
+    2a4a017bb394833aa0983e6e";
+
+/// Reverse the obfuscation of an identity which was exported by the TeamSpeak
+/// client.
+///
+/// `data` is the base64 decoded part after the key offset. The result is the
+/// private key, encoded like for [`EccKey::from_ts`].
+///
+/// [`EccKey::from_ts`]: ../crypto/enum.EccKey.html#method.from_ts
+pub fn deobfuscate_identity(data: &[u8]) -> Result<String> {
+    if data.len() < 20 {
+        return Err(format_err!("The identity is too short").into());
+    }
+    let mut data = data.to_vec();
+    // The hash covers the data until the first zero byte
+    let hash_len = data[20..].iter().position(|b| *b == 0)
+        .unwrap_or(data.len() - 20);
+    let hash = digest::digest(&digest::SHA1, &data[20..20 + hash_len]);
+    for (d, h) in data.iter_mut().zip(hash.as_ref()) {
+        *d ^= *h;
+    }
+    for (d, k) in data.iter_mut().zip(&IDENTITY_OBFUSCATION_KEY[..100]) {
+        *d ^= *k;
+    }
+    Ok(::std::str::from_utf8(&data)?.to_string())
+}
+
+/// Hash a server or channel password like the server expects it, as base64
+/// encoded SHA1 hash.
+///
+/// An empty password stays empty, it means that no password is used.
+pub fn hash_password(password: &str) -> String {
+    if password.is_empty() {
+        return String::new();
+    }
+    base64::encode(digest::digest(&digest::SHA1, password.as_bytes()).as_ref())
+}
+
 pub fn biguint_to_array(i: &BigUint) -> [u8; 64] {
     let mut v = i.to_bytes_le();
 
@@ -308,4 +441,54 @@ mod tests {
         ];
         assert_eq!(real_res, buf.as_slice());
     }
+
+    #[test]
+    fn test_default_crypto_params() {
+        ::init().unwrap();
+        let params = CryptoParams::default();
+        assert_eq!(params.fake_key, ::FAKE_KEY);
+        assert_eq!(params.fake_nonce, ::FAKE_NONCE);
+
+        // The shared iv and mac are computed like TeamSpeak does it
+        let our_key = EccKey::create().unwrap();
+        let other_key = EccKey::create().unwrap();
+        let alpha = [1; 10];
+        let beta = [2; 10];
+        let (iv, mac) = compute_iv_mac(&alpha, &beta, &our_key, &other_key,
+            &params).unwrap();
+        let secret = our_key.create_shared_secret(&other_key).unwrap();
+        let mut expected_iv = [0; 20];
+        expected_iv.copy_from_slice(
+            digest::digest(&digest::SHA1, &secret).as_ref());
+        for i in 0..10 {
+            expected_iv[i] ^= alpha[i];
+            expected_iv[i + 10] ^= beta[i];
+        }
+        assert_eq!(iv, expected_iv);
+        assert_eq!(&mac[..],
+            &digest::digest(&digest::SHA1, &iv).as_ref()[..8]);
+
+        // The key and nonce of a packet
+        let mut header = Header::default();
+        header.c_id = Some(0);
+        header.set_type(PacketType::Command);
+        header.p_id = 0x0102;
+        let (key, nonce) = create_key_nonce(&header, 3, &iv, &params)
+            .unwrap();
+        let mut temp = vec![0x31, PacketType::Command as u8, 0, 0, 0, 3];
+        temp.extend_from_slice(&iv);
+        let hash = digest::digest(&digest::SHA256, &temp);
+        let mut expected_key = [0; 16];
+        expected_key.copy_from_slice(&hash.as_ref()[..16]);
+        expected_key[0] ^= 1;
+        expected_key[1] ^= 2;
+        assert_eq!(key, expected_key);
+        assert_eq!(&nonce[..], &hash.as_ref()[16..32]);
+    }
+
+    #[test]
+    fn test_hash_password() {
+        assert_eq!(hash_password("password"), "W6ph5Mm5Pz8GgiULbPgzG37mj9g=");
+        assert_eq!(hash_password(""), "");
+    }
 }
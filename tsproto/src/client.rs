@@ -3,6 +3,7 @@ use std::fmt::Display;
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use base64;
 use chrono::Utc;
@@ -12,7 +13,8 @@ use futures::task::{self, Task};
 use futures::unsync::oneshot;
 use num::{BigUint, One, ToPrimitive};
 use rand::{self, Rng};
-use tokio_core::reactor::Handle;
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Timeout};
 
 use {packets, BoxFuture, Error, Result};
 use algorithms as algs;
@@ -100,7 +102,13 @@ impl<CM: AttachedDataConnectionManager<ServerConnectionData> + 'static>
 
             // Packet encoding
             ::packet_codec::PacketCodecSink::apply(con.clone());
-            ::packet_codec::PacketCodecStream::apply(con.clone(), true);
+            let ack_delay = con.borrow().ack_delay;
+            if let Some(ack_delay) = ack_delay {
+                ::packet_codec::PacketCodecStream::apply_with_ack_delay(
+                    con.clone(), ack_delay, &self.handle);
+            } else {
+                ::packet_codec::PacketCodecStream::apply(con.clone(), true);
+            }
 
             if let Some(ref logger) = self.logger {
                 // Logging
@@ -114,6 +122,11 @@ impl<CM: AttachedDataConnectionManager<ServerConnectionData> + 'static>
 
             // Distribute packets
             Connection::start_packet_distributor(con.clone(), &self.handle);
+
+            // Answer pings while received commands are not consumed
+            if con.borrow().ping_responder {
+                ::packet_codec::PingResponder::apply(con.clone(), &self.handle);
+            }
         }
 
         // Default handlers are not usable with the wrapper system
@@ -273,6 +286,56 @@ pub fn connect(
     )
 }
 
+/// Send the first init packet to a server and measure the time until it
+/// answers.
+///
+/// No connection is created and the handshake is not continued, so this is
+/// much cheaper than connecting, e.g. to check if many servers are reachable.
+///
+/// Returns `None` if the server does not answer within `timeout`.
+pub fn ping_server(
+    handle: &Handle,
+    server_addr: SocketAddr,
+    timeout: Duration,
+) -> BoxFuture<Option<Duration>, Error> {
+    let local_addr = if server_addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    }.parse().unwrap();
+    let socket = tryf!(UdpSocket::bind(&local_addr, handle));
+    let timeout = tryf!(Timeout::new(timeout, handle));
+
+    let timestamp = Utc::now().timestamp() as u32;
+    let packet_data = packets::Data::C2SInit(C2SInit::Init0 {
+        version: timestamp,
+        timestamp: timestamp,
+        random0: rand::thread_rng().gen::<[u8; 4]>(),
+    });
+    let mut buf = Vec::new();
+    tryf!(create_init_header().write(&mut buf));
+    packet_data.write(&mut buf).unwrap();
+
+    let start = Instant::now();
+    let ping = socket.send_dgram(buf, server_addr)
+        .and_then(move |(socket, _)| {
+            // Wait for a packet of the server
+            future::loop_fn(socket, move |socket| {
+                socket.recv_dgram(vec![0; 1024]).map(move |(socket, _, _, addr)|
+                    if addr == server_addr {
+                        future::Loop::Break(start.elapsed())
+                    } else {
+                        future::Loop::Continue(socket)
+                    })
+            })
+        });
+    Box::new(ping.select2(timeout).then(|res| match res {
+        Ok(Either::A((rtt, _))) => Ok(Some(rtt)),
+        Ok(Either::B(_)) => Ok(None),
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e.into()),
+    }))
+}
+
 pub struct DefaultPacketHandlerStream {
     inner_stream: Box<Stream<Item = Packet, Error = Error>>,
 }
@@ -402,6 +465,7 @@ impl DefaultPacketHandlerStream {
                         }
                         ServerConnectionState::ClientInitIv { ref alpha } => {
                             let private_key = &data.private_key;
+                            let crypto_params = con.crypto_params;
                             let res = (|con_params: &mut Option<ConnectedParams>| -> Result<()> {
                                 if let Packet { data: packets::Data::Command(ref command), .. } = packet {
                                     let cmd = command.get_commands().remove(0);
@@ -423,7 +487,8 @@ impl DefaultPacketHandlerStream {
                                             from_ts(cmd.args["omega"])?;
 
                                         let (iv, mac) = algs::compute_iv_mac(
-                                            alpha, &beta, private_key, &mut server_key)?;
+                                            alpha, &beta, private_key, &mut server_key,
+                                            &crypto_params)?;
                                         let mut params = ConnectedParams::new(
                                             server_key, iv, mac);
                                         // We already sent a command packet.
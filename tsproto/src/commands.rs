@@ -179,8 +179,18 @@ impl Command {
 
     pub fn write(&self, w: &mut Write) -> Result<()> {
         w.write_all(self.command.as_bytes())?;
-        for &(ref k, ref v) in &self.static_args {
+        if !self.static_args.is_empty() || !self.list_args.is_empty() {
             write!(w, " ")?;
+        }
+        self.write_arguments(w)
+    }
+
+    /// Write only the arguments of the command, without its name.
+    pub fn write_arguments(&self, w: &mut Write) -> Result<()> {
+        for (i, &(ref k, ref v)) in self.static_args.iter().enumerate() {
+            if i != 0 {
+                write!(w, " ")?;
+            }
             Self::write_key_val(w, k, v)?;
         }
         for (i, args) in self.list_args.iter().enumerate() {
@@ -188,7 +198,7 @@ impl Command {
                 write!(w, "|")?;
             }
             for (j, &(ref k, ref v)) in args.iter().enumerate() {
-                if j != 0 || i == 0 {
+                if j != 0 || (i == 0 && !self.static_args.is_empty()) {
                     write!(w, " ")?;
                 }
                 Self::write_key_val(w, k, v)?;
@@ -247,6 +257,19 @@ impl<'a> CanonicalCommand<'a> {
     pub fn has_arg(&self, arg: &str) -> bool {
         self.args.contains_key(arg)
     }
+
+    /// Convert this into a command with a single row.
+    ///
+    /// The arguments are sorted by their name.
+    pub fn to_command(&self) -> Command {
+        let mut args: Vec<_> = self.args.iter().collect();
+        args.sort();
+        let mut cmd = Command::new(self.command);
+        for (k, v) in args {
+            cmd.push(*k, *v);
+        }
+        cmd
+    }
 }
 
 #[cfg(test)]
@@ -291,6 +314,18 @@ mod tests {
         assert_eq!(&s[..], s_r.as_slice());
     }
 
+    #[test]
+    fn arguments() {
+        let mut cmd = Command::new("cmd");
+        cmd.push("a", "x y");
+        cmd.list_args.push(vec![("b".into(), "2".into())]);
+        cmd.list_args.push(vec![("b".into(), "4".into())]);
+
+        let mut s = Vec::new();
+        cmd.write_arguments(&mut s).unwrap();
+        assert_eq!(&b"a=x\\sy b=2|b=4"[..], s.as_slice());
+    }
+
     #[test]
     fn array() {
         let s = b"cmd a=1 c=3 b=2|b=4|b=5";
@@ -346,6 +381,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn canonical_to_command() {
+        let s = b"cmd c=3 a=1 b=2|b=4";
+        let cmd = Command::read((), &mut Cursor::new(s)).unwrap();
+        let mut expected = Command::new("cmd");
+        expected.push("a", "1");
+        expected.push("b", "4");
+        expected.push("c", "3");
+        assert_eq!(cmd.get_commands()[1].to_command(), expected);
+    }
+
     #[test]
     fn optional_arg() {
         let s = b"cmd a";
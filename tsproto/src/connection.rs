@@ -1,19 +1,28 @@
 use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Cursor;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 use std::u16;
 
 use slog;
 use futures::{self, future, Future, Sink, Stream, task};
 use futures::task::Task;
+use futures::unsync::mpsc;
 use num::ToPrimitive;
 use tokio_core::reactor::Handle;
 
-use {Error, StreamWrapper, SinkWrapper};
+use {Error, Map, StreamWrapper, SinkWrapper};
+use algorithms::CryptoParams;
 use connectionmanager::ConnectionManager;
 use packets::*;
 use handler_data::Data;
 
+/// The maximum number of sent pings which are remembered to measure the round
+/// trip time.
+const MAX_PENDING_PINGS: usize = 8;
+
 /// Data that has to be stored for a connection when it is connected.
 #[derive(Debug)]
 pub struct ConnectedParams {
@@ -37,11 +46,28 @@ pub struct ConnectedParams {
     ///
     /// Works like the `outgoing_p_ids`.
     pub incoming_p_ids: [(u32, u16); 8],
+    /// The number of packet ids after the next expected id, which are accepted.
+    ///
+    /// Packets with an id outside of this window are treated as duplicates of
+    /// already received packets. A bigger window tolerates more reordering.
+    /// It must not be bigger than half of the id space.
+    pub receive_window: u16,
+    /// The maximum size of a received command in bytes, after it was
+    /// reassembled and decompressed.
+    ///
+    /// Bigger commands are rejected. Commands with a large payload, like
+    /// server snapshots, need a higher limit.
+    pub max_command_size: usize,
 
     /// The client id of this connection.
     pub c_id: u16,
     /// If voice packets should be encrypted
     pub voice_encryption: bool,
+    /// The ids and send times of the last pings which were not answered yet,
+    /// the oldest ping first.
+    pub pings_sent: VecDeque<(u16, Instant)>,
+    /// The round trip time of the last answered ping.
+    pub ping_rtt: Option<Duration>,
 
     pub public_key: ::crypto::EccKey,
     /// The iv used to encrypt and decrypt packets.
@@ -58,34 +84,104 @@ impl ConnectedParams {
             receive_queue: Default::default(),
             fragmented_queue: Default::default(),
             incoming_p_ids: Default::default(),
+            receive_window: u16::MAX / 2,
+            max_command_size: ::MAX_FRAGMENTS_LENGTH,
             c_id: 0,
             voice_encryption: true,
+            pings_sent: VecDeque::new(),
+            ping_rtt: None,
             public_key,
             shared_iv,
             shared_mac,
         }
     }
 
+    /// Remember when a ping was sent to measure the round trip time.
+    ///
+    /// Only the last `MAX_PENDING_PINGS` pings are remembered.
+    pub fn sent_ping(&mut self, p_id: u16) {
+        if self.pings_sent.len() >= MAX_PENDING_PINGS {
+            self.pings_sent.pop_front();
+        }
+        self.pings_sent.push_back((p_id, Instant::now()));
+    }
+
+    /// Update the round trip time with the ping which is answered by a pong.
+    ///
+    /// Older pings are forgotten, their pongs were lost or will arrive after
+    /// a newer pong. Returns the measured round trip time.
+    pub fn received_pong(&mut self, p_id: u16) -> Option<Duration> {
+        let pos = self.pings_sent.iter().position(|&(id, _)| id == p_id)?;
+        let (_, sent) = self.pings_sent.drain(..pos + 1).last()?;
+        let rtt = sent.elapsed();
+        self.ping_rtt = Some(rtt);
+        Some(rtt)
+    }
+
     /// Check if a given id is in the receive window.
+    ///
+    /// Returns if the id is in the window, the start and the (exclusive) end
+    /// of the window.
     pub(crate) fn in_receive_window(
         &self,
         p_type: PacketType,
         p_id: u16,
     ) -> (bool, u16, u16) {
         let type_i = p_type.to_usize().unwrap();
-        // Receive window is the next half of ids
+        // Receive window is the next ids after the expected id
         let cur_next = self.incoming_p_ids[type_i].1;
-        let limit = ((u32::from(cur_next) + u32::from(u16::MAX) / 2)
-            % u32::from(u16::MAX)) as u16;
-        (
-            (cur_next < limit && p_id >= cur_next && p_id < limit)
-                || (cur_next > limit && (p_id >= cur_next || p_id < limit)),
-            cur_next,
-            limit,
-        )
+        let limit = cur_next.wrapping_add(self.receive_window);
+        (p_id.wrapping_sub(cur_next) < self.receive_window, cur_next, limit)
+    }
+}
+
+/// Counters for the traffic of a connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct ConnectionStats {
+    /// Received udp packets, including packets which are dropped later.
+    pub packets_received: u64,
+    /// Bytes of the received udp packets.
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// How often command packets were sent again because they were not
+    /// acknowledged in time.
+    pub resent_packets: u64,
+}
+
+/// Why a received packet was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// The buffer for received packets was full.
+    BufferFull,
+    /// The packet could not be decrypted or parsed.
+    Invalid,
+}
+
+/// Receives the raw bytes of dropped packets, e.g. to collect a corpus for
+/// fuzzing the parser.
+pub type DroppedPacketSink = mpsc::UnboundedSender<(DropReason, Vec<u8>)>;
+
+/// Give the raw bytes of a dropped packet to a sink, if there is one.
+///
+/// The sink is removed when its receiver is gone.
+pub(crate) fn report_dropped_packet(sink: &mut Option<DroppedPacketSink>,
+    reason: DropReason, data: &[u8]) {
+    let failed = if let Some(ref sink) = *sink {
+        sink.unbounded_send((reason, data.to_vec())).is_err()
+    } else {
+        false
+    };
+    if failed {
+        *sink = None;
     }
 }
 
+/// The length of the longest packet header, the header of packets which are
+/// sent by a client.
+const MAX_HEADER_LEN: usize = 13;
+
 /// Represents a currently alive connection.
 pub struct Connection<CM: ConnectionManager + 'static> {
     /// A logger for this connection.
@@ -128,17 +224,43 @@ pub struct Connection<CM: ConnectionManager + 'static> {
 
     /// The task of the packet distributor.
     distributor_task: Option<Task>,
+    /// The task which answers pings while the distributor is stalled.
+    pub(crate) ping_responder_task: Option<Task>,
 
     pub resender: CM::Resend,
+    /// Traffic counters of this connection.
+    pub stats: ConnectionStats,
+    /// The number of sent and received udp packets of each type.
+    pub packet_type_stats: Map<PacketType, (u64, u64)>,
+    /// If the last voice packet could not be sent because the packets before
+    /// it are still waiting for the socket.
+    pub voice_send_congested: bool,
+    /// When the last udp packet of the other side arrived.
+    ///
+    /// This is updated when the packet is received from the socket, even if it
+    /// is dropped later.
+    pub last_received: Instant,
+    /// Receives packets of this connection which were dropped.
+    pub dropped_packet_sink: Option<DroppedPacketSink>,
+    /// The parameters of the packet encryption.
+    pub crypto_params: CryptoParams,
+    /// If pings are answered on a separate task while the received commands
+    /// are not consumed.
+    pub ping_responder: bool,
+    /// How long acks are collected before they are sent.
+    pub ack_delay: Option<Duration>,
 }
 
 impl<CM: ConnectionManager + 'static> Connection<CM> {
     /// Creates a new connection struct.
     pub fn new(data: Rc<RefCell<Data<CM>>>, address: SocketAddr,
         resender: CM::Resend) -> Rc<RefCell<Self>> {
-        let (logger, is_client) = {
+        let (logger, is_client, dropped_packet_sink, crypto_params,
+            ping_responder, ack_delay) = {
             let data = data.borrow();
-            (data.logger.clone(), data.is_client)
+            (data.logger.clone(), data.is_client,
+                data.dropped_packet_sink.clone(), data.crypto_params,
+                data.ping_responder, data.ack_delay)
         };
 
         let con = Rc::new(RefCell::new(Self {
@@ -156,8 +278,17 @@ impl<CM: ConnectionManager + 'static> Connection<CM> {
             command_buffer_stream: Default::default(),
             voice_buffer_stream: Default::default(),
             distributor_task: None,
+            ping_responder_task: None,
 
             resender,
+            stats: Default::default(),
+            packet_type_stats: Map::new(),
+            voice_send_congested: false,
+            last_received: Instant::now(),
+            dropped_packet_sink,
+            crypto_params,
+            ping_responder,
+            ack_delay,
         }));
 
         // Set the udp stream and sink
@@ -243,6 +374,40 @@ impl<CM: ConnectionManager + 'static> Connection<CM> {
         ConnectionVoicePacketStream::new(connection)
     }
 
+    /// Count a sent or received udp packet for the statistics of its type.
+    ///
+    /// Packets with an invalid header are not counted.
+    fn count_packet_type(&mut self, packet: &[u8], sent: bool) {
+        // The header differs depending on who sent the packet
+        let from_client = self.is_client == sent;
+        if let Ok(header) = Header::read(&from_client,
+            &mut Cursor::new(packet)) {
+            let stats = self.packet_type_stats.entry(header.get_type())
+                .or_insert((0, 0));
+            if sent {
+                stats.0 += 1;
+            } else {
+                stats.1 += 1;
+            }
+        }
+    }
+
+    /// If the packet codec takes no more command packets because the received
+    /// commands are not consumed.
+    pub(crate) fn is_receive_stalled(&self) -> bool {
+        self.command_buffer_stream.buffer.len() >= ::STREAM_BUFFER_MAX_SIZE
+    }
+
+    /// Return an error from the stream of received commands, so the consumer
+    /// can tear down the connection.
+    pub(crate) fn fail_commands(&mut self, error: Error) {
+        let stream = &mut self.command_buffer_stream;
+        stream.error = Some(error);
+        if let Some(ref task) = stream.task {
+            task.notify();
+        }
+    }
+
     /// Enables distributing incoming packets to the connections.
     pub fn start_packet_distributor(connection: Rc<RefCell<Self>>,
         handle: &Handle) {
@@ -434,7 +599,13 @@ impl<CM: ConnectionManager + 'static> Stream for ConnectionUdpPacketStream<CM> {
             return Ok(futures::Async::Ready(None));
         };
         let mut con = con.borrow_mut();
-        con.udp_packet_buffer_stream.poll()
+        let res = con.udp_packet_buffer_stream.poll();
+        if let Ok(futures::Async::Ready(Some(ref packet))) = res {
+            con.stats.packets_received += 1;
+            con.stats.bytes_received += packet.0.len() as u64;
+            con.count_packet_type(&packet.0, false);
+        }
+        res
     }
 }
 
@@ -517,15 +688,20 @@ impl<
 
     fn start_send(&mut self, item: Self::SinkItem)
         -> futures::StartSend<Self::SinkItem, Self::SinkError> {
-        let addr = {
-            let con = self.connection.upgrade().unwrap();
-            let con = con.borrow();
-            con.address
-        };
+        let con = self.connection.upgrade().unwrap();
+        let addr = con.borrow().address;
+        let len = item.0.len() as u64;
+        // Only the header is needed for the statistics
+        let header = item.0[..::std::cmp::min(item.0.len(), MAX_HEADER_LEN)]
+            .to_vec();
         if let futures::AsyncSink::NotReady((_, item)) =
             self.inner.start_send((addr, item))? {
             Ok(futures::AsyncSink::NotReady(item))
         } else {
+            let mut con = con.borrow_mut();
+            con.stats.packets_sent += 1;
+            con.stats.bytes_sent += len;
+            con.count_packet_type(&header, true);
             Ok(futures::AsyncSink::Ready)
         }
     }
@@ -572,7 +748,13 @@ impl<
         } else {
             return Ok(futures::Async::Ready(None));
         };
-        let res = self.inner.poll()?;
+        let res = match self.inner.poll() {
+            Ok(res) => res,
+            Err(error) => {
+                connection.borrow_mut().fail_commands(error);
+                return Ok(futures::Async::Ready(None));
+            }
+        };
 
         let mut con = connection.borrow_mut();
         // Set the task
@@ -592,7 +774,13 @@ impl<
                 };
 
                 if let Some(buffer_stream) = buffer_stream {
-                    if buffer_stream.buffer.len() >= ::STREAM_BUFFER_MAX_SIZE {
+                    // Command packets are already acknowledged, so they are
+                    // never dropped. The packet codec takes no new commands
+                    // while the buffer is full, only queued commands which
+                    // were received out of order can still arrive.
+                    if !packet.header.get_type().is_command()
+                        && buffer_stream.buffer.len()
+                        >= ::STREAM_BUFFER_MAX_SIZE {
                         warn!(logger,
                             "Dropping packet, stream buffer too full";
                             "length" => buffer_stream.buffer.len());
@@ -621,3 +809,135 @@ impl<
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::u16;
+
+    use futures::{self, Future, Stream};
+    use num::ToPrimitive;
+    use slog;
+    use tokio_core::reactor::Core;
+
+    use connection::*;
+    use connectionmanager::SocketConnectionManager;
+    use handler_data::Data as HandlerData;
+    use packets::{Data as PacketData, Header, Packet, PacketType};
+
+    fn create_params(next_id: u16, window: u16) -> ConnectedParams {
+        ::init().unwrap();
+        let key = ::crypto::EccKey::create().unwrap();
+        let mut params = ConnectedParams::new(key, [0; 20], [0; 8]);
+        params.incoming_p_ids[PacketType::Command.to_usize().unwrap()].1 =
+            next_id;
+        params.receive_window = window;
+        params
+    }
+
+    #[test]
+    fn receive_window_edges() {
+        let params = create_params(100, 10);
+        assert!(!params.in_receive_window(PacketType::Command, 99).0);
+        assert!(params.in_receive_window(PacketType::Command, 100).0);
+        assert!(params.in_receive_window(PacketType::Command, 109).0);
+        assert!(!params.in_receive_window(PacketType::Command, 110).0);
+    }
+
+    #[test]
+    fn receive_window_wraps() {
+        let params = create_params(u16::MAX - 2, 10);
+        assert!(!params.in_receive_window(PacketType::Command, u16::MAX - 3)
+            .0);
+        assert!(params.in_receive_window(PacketType::Command, u16::MAX).0);
+        assert!(params.in_receive_window(PacketType::Command, 0).0);
+        assert!(params.in_receive_window(PacketType::Command, 6).0);
+        assert!(!params.in_receive_window(PacketType::Command, 7).0);
+    }
+
+    #[test]
+    fn pong_measures_rtt() {
+        let mut params = create_params(0, 10);
+        params.sent_ping(3);
+        params.sent_ping(4);
+        params.sent_ping(5);
+        // An unknown pong
+        assert!(params.received_pong(7).is_none());
+        assert!(params.ping_rtt.is_none());
+        // The pong for the oldest ping arrives after the next ping was sent
+        assert!(params.received_pong(3).is_some());
+        assert!(params.ping_rtt.is_some());
+        assert_eq!(params.pings_sent.len(), 2);
+        // The answer for 4 was lost
+        assert!(params.received_pong(5).is_some());
+        assert!(params.pings_sent.is_empty());
+        assert!(params.received_pong(4).is_none());
+
+        // Only the last pings are remembered
+        for i in 0..20 {
+            params.sent_ping(i);
+        }
+        assert_eq!(params.pings_sent.len(), MAX_PENDING_PINGS);
+        assert!(params.received_pong(0).is_none());
+        assert!(params.received_pong(19).is_some());
+    }
+
+    #[test]
+    fn receive_window_default_is_half() {
+        ::init().unwrap();
+        let key = ::crypto::EccKey::create().unwrap();
+        let params = ConnectedParams::new(key, [0; 20], [0; 8]);
+        let (_, start, end) = params.in_receive_window(PacketType::Command, 0);
+        assert_eq!(end.wrapping_sub(start), u16::MAX / 2);
+    }
+
+    /// Create a client connection to `127.0.0.1:1` without parameters.
+    pub(crate) fn create_connection(core: &Core)
+        -> Rc<RefCell<Connection<SocketConnectionManager<()>>>> {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let data = HandlerData::new("127.0.0.1:0".parse().unwrap(),
+            ::crypto::EccKey::create().unwrap(), core.handle(), true,
+            SocketConnectionManager::<()>::new(), logger).unwrap();
+        let data2 = data.clone();
+        data.borrow_mut().connection_manager.set_data_ref(data2);
+        HandlerData::create_connection(data, "127.0.0.1:1".parse().unwrap())
+    }
+
+    #[test]
+    fn distributor_keeps_commands_of_full_buffer() {
+        ::init().unwrap();
+        let core = Core::new().unwrap();
+        let con = create_connection(&core);
+        {
+            let mut con = con.borrow_mut();
+            for _ in 0..::STREAM_BUFFER_MAX_SIZE {
+                con.command_buffer_stream.buffer.push_back(Packet::new(
+                    Header::new(PacketType::Command), PacketData::Ack(0)));
+                con.voice_buffer_stream.buffer.push_back(Packet::new(
+                    Header::new(PacketType::Voice), PacketData::Ack(0)));
+            }
+        }
+
+        // Commands which were queued in the codec arrive together
+        let packets = vec![
+            Packet::new(Header::new(PacketType::Command), PacketData::Ack(1)),
+            Packet::new(Header::new(PacketType::Voice), PacketData::Ack(1)),
+            Packet::new(Header::new(PacketType::Command), PacketData::Ack(2)),
+            Packet::new(Header::new(PacketType::Pong), PacketData::Pong(0)),
+        ];
+        let distributor = PacketDistributor::new(
+            futures::stream::iter_ok(packets), con.clone());
+        let rest = distributor.collect().wait().unwrap();
+
+        // The distributor does not stall, other packets are still handed out
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].header.get_type(), PacketType::Pong);
+        let con = con.borrow();
+        assert_eq!(con.command_buffer_stream.buffer.len(),
+            ::STREAM_BUFFER_MAX_SIZE + 2);
+        // Voice packets are still dropped
+        assert_eq!(con.voice_buffer_stream.buffer.len(),
+            ::STREAM_BUFFER_MAX_SIZE);
+    }
+}
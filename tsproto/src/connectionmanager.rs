@@ -3,6 +3,7 @@ use std::mem;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
 
+use chrono::Duration;
 use futures::{future, Future, Sink};
 use tokio_core::reactor::Handle;
 
@@ -10,7 +11,7 @@ use {Error, Map};
 use connection::Connection;
 use handler_data::Data;
 use packets::{PacketType, UdpPacket};
-use resend::{DefaultResender, ResendConfig, ResendFuture};
+use resend::{DefaultResender, KeepaliveFuture, ResendConfig, ResendFuture};
 
 /// Implementers of this trait store all connections for a specific socket.
 ///
@@ -51,6 +52,9 @@ pub trait ConnectionManager: Sized {
     fn get_connection(&self, key: Self::ConnectionsKey)
         -> Option<Rc<RefCell<Connection<Self>>>>;
 
+    /// The keys of all connections.
+    fn connection_keys(&self) -> Vec<Self::ConnectionsKey>;
+
     /// Find the connection for an incoming udp packet.
     fn get_connection_for_udp_packet(&self, src_addr: SocketAddr,
         udp_packet: &UdpPacket) -> Option<Rc<RefCell<Connection<Self>>>>;
@@ -117,6 +121,16 @@ pub trait Resender: Sink<SinkItem = (PacketType, u16, UdpPacket),
 
     /// Called for received udp packets.
     fn udp_packet_received(&mut self, packet: &UdpPacket);
+
+    /// Called when a round trip time was measured outside of the resender,
+    /// e.g. by answered keepalive pings.
+    fn received_rtt(&mut self, rtt: Duration);
+
+    /// The smoothed round trip time.
+    fn get_srtt(&self) -> Duration;
+
+    /// The deviation of the smoothed round trip time.
+    fn get_srtt_dev(&self) -> Duration;
 }
 
 /// An implementation of a connectionmanager, that identifies a connection its
@@ -215,6 +229,7 @@ impl<T: Default + 'static> ConnectionManager for SocketConnectionManager<T> {
         self.connections.insert(key, (Default::default(), con));
 
         let data = self.data.as_ref().unwrap().clone();
+        let data2 = data.clone();
         handle.spawn(future::lazy(move || {
             let data_tmp = data.upgrade().unwrap();
             let resend = ResendFuture::new(data_tmp.clone(), key);
@@ -224,14 +239,34 @@ impl<T: Default + 'static> ConnectionManager for SocketConnectionManager<T> {
 
             resend.map_err(move |e| {
                 error!(logger, "Resender exited with error"; "error" => ?e);
-                // Remove connection if it exists
                 if let Some(data) = data.upgrade() {
+                    if let Error::Socket(error) = e {
+                        // Let the consumer of the connection handle it
+                        let con = data.borrow().connection_manager
+                            .get_connection(key);
+                        if let Some(con) = con {
+                            con.borrow_mut().fail_commands(
+                                Error::Socket(error));
+                            return;
+                        }
+                    }
+                    // Remove connection if it exists
                     let mut data = data.borrow_mut();
                     data.connection_manager.remove_connection(key);
                 }
             })
         }));
 
+        handle.spawn(future::lazy(move || {
+            let data = data2.upgrade().unwrap();
+            let keepalive = KeepaliveFuture::new(data.clone(), key);
+            let logger = data.borrow().logger.clone();
+
+            keepalive.map_err(move |e| {
+                error!(logger, "Keepalive exited with error"; "error" => ?e);
+            })
+        }));
+
         key2
     }
 
@@ -245,6 +280,10 @@ impl<T: Default + 'static> ConnectionManager for SocketConnectionManager<T> {
         self.connections.get(&key).map(|&(_, ref c)| c.clone())
     }
 
+    fn connection_keys(&self) -> Vec<Self::ConnectionsKey> {
+        self.connections.keys().cloned().collect()
+    }
+
     fn get_connection_for_udp_packet(&self, src_addr: SocketAddr,
         _: &UdpPacket) -> Option<Rc<RefCell<Connection<Self>>>> {
         self.get_connection(src_addr)
@@ -3,6 +3,7 @@ use std::collections::VecDeque;
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
+use std::time::Instant;
 
 use {slog, slog_async, slog_term};
 use futures::{self, Future, Sink, Stream, task};
@@ -12,6 +13,7 @@ use tokio_core::net::UdpSocket;
 use tokio_core::reactor::Handle;
 
 use {Error, Result, TsCodec, StreamWrapper, SinkWrapper};
+use algorithms::CryptoParams;
 use connection::*;
 use connectionmanager::ConnectionManager;
 use packets::*;
@@ -90,6 +92,24 @@ pub struct Data<CM: ConnectionManager> {
     pub connection_manager: CM,
     /// Listen for new or removed connections.
     pub connection_listeners: Vec<Box<ConnectionListener<CM>>>,
+    /// Receives the raw bytes of packets which were dropped.
+    ///
+    /// Connections which are created afterwards use this sink too.
+    pub dropped_packet_sink: Option<DroppedPacketSink>,
+    /// The parameters of the packet encryption.
+    ///
+    /// Connections which are created afterwards use these parameters.
+    pub crypto_params: CryptoParams,
+    /// Answer pings of the other side on a separate task while the received
+    /// commands of a connection are not consumed.
+    ///
+    /// Connections which are created afterwards use this setting.
+    pub ping_responder: bool,
+    /// Collect the acks of a connection for this time and send them
+    /// together.
+    ///
+    /// Connections which are created afterwards use this setting.
+    pub ack_delay: Option<::std::time::Duration>,
 }
 
 impl<CM: ConnectionManager + 'static> Data<CM> {
@@ -115,8 +135,8 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
         let socket = UdpSocket::bind(&local_addr, &handle)?;
         let local_addr = socket.local_addr().unwrap_or(local_addr);
         let (sink, stream) = socket.framed(TsCodec::default()).split();
-        let sink = Box::new(sink.sink_map_err(|e| e.into()));
-        let stream = Box::new(stream.map_err(|e| e.into()));
+        let sink = Box::new(sink.sink_map_err(Error::Socket));
+        let stream = Box::new(stream.map_err(Error::Socket));
 
         let data = Rc::new(RefCell::new(Self {
             is_client,
@@ -132,6 +152,10 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
             distributor_task: None,
             connection_manager,
             connection_listeners: Vec::new(),
+            dropped_packet_sink: None,
+            crypto_params: Default::default(),
+            ping_responder: true,
+            ack_delay: None,
         }));
 
         // Set stream for unknown packets
@@ -476,8 +500,31 @@ impl<
             data.distributor_task = Some(task::current());
         }
 
+        let res = match self.inner.poll() {
+            Ok(res) => res,
+            Err(Error::Socket(error)) => {
+                // Pass the error to all connections, nothing can be received
+                // anymore.
+                let data = data.borrow();
+                for key in data.connection_manager.connection_keys() {
+                    if let Some(con) = data.connection_manager
+                        .get_connection(key) {
+                        let mut con = con.borrow_mut();
+                        let stream = &mut con.udp_packet_buffer_stream;
+                        stream.error = Some(Error::Socket(
+                            ::copy_io_error(&error)));
+                        if let Some(ref task) = stream.task {
+                            task.notify();
+                        }
+                    }
+                }
+                return Err(Error::Socket(error));
+            }
+            Err(error) => return Err(error),
+        };
+
         // Check if a packet is available
-        if let futures::Async::Ready(res) = self.inner.poll()? {
+        if let futures::Async::Ready(res) = res {
             if let Some((addr, packet)) = res {
                 // Find the connection
                 let mut data = data.borrow_mut();
@@ -485,12 +532,15 @@ impl<
                     addr, &packet);
                 if let Some(con) = con {
                     let mut con = con.borrow_mut();
+                    con.last_received = Instant::now();
                     if con.udp_packet_buffer_stream.buffer.len() >=
                         ::STREAM_BUFFER_MAX_SIZE {
                         warn!(data.logger,
                             "Dropping packet, stream buffer too full";
                             "length" => con.udp_packet_buffer_stream.buffer
                                 .len());
+                        report_dropped_packet(&mut con.dropped_packet_sink,
+                            DropReason::BufferFull, &packet.0);
                     } else {
                         // Add packet to queue and notify stream
                         con.udp_packet_buffer_stream.buffer.push_back(packet);
@@ -501,6 +551,11 @@ impl<
                             warn!(data.logger,
                                 "Distributor found no stream task");
                         }
+                        if con.is_receive_stalled() {
+                            if let Some(ref task) = con.ping_responder_task {
+                                task.notify();
+                            }
+                        }
                     }
                 } else {
                     // Add packet to unknown stream
@@ -508,6 +563,8 @@ impl<
                         >= ::STREAM_BUFFER_MAX_SIZE {
                         warn!(data.logger,
                             "Dropping packet, unknown stream buffer too full");
+                        report_dropped_packet(&mut data.dropped_packet_sink,
+                            DropReason::BufferFull, &packet.0);
                     } else {
                         // Add packet to queue and notify stream
                         data.unknown_stream_buffer.push_back((addr, packet));
@@ -17,6 +17,9 @@ extern crate openssl;
 extern crate quicklz;
 extern crate rand;
 extern crate ring;
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
@@ -63,7 +66,7 @@ type BoxFuture<T, E> = Box<Future<Item = T, Error = E>>;
 type Map<K, V> = std::collections::HashMap<K, V>;
 type Result<T> = std::result::Result<T, Error>;
 
-/// The maximum number of bytes for a fragmented packet.
+/// The default maximum number of bytes for a fragmented or compressed packet.
 #[cfg_attr(feature = "cargo-clippy", allow(unreadable_literal))]
 const MAX_FRAGMENTS_LENGTH: usize = 40960;
 /// The maximum number of packets which are stored, if they are received
@@ -72,9 +75,6 @@ const MAX_QUEUE_LEN: usize = 50;
 /// The maximum number of packets which are put into the stream buffer of a
 /// connection.
 const STREAM_BUFFER_MAX_SIZE: usize = 50;
-/// The maximum decompressed size of a packet.
-#[cfg_attr(feature = "cargo-clippy", allow(unreadable_literal))]
-const MAX_DECOMPRESSED_SIZE: u32 = 40960;
 const FAKE_KEY: [u8; 16] = *b"c:\\windows\\syste";
 const FAKE_NONCE: [u8; 16] = *b"m\\firewall32.cpl";
 
@@ -82,6 +82,9 @@ const FAKE_NONCE: [u8; 16] = *b"m\\firewall32.cpl";
 pub enum Error {
     #[fail(display = "{}", _0)]
     Io(std::io::Error),
+    /// The udp socket failed, e.g. because the network interface was removed.
+    #[fail(display = "Socket error: {}", _0)]
+    Socket(#[cause] std::io::Error),
     #[fail(display = "{}", _0)]
     Ring(ring::error::Unspecified),
     #[fail(display = "{}", _0)]
@@ -130,6 +133,15 @@ impl From<std::io::Error> for Error {
     }
 }
 
+/// Copy an io error, the os error code is kept.
+pub(crate) fn copy_io_error(error: &std::io::Error) -> std::io::Error {
+    if let Some(code) = error.raw_os_error() {
+        std::io::Error::from_raw_os_error(code)
+    } else {
+        std::io::Error::new(error.kind(), error.to_string())
+    }
+}
+
 impl From<ring::error::Unspecified> for Error {
     fn from(e: ring::error::Unspecified) -> Self {
         Error::Ring(e)
@@ -290,7 +302,8 @@ pub struct BufferStream<T, E> {
     pub buffer: VecDeque<T>,
     /// The task should be notified if a new packet was inserted.
     pub task: Option<Task>,
-    phantom: std::marker::PhantomData<E>,
+    /// An error which is returned after the buffer is empty.
+    pub error: Option<E>,
 }
 
 impl<T, E> Default for BufferStream<T, E> {
@@ -298,7 +311,7 @@ impl<T, E> Default for BufferStream<T, E> {
         Self {
             buffer: Default::default(),
             task: None,
-            phantom: std::marker::PhantomData,
+            error: None,
         }
     }
 }
@@ -319,6 +332,8 @@ impl<T, E> Stream for BufferStream<T, E> {
         // Check if there is a packet available
         if let Some(packet) = self.buffer.pop_front() {
             Ok(futures::Async::Ready(Some(packet)))
+        } else if let Some(error) = self.error.take() {
+            Err(error)
         } else {
             Ok(futures::Async::NotReady)
         }
@@ -1,16 +1,19 @@
 use std::cell::RefCell;
 use std::io::Cursor;
 use std::rc::{Rc, Weak};
-use std::u16;
+use std::time::Duration;
+use std::{cmp, mem, u16};
 
-use futures::{self, Sink, Stream};
-use futures::task;
+use futures::{self, Future, Sink, Stream};
+use futures::task::{self, Task};
 use num::ToPrimitive;
 use slog;
+use tokio_core::reactor::{Handle, Timeout};
 
-use {packets, Error, Result, MAX_FRAGMENTS_LENGTH, MAX_QUEUE_LEN };
+use {packets, Error, Result, MAX_QUEUE_LEN };
 use algorithms as algs;
-use connection::{Connection, ConnectedParams};
+use connection::{report_dropped_packet, Connection, ConnectedParams,
+    DropReason};
 use connectionmanager::{ConnectionManager, Resender};
 use packets::*;
 
@@ -40,7 +43,11 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
 
     /// Handle `Command` and `CommandLow` packets.
     ///
-    /// They have to be handled in the right order.
+    /// They have to be handled in the right order. Packets which arrive too
+    /// early are queued until the missing packets arrived, so commands are
+    /// always returned in the order in which the server sent them.
+    ///
+    /// The returned list is reversed, the first packet is at the end.
     fn handle_command_packet(
         logger: slog::Logger,
         params: &mut ConnectedParams,
@@ -48,6 +55,7 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
         mut packet: UdpPacket,
     ) -> Result<Vec<Packet>> {
         let mut id = header.p_id;
+        let max_size = params.max_command_size;
         let type_i = header.get_type().to_usize().unwrap();
         let cmd_i = if header.get_type() == PacketType::Command {
             0
@@ -79,7 +87,7 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
                             //debug!(logger, "Compressed"; "data" => ?::HexSlice(&frag_queue));
                             ::quicklz::decompress(
                                 &mut Cursor::new(frag_queue),
-                                ::MAX_DECOMPRESSED_SIZE,
+                                max_size as u32,
                             )?
                         } else {
                             frag_queue
@@ -102,7 +110,7 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
                     }
                 } else if let Some((_, ref mut frag_queue)) = *frag_queue {
                     // The packet is fragmented
-                    if frag_queue.len() < MAX_FRAGMENTS_LENGTH {
+                    if frag_queue.len() < max_size {
                         frag_queue.append(&mut packet.0);
                         None
                     } else {
@@ -115,7 +123,7 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
                         //debug!(logger, "Compressed"; "data" => ?::HexSlice(&packet.0));
                         ::quicklz::decompress(
                             &mut Cursor::new(packet.0),
-                            ::MAX_DECOMPRESSED_SIZE,
+                            max_size as u32,
                         )?
                     } else {
                         packet.0
@@ -176,6 +184,8 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
         };
         let con = &mut *con.borrow_mut();
         let is_client = self.is_client;
+        let crypto_params = con.crypto_params;
+        let commands_stalled = con.is_receive_stalled();
         let (header, pos) = {
             let mut r = Cursor::new(&udp_packet);
             (
@@ -198,11 +208,19 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
                 let mut ack = None;
                 let (in_recv_win, cur_next, limit) =
                     params.in_receive_window(p_type, id);
-                // Ignore range for acks
-                let res = if p_type == PacketType::Ack
+                let res = if p_type.is_command() && in_recv_win
+                    && commands_stalled {
+                    // Do not take new commands while the command buffer is
+                    // full. The packet is not acknowledged, so the server
+                    // sends it again later. Other packets are still handled.
+                    debug!(logger, "Command buffer full, ignoring command";
+                        "id" => id);
+                    Ok(Vec::new())
+                } else if p_type == PacketType::Ack
                     || p_type == PacketType::AckLow
                     || in_recv_win
                 {
+                    // Ignore range for acks
                     let gen_id = params.incoming_p_ids[type_i].0;
 
                     if !header.get_unencrypted() {
@@ -212,9 +230,10 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
                             && header.p_id == 0 && !is_client
                         {
                             let udp_packet_bak = udp_packet.clone();
-                            if algs::decrypt_fake(
+                            if algs::decrypt_fake_with(
                                 &header,
                                 &mut udp_packet,
+                                &crypto_params,
                             ).is_ok() {
                                 true
                             } else {
@@ -231,6 +250,7 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
                                 &mut udp_packet,
                                 gen_id,
                                 &params.shared_iv,
+                                &crypto_params,
                             )?
                         }
                     } else if algs::must_encrypt(header.get_type()) {
@@ -304,6 +324,17 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
                                             };
                                             con.resender.ack_packet(p_type, p_id);
                                         }
+                                        packets::Data::Pong(p_id) => {
+                                            let rtt = params
+                                                .received_pong(p_id)
+                                                .and_then(|rtt|
+                                                    ::chrono::Duration
+                                                        ::from_std(rtt)
+                                                        .ok());
+                                            if let Some(rtt) = rtt {
+                                                con.resender.received_rtt(rtt);
+                                            }
+                                        }
                                         _ => {}
                                     }
                                     Ok(vec![Packet::new(header, p_data)])
@@ -342,7 +373,8 @@ impl<CM: ConnectionManager, Inner: Stream<Item = UdpPacket, Error = Error>>
                 // Try to fake decrypt the initivexpand packet
                 if header.get_type() == PacketType::Command && is_client {
                     let udp_packet_bak = udp_packet.clone();
-                    if algs::decrypt_fake(&header, &mut udp_packet).is_ok() {
+                    if algs::decrypt_fake_with(&header, &mut udp_packet,
+                        &crypto_params).is_ok() {
                         // Send ack
                         let mut ack_header = Header::default();
                         ack_header.set_type(PacketType::Ack);
@@ -402,6 +434,28 @@ impl<CM: ConnectionManager + 'static> PacketCodecStream<CM,
 
         connection.packet_stream = Some(stream);
     }
+
+    /// Add a packet codec stream to the connection, which collects the acks
+    /// for `ack_delay` and sends them together.
+    ///
+    /// The acks are sent by an [`AckBatcher`], which is spawned on `handle`.
+    /// The delay is limited to [`MAX_ACK_DELAY_MS`].
+    ///
+    /// [`AckBatcher`]: struct.AckBatcher.html
+    /// [`MAX_ACK_DELAY_MS`]: constant.MAX_ACK_DELAY_MS.html
+    pub fn apply_with_ack_delay(connection: Rc<RefCell<Connection<CM>>>,
+        ack_delay: Duration, handle: &Handle) {
+        let ack_delay = cmp::min(ack_delay,
+            Duration::from_millis(MAX_ACK_DELAY_MS));
+        let queue = Rc::new(RefCell::new(AckQueue::default()));
+        AckBatcher::apply(connection.clone(), queue.clone(), ack_delay,
+            handle);
+
+        let stream = Self::new(connection.clone(),
+            Connection::get_udp_packets(connection.clone()));
+        connection.borrow_mut().packet_stream = Some(Box::new(
+            AckHandler::new(stream, DelayedAcks(queue))));
+    }
 }
 
 impl<
@@ -422,8 +476,24 @@ impl<
         }
         // Don't return an error, that will terminate the stream (log them only)
         let res: Result<_> = match self.inner.poll()? {
-            futures::Async::Ready(Some(UdpPacket(mut udp_packet))) =>
-                self.on_packet_received(udp_packet),
+            futures::Async::Ready(Some(UdpPacket(mut udp_packet))) => {
+                // Keep the raw bytes if someone wants to see dropped packets
+                let raw = self.connection.upgrade().and_then(|con|
+                    if con.borrow().dropped_packet_sink.is_some() {
+                        Some(udp_packet.clone())
+                    } else {
+                        None
+                    });
+                let res = self.on_packet_received(udp_packet);
+                if let (true, Some(raw)) = (res.is_err(), raw) {
+                    if let Some(con) = self.connection.upgrade() {
+                        report_dropped_packet(
+                            &mut con.borrow_mut().dropped_packet_sink,
+                            DropReason::Invalid, &raw);
+                    }
+                }
+                res
+            }
             futures::Async::Ready(None) => Ok(futures::Async::Ready(None)),
             futures::Async::NotReady => Ok(futures::Async::NotReady),
         };
@@ -506,6 +576,11 @@ impl<
             if !self.other_send_buffer.is_empty() {
                 self.poll_complete()?;
                 if !self.other_send_buffer.is_empty() {
+                    if p_type.is_voice() {
+                        if let Some(con) = self.connection.upgrade() {
+                            con.borrow_mut().voice_send_congested = true;
+                        }
+                    }
                     return Ok(futures::AsyncSink::NotReady(packet));
                 }
             }
@@ -522,6 +597,7 @@ impl<
             // Get the connection parameters
             let con = self.connection.upgrade().unwrap();
             let mut con = con.borrow_mut();
+            let crypto_params = con.crypto_params;
             if let Some(params) = con.params.as_mut() {
                 let p_type = packet.header.get_type();
                 let type_i = p_type.to_usize().unwrap();
@@ -542,6 +618,9 @@ impl<
                         // Get packet id
                         let (mut gen, mut p_id) = params.outgoing_p_ids[type_i];
                         header.p_id = p_id;
+                        if header.get_type() == PacketType::Ping {
+                            params.sent_ping(p_id);
+                        }
 
                         // Client id for clients
                         if is_client {
@@ -564,13 +643,15 @@ impl<
                             if header.get_type() == PacketType::Command
                                 && !is_client && header.p_id == 0
                             {
-                                algs::encrypt_fake(&mut header, &mut p_data)?;
+                                algs::encrypt_fake_with(&mut header,
+                                    &mut p_data, &crypto_params)?;
                             } else {
                                 algs::encrypt(
                                     &mut header,
                                     &mut p_data,
                                     gen,
                                     &params.shared_iv,
+                                    &crypto_params,
                                 )?;
                             }
                         } else {
@@ -605,7 +686,8 @@ impl<
                 // Fake encrypt if needed
                 if algs::should_encrypt(header.get_type(), false) {
                     header.set_unencrypted(false);
-                    algs::encrypt_fake(&mut header, &mut p_data)?;
+                    algs::encrypt_fake_with(&mut header, &mut p_data,
+                        &crypto_params)?;
                 }
 
                 let mut buf = Vec::new();
@@ -623,6 +705,11 @@ impl<
             self.command_p_type = p_type;
         } else {
             self.other_send_buffer = packets;
+            if p_type.is_voice() {
+                if let Some(con) = self.connection.upgrade() {
+                    con.borrow_mut().voice_send_congested = false;
+                }
+            }
         }
         Ok(futures::AsyncSink::Ready)
     }
@@ -755,3 +842,504 @@ impl<
         }
     }
 }
+
+/// The maximum time for which acks are collected.
+///
+/// The other side sends a command again if its ack takes too long, which
+/// costs more packets than batching the acks saves.
+pub const MAX_ACK_DELAY_MS: u64 = 100;
+/// The maximum number of acks which are collected before they are sent.
+pub const MAX_DELAYED_ACKS: usize = 16;
+
+/// The acks which are collected for an [`AckBatcher`].
+///
+/// [`AckBatcher`]: struct.AckBatcher.html
+#[derive(Default)]
+pub struct AckQueue {
+    acks: Vec<Packet>,
+    /// The task of the batcher, which is notified about new acks.
+    task: Option<Task>,
+}
+
+/// The sink of an [`AckHandler`] when acks are sent with a delay.
+///
+/// The acks are put into the [`AckQueue`] of the [`AckBatcher`]. The
+/// protocol has no cumulative acks, so every packet keeps its own ack, only
+/// duplicate acks for a packet which was resent are dropped.
+///
+/// [`AckHandler`]: struct.AckHandler.html
+/// [`AckQueue`]: struct.AckQueue.html
+/// [`AckBatcher`]: struct.AckBatcher.html
+pub struct DelayedAcks(Rc<RefCell<AckQueue>>);
+
+impl Sink for DelayedAcks {
+    type SinkItem = Packet;
+    type SinkError = Error;
+
+    fn start_send(&mut self, packet: Packet)
+        -> futures::StartSend<Self::SinkItem, Self::SinkError> {
+        let mut queue = self.0.borrow_mut();
+        let key = ack_key(&packet);
+        if key.is_none() || queue.acks.iter().all(|p| ack_key(p) != key) {
+            queue.acks.push(packet);
+            if let Some(ref task) = queue.task {
+                task.notify();
+            }
+        }
+        Ok(futures::AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> futures::Poll<(), Self::SinkError> {
+        Ok(futures::Async::Ready(()))
+    }
+}
+
+/// The packet type and id which is acknowledged by an `Ack`, `AckLow` or
+/// `Pong` packet.
+fn ack_key(packet: &Packet) -> Option<(PacketType, u16)> {
+    match packet.data {
+        packets::Data::Ack(id) |
+        packets::Data::AckLow(id) |
+        packets::Data::Pong(id) => Some((packet.header.get_type(), id)),
+        _ => None,
+    }
+}
+
+/// Sends the acks of a connection, which are collected in an [`AckQueue`].
+///
+/// The acks are sent together when the first of them waited for the delay or
+/// when [`MAX_DELAYED_ACKS`] acks are waiting. This runs on its own task, so
+/// the timer also fires while the received packets are not consumed.
+///
+/// [`AckQueue`]: struct.AckQueue.html
+/// [`MAX_DELAYED_ACKS`]: constant.MAX_DELAYED_ACKS.html
+pub struct AckBatcher<CM: ConnectionManager + 'static> {
+    connection: Weak<RefCell<Connection<CM>>>,
+    sink: ::connection::Packets<CM>,
+    queue: Rc<RefCell<AckQueue>>,
+    delay: Duration,
+    handle: Handle,
+    /// Runs while acks are collected.
+    timer: Option<Timeout>,
+    /// Acks which were not yet sent.
+    send_buffer: Vec<Packet>,
+    /// If we have put a packet into the sink and should poll for completion.
+    should_poll_complete: bool,
+}
+
+impl<CM: ConnectionManager + 'static> AckBatcher<CM> {
+    pub fn new(connection: Rc<RefCell<Connection<CM>>>,
+        queue: Rc<RefCell<AckQueue>>, delay: Duration, handle: Handle)
+        -> Self {
+        Self {
+            connection: Rc::downgrade(&connection),
+            sink: Connection::get_packets(connection),
+            queue,
+            delay,
+            handle,
+            timer: None,
+            send_buffer: Vec::new(),
+            should_poll_complete: false,
+        }
+    }
+
+    /// Spawn an ack batcher for the connection.
+    pub fn apply(connection: Rc<RefCell<Connection<CM>>>,
+        queue: Rc<RefCell<AckQueue>>, delay: Duration, handle: &Handle) {
+        let logger = connection.borrow().logger.clone();
+        handle.spawn(Self::new(connection, queue, delay, handle.clone())
+            .map_err(move |e| {
+                error!(logger, "Ack batcher exited with error"; "error" => ?e);
+            }));
+    }
+}
+
+impl<CM: ConnectionManager + 'static> Future for AckBatcher<CM> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        if self.connection.upgrade().is_none() {
+            // The connection does not exist anymore
+            return Ok(futures::Async::Ready(()));
+        }
+
+        if self.send_buffer.is_empty() {
+            let mut queue = self.queue.borrow_mut();
+            queue.task = Some(task::current());
+            if !queue.acks.is_empty() {
+                if self.timer.is_none() {
+                    self.timer = Some(Timeout::new(self.delay,
+                        &self.handle)?);
+                }
+                let expired = self.timer.as_mut().unwrap().poll()?
+                    .is_ready();
+                if expired || queue.acks.len() >= MAX_DELAYED_ACKS {
+                    self.timer = None;
+                    self.send_buffer = mem::replace(&mut queue.acks,
+                        Vec::new());
+                }
+            }
+        }
+
+        while !self.send_buffer.is_empty() {
+            let p = self.send_buffer.remove(0);
+            if let futures::AsyncSink::NotReady(p) =
+                self.sink.start_send(p)? {
+                self.send_buffer.insert(0, p);
+                break;
+            }
+            self.should_poll_complete = true;
+        }
+
+        if self.should_poll_complete {
+            if let futures::Async::Ready(()) = self.sink.poll_complete()? {
+                self.should_poll_complete = false;
+            }
+        }
+        if self.send_buffer.is_empty() && !self.queue.borrow().acks.is_empty()
+            && self.timer.is_none() {
+            // More acks arrived while sending, start the next batch
+            task::current().notify();
+        }
+        Ok(futures::Async::NotReady)
+    }
+}
+
+/// Answers pings of the other side while the received commands of a
+/// connection are not consumed.
+///
+/// The packet codec ignores new commands in this case, so the udp packet
+/// queue fills up with commands which the server sends again. This task takes
+/// the pings out of the queue of received udp packets and sends the pongs
+/// directly, so they do not wait behind the ignored commands.
+pub struct PingResponder<CM: ConnectionManager + 'static> {
+    connection: Weak<RefCell<Connection<CM>>>,
+    sink: ::connection::Packets<CM>,
+    /// Pongs which were not yet sent.
+    send_buffer: Vec<Packet>,
+    /// If we have put a packet into the sink and should poll for completion.
+    should_poll_complete: bool,
+}
+
+impl<CM: ConnectionManager + 'static> PingResponder<CM> {
+    pub fn new(connection: Rc<RefCell<Connection<CM>>>) -> Self {
+        Self {
+            connection: Rc::downgrade(&connection),
+            sink: Connection::get_packets(connection),
+            send_buffer: Vec::new(),
+            should_poll_complete: false,
+        }
+    }
+
+    /// Spawn a ping responder for the connection.
+    pub fn apply(connection: Rc<RefCell<Connection<CM>>>, handle: &Handle) {
+        let logger = connection.borrow().logger.clone();
+        handle.spawn(Self::new(connection).map_err(move |e| {
+            error!(logger, "Ping responder exited with error"; "error" => ?e);
+        }));
+    }
+
+    /// Remove the pings from the received udp packets and create the answers.
+    fn take_pings(con: &mut Connection<CM>) -> Vec<Packet> {
+        let mut pongs = Vec::new();
+        let is_client = con.is_client;
+        let params = if let Some(ref mut params) = con.params {
+            params
+        } else {
+            return pongs;
+        };
+        let stats = &mut con.stats;
+        let type_stats = &mut con.packet_type_stats;
+        let type_i = PacketType::Ping.to_usize().unwrap();
+        con.udp_packet_buffer_stream.buffer.retain(|packet| {
+            let header = match Header::read(&!is_client,
+                &mut Cursor::new(&packet.0)) {
+                Ok(header) => header,
+                Err(_) => return true,
+            };
+            // Pings are never encrypted, everything else is left to the
+            // packet codec.
+            if header.get_type() != PacketType::Ping
+                || !header.get_unencrypted()
+                || !params.in_receive_window(PacketType::Ping, header.p_id).0 {
+                return true;
+            }
+            stats.packets_received += 1;
+            stats.bytes_received += packet.0.len() as u64;
+            type_stats.entry(PacketType::Ping).or_insert((0, 0)).1 += 1;
+
+            // Update packet ids
+            let id = header.p_id.wrapping_add(1);
+            if id < params.incoming_p_ids[type_i].1 {
+                params.incoming_p_ids[type_i].0 =
+                    params.incoming_p_ids[type_i].0.wrapping_add(1);
+            }
+            params.incoming_p_ids[type_i].1 = id;
+
+            let mut pong_header = Header::default();
+            pong_header.set_type(PacketType::Pong);
+            pongs.push(Packet::new(pong_header,
+                packets::Data::Pong(header.p_id)));
+            false
+        });
+        pongs
+    }
+}
+
+impl<CM: ConnectionManager + 'static> Future for PingResponder<CM> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        let con = if let Some(con) = self.connection.upgrade() {
+            con
+        } else {
+            // The connection does not exist anymore
+            return Ok(futures::Async::Ready(()));
+        };
+        {
+            let mut con = con.borrow_mut();
+            con.ping_responder_task = Some(task::current());
+            // Otherwise the packet codec answers the pings
+            if con.is_receive_stalled() {
+                let mut pongs = Self::take_pings(&mut *con);
+                self.send_buffer.append(&mut pongs);
+            }
+        }
+
+        while !self.send_buffer.is_empty() {
+            let p = self.send_buffer.remove(0);
+            if let futures::AsyncSink::NotReady(p) =
+                self.sink.start_send(p)? {
+                self.send_buffer.insert(0, p);
+                break;
+            }
+            self.should_poll_complete = true;
+        }
+
+        if self.should_poll_complete {
+            if let futures::Async::Ready(()) = self.sink.poll_complete()? {
+                self.should_poll_complete = false;
+            }
+        }
+        Ok(futures::Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use futures::{self, Future, Sink, Stream};
+    use num::ToPrimitive;
+    use slog;
+    use tokio_core::reactor::Core;
+
+    use connection::{ConnectedParams, Connection};
+    use connectionmanager::SocketConnectionManager;
+    use packet_codec::*;
+
+    type Codec = PacketCodecStream<SocketConnectionManager<()>,
+        Box<Stream<Item = UdpPacket, Error = Error>>>;
+
+    fn create_params() -> ConnectedParams {
+        ::init().unwrap();
+        let key = ::crypto::EccKey::create().unwrap();
+        ConnectedParams::new(key, [0; 20], [0; 8])
+    }
+
+    fn handle(params: &mut ConnectedParams, p_id: u16, data: &str)
+        -> Vec<Packet> {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut header = Header::new(PacketType::Command);
+        header.p_id = p_id;
+        Codec::handle_command_packet(logger, params, header,
+            UdpPacket(data.as_bytes().to_vec())).unwrap()
+    }
+
+    fn command_names(mut packets: Vec<Packet>) -> Vec<String> {
+        // The stream returns the packets from the end of the list
+        packets.reverse();
+        packets.into_iter().map(|p| match p.data {
+            Data::Command(cmd) => cmd.command,
+            _ => panic!("Expected a command"),
+        }).collect()
+    }
+
+    #[test]
+    fn out_of_order_commands_are_sorted() {
+        let mut params = create_params();
+        assert!(handle(&mut params, 2, "notifyclientmoved clid=2 ctid=1")
+            .is_empty());
+        assert!(handle(&mut params, 1, "notifycliententerview clid=2")
+            .is_empty());
+        let packets = handle(&mut params, 0, "notifychannelcreated cid=1");
+        assert_eq!(command_names(packets), vec!["notifychannelcreated",
+            "notifycliententerview", "notifyclientmoved"]);
+
+        let cmd_i = PacketType::Command.to_usize().unwrap();
+        assert_eq!(params.incoming_p_ids[cmd_i].1, 3);
+        assert!(params.receive_queue[0].is_empty());
+    }
+
+    #[test]
+    fn interleaved_commands_keep_order() {
+        let mut params = create_params();
+        let mut names = command_names(handle(&mut params, 0,
+            "notifycliententerview clid=2"));
+        assert!(handle(&mut params, 3, "notifyclientleftview clid=2")
+            .is_empty());
+        names.extend(command_names(handle(&mut params, 1,
+            "notifyclientmoved clid=2 ctid=1")));
+        names.extend(command_names(handle(&mut params, 2,
+            "notifyclientmoved clid=2 ctid=2")));
+        assert_eq!(names, vec!["notifycliententerview", "notifyclientmoved",
+            "notifyclientmoved", "notifyclientleftview"]);
+    }
+
+    /// Collects the sent packets.
+    struct TestSink(Rc<RefCell<Vec<Packet>>>);
+
+    impl Sink for TestSink {
+        type SinkItem = Packet;
+        type SinkError = Error;
+
+        fn start_send(&mut self, p: Packet)
+            -> futures::StartSend<Packet, Error> {
+            self.0.borrow_mut().push(p);
+            Ok(futures::AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> futures::Poll<(), Error> {
+            Ok(futures::Async::Ready(()))
+        }
+    }
+
+    fn create_connection(core: &Core)
+        -> Rc<RefCell<Connection<SocketConnectionManager<()>>>> {
+        let params = create_params();
+        let con = ::connection::tests::create_connection(core);
+        con.borrow_mut().params = Some(params);
+        con
+    }
+
+    fn udp_packet(p_type: PacketType, id: u16, data: &[u8]) -> UdpPacket {
+        let mut header = Header::new(p_type);
+        header.p_id = id;
+        header.set_unencrypted(!::algorithms::must_encrypt(p_type));
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        buf.extend_from_slice(data);
+        UdpPacket(buf)
+    }
+
+    #[test]
+    fn idle_connection_answers_pings() {
+        let core = Core::new().unwrap();
+        let con = create_connection(&core);
+
+        // The server sends no commands, only pings
+        let pings = (0..100).map(|id| udp_packet(PacketType::Ping, id, &[]))
+            .collect::<Vec<_>>();
+        let stream: Box<Stream<Item = UdpPacket, Error = Error>> =
+            Box::new(futures::stream::iter_ok(pings));
+        let pongs = Codec::new(con.clone(), stream)
+            .filter_map(|(_, ack)| ack).collect().wait().unwrap();
+
+        // Every ping is answered, so the server keeps the connection
+        assert_eq!(pongs.len(), 100);
+        for (i, p) in pongs.iter().enumerate() {
+            assert_eq!(p.header.get_type(), PacketType::Pong);
+            match p.data {
+                Data::Pong(id) => assert_eq!(id, i as u16),
+                _ => panic!("Expected a pong"),
+            }
+        }
+        let ping_i = PacketType::Ping.to_usize().unwrap();
+        assert_eq!(con.borrow().params.as_ref().unwrap()
+            .incoming_p_ids[ping_i].1, 100);
+    }
+
+    #[test]
+    fn full_command_buffer_ignores_only_commands() {
+        let core = Core::new().unwrap();
+        let con = create_connection(&core);
+        for _ in 0..::STREAM_BUFFER_MAX_SIZE {
+            con.borrow_mut().command_buffer_stream.buffer.push_back(
+                Packet::new(Header::new(PacketType::Command), Data::Ack(0)));
+        }
+
+        // The ignored command is not decrypted, so its content is irrelevant
+        let packets = vec![
+            udp_packet(PacketType::Command, 0, b"clientinit"),
+            udp_packet(PacketType::Ping, 0, &[]),
+        ];
+        let stream: Box<Stream<Item = UdpPacket, Error = Error>> =
+            Box::new(futures::stream::iter_ok(packets));
+        let res = Codec::new(con.clone(), stream).collect().wait().unwrap();
+
+        // The command is neither handed out nor acknowledged, so the server
+        // sends it again, but the ping is still answered.
+        assert_eq!(res.len(), 1);
+        let (ref ping, ref pong) = res[0];
+        assert_eq!(ping.as_ref().unwrap().header.get_type(), PacketType::Ping);
+        assert_eq!(pong.as_ref().unwrap().header.get_type(), PacketType::Pong);
+
+        let con = con.borrow();
+        let params = con.params.as_ref().unwrap();
+        let cmd_i = PacketType::Command.to_usize().unwrap();
+        assert_eq!(params.incoming_p_ids[cmd_i].1, 0);
+        assert_eq!(con.command_buffer_stream.buffer.len(),
+            ::STREAM_BUFFER_MAX_SIZE);
+    }
+
+    #[test]
+    fn acks_are_sent_with_their_packet() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let packets = (0..3).map(|i| {
+            let command = Packet::new(Header::new(PacketType::Command),
+                Data::Ack(i));
+            let ack = Packet::new(Header::new(PacketType::Ack),
+                Data::Ack(i));
+            (Some(command), Some(ack))
+        }).collect::<Vec<_>>();
+        let mut handler = AckHandler::new(futures::stream::iter_ok(packets),
+            TestSink(sent.clone())).wait();
+
+        for i in 0..3 {
+            // Every ack is sent before its packet is handed out
+            handler.next().unwrap().unwrap();
+            let sent = sent.borrow();
+            assert_eq!(sent.len(), i + 1);
+            match sent[i].data {
+                Data::Ack(id) => assert_eq!(id, i as u16),
+                _ => panic!("Expected an ack"),
+            }
+        }
+        assert!(handler.next().is_none());
+    }
+
+    #[test]
+    fn delayed_acks_drop_duplicates() {
+        let queue = Rc::new(RefCell::new(AckQueue::default()));
+        // The second packet was resent by the server
+        let packets = [0, 0, 1].iter().map(|&i| {
+            let command = Packet::new(Header::new(PacketType::Command),
+                Data::Ack(i));
+            let ack = Packet::new(Header::new(PacketType::Ack),
+                Data::Ack(i));
+            (Some(command), Some(ack))
+        }).collect::<Vec<_>>();
+        let handler = AckHandler::new(futures::stream::iter_ok(packets),
+            DelayedAcks(queue.clone()));
+        assert_eq!(handler.collect().wait().unwrap().len(), 3);
+
+        let ids = queue.borrow().acks.iter().map(|p| match p.data {
+            Data::Ack(id) => id,
+            _ => panic!("Expected an ack"),
+        }).collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 1]);
+    }
+}
@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::cmp::{Ord, Ordering};
+use std::cmp::{self, Ord, Ordering};
 use std::collections::{binary_heap, BinaryHeap};
 use std::convert::From;
 use std::mem;
@@ -7,9 +7,10 @@ use std::ops::{Deref, DerefMut};
 use std::rc::{Rc, Weak};
 use std::time::Instant;
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::Duration;
 use futures::{self, Future, Sink};
 use futures::task::{self, Task};
+use rand::{self, Rng};
 use slog::Logger;
 use tokio_core::reactor::Timeout;
 
@@ -23,9 +24,9 @@ use packets::*;
 #[derive(Clone, Debug)]
 struct SendRecord {
     /// When this packet was sent.
-    pub sent: DateTime<Utc>,
+    pub sent: Instant,
     /// The last time when the packet was sent.
-    pub last: DateTime<Utc>,
+    pub last: Instant,
     /// How often the packet was already resent.
     pub tries: usize,
     pub p_type: PacketType,
@@ -71,7 +72,7 @@ impl Ord for SendRecord {
 
 /// An implementation of a [`Resender`] that is provided by this library.
 ///
-/// [`Resender`]:
+/// [`Resender`]: ../connectionmanager/trait.Resender.html
 pub struct DefaultResender {
     logger: Logger,
 
@@ -82,6 +83,8 @@ pub struct DefaultResender {
     srtt: Duration,
     /// Deviation of the srtt.
     srtt_dev: Duration,
+    /// The last measured round trip time.
+    last_rtt: Option<Duration>,
 
     /// The task of the sink, which is used to put new packets into the queue.
     ///
@@ -103,12 +106,13 @@ impl DefaultResender {
         Self {
             logger,
             state: ResendStates::Connecting {
-                start_time: Utc::now(),
+                start_time: Instant::now(),
                 to_send: Default::default(),
             },
             config,
             srtt,
             srtt_dev,
+            last_rtt: None,
 
             resender_task: Vec::new(),
             resender_future_task: None,
@@ -124,6 +128,56 @@ impl DefaultResender {
         };
         self.srtt_dev = self.srtt_dev * 3 / 4 + diff / 4;
         self.srtt = self.srtt * 7 / 8 + rtt / 8;
+        self.last_rtt = Some(rtt);
+    }
+
+    /// The interval to resend packets in [`Connecting`] state, before the
+    /// backoff is applied.
+    ///
+    /// The smoothed rtt needs many samples until it reflects the real round
+    /// trip time, so the last measured rtt of the handshake is used instead.
+    /// On links with a high latency, this avoids sending the next handshake
+    /// packet again before the answer could arrive.
+    ///
+    /// [`Connecting`]: enum.ResendState.html
+    fn connecting_interval(&self) -> Duration {
+        match self.last_rtt {
+            Some(rtt) if rtt * 2 > self.config.connecting_interval => rtt * 2,
+            _ => self.config.connecting_interval,
+        }
+    }
+
+    /// The current state of the resender.
+    pub fn state(&self) -> ResendState {
+        match self.state {
+            ResendStates::Connecting { .. } => ResendState::Connecting,
+            ResendStates::Normal { .. } => ResendState::Normal,
+            ResendStates::Stalling { .. } => ResendState::Stalling,
+            ResendStates::Dead { .. } => ResendState::Dead,
+            ResendStates::Disconnecting { .. } => ResendState::Disconnecting,
+        }
+    }
+
+    /// The smoothed round trip time.
+    pub fn srtt(&self) -> Duration {
+        self.srtt
+    }
+
+    /// The deviation of the smoothed round trip time.
+    pub fn srtt_dev(&self) -> Duration {
+        self.srtt_dev
+    }
+
+    /// The number of packets which were not yet acknowledged.
+    pub fn queue_len(&self) -> usize {
+        // The queues are a `BinaryHeap` or a `Vec`, so they need two arms
+        match self.state {
+            ResendStates::Connecting    { ref to_send, .. } |
+            ResendStates::Disconnecting { ref to_send, .. } |
+            ResendStates::Normal        { ref to_send, .. } => to_send.len(),
+            ResendStates::Stalling      { ref to_send, .. } |
+            ResendStates::Dead          { ref to_send, .. } => to_send.len(),
+        }
     }
 
     /// Replaces the current state by a new state and return the old state.
@@ -191,9 +245,7 @@ impl Resender for DefaultResender {
         if let Some(rec) = rec {
             // Update srtt only if the packet was not resent
             if rec.tries == 1 {
-                let now = Utc::now();
-                let diff = now.naive_utc().signed_duration_since(
-                    rec.sent.naive_utc());
+                let diff = Duration::from_std(rec.sent.elapsed()).unwrap();
                 self.update_srtt(diff);
             }
         }
@@ -259,7 +311,7 @@ impl Resender for DefaultResender {
             ResenderEvent::Connecting |
             ResenderEvent::Disconnecting => {
                 // Switch to connecting or disconnecting state
-                let now = Utc::now();
+                let now = Instant::now();
                 let to_send = match self.state {
                     ResendStates::Stalling { ref mut to_send, .. } |
                     ResendStates::Dead     { ref mut to_send, .. } => {
@@ -329,7 +381,7 @@ impl Resender for DefaultResender {
                 // Switch to Stalling if the connection was dead
                 Some(ResendStates::Stalling {
                     to_send,
-                    start_time: Utc::now(),
+                    start_time: Instant::now(),
                 })
             }
             // We will switch to Normal from stalling after we received an ack
@@ -344,6 +396,18 @@ impl Resender for DefaultResender {
             }
         }
     }
+
+    fn received_rtt(&mut self, rtt: Duration) {
+        self.update_srtt(rtt);
+    }
+
+    fn get_srtt(&self) -> Duration {
+        self.srtt
+    }
+
+    fn get_srtt_dev(&self) -> Duration {
+        self.srtt_dev
+    }
 }
 
 impl Sink for DefaultResender {
@@ -353,8 +417,8 @@ impl Sink for DefaultResender {
     fn start_send(&mut self, (p_type, p_id, packet): Self::SinkItem)
         -> futures::StartSend<Self::SinkItem, Self::SinkError> {
         let rec = SendRecord {
-            sent: Utc::now(),
-            last: Utc::now(),
+            sent: Instant::now(),
+            last: Instant::now(),
             tries: 0,
             p_type,
             p_id,
@@ -365,16 +429,23 @@ impl Sink for DefaultResender {
         // otherwise, put it into rec_res.
         let mut rec_res = None;
         match self.state {
-            ResendStates::Connecting    { to_send: ref mut v, ref mut start_time } |
-            ResendStates::Disconnecting { to_send: ref mut v, ref mut start_time } => {
+            ResendStates::Connecting { to_send: ref mut v, ref mut start_time } => {
                 if v.len() >= self.config.max_send_queue_len {
                     rec_res = Some(rec);
                 } else {
                     v.push(rec);
                     // Update start time
-                    *start_time = Utc::now();
+                    *start_time = Instant::now();
                 }
             }
+            ResendStates::Disconnecting { to_send: ref mut v, ref mut start_time } => {
+                // Ignore the queue limit, the disconnect packet has to be sent
+                // even if the queue is full. Otherwise the server keeps our
+                // client until it times out.
+                v.push(rec);
+                // Update start time
+                *start_time = Instant::now();
+            }
             ResendStates::Stalling      { to_send: ref mut v, .. } |
             ResendStates::Dead          { to_send: ref mut v, .. } => {
                 if v.len() >= self.config.max_send_queue_len {
@@ -411,6 +482,19 @@ impl Sink for DefaultResender {
     }
 }
 
+/// The state of a [`DefaultResender`], without the queued packets.
+///
+/// [`DefaultResender`]: struct.DefaultResender.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub enum ResendState {
+    Connecting,
+    Normal,
+    Stalling,
+    Dead,
+    Disconnecting,
+}
+
 /// State per connection
 ///
 /// In `Vec`s, the first element is the element that should be sent first, new
@@ -422,7 +506,7 @@ enum ResendStates {
     /// The `Vec` is unsorted in this case as there exists no real sorting.
     Connecting {
         to_send: BinaryHeap<SendRecord>,
-        start_time: DateTime<Utc>,
+        start_time: Instant,
     },
     /// Everything is clear, normal operation.
     Normal {
@@ -434,20 +518,20 @@ enum ResendStates {
     /// No voice packets are sent in this mode.
     Stalling {
         to_send: Vec<SendRecord>,
-        start_time: DateTime<Utc>,
+        start_time: Instant,
     },
     /// Resending did not succeed for a longer time. Don't even try anymore.
     ///
     /// No voice packets are sent in this mode.
     Dead {
         to_send: Vec<SendRecord>,
-        start_time: DateTime<Utc>,
+        start_time: Instant,
     },
     /// Sent the packet to close the connection, but the acknowledgement was not
     /// yet received.
     Disconnecting {
         to_send: BinaryHeap<SendRecord>,
-        start_time: DateTime<Utc>,
+        start_time: Instant,
     },
 }
 
@@ -527,7 +611,16 @@ impl<'a, T: Ord + 'a> From<binary_heap::PeekMut<'a, T>> for PeekMut<'a, T> {
 #[derive(Clone, Debug)]
 pub struct ResendConfig {
     /// Interval to resend the first packet.
+    ///
+    /// If the round trip time of an earlier handshake packet was measured, the
+    /// interval is at least twice this time.
     pub connecting_interval: Duration,
+    /// The factor by which the `connecting_interval` grows with every resend
+    /// of the same packet.
+    ///
+    /// The interval does not grow beyond the `connecting_timeout`. A factor
+    /// of `1.0` resends in a fixed interval.
+    pub connecting_backoff: f32,
     /// Timeout to give up sending the first packet and close the connection.
     pub connecting_timeout: Duration,
     /// Swith to [`Stalling`] when no awaited response was received after this
@@ -553,13 +646,30 @@ pub struct ResendConfig {
     pub srtt_dev: Duration,
 
     /// The maximum number of not acknowledged packets which are stored.
+    ///
+    /// The limit does not apply in [`Disconnecting`] state.
+    ///
+    /// [`Disconnecting`]: enum.ResendState.html#variant.Disconnecting
     pub max_send_queue_len: usize,
+
+    /// Interval to send ping packets to keep the connection alive.
+    ///
+    /// Intervals shorter than a millisecond are raised to one millisecond.
+    pub keepalive_interval: Duration,
+    /// Random variation of the keepalive interval, relative to the interval.
+    ///
+    /// A value of `0.1` spreads the pings between 90 % and 110 % of
+    /// `keepalive_interval`, so many connections that were started at the same
+    /// time do not send their pings in bursts. Values above `0.9` are lowered
+    /// to `0.9`, negative values and `NaN` disable the jitter.
+    pub keepalive_jitter: f32,
 }
 
 impl Default for ResendConfig {
     fn default() -> Self {
         ResendConfig {
             connecting_interval: Duration::seconds(1),
+            connecting_backoff: 1.5,
             connecting_timeout: Duration::seconds(5),
             normal_timeout: Duration::seconds(10),
             stalling_interval: Duration::seconds(5),
@@ -572,7 +682,42 @@ impl Default for ResendConfig {
             srtt_dev: Duration::milliseconds(0),
 
             max_send_queue_len: 50,
+
+            keepalive_interval: Duration::seconds(1),
+            keepalive_jitter: 0.1,
+        }
+    }
+}
+
+impl ResendConfig {
+    /// The time until a packet in [`Connecting`] state is sent again after it
+    /// was sent `tries` times.
+    ///
+    /// [`Connecting`]: enum.ResendState.html
+    fn connecting_resend_interval(&self, interval: Duration, tries: usize)
+        -> Duration {
+        let exponent = cmp::min(tries.saturating_sub(1),
+            i32::max_value() as usize) as i32;
+        let factor = f64::from(self.connecting_backoff.max(1.0))
+            .powi(exponent);
+        let ms = interval.num_milliseconds() as f64 * factor;
+        let max = self.connecting_timeout.num_milliseconds() as f64;
+        Duration::milliseconds(ms.min(max) as i64)
+    }
+
+    /// The time until the next keepalive packet should be sent, including the
+    /// random jitter.
+    fn next_keepalive(&self) -> Duration {
+        let interval = cmp::max(self.keepalive_interval,
+            Duration::milliseconds(1));
+        let jitter = f64::from(self.keepalive_jitter);
+        if jitter.is_nan() || jitter <= 0.0 {
+            return interval;
         }
+        let jitter = jitter.min(0.9);
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter, jitter);
+        let ms = interval.num_milliseconds() as f64 * factor;
+        Duration::milliseconds(cmp::max(ms as i64, 1))
     }
 }
 
@@ -647,8 +792,7 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
             }
         }
 
-        let now = Utc::now();
-        let now_naive = now.naive_utc();
+        let now = Instant::now();
 
         // Check if we are over time in the current state
         enum StateChange {
@@ -662,17 +806,13 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
             let resender = &mut con.resender;
             match resender.state {
                 ResendStates::Connecting { ref start_time, .. } =>
-                    if now_naive.signed_duration_since(start_time.naive_utc())
-                        >= resender.config
-                            .connecting_timeout {
+                    if now >= *start_time + resender.config
+                        .connecting_timeout.to_std().unwrap() {
                         StateChange::EndConnection
                     } else {
                         // Schedule timeout
-                        let dur = (*start_time + resender.config
-                                .connecting_timeout)
-                            .naive_utc()
-                            .signed_duration_since(now_naive);
-                        let next = Instant::now() + dur.to_std().unwrap();
+                        let next = *start_time + resender.config
+                            .connecting_timeout.to_std().unwrap();
                         self.state_timeout.reset(next);
                         if let futures::Async::Ready(()) =
                             self.state_timeout.poll()? {
@@ -682,19 +822,16 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
                     }
                 ResendStates::Normal { .. } => StateChange::Nothing,
                 ResendStates::Stalling { ref mut to_send, ref start_time } =>
-                    if now_naive.signed_duration_since(start_time.naive_utc())
-                        >= resender.config.stalling_timeout {
+                    if now >= *start_time + resender.config
+                        .stalling_timeout.to_std().unwrap() {
                         StateChange::NewState(ResendStates::Dead {
                             to_send: mem::replace(to_send, Vec::new()),
-                            start_time: Utc::now(),
+                            start_time: Instant::now(),
                         })
                     } else {
                         // Schedule timeout
-                        let dur = (*start_time + resender.config
-                                .stalling_timeout)
-                            .naive_utc()
-                            .signed_duration_since(now_naive);
-                        let next = Instant::now() + dur.to_std().unwrap();
+                        let next = *start_time + resender.config
+                            .stalling_timeout.to_std().unwrap();
                         self.state_timeout.reset(next);
                         if let futures::Async::Ready(()) =
                             self.state_timeout.poll()? {
@@ -703,16 +840,13 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
                         StateChange::Nothing
                     }
                 ResendStates::Dead { ref start_time, .. } =>
-                    if now_naive.signed_duration_since(start_time.naive_utc())
-                        >= resender.config.dead_timeout {
+                    if now >= *start_time + resender.config
+                        .dead_timeout.to_std().unwrap() {
                         StateChange::EndConnection
                     } else {
                         // Schedule timeout
-                        let dur = (*start_time + resender.config
-                                .dead_timeout)
-                            .naive_utc()
-                            .signed_duration_since(now_naive);
-                        let next = Instant::now() + dur.to_std().unwrap();
+                        let next = *start_time + resender.config
+                            .dead_timeout.to_std().unwrap();
                         self.state_timeout.reset(next);
                         if let futures::Async::Ready(()) =
                             self.state_timeout.poll()? {
@@ -721,16 +855,13 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
                         StateChange::Nothing
                     }
                 ResendStates::Disconnecting { ref start_time, .. } =>
-                    if now_naive.signed_duration_since(start_time.naive_utc())
-                        >= resender.config.disconnect_timeout {
+                    if now >= *start_time + resender.config
+                        .disconnect_timeout.to_std().unwrap() {
                         StateChange::EndConnection
                     } else {
                         // Schedule timeout
-                        let dur = (*start_time + resender.config
-                                .disconnect_timeout)
-                            .naive_utc()
-                            .signed_duration_since(now_naive);
-                        let next = Instant::now() + dur.to_std().unwrap();
+                        let next = *start_time + resender.config
+                            .disconnect_timeout.to_std().unwrap();
                         self.state_timeout.reset(next);
                         if let futures::Async::Ready(()) =
                             self.state_timeout.poll()? {
@@ -760,10 +891,14 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
         // Check if there are packets to send.
         // If there is no record, we will be notified by the sink.
         let mut switch_to_stalling = false;
-        let packet_interval = {
+        let (packet_interval, is_connecting) = {
             let con = &*con.borrow();
-            con.resender.state
-                .get_packet_interval(&con.resender.config)
+            if let ResendStates::Connecting { .. } = con.resender.state {
+                (Some(con.resender.connecting_interval()), true)
+            } else {
+                (con.resender.state.get_packet_interval(&con.resender.config),
+                    false)
+            }
         };
 
         // Retransmission timeout
@@ -775,18 +910,23 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
                 con.resender.srtt + con.resender.srtt_dev * 4
             }
         };
-        let last_threshold = now - rto;
+        let rto_std = rto.to_std().unwrap();
 
         while let Some(packet) = {
             let con = &mut *con.borrow_mut();
+            let config = &con.resender.config;
             let packet = if let Some(mut rec) = con.resender.state.peek_mut_next_record() {
+                // Back off while connecting
+                let rto_std = if is_connecting {
+                    config.connecting_resend_interval(rto, rec.tries).to_std()
+                        .unwrap()
+                } else {
+                    rto_std
+                };
                 // Check if we should resend this packet or not
-                if rec.tries != 0 && rec.last > last_threshold {
+                let next = rec.last + rto_std;
+                if rec.tries != 0 && next > now {
                     // Schedule next send
-                    let dur = rec.last
-                        .naive_utc()
-                        .signed_duration_since(last_threshold.naive_utc());
-                    let next = Instant::now() + dur.to_std().unwrap();
                     self.timeout.reset(next);
                     if let futures::Async::Ready(()) = self.timeout.poll()? {
                         task::current().notify();
@@ -835,11 +975,7 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
                     false
                 };
 
-                let next = now + rto;
-                let dur = next.naive_utc().signed_duration_since(
-                    now.naive_utc());
-
-                if is_normal_state && dur > con.resender.config.normal_timeout {
+                if is_normal_state && rto > con.resender.config.normal_timeout {
                     warn!(con.logger, "Max resend timeout exceeded";
                           "p_id" => rec.p_id);
                     // Switch connection to stalling state
@@ -852,11 +988,12 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
                 rec.tries += 1;
 
                 if rec.tries != 1 {
+                    con.stats.resent_packets += 1;
                     let to_s = if con.is_client { "S" } else { "C" };
                     warn!(con.logger, "Resend";
                         "p_id" => rec.p_id,
                         "tries" => rec.tries,
-                        "last" => %rec.last,
+                        "since_sent" => ?rec.sent.elapsed(),
                         "to" => to_s,
                     );
                 }
@@ -883,3 +1020,209 @@ impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
         Ok(futures::Async::NotReady)
     }
 }
+
+/// This future is running in parallel to the [`ResendFuture`] and sends ping
+/// packets to keep a client connection alive.
+///
+/// Pings are only sent in [`Normal`] mode, the intervals are randomized by
+/// [`ResendConfig::keepalive_jitter`].
+///
+/// [`ResendFuture`]: struct.ResendFuture.html
+/// [`Normal`]: enum.ResendState.html#variant.Normal
+/// [`ResendConfig::keepalive_jitter`]: struct.ResendConfig.html#structfield.keepalive_jitter
+pub struct KeepaliveFuture<CM: ConnectionManager + 'static> {
+    connection: Weak<RefCell<Connection<CM>>>,
+    sink: ::connection::Packets<CM>,
+    /// The future to wake us up when the next ping should be sent.
+    timeout: Timeout,
+    /// If we are sending and should poll the sink.
+    is_sending: bool,
+}
+
+impl<CM: ConnectionManager<Resend = DefaultResender> + 'static>
+    KeepaliveFuture<CM> {
+    pub fn new(
+        data: Rc<RefCell<Data<CM>>>,
+        connection_key: CM::ConnectionsKey,
+    ) -> Self {
+        let (handle, connection) = {
+            let data = data.borrow();
+            (data.handle.clone(),
+                data.connection_manager.get_connection(connection_key)
+                .unwrap())
+        };
+        let first = connection.borrow().resender.config.next_keepalive();
+        Self {
+            connection: Rc::downgrade(&connection),
+            sink: Connection::get_packets(connection),
+            timeout: Timeout::new(first.to_std().unwrap(), &handle).unwrap(),
+            is_sending: false,
+        }
+    }
+}
+
+impl<CM: ConnectionManager<Resend = DefaultResender> + 'static> Future for
+    KeepaliveFuture<CM> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        // Get connection
+        let con = if let Some(con) = self.connection.upgrade() {
+            con
+        } else {
+            // Quit if the connection does not exist anymore
+            return Ok(futures::Async::Ready(()));
+        };
+
+        if self.is_sending {
+            if let futures::Async::Ready(()) = self.sink.poll_complete()? {
+                self.is_sending = false;
+            } else {
+                return Ok(futures::Async::NotReady);
+            }
+        }
+
+        while let futures::Async::Ready(()) = self.timeout.poll()? {
+            let (next, send_ping) = {
+                let con = con.borrow();
+                let is_normal = if let ResendStates::Normal { .. } =
+                    con.resender.state {
+                    true
+                } else {
+                    false
+                };
+                (con.resender.config.next_keepalive(),
+                    con.is_client && is_normal)
+            };
+            self.timeout.reset(Instant::now() + next.to_std().unwrap());
+
+            if send_ping {
+                let packet = Packet::new(Header::new(PacketType::Ping),
+                    ::packets::Data::Ping);
+                if let futures::AsyncSink::NotReady(_) =
+                    self.sink.start_send(packet)? {
+                    // The sink is full, skip this ping, the next one will
+                    // follow soon.
+                    continue;
+                }
+                if let futures::Async::NotReady = self.sink.poll_complete()? {
+                    self.is_sending = true;
+                    return Ok(futures::Async::NotReady);
+                }
+            }
+        }
+
+        Ok(futures::Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use futures::{future, Future, Sink};
+    use slog;
+
+    use Error;
+    use connectionmanager::{Resender, ResenderEvent};
+    use packets::{PacketType, UdpPacket};
+    use resend::*;
+
+    #[test]
+    fn disconnect_with_full_queue() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut config = ResendConfig::default();
+        config.max_send_queue_len = 2;
+        let mut resender = DefaultResender::new(config, logger);
+        resender.handle_event(ResenderEvent::Connected);
+
+        // Run in a task, a full queue registers the current task
+        future::lazy(move || {
+            for i in 0..2 {
+                assert!(resender.start_send((PacketType::Command, i,
+                    UdpPacket(vec![0; 4])))?.is_ready());
+            }
+            assert!(resender.start_send((PacketType::Command, 2,
+                UdpPacket(vec![0; 4])))?.is_not_ready());
+
+            // The disconnect packet is accepted anyway
+            resender.handle_event(ResenderEvent::Disconnecting);
+            assert_eq!(resender.state(), ResendState::Disconnecting);
+            assert!(resender.start_send((PacketType::Command, 2,
+                UdpPacket(vec![0; 4])))?.is_ready());
+            assert_eq!(resender.queue_len(), 3);
+            Ok::<_, Error>(())
+        }).wait().unwrap();
+    }
+    #[test]
+    fn public_state() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut resender = DefaultResender::new(ResendConfig::default(),
+            logger);
+        assert_eq!(resender.state(), ResendState::Connecting);
+        resender.handle_event(ResenderEvent::Connected);
+        assert_eq!(resender.state(), ResendState::Normal);
+        assert_eq!(resender.queue_len(), 0);
+        resender.handle_event(ResenderEvent::Disconnecting);
+        assert_eq!(resender.state(), ResendState::Disconnecting);
+    }
+
+    #[test]
+    fn connecting_backoff() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut config = ResendConfig::default();
+        config.connecting_backoff = 2.0;
+        let interval = Duration::seconds(1);
+        assert_eq!(config.connecting_resend_interval(interval, 1), interval);
+        assert_eq!(config.connecting_resend_interval(interval, 2),
+            Duration::seconds(2));
+        // Limited by the connecting timeout
+        assert_eq!(config.connecting_resend_interval(interval, 10),
+            config.connecting_timeout);
+        assert_eq!(config.connecting_resend_interval(interval,
+            usize::max_value()), config.connecting_timeout);
+
+        // Wait longer on high latency links
+        let mut resender = DefaultResender::new(config, logger);
+        assert_eq!(resender.connecting_interval(), interval);
+        resender.update_srtt(Duration::milliseconds(700));
+        assert_eq!(resender.connecting_interval(),
+            Duration::milliseconds(1400));
+    }
+
+    #[test]
+    fn keepalive_jitter() {
+        let mut config = ResendConfig::default();
+        config.keepalive_interval = Duration::seconds(1);
+        for _ in 0..100 {
+            let next = config.next_keepalive();
+            assert!(next >= Duration::milliseconds(900));
+            assert!(next <= Duration::milliseconds(1100));
+        }
+
+        config.keepalive_jitter = 0.0;
+        assert_eq!(config.next_keepalive(), Duration::seconds(1));
+    }
+
+    #[test]
+    fn invalid_keepalive_config() {
+        let mut config = ResendConfig::default();
+        for &jitter in &[1.0, 5.0, -1.0, ::std::f32::NAN,
+            ::std::f32::INFINITY] {
+            config.keepalive_jitter = jitter;
+            for _ in 0..100 {
+                let next = config.next_keepalive();
+                assert!(next > Duration::zero());
+                assert!(next.to_std().is_ok());
+            }
+        }
+
+        // A zero interval would send pings in a busy loop
+        config.keepalive_interval = Duration::zero();
+        config.keepalive_jitter = 0.0;
+        assert_eq!(config.next_keepalive(), Duration::milliseconds(1));
+        config.keepalive_interval = Duration::seconds(-1);
+        config.keepalive_jitter = 0.5;
+        assert!(config.next_keepalive() >= Duration::milliseconds(1));
+    }
+}